@@ -36,10 +36,26 @@ pub struct BlobChangeset {
     revlogcs: RevlogChangeset,
 }
 
-fn cskey(nodeid: &NodeHash) -> String {
+/// The blobstore key the changeset with this hash is stored under.
+pub fn cskey(nodeid: &NodeHash) -> String {
     format!("changeset-{}.bincode", nodeid)
 }
 
+/// Recompute the hash of a changeset from the blob it is stored as. For an intact blob this is the
+/// hash it's keyed by, so comparing the two catches corruption that still deserializes.
+pub fn cs_content_hash(blob: &[u8]) -> Result<NodeHash> {
+    cs_blob_node(blob)?
+        .nodeid()
+        .ok_or_else(|| failure::err_msg("changeset blob has no content"))
+}
+
+/// The changeset text and parents a changeset is stored as, exactly as they were in the revlog.
+pub fn cs_blob_node(blob: &[u8]) -> Result<BlobNode> {
+    let RawCSBlob { parents, blob } = bincode::deserialize(blob)?;
+    let (p1, p2) = parents.get_nodes();
+    Ok(BlobNode::new(Blob::from(blob.into_owned()), p1, p2))
+}
+
 impl BlobChangeset {
     pub fn new(nodeid: &NodeHash, revlogcs: RevlogChangeset) -> Self {
         Self {
@@ -48,6 +64,10 @@ impl BlobChangeset {
         }
     }
 
+    pub fn nodeid(&self) -> &NodeHash {
+        &self.nodeid
+    }
+
     pub fn load<B>(
         blobstore: &B,
         nodeid: &NodeHash,
@@ -80,7 +100,20 @@ impl BlobChangeset {
         B::PutBlob: Send + 'static,
     {
         let key = cskey(&self.nodeid);
+        self.save_with_key(blobstore, key)
+    }
 
+    /// Like `save`, but stores the changeset under `key` instead of the usual key. `load` won't
+    /// be able to find changesets saved like this, so this is only for exporting to other stores.
+    pub fn save_with_key<B>(
+        &self,
+        blobstore: B,
+        key: String,
+    ) -> impl Future<Item = (), Error = Error> + Send + 'static
+    where
+        B: Blobstore + Send + 'static,
+        B::PutBlob: Send + 'static,
+    {
         self.revlogcs.get_node() // FIXME: generate from scratch
             .map_err(Error::from)
             .and_then(|node| {
@@ -40,6 +40,8 @@ extern crate memheads;
 extern crate memlinknodes;
 extern crate mercurial;
 extern crate mercurial_types;
+#[cfg(test)]
+extern crate mercurial_types_mocks;
 extern crate rocksblob;
 extern crate storage_types;
 
@@ -53,7 +55,7 @@ mod utils;
 
 pub use errors::*;
 
-pub use changeset::BlobChangeset;
+pub use changeset::{cs_blob_node, cs_content_hash, cskey, BlobChangeset};
 pub use manifest::BlobManifest;
 pub use repo::BlobRepo;
 pub use state::{BlobState, FilesBlobState, MemBlobState, RocksBlobState, TestManifoldBlobState};
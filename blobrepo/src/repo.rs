@@ -9,7 +9,7 @@ use std::mem;
 use std::sync::Arc;
 
 use futures::{Async, Poll};
-use futures::future::Future;
+use futures::future::{self, Future};
 use futures::stream::{self, Stream};
 use futures_ext::{BoxFuture, BoxStream, FutureExt, StreamExt};
 
@@ -43,6 +43,26 @@ where
     pub fn get_blob(&self, key: &NodeHash) -> BoxFuture<Vec<u8>, Error> {
         fetch_blob_from_blobstore(self.inner.blobstore().clone(), *key)
     }
+
+    /// Look up a bookmark, along with whether the changeset it points at is currently a head.
+    pub fn resolve_with_head_status(
+        &self,
+        name: &[u8],
+    ) -> BoxFuture<Option<(NodeHash, bool)>, Error> {
+        let inner = self.inner.clone();
+        self.inner
+            .bookmarks()
+            .get(&name)
+            .and_then(move |value| match value {
+                Some((hash, _version)) => inner
+                    .heads()
+                    .is_head(&hash)
+                    .map(move |is_head| Some((hash, is_head)))
+                    .boxify(),
+                None => future::ok(None).boxify(),
+            })
+            .boxify()
+    }
 }
 
 impl<State> Repo for BlobRepo<State>
@@ -175,3 +195,46 @@ where
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use bookmarks::BookmarksMut;
+    use memblob::Memblob;
+    use membookmarks::MemBookmarks;
+    use memheads::MemHeads;
+    use memlinknodes::MemLinknodes;
+    use mercurial_types_mocks::nodehash::*;
+
+    use MemBlobState;
+
+    #[test]
+    fn resolve_with_head_status() {
+        let heads = MemHeads::new();
+        let bookmarks = MemBookmarks::new();
+        heads.add(&ONES_HASH).wait().unwrap();
+        bookmarks.create(&"on-head", &ONES_HASH).wait().unwrap();
+        bookmarks.create(&"not-on-head", &TWOS_HASH).wait().unwrap();
+
+        let repo = BlobRepo::new(MemBlobState::new(
+            heads,
+            bookmarks,
+            Memblob::new(),
+            MemLinknodes::new(),
+        ));
+
+        assert_eq!(
+            repo.resolve_with_head_status(b"on-head").wait().unwrap(),
+            Some((ONES_HASH, true))
+        );
+        assert_eq!(
+            repo.resolve_with_head_status(b"not-on-head").wait().unwrap(),
+            Some((TWOS_HASH, false))
+        );
+        assert_eq!(
+            repo.resolve_with_head_status(b"missing").wait().unwrap(),
+            None
+        );
+    }
+}
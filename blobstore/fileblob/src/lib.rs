@@ -10,25 +10,43 @@ extern crate bytes;
 #[macro_use]
 extern crate failure;
 extern crate futures;
+#[macro_use]
 extern crate url;
 
 extern crate blobstore;
 extern crate futures_ext;
 
-use std::fs::{create_dir_all, File};
+use std::collections::HashSet;
+use std::collections::hash_map::RandomState;
+use std::fs::{self, create_dir_all, File};
+use std::hash::{BuildHasher, Hasher};
 use std::io::{self, Read, Write};
+use std::mem;
 use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering, ATOMIC_USIZE_INIT};
 
 use bytes::Bytes;
 use failure::Error;
-use futures::Async;
-use futures::future::poll_fn;
-use futures_ext::{BoxFuture, FutureExt};
-use url::percent_encoding::{percent_encode, DEFAULT_ENCODE_SET};
+use futures::{Async, Future};
+use futures::future::{lazy, poll_fn};
+use futures::stream::{self, Stream};
+use futures_ext::{BoxFuture, BoxStream, FutureExt, StreamExt};
+use url::percent_encoding::{percent_decode, percent_encode, DEFAULT_ENCODE_SET};
 
-use blobstore::Blobstore;
+use blobstore::{Blobstore, ErrorKind};
 
 const PREFIX: &str = "blob";
+// Blobs being written by put_stream. These don't start with PREFIX, so they aren't listed as keys.
+const PARTIAL_PREFIX: &str = "partial";
+
+// Numbers the blobs being written in this process, so that each gets its own partial file.
+static PARTIAL_COUNTER: AtomicUsize = ATOMIC_USIZE_INIT;
+
+define_encode_set! {
+    /// Keys in file names. `%` is encoded too, so that decoding a file name gives back the key.
+    pub KEY_ENCODE_SET = [DEFAULT_ENCODE_SET] | {'%'}
+}
 
 pub type Result<T> = std::result::Result<T, Error>;
 
@@ -41,6 +59,8 @@ macro_rules! bail {
 #[derive(Debug, Clone)]
 pub struct Fileblob {
     base: PathBuf,
+    // Blobs written since the last flush, which still need to be fsynced.
+    unsynced: Arc<Mutex<HashSet<PathBuf>>>,
 }
 
 impl Fileblob {
@@ -53,6 +73,7 @@ impl Fileblob {
 
         Ok(Self {
             base: base.to_owned(),
+            unsynced: Arc::new(Mutex::new(HashSet::new())),
         })
     }
 
@@ -63,9 +84,22 @@ impl Fileblob {
     }
 
     fn path(&self, key: &String) -> PathBuf {
-        let key = percent_encode(key.as_bytes(), DEFAULT_ENCODE_SET);
+        let key = percent_encode(key.as_bytes(), KEY_ENCODE_SET);
         self.base.join(format!("{}-{}", PREFIX, key))
     }
+
+    /// A new path to write `key` to before moving it into place. Every call returns a different
+    /// one, so that concurrent writes of the same key don't share a file: the counter tells
+    /// apart the writes in this process, and the random token those of other processes using
+    /// the same directory.
+    fn partial_path(&self, key: &String) -> PathBuf {
+        let key = percent_encode(key.as_bytes(), KEY_ENCODE_SET);
+        // RandomState is keyed randomly, which is all the randomness this needs.
+        let token = RandomState::new().build_hasher().finish();
+        let count = PARTIAL_COUNTER.fetch_add(1, Ordering::Relaxed);
+        self.base
+            .join(format!("{}-{:016x}-{}-{}", PARTIAL_PREFIX, token, count, key))
+    }
 }
 
 impl Blobstore for Fileblob {
@@ -91,10 +125,165 @@ impl Blobstore for Fileblob {
 
     fn put(&self, key: String, val: Bytes) -> Self::PutBlob {
         let p = self.path(&key);
+        let unsynced = self.unsynced.clone();
 
         poll_fn(move || {
             File::create(&p)?.write_all(val.as_ref())?;
+            unsynced.lock().expect("lock poison").insert(p.clone());
             Ok(Async::Ready(()))
         }).boxify()
     }
+
+    fn put_stream(
+        &self,
+        key: String,
+        body: BoxStream<Bytes, Error>,
+        _len_hint: Option<usize>,
+    ) -> BoxFuture<(), Error> {
+        let p = self.path(&key);
+        let partial = self.partial_path(&key);
+        let unsynced = self.unsynced.clone();
+
+        // Write the chunks to the side as they arrive, and only move the blob into place once
+        // it's complete, so that a failed stream doesn't leave a truncated blob behind.
+        let create_partial = partial.clone();
+        lazy(move || File::create(&create_partial).map_err(Error::from))
+            .and_then(move |f| {
+                body.fold(f, |mut f, chunk| {
+                    f.write_all(chunk.as_ref())?;
+                    Ok::<_, Error>(f)
+                })
+            })
+            .then(move |res| -> Result<()> {
+                match res {
+                    Ok(_) => fs::rename(&partial, &p)?,
+                    Err(e) => {
+                        let _ = fs::remove_file(&partial);
+                        return Err(e);
+                    }
+                }
+                unsynced.lock().expect("lock poison").insert(p);
+                Ok(())
+            })
+            .boxify()
+    }
+
+    fn exists(&self, key: String) -> BoxFuture<bool, Error> {
+        let p = self.path(&key);
+
+        poll_fn(move || {
+            let ret = match fs::metadata(&p) {
+                Err(ref e) if e.kind() == io::ErrorKind::NotFound => false,
+                Err(e) => return Err(e.into()),
+                Ok(_) => true,
+            };
+            Ok(Async::Ready(ret))
+        }).boxify()
+    }
+
+    fn put_if_absent(&self, key: String, val: Bytes) -> BoxFuture<bool, Error> {
+        let p = self.path(&key);
+        let partial = self.partial_path(&key);
+        let unsynced = self.unsynced.clone();
+
+        poll_fn(move || {
+            // Write the blob to the side and then link it into place, so that readers never see
+            // a partial blob and a failed write leaves nothing behind. The filesystem makes the
+            // link fail if the key already exists, so there's no window for another writer.
+            let res = File::create(&partial)
+                .and_then(|mut f| f.write_all(val.as_ref()))
+                .and_then(|()| fs::hard_link(&partial, &p));
+            let _ = fs::remove_file(&partial);
+            match res {
+                Err(ref e) if e.kind() == io::ErrorKind::AlreadyExists => Ok(Async::Ready(false)),
+                Err(e) => Err(e.into()),
+                Ok(()) => {
+                    unsynced.lock().expect("lock poison").insert(p.clone());
+                    Ok(Async::Ready(true))
+                }
+            }
+        }).boxify()
+    }
+
+    fn copy(&self, src_key: String, dst_key: String) -> BoxFuture<(), Error> {
+        let src = self.path(&src_key);
+        let dst = self.path(&dst_key);
+        let partial = self.partial_path(&dst_key);
+        let unsynced = self.unsynced.clone();
+
+        poll_fn(move || {
+            // Like put_stream, copy to the side first so that a failed copy leaves nothing behind.
+            match fs::copy(&src, &partial) {
+                Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+                    let msg = format!("copy of missing blob {}", src_key);
+                    return Err(ErrorKind::StorageError(msg).into());
+                }
+                Err(e) => {
+                    let _ = fs::remove_file(&partial);
+                    return Err(e.into());
+                }
+                Ok(_) => fs::rename(&partial, &dst)?,
+            };
+            unsynced.lock().expect("lock poison").insert(dst.clone());
+            Ok(Async::Ready(()))
+        }).boxify()
+    }
+
+    fn delete(&self, key: String) -> BoxFuture<(), Error> {
+        let p = self.path(&key);
+
+        poll_fn(move || {
+            match fs::remove_file(&p) {
+                Err(ref e) if e.kind() == io::ErrorKind::NotFound => (),
+                Err(e) => return Err(e.into()),
+                Ok(()) => (),
+            };
+            Ok(Async::Ready(()))
+        }).boxify()
+    }
+
+    fn flush(&self) -> BoxFuture<(), Error> {
+        let base = self.base.clone();
+        let unsynced = self.unsynced.clone();
+
+        poll_fn(move || {
+            let paths = mem::replace(&mut *unsynced.lock().expect("lock poison"), HashSet::new());
+            for p in paths {
+                match File::open(&p) {
+                    // Deleted since it was written, so there's nothing to sync.
+                    Err(ref e) if e.kind() == io::ErrorKind::NotFound => (),
+                    Err(e) => return Err(e.into()),
+                    Ok(f) => f.sync_all()?,
+                }
+            }
+            // Make sure the directory entries for new blobs are durable too.
+            File::open(&base)?.sync_all()?;
+            Ok(Async::Ready(()))
+        }).boxify()
+    }
+
+    fn keys(&self) -> BoxStream<String, Error> {
+        let prefix = format!("{}-", PREFIX);
+
+        let names = fs::read_dir(&self.base).map(move |entries| {
+            entries.filter_map(move |entry| {
+                let name = match entry {
+                    Ok(entry) => entry.file_name().to_string_lossy().into_owned(),
+                    Err(err) => return Some(Err(Error::from(err))),
+                };
+                if !name.starts_with(&prefix) {
+                    return None;
+                }
+                let key = percent_decode(name[prefix.len()..].as_bytes())
+                    .decode_utf8()
+                    .map(|key| key.into_owned())
+                    .map_err(Error::from);
+                Some(key)
+            })
+        });
+        match names {
+            Ok(v) => stream::iter_ok(v).and_then(|x| x).boxify(),
+            Err(e) => stream::once(Err(e.into())).boxify(),
+        }
+    }
 }
@@ -11,15 +11,19 @@ extern crate blobstore;
 extern crate bytes;
 extern crate failure;
 extern crate futures;
+extern crate futures_ext;
 
 use std::collections::HashMap;
 use std::sync::{Arc, Mutex};
 
 use bytes::Bytes;
 use failure::Error;
-use futures::future::{FutureResult, IntoFuture};
+use futures::Future;
+use futures::future::{err, ok, FutureResult, IntoFuture};
+use futures::stream::iter_ok;
+use futures_ext::{BoxFuture, BoxStream, FutureExt, StreamExt};
 
-use blobstore::Blobstore;
+use blobstore::{collect_body, Blobstore, ErrorKind};
 
 /// In-memory "blob store"
 ///
@@ -53,4 +57,63 @@ impl Blobstore for Memblob {
 
         Ok(inner.get(&k).map(Clone::clone)).into_future()
     }
+
+    fn exists(&self, k: String) -> BoxFuture<bool, Error> {
+        let inner = self.hash.lock().expect("lock poison");
+
+        ok(inner.contains_key(&k)).boxify()
+    }
+
+    fn put_if_absent(&self, k: String, v: Bytes) -> BoxFuture<bool, Error> {
+        let mut inner = self.hash.lock().expect("lock poison");
+
+        if inner.contains_key(&k) {
+            ok(false).boxify()
+        } else {
+            inner.insert(k, v);
+            ok(true).boxify()
+        }
+    }
+
+    fn put_stream(
+        &self,
+        k: String,
+        body: BoxStream<Bytes, Error>,
+        len_hint: Option<usize>,
+    ) -> BoxFuture<(), Error> {
+        let this = self.clone();
+        collect_body(body, len_hint)
+            .and_then(move |v| this.put(k, v))
+            .boxify()
+    }
+
+    fn copy(&self, src: String, dst: String) -> BoxFuture<(), Error> {
+        let mut inner = self.hash.lock().expect("lock poison");
+
+        let v = inner.get(&src).cloned();
+        match v {
+            Some(v) => {
+                inner.insert(dst, v);
+                ok(()).boxify()
+            }
+            None => {
+                let msg = format!("copy of missing blob {}", src);
+                err(ErrorKind::StorageError(msg).into()).boxify()
+            }
+        }
+    }
+
+    fn delete(&self, k: String) -> BoxFuture<(), Error> {
+        let mut inner = self.hash.lock().expect("lock poison");
+
+        inner.remove(&k);
+        ok(()).boxify()
+    }
+
+    fn keys(&self) -> BoxStream<String, Error> {
+        let inner = self.hash.lock().expect("lock poison");
+
+        let keys: Vec<_> = inner.keys().cloned().collect();
+        iter_ok(keys).boxify()
+    }
 }
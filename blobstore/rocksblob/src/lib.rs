@@ -11,23 +11,30 @@ extern crate failure;
 extern crate futures;
 
 extern crate blobstore;
+extern crate futures_ext;
 extern crate rocksdb;
 
 use std::path::Path;
+use std::sync::{Arc, Mutex};
 
 use bytes::Bytes;
 use failure::Error;
 use futures::{Async, Future, Poll};
+use futures::future::poll_fn;
+use futures_ext::{BoxFuture, BoxStream, FutureExt};
 
-use rocksdb::{Db, ReadOptions, WriteOptions};
+use rocksdb::{Db, FlushOptions, ReadOptions, WriteOptions};
 
-use blobstore::Blobstore;
+use blobstore::{collect_body, get_then_put, Blobstore};
 
 pub type Result<T> = std::result::Result<T, Error>;
 
 #[derive(Clone)]
 pub struct Rocksblob {
     db: Db,
+    // Held across the check and the write in put_if_absent. RocksDB has no conditional put, so
+    // this only makes it atomic with respect to other users of this Rocksblob.
+    put_if_absent_lock: Arc<Mutex<()>>,
 }
 
 impl Rocksblob {
@@ -48,6 +55,7 @@ impl Rocksblob {
 
         Ok(Rocksblob {
             db: Db::open(path, opts)?,
+            put_if_absent_lock: Arc::new(Mutex::new(())),
         })
     }
 }
@@ -96,4 +104,50 @@ impl Blobstore for Rocksblob {
 
         PutBlob(db, key, val)
     }
+
+    fn put_if_absent(&self, key: String, val: Bytes) -> BoxFuture<bool, Error> {
+        let db = self.db.clone();
+        let lock = self.put_if_absent_lock.clone();
+
+        poll_fn(move || {
+            let _guard = lock.lock().expect("lock poison");
+            let rdopts = ReadOptions::new();
+            if db.get(&key, &rdopts).map_err(Error::from)?.is_some() {
+                return Ok(Async::Ready(false));
+            }
+            let wropts = WriteOptions::new().set_sync(false);
+            db.put(&key, &val, &wropts).map_err(Error::from)?;
+            Ok(Async::Ready(true))
+        }).boxify()
+    }
+
+    fn put_stream(
+        &self,
+        key: String,
+        body: BoxStream<Bytes, Error>,
+        len_hint: Option<usize>,
+    ) -> BoxFuture<(), Error> {
+        let db = self.db.clone();
+
+        // RocksDB can't take a value in pieces, so this still buffers the whole blob.
+        collect_body(body, len_hint)
+            .and_then(move |val| PutBlob(db, key, val))
+            .boxify()
+    }
+
+    fn copy(&self, src_key: String, dst_key: String) -> BoxFuture<(), Error> {
+        get_then_put(self.clone(), src_key, dst_key)
+    }
+
+    fn flush(&self) -> BoxFuture<(), Error> {
+        let db = self.db.clone();
+
+        // Puts are written without syncing the WAL, so flush the memtables and wait for that
+        // to finish to make them durable.
+        poll_fn(move || {
+            let flushopts = FlushOptions::new().set_wait(true);
+            db.flush(&flushopts).map_err(Error::from)?;
+            Ok(Async::Ready(()))
+        }).boxify()
+    }
 }
@@ -4,12 +4,13 @@
 // This software may be used and distributed according to the terms of the
 // GNU General Public License version 2 or any later version.
 
+use std::collections::HashMap;
 use std::sync::Arc;
 
 use bytes::Bytes;
 use failure::Error;
 
-use futures_ext::{BoxFuture, FutureExt};
+use futures_ext::{BoxFuture, BoxStream, FutureExt};
 
 use super::*;
 
@@ -66,4 +67,41 @@ where
     fn put(&self, key: String, value: Bytes) -> Self::PutBlob {
         self.blobstore.put(key, value).boxify()
     }
+
+    fn get_batch(&self, keys: Vec<String>) -> BoxFuture<HashMap<String, Option<Bytes>>, Error> {
+        self.blobstore.get_batch(keys)
+    }
+
+    fn exists(&self, key: String) -> BoxFuture<bool, Error> {
+        self.blobstore.exists(key)
+    }
+
+    fn put_if_absent(&self, key: String, value: Bytes) -> BoxFuture<bool, Error> {
+        self.blobstore.put_if_absent(key, value)
+    }
+
+    fn put_stream(
+        &self,
+        key: String,
+        body: BoxStream<Bytes, Error>,
+        len_hint: Option<usize>,
+    ) -> BoxFuture<(), Error> {
+        self.blobstore.put_stream(key, body, len_hint)
+    }
+
+    fn copy(&self, src_key: String, dst_key: String) -> BoxFuture<(), Error> {
+        self.blobstore.copy(src_key, dst_key)
+    }
+
+    fn delete(&self, key: String) -> BoxFuture<(), Error> {
+        self.blobstore.delete(key)
+    }
+
+    fn keys(&self) -> BoxStream<String, Error> {
+        self.blobstore.keys()
+    }
+
+    fn flush(&self) -> BoxFuture<(), Error> {
+        self.blobstore.flush()
+    }
 }
@@ -0,0 +1,141 @@
+// Copyright (c) 2004-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+use std::collections::HashMap;
+
+use bytes::Bytes;
+use failure::{Error, ResultExt};
+use futures::{Future, IntoFuture};
+use zstd;
+
+use futures_ext::{BoxFuture, BoxStream, FutureExt};
+
+use super::*;
+
+/// Header put in front of every compressed blob: a magic string followed by a format version.
+const HEADER: &[u8] = b"\0MZC\x01";
+
+/// The zstd compression level used by `CompressingBlobstore::new`.
+pub const DEFAULT_COMPRESSION_LEVEL: i32 = 3;
+
+/// A blobstore that zstd-compresses values on the way into the underlying blobstore, and
+/// decompresses them on the way out.
+///
+/// Compressed values are marked with a small header. Values without it are returned as they are,
+/// so a store that already has uncompressed blobs in it can be switched over without rewriting
+/// them.
+pub struct CompressingBlobstore<B> {
+    blobstore: B,
+    level: i32,
+}
+
+impl<B> CompressingBlobstore<B> {
+    pub fn new(blobstore: B) -> Self {
+        Self::with_level(blobstore, DEFAULT_COMPRESSION_LEVEL)
+    }
+
+    /// Compress with the given zstd level, from 1 (fastest) to 22 (smallest).
+    pub fn with_level(blobstore: B, level: i32) -> Self {
+        CompressingBlobstore { blobstore, level }
+    }
+}
+
+fn compress(value: &[u8], level: i32) -> Result<Bytes, Error> {
+    let compressed = zstd::encode_all(value, level).context("compressing blob")?;
+    let mut out = Vec::with_capacity(HEADER.len() + compressed.len());
+    out.extend_from_slice(HEADER);
+    out.extend_from_slice(&compressed);
+    Ok(out.into())
+}
+
+fn decompress(value: Bytes) -> Result<Bytes, Error> {
+    if !value.starts_with(HEADER) {
+        return Ok(value);
+    }
+    let decompressed = zstd::decode_all(&value[HEADER.len()..]).context("decompressing blob")?;
+    Ok(decompressed.into())
+}
+
+fn decompress_opt(value: Option<Bytes>) -> Result<Option<Bytes>, Error> {
+    match value {
+        Some(value) => decompress(value).map(Some),
+        None => Ok(None),
+    }
+}
+
+impl<B> Blobstore for CompressingBlobstore<B>
+where
+    B: Blobstore,
+{
+    type GetBlob = BoxFuture<Option<Bytes>, Error>;
+    type PutBlob = BoxFuture<(), Error>;
+
+    fn get(&self, key: String) -> Self::GetBlob {
+        self.blobstore.get(key).and_then(decompress_opt).boxify()
+    }
+
+    fn put(&self, key: String, value: Bytes) -> Self::PutBlob {
+        match compress(&value, self.level) {
+            Ok(value) => self.blobstore.put(key, value).boxify(),
+            Err(err) => Err(err).into_future().boxify(),
+        }
+    }
+
+    fn get_batch(&self, keys: Vec<String>) -> BoxFuture<HashMap<String, Option<Bytes>>, Error> {
+        self.blobstore
+            .get_batch(keys)
+            .and_then(|values| {
+                values
+                    .into_iter()
+                    .map(|(key, value)| decompress_opt(value).map(|value| (key, value)))
+                    .collect()
+            })
+            .boxify()
+    }
+
+    fn exists(&self, key: String) -> BoxFuture<bool, Error> {
+        self.blobstore.exists(key)
+    }
+
+    fn put_if_absent(&self, key: String, value: Bytes) -> BoxFuture<bool, Error> {
+        match compress(&value, self.level) {
+            Ok(value) => self.blobstore.put_if_absent(key, value),
+            Err(err) => Err(err).into_future().boxify(),
+        }
+    }
+
+    fn put_stream(
+        &self,
+        key: String,
+        body: BoxStream<Bytes, Error>,
+        len_hint: Option<usize>,
+    ) -> BoxFuture<(), Error> {
+        // zstd needs the whole blob, so the underlying blobstore gets it compressed in one chunk.
+        // Its size isn't known until then.
+        let level = self.level;
+        let compressed = collect_body(body, len_hint)
+            .and_then(move |value| compress(&value, level))
+            .into_stream()
+            .boxify();
+        self.blobstore.put_stream(key, compressed, None)
+    }
+
+    fn copy(&self, src_key: String, dst_key: String) -> BoxFuture<(), Error> {
+        self.blobstore.copy(src_key, dst_key)
+    }
+
+    fn delete(&self, key: String) -> BoxFuture<(), Error> {
+        self.blobstore.delete(key)
+    }
+
+    fn keys(&self) -> BoxStream<String, Error> {
+        self.blobstore.keys()
+    }
+
+    fn flush(&self) -> BoxFuture<(), Error> {
+        self.blobstore.flush()
+    }
+}
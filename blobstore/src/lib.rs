@@ -8,20 +8,42 @@
 
 extern crate bytes;
 extern crate failure;
+#[macro_use]
+extern crate failure_derive;
 extern crate futures;
 extern crate futures_ext;
 extern crate tokio_core;
+extern crate zstd;
 
+use std::collections::HashMap;
 use std::sync::Arc;
 
 use bytes::Bytes;
 
 use failure::Error;
-use futures::Future;
+use futures::{future, stream, Future, Stream};
+
+use futures_ext::{BoxFuture, BoxStream, FutureExt, StreamExt};
 
 mod boxed;
+mod compress;
+mod metered;
+mod prefix;
+mod scrub;
+mod tiered;
 
 pub use boxed::{ArcBlobstore, BoxBlobstore};
+pub use compress::{CompressingBlobstore, DEFAULT_COMPRESSION_LEVEL};
+pub use metered::{AccessMetrics, MeteredBlobstore};
+pub use prefix::PrefixBlobstore;
+pub use scrub::{scrub, ScrubDifference};
+pub use tiered::TieredBlobstore;
+
+#[derive(Debug, Fail)]
+pub enum ErrorKind {
+    #[fail(display = "blobstore storage error: {}", _0)] StorageError(String),
+    #[fail(display = "{} is not supported by this blobstore", _0)] Unsupported(&'static str),
+}
 
 /// Basic trait for the Blob Store interface
 ///
@@ -41,9 +63,9 @@ pub use boxed::{ArcBlobstore, BoxBlobstore};
 // to check that the blob integrity is OK, even if we don't actually fetch the data.
 //
 // Delete blob?
-// I'll avoid delete for now. The current design for Mononoke doesn't need delete for normal
-// operations. If delete is going to be needed then it will be some maintenance operation like gc,
-// but that opens up a whole pile of other design questions that we haven't got to yet.
+// The current design for Mononoke doesn't need delete for normal operations. `delete` and `keys`
+// exist for maintenance operations like gc and for tooling, so they're optional: append-only
+// backends just fail them with `ErrorKind::Unsupported`.
 //
 // Metadata?
 // Will definitely need some kind of metadata interface. The open questions there are:
@@ -60,13 +82,15 @@ pub use boxed::{ArcBlobstore, BoxBlobstore};
 // The interface is async so that clients can issue lots of discrete ops to keep the pipeline full.
 // An implementation can have batching under the covers if it makes sense. In general I find
 // batching is a design antipattern that should be avoided. (Manifold also avoids batching in
-// favour of lots of concurrent requests.)
+// favour of lots of concurrent requests.) `get_batch` exists for the few callers that fetch lots
+// of keys at once, and by default it's just concurrent `get`s.
 //
 // Consistency guarantees?
 // I'm not sure about what consistency guarantees to make at this interface level. I'm tempted to
 // make them fairly strong, so that an implementation based on a store with weaker consistency is
 // responsible for implementing strong consistency. At the very least:
-// - successful `put` response means that the data is durable
+// - successful `put` response means that the data is durable, or at least will be once a
+//   subsequent `flush` succeeds
 // - puts are atomic, last put wins (no tearing, ordering determined by implementation)
 // - single gets are atomic (no tearing), partial/range gets use generation number for consistency
 // - `get` can return stale data for a bounded time (but strong put-get consistency would be
@@ -84,7 +108,8 @@ pub use boxed::{ArcBlobstore, BoxBlobstore};
 // a bug-finding consistency check.
 //
 // How to deal with very large objects?
-// - streaming get/put?
+// - streaming get/put? `put_stream` exists for writers that don't want to hold a whole large blob
+//   in memory.
 // - range get/put? (how does range put work? put-put-put-commit?)
 pub trait Blobstore: Send + 'static {
     type GetBlob: Future<Item = Option<Bytes>, Error = Error> + Send + 'static;
@@ -93,6 +118,77 @@ pub trait Blobstore: Send + 'static {
     fn get(&self, key: String) -> Self::GetBlob;
     fn put(&self, key: String, value: Bytes) -> Self::PutBlob;
 
+    /// Fetch several blobs at once. Every requested key is present in the result, mapped to
+    /// `None` if the blob doesn't exist.
+    fn get_batch(&self, keys: Vec<String>) -> BoxFuture<HashMap<String, Option<Bytes>>, Error> {
+        let gets: Vec<_> = keys.into_iter()
+            .map(|key| self.get(key.clone()).map(move |value| (key, value)))
+            .collect();
+        future::join_all(gets)
+            .map(|values| values.into_iter().collect())
+            .boxify()
+    }
+
+    /// Check whether a blob exists. Backends that can do this without fetching the blob should
+    /// override this.
+    fn exists(&self, key: String) -> BoxFuture<bool, Error> {
+        self.get(key).map(|value| value.is_some()).boxify()
+    }
+
+    /// Store a blob only if there isn't one with this key already, and report whether it was
+    /// stored. This is what concurrent writers should use to avoid clobbering each other.
+    ///
+    /// The generic way is `check_then_put`, but that needs a handle on the blobstore that outlives
+    /// this call, which a default that also works through `BoxBlobstore` can't have. So backends
+    /// that can do it atomically override this, the ones that can't but are cheap to clone
+    /// override it with `check_then_put`, and the rest fail with `ErrorKind::Unsupported`.
+    fn put_if_absent(&self, _key: String, _value: Bytes) -> BoxFuture<bool, Error> {
+        future::err(ErrorKind::Unsupported("put_if_absent").into()).boxify()
+    }
+
+    /// Store a blob whose contents arrive in chunks, without having to hold all of it in
+    /// memory first. `len_hint` is the total size, if the caller knows it. The blob only becomes
+    /// visible once the whole stream has been written.
+    ///
+    /// Backends that can write as the chunks arrive override this. Others can gather the body with
+    /// `collect_body` and `put` it, if they can keep a clone of themselves around until it's in.
+    /// The rest fail with `ErrorKind::Unsupported`.
+    fn put_stream(
+        &self,
+        _key: String,
+        _body: BoxStream<Bytes, Error>,
+        _len_hint: Option<usize>,
+    ) -> BoxFuture<(), Error> {
+        future::err(ErrorKind::Unsupported("put_stream").into()).boxify()
+    }
+
+    /// Store the blob at `src_key` under `dst_key` as well, for re-keying blobs without the
+    /// contents having to go through the caller. Copying a blob that doesn't exist is an
+    /// `ErrorKind::StorageError`.
+    ///
+    /// The fallback is `get_then_put`, which needs its own handle on the blobstore for the put, so
+    /// it can't be the default. Backends that can copy in place override this, the ones that are
+    /// cheap to clone override it with `get_then_put`, and wrappers forward it.
+    fn copy(&self, _src_key: String, _dst_key: String) -> BoxFuture<(), Error> {
+        future::err(ErrorKind::Unsupported("copy").into()).boxify()
+    }
+
+    /// Remove a blob. Deleting a key that doesn't exist is not an error.
+    fn delete(&self, _key: String) -> BoxFuture<(), Error> {
+        future::err(ErrorKind::Unsupported("delete").into()).boxify()
+    }
+
+    /// List all the keys in the blobstore, in no particular order.
+    fn keys(&self) -> BoxStream<String, Error> {
+        stream::once(Err(ErrorKind::Unsupported("keys").into())).boxify()
+    }
+
+    /// Make sure that all previously completed `put`s are durable. Backends that buffer writes
+    /// must override this; for everything else it's a no-op.
+    fn flush(&self) -> BoxFuture<(), Error> {
+        future::ok(()).boxify()
+    }
+
     fn boxed(self) -> BoxBlobstore
     where
         Self: Sized,
@@ -108,6 +204,57 @@ pub trait Blobstore: Send + 'static {
     }
 }
 
+/// Collect the chunks of a `put_stream` body into a single blob, for backends that can only store
+/// whole blobs.
+pub fn collect_body(
+    body: BoxStream<Bytes, Error>,
+    len_hint: Option<usize>,
+) -> BoxFuture<Bytes, Error> {
+    body.fold(Vec::with_capacity(len_hint.unwrap_or(0)), |mut blob, chunk| {
+        blob.extend_from_slice(&chunk);
+        Ok::<_, Error>(blob)
+    }).map(Bytes::from)
+        .boxify()
+}
+
+/// `Blobstore::put_if_absent` for backends that can't do it atomically: check whether the key
+/// exists, and put the blob if it doesn't. This isn't atomic, so if another writer puts the same
+/// key between the check and the put, both report having stored it and the last put wins. That
+/// is harmless when blobs are keyed by their contents, and a race otherwise.
+pub fn check_then_put<B>(blobstore: B, key: String, value: Bytes) -> BoxFuture<bool, Error>
+where
+    B: Blobstore,
+{
+    blobstore
+        .exists(key.clone())
+        .and_then(move |exists| {
+            if exists {
+                future::ok(false).boxify()
+            } else {
+                blobstore.put(key, value).map(|()| true).boxify()
+            }
+        })
+        .boxify()
+}
+
+/// Copy a blob by getting it and putting it back under the new key, for `Blobstore::copy` on
+/// backends that have no better way.
+pub fn get_then_put<B>(blobstore: B, src_key: String, dst_key: String) -> BoxFuture<(), Error>
+where
+    B: Blobstore,
+{
+    blobstore
+        .get(src_key.clone())
+        .and_then(move |value| match value {
+            Some(value) => blobstore.put(dst_key, value).boxify(),
+            None => {
+                let msg = format!("copy of missing blob {}", src_key);
+                future::err(ErrorKind::StorageError(msg).into()).boxify()
+            }
+        })
+        .boxify()
+}
+
 impl<GB, PB> Blobstore for Arc<Blobstore<GetBlob = GB, PutBlob = PB> + Sync>
 where
     GB: Future<Item = Option<Bytes>, Error = Error> + Send + 'static,
@@ -123,6 +270,43 @@ where
     fn put(&self, key: String, val: Bytes) -> Self::PutBlob {
         self.as_ref().put(key, val)
     }
+
+    fn get_batch(&self, keys: Vec<String>) -> BoxFuture<HashMap<String, Option<Bytes>>, Error> {
+        self.as_ref().get_batch(keys)
+    }
+
+    fn exists(&self, key: String) -> BoxFuture<bool, Error> {
+        self.as_ref().exists(key)
+    }
+
+    fn put_if_absent(&self, key: String, value: Bytes) -> BoxFuture<bool, Error> {
+        self.as_ref().put_if_absent(key, value)
+    }
+
+    fn put_stream(
+        &self,
+        key: String,
+        body: BoxStream<Bytes, Error>,
+        len_hint: Option<usize>,
+    ) -> BoxFuture<(), Error> {
+        self.as_ref().put_stream(key, body, len_hint)
+    }
+
+    fn copy(&self, src_key: String, dst_key: String) -> BoxFuture<(), Error> {
+        self.as_ref().copy(src_key, dst_key)
+    }
+
+    fn delete(&self, key: String) -> BoxFuture<(), Error> {
+        self.as_ref().delete(key)
+    }
+
+    fn keys(&self) -> BoxStream<String, Error> {
+        self.as_ref().keys()
+    }
+
+    fn flush(&self) -> BoxFuture<(), Error> {
+        self.as_ref().flush()
+    }
 }
 
 impl<GB, PB> Blobstore for Box<Blobstore<GetBlob = GB, PutBlob = PB>>
@@ -140,4 +324,41 @@ where
     fn put(&self, key: String, val: Bytes) -> Self::PutBlob {
         self.as_ref().put(key, val)
     }
+
+    fn get_batch(&self, keys: Vec<String>) -> BoxFuture<HashMap<String, Option<Bytes>>, Error> {
+        self.as_ref().get_batch(keys)
+    }
+
+    fn exists(&self, key: String) -> BoxFuture<bool, Error> {
+        self.as_ref().exists(key)
+    }
+
+    fn put_if_absent(&self, key: String, value: Bytes) -> BoxFuture<bool, Error> {
+        self.as_ref().put_if_absent(key, value)
+    }
+
+    fn put_stream(
+        &self,
+        key: String,
+        body: BoxStream<Bytes, Error>,
+        len_hint: Option<usize>,
+    ) -> BoxFuture<(), Error> {
+        self.as_ref().put_stream(key, body, len_hint)
+    }
+
+    fn copy(&self, src_key: String, dst_key: String) -> BoxFuture<(), Error> {
+        self.as_ref().copy(src_key, dst_key)
+    }
+
+    fn delete(&self, key: String) -> BoxFuture<(), Error> {
+        self.as_ref().delete(key)
+    }
+
+    fn keys(&self) -> BoxStream<String, Error> {
+        self.as_ref().keys()
+    }
+
+    fn flush(&self) -> BoxFuture<(), Error> {
+        self.as_ref().flush()
+    }
 }
@@ -0,0 +1,192 @@
+// Copyright (c) 2004-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use bytes::Bytes;
+use failure::Error;
+use futures::{Future, Stream};
+
+use futures_ext::{BoxFuture, BoxStream, FutureExt, StreamExt};
+
+use super::*;
+
+/// A snapshot of the counters of a `MeteredBlobstore`.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct AccessMetrics {
+    /// Blobs asked for, whether through `get` or `get_batch`.
+    pub gets: usize,
+    /// Gets that found a blob.
+    pub hits: usize,
+    /// Gets that didn't.
+    pub misses: usize,
+    /// Total size of the blobs found.
+    pub bytes_read: usize,
+    /// Blobs written, whether through `put`, `put_if_absent` or `put_stream`.
+    pub puts: usize,
+    /// Total size of the blobs written.
+    pub bytes_written: usize,
+}
+
+#[derive(Debug, Default)]
+struct Counters {
+    gets: AtomicUsize,
+    hits: AtomicUsize,
+    misses: AtomicUsize,
+    bytes_read: AtomicUsize,
+    puts: AtomicUsize,
+    bytes_written: AtomicUsize,
+}
+
+impl Counters {
+    fn record_get(&self, value: &Option<Bytes>) {
+        match *value {
+            Some(ref value) => {
+                self.hits.fetch_add(1, Ordering::Relaxed);
+                self.bytes_read.fetch_add(value.len(), Ordering::Relaxed);
+            }
+            None => {
+                self.misses.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    }
+}
+
+/// A blobstore that counts the reads and writes that go through it to the underlying blobstore,
+/// to find out how a store is being used. Hits and misses are counted once a get completes, and
+/// `put_if_absent` only counts the blobs it stored; everything else is counted when the call is
+/// made. `exists`, `copy`, `delete` and `keys` aren't counted.
+///
+/// Clones share their counters.
+#[derive(Clone, Debug)]
+pub struct MeteredBlobstore<B> {
+    blobstore: B,
+    counters: Arc<Counters>,
+}
+
+impl<B> MeteredBlobstore<B> {
+    pub fn new(blobstore: B) -> Self {
+        MeteredBlobstore {
+            blobstore,
+            counters: Arc::new(Counters::default()),
+        }
+    }
+
+    /// The counters as they are now. As they're read one at a time, a snapshot taken while
+    /// operations are in flight may not add up exactly.
+    pub fn metrics(&self) -> AccessMetrics {
+        let counters = &self.counters;
+        AccessMetrics {
+            gets: counters.gets.load(Ordering::Relaxed),
+            hits: counters.hits.load(Ordering::Relaxed),
+            misses: counters.misses.load(Ordering::Relaxed),
+            bytes_read: counters.bytes_read.load(Ordering::Relaxed),
+            puts: counters.puts.load(Ordering::Relaxed),
+            bytes_written: counters.bytes_written.load(Ordering::Relaxed),
+        }
+    }
+
+    pub fn into_inner(self) -> B {
+        self.blobstore
+    }
+}
+
+impl<B> Blobstore for MeteredBlobstore<B>
+where
+    B: Blobstore,
+{
+    type GetBlob = BoxFuture<Option<Bytes>, Error>;
+    type PutBlob = B::PutBlob;
+
+    fn get(&self, key: String) -> Self::GetBlob {
+        self.counters.gets.fetch_add(1, Ordering::Relaxed);
+        let counters = self.counters.clone();
+        self.blobstore
+            .get(key)
+            .map(move |value| {
+                counters.record_get(&value);
+                value
+            })
+            .boxify()
+    }
+
+    fn put(&self, key: String, value: Bytes) -> Self::PutBlob {
+        self.counters.puts.fetch_add(1, Ordering::Relaxed);
+        self.counters
+            .bytes_written
+            .fetch_add(value.len(), Ordering::Relaxed);
+        self.blobstore.put(key, value)
+    }
+
+    fn get_batch(&self, keys: Vec<String>) -> BoxFuture<HashMap<String, Option<Bytes>>, Error> {
+        self.counters.gets.fetch_add(keys.len(), Ordering::Relaxed);
+        let counters = self.counters.clone();
+        self.blobstore
+            .get_batch(keys)
+            .map(move |values| {
+                for value in values.values() {
+                    counters.record_get(value);
+                }
+                values
+            })
+            .boxify()
+    }
+
+    fn exists(&self, key: String) -> BoxFuture<bool, Error> {
+        self.blobstore.exists(key)
+    }
+
+    fn put_if_absent(&self, key: String, value: Bytes) -> BoxFuture<bool, Error> {
+        // Only count the blobs that were actually stored.
+        let counters = self.counters.clone();
+        let len = value.len();
+        self.blobstore
+            .put_if_absent(key, value)
+            .map(move |stored| {
+                if stored {
+                    counters.puts.fetch_add(1, Ordering::Relaxed);
+                    counters.bytes_written.fetch_add(len, Ordering::Relaxed);
+                }
+                stored
+            })
+            .boxify()
+    }
+
+    fn put_stream(
+        &self,
+        key: String,
+        body: BoxStream<Bytes, Error>,
+        len_hint: Option<usize>,
+    ) -> BoxFuture<(), Error> {
+        self.counters.puts.fetch_add(1, Ordering::Relaxed);
+        let counters = self.counters.clone();
+        let body = body.map(move |chunk| {
+            counters
+                .bytes_written
+                .fetch_add(chunk.len(), Ordering::Relaxed);
+            chunk
+        });
+        self.blobstore.put_stream(key, body.boxify(), len_hint)
+    }
+
+    fn copy(&self, src_key: String, dst_key: String) -> BoxFuture<(), Error> {
+        self.blobstore.copy(src_key, dst_key)
+    }
+
+    fn delete(&self, key: String) -> BoxFuture<(), Error> {
+        self.blobstore.delete(key)
+    }
+
+    fn keys(&self) -> BoxStream<String, Error> {
+        self.blobstore.keys()
+    }
+
+    fn flush(&self) -> BoxFuture<(), Error> {
+        self.blobstore.flush()
+    }
+}
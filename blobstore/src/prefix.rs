@@ -0,0 +1,111 @@
+// Copyright (c) 2004-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+use std::collections::HashMap;
+
+use bytes::Bytes;
+use failure::Error;
+use futures::{Future, Stream};
+
+use futures_ext::{BoxFuture, BoxStream, FutureExt, StreamExt};
+
+use super::*;
+
+/// A blobstore that prepends a fixed prefix to every key before passing it on to the underlying
+/// blobstore. This lets several logical stores (say, one per repo) share a single backend
+/// without their keys colliding.
+pub struct PrefixBlobstore<B> {
+    blobstore: B,
+    prefix: String,
+}
+
+impl<B> PrefixBlobstore<B> {
+    pub fn new<S: Into<String>>(blobstore: B, prefix: S) -> Self {
+        PrefixBlobstore {
+            blobstore,
+            prefix: prefix.into(),
+        }
+    }
+
+    #[inline]
+    fn prepend(&self, key: String) -> String {
+        format!("{}{}", self.prefix, key)
+    }
+}
+
+impl<B> Blobstore for PrefixBlobstore<B>
+where
+    B: Blobstore,
+{
+    type GetBlob = B::GetBlob;
+    type PutBlob = B::PutBlob;
+
+    fn get(&self, key: String) -> Self::GetBlob {
+        self.blobstore.get(self.prepend(key))
+    }
+
+    fn put(&self, key: String, value: Bytes) -> Self::PutBlob {
+        self.blobstore.put(self.prepend(key), value)
+    }
+
+    fn get_batch(&self, keys: Vec<String>) -> BoxFuture<HashMap<String, Option<Bytes>>, Error> {
+        let keys = keys.into_iter().map(|key| self.prepend(key)).collect();
+        let prefix_len = self.prefix.len();
+        self.blobstore
+            .get_batch(keys)
+            .map(move |values| {
+                values
+                    .into_iter()
+                    .map(|(key, value)| (key[prefix_len..].to_string(), value))
+                    .collect()
+            })
+            .boxify()
+    }
+
+    fn exists(&self, key: String) -> BoxFuture<bool, Error> {
+        self.blobstore.exists(self.prepend(key))
+    }
+
+    fn put_if_absent(&self, key: String, value: Bytes) -> BoxFuture<bool, Error> {
+        self.blobstore.put_if_absent(self.prepend(key), value)
+    }
+
+    fn put_stream(
+        &self,
+        key: String,
+        body: BoxStream<Bytes, Error>,
+        len_hint: Option<usize>,
+    ) -> BoxFuture<(), Error> {
+        self.blobstore.put_stream(self.prepend(key), body, len_hint)
+    }
+
+    fn copy(&self, src_key: String, dst_key: String) -> BoxFuture<(), Error> {
+        self.blobstore.copy(self.prepend(src_key), self.prepend(dst_key))
+    }
+
+    fn delete(&self, key: String) -> BoxFuture<(), Error> {
+        self.blobstore.delete(self.prepend(key))
+    }
+
+    fn keys(&self) -> BoxStream<String, Error> {
+        // Only report keys under our prefix, with the prefix stripped off again.
+        let prefix = self.prefix.clone();
+        self.blobstore
+            .keys()
+            .filter_map(move |key| {
+                if key.starts_with(&prefix) {
+                    Some(key[prefix.len()..].to_string())
+                } else {
+                    None
+                }
+            })
+            .boxify()
+    }
+
+    fn flush(&self) -> BoxFuture<(), Error> {
+        self.blobstore.flush()
+    }
+}
@@ -0,0 +1,80 @@
+// Copyright (c) 2004-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+//! Comparing the blobs in two blobstores, to check that a migration from one to the other is
+//! complete before cutting over.
+
+use std::collections::HashSet;
+
+use bytes::Bytes;
+use failure::Error;
+use futures::{stream, Future, Stream};
+
+use futures_ext::{BoxStream, StreamExt};
+
+use Blobstore;
+
+/// How a key reported by `scrub` differs between the two blobstores.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum ScrubDifference {
+    /// The blob is in the first blobstore but not the second.
+    MissingInB,
+    /// The blob is in the second blobstore but not the first.
+    MissingInA,
+    /// Both blobstores have the blob, with different contents.
+    Differs,
+}
+
+fn compare(a: Option<Bytes>, b: Option<Bytes>) -> Option<ScrubDifference> {
+    match (a, b) {
+        (Some(_), None) => Some(ScrubDifference::MissingInB),
+        (None, Some(_)) => Some(ScrubDifference::MissingInA),
+        (Some(a), Some(b)) => if a == b {
+            None
+        } else {
+            Some(ScrubDifference::Differs)
+        },
+        (None, None) => None,
+    }
+}
+
+/// Look up `keys` in both `a` and `b`, `batch_size` at a time with `get_batch`, and yield the ones
+/// that aren't the same in both with how they differ. Keys that are in neither blobstore aren't
+/// reported.
+///
+/// Keys are only checked the first time they come up, so `keys` can be the `keys()` of both
+/// blobstores chained together. That means remembering every key seen, so very large stores are
+/// better scrubbed a range of keys at a time. `batch_size` must be at least 1.
+pub fn scrub<A, B>(
+    a: A,
+    b: B,
+    keys: BoxStream<String, Error>,
+    batch_size: usize,
+) -> BoxStream<(String, ScrubDifference), Error>
+where
+    A: Blobstore,
+    B: Blobstore,
+{
+    let mut seen = HashSet::new();
+    keys.filter(move |key| seen.insert(key.clone()))
+        .chunks(batch_size)
+        .and_then(move |batch| {
+            a.get_batch(batch.clone())
+                .join(b.get_batch(batch.clone()))
+                .map(move |(mut in_a, mut in_b)| {
+                    let differences: Vec<_> = batch
+                        .into_iter()
+                        .filter_map(|key| {
+                            let difference = compare(in_a.remove(&key), in_b.remove(&key));
+                            difference.map(|difference| (key, difference))
+                        })
+                        .collect();
+                    stream::iter_ok::<_, Error>(differences)
+                })
+        })
+        .flatten()
+        .boxify()
+}
@@ -0,0 +1,175 @@
+// Copyright (c) 2004-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+use std::sync::Arc;
+
+use bytes::Bytes;
+use failure::Error;
+use futures::{future, Future};
+
+use futures_ext::{BoxFuture, BoxStream, FutureExt};
+
+use super::*;
+
+/// Stored in the small tier in place of a blob that went to the large tier.
+const LARGE_MARKER: &[u8] = b"\0MZT\x01large";
+
+/// A blobstore that keeps blobs smaller than a threshold in one backend, and spills the rest to
+/// another. The usual setup is a fast local store for the many small blobs, and a big remote one
+/// for the few large ones.
+///
+/// Every key has an entry in the small tier: either the blob itself, or a marker saying that it's
+/// in the large tier. So `get` only goes to the large tier for blobs that are actually there, and
+/// listing the small tier's keys lists everything.
+pub struct TieredBlobstore<S, L> {
+    small: Arc<S>,
+    large: Arc<L>,
+    threshold: usize,
+}
+
+impl<S, L> TieredBlobstore<S, L> {
+    /// Blobs of `threshold` bytes or more go to `large`, everything else to `small`.
+    pub fn new(small: S, large: L, threshold: usize) -> Self {
+        TieredBlobstore {
+            small: Arc::new(small),
+            large: Arc::new(large),
+            threshold,
+        }
+    }
+
+    fn is_large(&self, value: &[u8]) -> bool {
+        // Anything that could be mistaken for the marker goes to the large tier too, so that
+        // whatever looks like a marker in the small tier really is one.
+        value.len() >= self.threshold || value.starts_with(LARGE_MARKER)
+    }
+}
+
+// Not derived, as that would need the tiers themselves to be `Clone`.
+impl<S, L> Clone for TieredBlobstore<S, L> {
+    fn clone(&self) -> Self {
+        TieredBlobstore {
+            small: self.small.clone(),
+            large: self.large.clone(),
+            threshold: self.threshold,
+        }
+    }
+}
+
+impl<S, L> Blobstore for TieredBlobstore<S, L>
+where
+    S: Blobstore + Sync,
+    L: Blobstore + Sync,
+{
+    type GetBlob = BoxFuture<Option<Bytes>, Error>;
+    type PutBlob = BoxFuture<(), Error>;
+
+    fn get(&self, key: String) -> Self::GetBlob {
+        let large = self.large.clone();
+        self.small
+            .get(key.clone())
+            .and_then(move |value| match value {
+                Some(ref value) if &value[..] == LARGE_MARKER => large.get(key).boxify(),
+                value => future::ok(value).boxify(),
+            })
+            .boxify()
+    }
+
+    fn put(&self, key: String, value: Bytes) -> Self::PutBlob {
+        if self.is_large(&value) {
+            // Only write the marker once the blob is in place, so that it never points at
+            // nothing.
+            let small = self.small.clone();
+            self.large
+                .put(key.clone(), value)
+                .and_then(move |()| small.put(key, Bytes::from_static(LARGE_MARKER)))
+                .boxify()
+        } else {
+            self.small.put(key, value).boxify()
+        }
+    }
+
+    fn put_if_absent(&self, key: String, value: Bytes) -> BoxFuture<bool, Error> {
+        if self.is_large(&value) {
+            // Every key has an entry in the small tier, so that's what decides whether the key is
+            // taken. The large blob goes in first, as with `put`, but without replacing one that's
+            // already there under this key.
+            let small = self.small.clone();
+            self.large
+                .put_if_absent(key.clone(), value)
+                .and_then(move |_| small.put_if_absent(key, Bytes::from_static(LARGE_MARKER)))
+                .boxify()
+        } else {
+            self.small.put_if_absent(key, value)
+        }
+    }
+
+    fn put_stream(
+        &self,
+        key: String,
+        body: BoxStream<Bytes, Error>,
+        len_hint: Option<usize>,
+    ) -> BoxFuture<(), Error> {
+        let small = self.small.clone();
+        match len_hint {
+            // Small enough to buffer, so it can go through `put` like any other blob.
+            Some(len) if len < self.threshold => {
+                let this = self.clone();
+                collect_body(body, len_hint)
+                    .and_then(move |value| this.put(key, value))
+                    .boxify()
+            }
+            // Anything big, or of unknown size, is streamed to the large tier.
+            _ => self.large
+                .put_stream(key.clone(), body, len_hint)
+                .and_then(move |()| small.put(key, Bytes::from_static(LARGE_MARKER)))
+                .boxify(),
+        }
+    }
+
+    fn exists(&self, key: String) -> BoxFuture<bool, Error> {
+        self.small.exists(key)
+    }
+
+    fn copy(&self, src_key: String, dst_key: String) -> BoxFuture<(), Error> {
+        // Large blobs are kept under the same key as their marker, so copying just the marker
+        // would leave it pointing at nothing. Going through `put` copies whichever tier has it.
+        get_then_put(self.clone(), src_key, dst_key)
+    }
+
+    fn delete(&self, key: String) -> BoxFuture<(), Error> {
+        // Only touch the large tier if the blob is there, and drop the marker first, so that
+        // nothing is left pointing at a deleted blob.
+        let small = self.small.clone();
+        let large = self.large.clone();
+        self.small
+            .get(key.clone())
+            .and_then(move |value| {
+                let is_marker = value.as_ref().map(|value| &value[..]) == Some(LARGE_MARKER);
+                small.delete(key.clone()).and_then(move |()| {
+                    if is_marker {
+                        large.delete(key)
+                    } else {
+                        future::ok(()).boxify()
+                    }
+                })
+            })
+            .boxify()
+    }
+
+    fn keys(&self) -> BoxStream<String, Error> {
+        self.small.keys()
+    }
+
+    fn flush(&self) -> BoxFuture<(), Error> {
+        // The large tier first, so that any markers made durable with the small tier are backed
+        // by durable blobs.
+        let small = self.small.clone();
+        self.large
+            .flush()
+            .and_then(move |()| small.flush())
+            .boxify()
+    }
+}
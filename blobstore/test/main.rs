@@ -22,10 +22,13 @@ extern crate memblob;
 extern crate rocksblob;
 
 use bytes::Bytes;
-use futures::Future;
+use failure::err_msg;
+use futures::{stream, Future, Stream};
+use futures_ext::StreamExt;
 use tempdir::TempDir;
 
-use blobstore::Blobstore;
+use blobstore::{check_then_put, scrub, AccessMetrics, Blobstore, CompressingBlobstore,
+                MeteredBlobstore, PrefixBlobstore, ScrubDifference, TieredBlobstore};
 use fileblob::Fileblob;
 use memblob::Memblob;
 use rocksblob::Rocksblob;
@@ -53,6 +56,47 @@ where
     assert!(out.is_none());
 }
 
+fn exists<B>(blobstore: B)
+where
+    B: Blobstore,
+{
+    let foo = "foo".to_string();
+    blobstore
+        .put(foo.clone(), Bytes::from_static(b"foo"))
+        .wait()
+        .expect("put failed");
+
+    assert!(blobstore.exists(foo).wait().expect("exists failed"));
+    assert!(!blobstore
+        .exists("missing".to_string())
+        .wait()
+        .expect("exists failed"));
+}
+
+fn get_batch<B>(blobstore: B)
+where
+    B: Blobstore,
+{
+    let foo = "foo".to_string();
+    let bar = "bar".to_string();
+    let missing = "missing".to_string();
+    blobstore
+        .put(foo.clone(), Bytes::from_static(b"foo"))
+        .and_then(|_| blobstore.put(bar.clone(), Bytes::from_static(b"bar")))
+        .wait()
+        .expect("put failed");
+
+    let out = blobstore
+        .get_batch(vec![foo.clone(), missing.clone(), bar.clone()])
+        .wait()
+        .expect("get_batch failed");
+
+    assert_eq!(out.len(), 3);
+    assert_eq!(out[&foo], Some(Bytes::from_static(b"foo")));
+    assert_eq!(out[&bar], Some(Bytes::from_static(b"bar")));
+    assert_eq!(out[&missing], None);
+}
+
 fn boxable<B>(blobstore: B)
 where
     B: Blobstore,
@@ -68,11 +112,144 @@ where
     assert_eq!(out, Bytes::from_static(b"bar"));
 }
 
+fn keys_delete<B>(blobstore: B)
+where
+    B: Blobstore,
+{
+    let foo = "foo".to_string();
+    let bar = "bar/baz".to_string();
+    blobstore
+        .put(foo.clone(), Bytes::from_static(b"foo"))
+        .wait()
+        .expect("put failed");
+    blobstore
+        .put(bar.clone(), Bytes::from_static(b"bar"))
+        .wait()
+        .expect("put failed");
+
+    let mut keys = blobstore.keys().collect().wait().expect("keys failed");
+    keys.sort();
+    assert_eq!(keys, vec![bar.clone(), foo.clone()]);
+
+    blobstore.delete(foo.clone()).wait().expect("delete failed");
+    assert!(blobstore.get(foo.clone()).wait().expect("get failed").is_none());
+    let keys = blobstore.keys().collect().wait().expect("keys failed");
+    assert_eq!(keys, vec![bar]);
+
+    // Deleting a missing key is fine.
+    blobstore.delete(foo).wait().expect("delete failed");
+}
+
+fn put_if_absent<B>(blobstore: B)
+where
+    B: Blobstore,
+{
+    let foo = "foo".to_string();
+    let written = blobstore
+        .put_if_absent(foo.clone(), Bytes::from_static(b"first"))
+        .wait()
+        .expect("put_if_absent failed");
+    assert!(written);
+
+    let written = blobstore
+        .put_if_absent(foo.clone(), Bytes::from_static(b"second"))
+        .wait()
+        .expect("put_if_absent failed");
+    assert!(!written);
+    assert_eq!(
+        blobstore.get(foo).wait().expect("get failed"),
+        Some(Bytes::from_static(b"first"))
+    );
+}
+
+fn put_stream<B>(blobstore: B)
+where
+    B: Blobstore,
+{
+    let content: Vec<u8> = (0..10000).map(|i| (i * 7) as u8).collect();
+    blobstore
+        .put("single".to_string(), Bytes::from(content.clone()))
+        .wait()
+        .expect("put failed");
+    let chunks: Vec<_> = content.chunks(999).map(Bytes::from).collect();
+    blobstore
+        .put_stream(
+            "chunked".to_string(),
+            stream::iter_ok(chunks).boxify(),
+            Some(content.len()),
+        )
+        .wait()
+        .expect("put_stream failed");
+
+    let single = blobstore.get("single".to_string()).wait().expect("get failed");
+    let chunked = blobstore.get("chunked".to_string()).wait().expect("get failed");
+    assert_eq!(chunked, Some(Bytes::from(content)));
+    assert_eq!(chunked, single);
+}
+
+fn copy<B>(blobstore: B)
+where
+    B: Blobstore,
+{
+    let content = Bytes::from_static(b"copied");
+    blobstore
+        .put("src".to_string(), content.clone())
+        .wait()
+        .expect("put failed");
+    blobstore
+        .copy("src".to_string(), "dst".to_string())
+        .wait()
+        .expect("copy failed");
+
+    assert_eq!(
+        blobstore.get("dst".to_string()).wait().expect("get failed"),
+        Some(content.clone())
+    );
+    assert_eq!(
+        blobstore.get("src".to_string()).wait().expect("get failed"),
+        Some(content)
+    );
+    assert!(
+        blobstore
+            .copy("missing".to_string(), "other".to_string())
+            .wait()
+            .is_err()
+    );
+    assert_eq!(
+        blobstore.get("other".to_string()).wait().expect("get failed"),
+        None
+    );
+}
+
+fn flush_persistence<F, B>(mut new_blobstore: F)
+where
+    F: FnMut() -> B,
+    B: Blobstore,
+{
+    let foo = "foo".to_string();
+    {
+        let blobstore = new_blobstore();
+        blobstore
+            .put(foo.clone(), Bytes::from_static(b"bar"))
+            .and_then(|_| blobstore.flush())
+            .wait()
+            .expect("put/flush failed");
+    }
+
+    let blobstore = new_blobstore();
+    let out = blobstore.get(foo).wait().expect("get failed").expect("missing");
+    assert_eq!(out, Bytes::from_static(b"bar"));
+}
+
 macro_rules! blobstore_test_impl {
     ($mod_name: ident => {
         state: $state: expr,
         new: $new_cb: expr,
         persistent: $persistent: expr,
+        keys_delete: $keys_delete: expr,
+        put_if_absent: $put_if_absent: expr,
+        put_stream: $put_stream: expr,
+        copy: $copy: expr,
     }) => {
         mod $mod_name {
             use super::*;
@@ -89,11 +266,75 @@ macro_rules! blobstore_test_impl {
                 missing($new_cb(&state));
             }
 
+            #[test]
+            fn test_exists() {
+                let state = $state;
+                exists($new_cb(&state));
+            }
+
+            #[test]
+            fn test_get_batch() {
+                let state = $state;
+                get_batch($new_cb(&state));
+            }
+
             #[test]
             fn test_boxable() {
                 let state = $state;
                 boxable($new_cb(&state));
             }
+
+            #[test]
+            fn test_flush() {
+                let state = $state;
+                let blobstore = $new_cb(&state);
+                blobstore.flush().wait().expect("flush failed");
+            }
+
+            #[test]
+            fn test_flush_persistence() {
+                // Not all blobstores are persistent.
+                if $persistent {
+                    let state = $state;
+                    flush_persistence(|| $new_cb(&state));
+                }
+            }
+
+            #[test]
+            fn test_keys_delete() {
+                // Not all blobstores support listing and deleting keys.
+                if $keys_delete {
+                    let state = $state;
+                    keys_delete($new_cb(&state));
+                }
+            }
+
+            #[test]
+            fn test_put_if_absent() {
+                // Not all blobstores support conditional puts.
+                if $put_if_absent {
+                    let state = $state;
+                    put_if_absent($new_cb(&state));
+                }
+            }
+
+            #[test]
+            fn test_put_stream() {
+                // Not all blobstores support streaming puts.
+                if $put_stream {
+                    let state = $state;
+                    put_stream($new_cb(&state));
+                }
+            }
+
+            #[test]
+            fn test_copy() {
+                // Not all blobstores support copying.
+                if $copy {
+                    let state = $state;
+                    copy($new_cb(&state));
+                }
+            }
         }
     }
 }
@@ -103,6 +344,10 @@ blobstore_test_impl! {
         state: (),
         new: |_| Memblob::new(),
         persistent: false,
+        keys_delete: true,
+        put_if_absent: true,
+        put_stream: true,
+        copy: true,
     }
 }
 
@@ -111,6 +356,10 @@ blobstore_test_impl! {
         state: TempDir::new("fileblob_test").unwrap(),
         new: |dir| Fileblob::open(dir).unwrap(),
         persistent: true,
+        keys_delete: true,
+        put_if_absent: true,
+        put_stream: true,
+        copy: true,
     }
 }
 
@@ -120,5 +369,346 @@ blobstore_test_impl! {
         // create/open may need to be unified once persistence tests are added
         new: |dir| Rocksblob::create(dir).unwrap(),
         persistent: true,
+        keys_delete: false,
+        put_if_absent: true,
+        put_stream: true,
+        copy: true,
     }
 }
+
+blobstore_test_impl! {
+    compressing_memblob_test => {
+        state: (),
+        new: |_| CompressingBlobstore::new(Memblob::new()),
+        persistent: false,
+        keys_delete: true,
+        put_if_absent: true,
+        put_stream: true,
+        copy: true,
+    }
+}
+
+blobstore_test_impl! {
+    metered_memblob_test => {
+        state: (),
+        new: |_| MeteredBlobstore::new(Memblob::new()),
+        persistent: false,
+        keys_delete: true,
+        put_if_absent: true,
+        put_stream: true,
+        copy: true,
+    }
+}
+
+blobstore_test_impl! {
+    tiered_memblob_test => {
+        state: (),
+        new: |_| TieredBlobstore::new(Memblob::new(), Memblob::new(), 16),
+        persistent: false,
+        keys_delete: true,
+        put_if_absent: true,
+        put_stream: true,
+        copy: true,
+    }
+}
+
+#[test]
+fn prefix_isolation() {
+    let backend = Memblob::new();
+    let plain = backend.clone();
+    let prefixed = PrefixBlobstore::new(backend.clone(), "repo1.");
+
+    plain
+        .put("foo".to_string(), Bytes::from_static(b"plain"))
+        .wait()
+        .expect("put failed");
+    prefixed
+        .put("bar".to_string(), Bytes::from_static(b"prefixed"))
+        .wait()
+        .expect("put failed");
+
+    // Neither sees the other's keys under the same name.
+    assert!(prefixed.get("foo".to_string()).wait().unwrap().is_none());
+    assert!(plain.get("bar".to_string()).wait().unwrap().is_none());
+
+    // Reads through the prefixed store find what it wrote.
+    assert_eq!(
+        prefixed.get("bar".to_string()).wait().unwrap(),
+        Some(Bytes::from_static(b"prefixed"))
+    );
+    assert_eq!(
+        plain.get("repo1.bar".to_string()).wait().unwrap(),
+        Some(Bytes::from_static(b"prefixed"))
+    );
+
+    let keys = prefixed.keys().collect().wait().unwrap();
+    assert_eq!(keys, vec!["bar".to_string()]);
+
+    prefixed.delete("bar".to_string()).wait().unwrap();
+    assert!(plain.get("repo1.bar".to_string()).wait().unwrap().is_none());
+    assert!(plain.get("foo".to_string()).wait().unwrap().is_some());
+}
+
+#[test]
+fn compressing_round_trip() {
+    let backend = Memblob::new();
+    let compressing = CompressingBlobstore::with_level(backend.clone(), 19);
+    let value = Bytes::from(vec![b'a'; 64 * 1024]);
+
+    compressing
+        .put("foo".to_string(), value.clone())
+        .wait()
+        .expect("put failed");
+    assert_eq!(
+        compressing.get("foo".to_string()).wait().unwrap(),
+        Some(value.clone())
+    );
+
+    // What's actually stored is a lot smaller.
+    let stored = backend
+        .get("foo".to_string())
+        .wait()
+        .unwrap()
+        .expect("missing");
+    assert!(stored.len() < value.len() / 10);
+}
+
+#[test]
+fn compressing_reads_uncompressed() {
+    let backend = Memblob::new();
+    let compressing = CompressingBlobstore::new(backend.clone());
+
+    // A blob written before compression was turned on.
+    backend
+        .put("legacy".to_string(), Bytes::from_static(b"plain"))
+        .wait()
+        .expect("put failed");
+    assert_eq!(
+        compressing.get("legacy".to_string()).wait().unwrap(),
+        Some(Bytes::from_static(b"plain"))
+    );
+    let batch = compressing
+        .get_batch(vec!["legacy".to_string()])
+        .wait()
+        .unwrap();
+    assert_eq!(batch["legacy"], Some(Bytes::from_static(b"plain")));
+}
+
+#[test]
+fn metered_counts() {
+    let metered = MeteredBlobstore::new(Memblob::new());
+    assert_eq!(metered.metrics(), AccessMetrics::default());
+
+    metered
+        .put("foo".to_string(), Bytes::from_static(b"hello"))
+        .wait()
+        .expect("put failed");
+    assert!(metered.get("foo".to_string()).wait().unwrap().is_some());
+    assert!(metered.get("missing".to_string()).wait().unwrap().is_none());
+    let batch = metered
+        .get_batch(vec!["foo".to_string(), "missing".to_string()])
+        .wait()
+        .unwrap();
+    assert_eq!(batch.len(), 2);
+    // Already there, so nothing is written.
+    assert!(
+        !metered
+            .put_if_absent("foo".to_string(), Bytes::from_static(b"again"))
+            .wait()
+            .unwrap()
+    );
+    let body = stream::iter_ok(vec![Bytes::from_static(b"ab"), Bytes::from_static(b"c")]).boxify();
+    metered
+        .put_stream("bar".to_string(), body, None)
+        .wait()
+        .expect("put_stream failed");
+
+    assert_eq!(
+        metered.metrics(),
+        AccessMetrics {
+            gets: 4,
+            hits: 2,
+            misses: 2,
+            bytes_read: 10,
+            puts: 2,
+            bytes_written: 8,
+        }
+    );
+}
+
+#[test]
+fn tiered_routing() {
+    let small = Memblob::new();
+    let large = Memblob::new();
+    let tiered = TieredBlobstore::new(small.clone(), large.clone(), 16);
+    let small_value = Bytes::from_static(b"small");
+    let large_value = Bytes::from(vec![b'a'; 1024]);
+
+    tiered
+        .put("small".to_string(), small_value.clone())
+        .wait()
+        .expect("put failed");
+    tiered
+        .put("large".to_string(), large_value.clone())
+        .wait()
+        .expect("put failed");
+
+    assert_eq!(
+        small.get("small".to_string()).wait().unwrap(),
+        Some(small_value.clone())
+    );
+    assert!(large.get("small".to_string()).wait().unwrap().is_none());
+    assert_eq!(
+        large.get("large".to_string()).wait().unwrap(),
+        Some(large_value.clone())
+    );
+    // The small tier only has a marker for the large blob.
+    let marker = small.get("large".to_string()).wait().unwrap().expect("no marker");
+    assert!(marker.len() < 16);
+
+    assert_eq!(
+        tiered.get("small".to_string()).wait().unwrap(),
+        Some(small_value)
+    );
+    assert_eq!(
+        tiered.get("large".to_string()).wait().unwrap(),
+        Some(large_value)
+    );
+    assert!(tiered.get("missing".to_string()).wait().unwrap().is_none());
+}
+
+#[test]
+fn tiered_put_if_absent_large() {
+    let small = Memblob::new();
+    let large = Memblob::new();
+    let blobstore = TieredBlobstore::new(small.clone(), large.clone(), 4);
+    let key = "foo".to_string();
+    let put = |value: &'static [u8]| {
+        blobstore
+            .put_if_absent(key.clone(), Bytes::from_static(value))
+            .wait()
+            .unwrap()
+    };
+    assert!(put(b"large"));
+    assert!(!put(b"sm"));
+    assert!(!put(b"larger"));
+
+    assert_eq!(
+        blobstore.get(key.clone()).wait().unwrap(),
+        Some(Bytes::from_static(b"large"))
+    );
+    assert_eq!(
+        large.get(key.clone()).wait().unwrap(),
+        Some(Bytes::from_static(b"large"))
+    );
+}
+
+#[test]
+fn check_then_put_memblob() {
+    let blobstore = Memblob::new();
+    let put = |value: &'static [u8]| {
+        check_then_put(blobstore.clone(), "foo".to_string(), Bytes::from_static(value))
+            .wait()
+            .unwrap()
+    };
+    assert!(put(b"first"));
+    assert!(!put(b"second"));
+    assert_eq!(
+        blobstore.get("foo".to_string()).wait().unwrap(),
+        Some(Bytes::from_static(b"first"))
+    );
+}
+
+#[test]
+fn scrub_memblobs() {
+    let a = Memblob::new();
+    let b = Memblob::new();
+    let put = |blobstore: &Memblob, key: &str, value: &'static [u8]| {
+        blobstore
+            .put(key.to_string(), Bytes::from_static(value))
+            .wait()
+            .unwrap()
+    };
+    put(&a, "same", b"value");
+    put(&b, "same", b"value");
+    put(&a, "divergent", b"old");
+    put(&b, "divergent", b"new");
+    put(&a, "only-a", b"value");
+    put(&b, "only-b", b"value");
+
+    let keys = a.keys().chain(b.keys()).boxify();
+    let mut differences = scrub(a.clone(), b.clone(), keys, 2)
+        .collect()
+        .wait()
+        .unwrap();
+    differences.sort_by(|x, y| x.0.cmp(&y.0));
+    assert_eq!(
+        differences,
+        vec![
+            ("divergent".to_string(), ScrubDifference::Differs),
+            ("only-a".to_string(), ScrubDifference::MissingInB),
+            ("only-b".to_string(), ScrubDifference::MissingInA),
+        ]
+    );
+}
+
+#[test]
+fn fileblob_failed_put_stream() {
+    let dir = TempDir::new("fileblob_failed_put_stream").unwrap();
+    let blobstore = Fileblob::open(&dir).unwrap();
+    let body = stream::iter_ok(vec![Bytes::from_static(b"partial")])
+        .chain(stream::once(Err(err_msg("stream failed"))))
+        .boxify();
+    assert!(
+        blobstore
+            .put_stream("foo".to_string(), body, None)
+            .wait()
+            .is_err()
+    );
+
+    // Nothing is left behind, not even the partly written blob.
+    assert_eq!(blobstore.get("foo".to_string()).wait().unwrap(), None);
+    assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 0);
+}
+
+#[test]
+fn fileblob_percent_keys() {
+    let dir = TempDir::new("fileblob_percent_keys").unwrap();
+    let blobstore = Fileblob::open(&dir).unwrap();
+    let key = "a%41".to_string();
+    blobstore
+        .put(key.clone(), Bytes::from_static(b"percent"))
+        .wait()
+        .unwrap();
+
+    // The key is listed as it was put, not percent-decoded into "aA".
+    assert_eq!(blobstore.keys().collect().wait().unwrap(), vec![key.clone()]);
+    assert_eq!(
+        blobstore.get(key.clone()).wait().unwrap(),
+        Some(Bytes::from_static(b"percent"))
+    );
+    blobstore.delete(key.clone()).wait().unwrap();
+    assert_eq!(blobstore.keys().collect().wait().unwrap(), Vec::<String>::new());
+}
+
+#[test]
+fn fileblob_put_if_absent_cleans_up() {
+    let dir = TempDir::new("fileblob_put_if_absent_cleans_up").unwrap();
+    let blobstore = Fileblob::open(&dir).unwrap();
+    for &(value, stored) in &[(&b"first"[..], true), (&b"second"[..], false)] {
+        assert_eq!(
+            blobstore
+                .put_if_absent("foo".to_string(), Bytes::from(value))
+                .wait()
+                .unwrap(),
+            stored
+        );
+    }
+
+    // Only the blob itself is left, whether or not the put went through.
+    assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 1);
+    assert_eq!(
+        blobstore.get("foo".to_string()).wait().unwrap(),
+        Some(Bytes::from_static(b"first"))
+    );
+}
@@ -22,7 +22,7 @@ use failure::Error;
 use futures::future::ok;
 use futures::stream::iter_ok;
 
-use bookmarks::{Bookmarks, BookmarksMut};
+use bookmarks::{BookmarkOp, Bookmarks, BookmarksMut};
 use futures_ext::{BoxFuture, BoxStream, FutureExt, StreamExt};
 use mercurial_types::NodeHash;
 use storage_types::Version;
@@ -62,6 +62,52 @@ impl Bookmarks for MemBookmarks {
         let keys = guard.keys().map(|k| k.clone()).collect::<Vec<_>>();
         iter_ok(keys.into_iter()).boxify()
     }
+
+    fn compare_and_set(
+        &self,
+        key: &AsRef<[u8]>,
+        expected: Option<NodeHash>,
+        new: NodeHash,
+    ) -> BoxFuture<bool, Error> {
+        let mut bookmarks = self.bookmarks.lock().unwrap();
+
+        let current = bookmarks.get(key.as_ref()).map(|&(hash, _)| hash);
+        if current == expected {
+            bookmarks.insert(key.as_ref().to_vec(), (new, version_next()));
+            ok(true).boxify()
+        } else {
+            ok(false).boxify()
+        }
+    }
+
+    fn apply_transaction(&self, ops: Vec<BookmarkOp>) -> BoxFuture<bool, Error> {
+        let mut bookmarks = self.bookmarks.lock().unwrap();
+
+        // Work on a copy, so that nothing changes unless every op succeeds.
+        let mut updated = bookmarks.clone();
+        for op in ops {
+            match op {
+                BookmarkOp::Set { name, new } => {
+                    updated.insert(name, (new, version_next()));
+                }
+                BookmarkOp::Delete { name } => {
+                    updated.remove(&name);
+                }
+                BookmarkOp::CompareAndSet {
+                    name,
+                    expected,
+                    new,
+                } => {
+                    if updated.get(&name).map(|&(hash, _)| hash) != expected {
+                        return ok(false).boxify();
+                    }
+                    updated.insert(name, (new, version_next()));
+                }
+            }
+        }
+        *bookmarks = updated;
+        ok(true).boxify()
+    }
 }
 
 impl BookmarksMut for MemBookmarks {
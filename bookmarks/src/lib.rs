@@ -7,27 +7,85 @@
 #![deny(warnings)]
 
 extern crate failure;
+#[macro_use]
+extern crate failure_derive;
 extern crate futures;
 
 extern crate futures_ext;
 extern crate mercurial_types;
 extern crate storage_types;
 
+use std::collections::HashSet;
 use std::sync::Arc;
 
-use futures_ext::{BoxFuture, BoxStream};
+use futures::{future, Future, Stream};
+use futures_ext::{BoxFuture, BoxStream, FutureExt};
 
 use mercurial_types::NodeHash;
 use storage_types::Version;
 
 use failure::Error;
 
+#[derive(Debug, Fail)]
+pub enum ErrorKind {
+    #[fail(display = "{} is not supported by this bookmark store", _0)] Unsupported(&'static str),
+}
+
+/// One step of a bookmark transaction.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum BookmarkOp {
+    /// Point `name` at `new`, creating it if it doesn't exist.
+    Set { name: Vec<u8>, new: NodeHash },
+    /// Remove `name`. Deleting a bookmark that doesn't exist is not an error.
+    Delete { name: Vec<u8> },
+    /// Point `name` at `new` if it currently points at `expected`, or doesn't exist if `expected`
+    /// is `None`, and fail the whole transaction otherwise.
+    CompareAndSet {
+        name: Vec<u8>,
+        expected: Option<NodeHash>,
+        new: NodeHash,
+    },
+}
+
 /// Trait representing read-only operations on a bookmark store, which maintains a global mapping
 /// of names to commit identifiers. Consistency is maintained using versioning.
 pub trait Bookmarks: Sync + Send + 'static {
     // Basic operations.
     fn get(&self, key: &AsRef<[u8]>) -> BoxFuture<Option<(NodeHash, Version)>, Error>;
     fn keys(&self) -> BoxStream<Vec<u8>, Error>;
+
+    /// All the bookmark names, as a set.
+    fn keys_set(&self) -> BoxFuture<HashSet<Vec<u8>>, Error> {
+        self.keys()
+            .fold(HashSet::new(), |mut set, key| {
+                set.insert(key);
+                Ok::<_, Error>(set)
+            })
+            .boxify()
+    }
+
+    /// Point `key` at `new`, but only if it currently points at `expected`, or doesn't exist if
+    /// `expected` is `None`. Resolves to whether the bookmark was changed. This is what
+    /// concurrent writers should use to move bookmarks safely.
+    ///
+    /// Read-only stores fail with `ErrorKind::Unsupported`.
+    fn compare_and_set(
+        &self,
+        _key: &AsRef<[u8]>,
+        _expected: Option<NodeHash>,
+        _new: NodeHash,
+    ) -> BoxFuture<bool, Error> {
+        future::err(ErrorKind::Unsupported("compare_and_set").into()).boxify()
+    }
+
+    /// Apply all of `ops` in order, or none of them. Resolves to whether they were applied,
+    /// which they aren't if any of the `CompareAndSet`s doesn't match. Each op sees the effect
+    /// of the ones before it.
+    ///
+    /// Read-only stores fail with `ErrorKind::Unsupported`.
+    fn apply_transaction(&self, _ops: Vec<BookmarkOp>) -> BoxFuture<bool, Error> {
+        future::err(ErrorKind::Unsupported("apply_transaction").into()).boxify()
+    }
 }
 
 // Implement Bookmarks for boxed Bookmarks trait object
@@ -39,6 +97,23 @@ impl Bookmarks for Box<Bookmarks> {
     fn keys(&self) -> BoxStream<Vec<u8>, Error> {
         (**self).keys()
     }
+
+    fn keys_set(&self) -> BoxFuture<HashSet<Vec<u8>>, Error> {
+        (**self).keys_set()
+    }
+
+    fn compare_and_set(
+        &self,
+        key: &AsRef<[u8]>,
+        expected: Option<NodeHash>,
+        new: NodeHash,
+    ) -> BoxFuture<bool, Error> {
+        (**self).compare_and_set(key, expected, new)
+    }
+
+    fn apply_transaction(&self, ops: Vec<BookmarkOp>) -> BoxFuture<bool, Error> {
+        (**self).apply_transaction(ops)
+    }
 }
 
 // Implement Bookmarks for Arced Bookmarks trait object
@@ -50,6 +125,23 @@ impl Bookmarks for Arc<Bookmarks> {
     fn keys(&self) -> BoxStream<Vec<u8>, Error> {
         (**self).keys()
     }
+
+    fn keys_set(&self) -> BoxFuture<HashSet<Vec<u8>>, Error> {
+        (**self).keys_set()
+    }
+
+    fn compare_and_set(
+        &self,
+        key: &AsRef<[u8]>,
+        expected: Option<NodeHash>,
+        new: NodeHash,
+    ) -> BoxFuture<bool, Error> {
+        (**self).compare_and_set(key, expected, new)
+    }
+
+    fn apply_transaction(&self, ops: Vec<BookmarkOp>) -> BoxFuture<bool, Error> {
+        (**self).apply_transaction(ops)
+    }
 }
 
 // Implement Bookmarks for Arc-wrapped Bookmark type
@@ -64,6 +156,23 @@ where
     fn keys(&self) -> BoxStream<Vec<u8>, Error> {
         (**self).keys()
     }
+
+    fn keys_set(&self) -> BoxFuture<HashSet<Vec<u8>>, Error> {
+        (**self).keys_set()
+    }
+
+    fn compare_and_set(
+        &self,
+        key: &AsRef<[u8]>,
+        expected: Option<NodeHash>,
+        new: NodeHash,
+    ) -> BoxFuture<bool, Error> {
+        (**self).compare_and_set(key, expected, new)
+    }
+
+    fn apply_transaction(&self, ops: Vec<BookmarkOp>) -> BoxFuture<bool, Error> {
+        (**self).apply_transaction(ops)
+    }
 }
 
 /// Trait representing write operations on a bookmark store. Consistency is maintained using
@@ -0,0 +1,84 @@
+// Copyright (c) 2004-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+use std::io::Read;
+use std::path::{Component, Path, PathBuf};
+
+use failure::Result;
+use tar::Archive;
+
+use {ErrorKind, StockBookmarks};
+
+/// `path` without any `.` components, so that `./.hg/bookmarks` and `.hg/bookmarks` match.
+fn normalize(path: &Path) -> PathBuf {
+    path.components()
+        .filter(|component| *component != Component::CurDir)
+        .collect()
+}
+
+impl StockBookmarks {
+    /// Read a bookmarks file stored in a tar archive, such as `.hg/bookmarks` in a snapshot of a
+    /// repo, without extracting it. The archive is read up to the first entry at `entry_path`,
+    /// and it's an `ErrorKind::MissingArchiveEntry` error if there isn't one.
+    pub fn from_tar<R: Read>(archive: R, entry_path: &str) -> Result<Self> {
+        let wanted = normalize(Path::new(entry_path));
+        let mut archive = Archive::new(archive);
+        for entry in archive.entries()? {
+            let entry = entry?;
+            let matches = normalize(&entry.path()?) == wanted;
+            if matches {
+                return Self::from_reader(entry);
+            }
+        }
+        Err(ErrorKind::MissingArchiveEntry(entry_path.to_string()).into())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use futures::Future;
+    use mercurial_types_mocks::nodehash;
+    use tar::{Builder, Header};
+
+    use bookmarks::Bookmarks;
+    use storage_types::Version;
+
+    use super::*;
+
+    fn archive_with(path: &str, contents: &[u8]) -> Vec<u8> {
+        let mut builder = Builder::new(Vec::new());
+        let mut header = Header::new_gnu();
+        header.set_path(path).unwrap();
+        header.set_size(contents.len() as u64);
+        header.set_mode(0o644);
+        header.set_cksum();
+        builder.append(&header, contents).unwrap();
+        builder.into_inner().unwrap()
+    }
+
+    #[test]
+    fn read_entry() {
+        let archive = archive_with(
+            "./.hg/bookmarks",
+            b"1111111111111111111111111111111111111111 abc\n",
+        );
+        let bookmarks = StockBookmarks::from_tar(&archive[..], ".hg/bookmarks").unwrap();
+        assert_eq!(
+            bookmarks.get(&"abc").wait().unwrap(),
+            Some((nodehash::ONES_HASH, Version::from(1)))
+        );
+    }
+
+    #[test]
+    fn missing_entry() {
+        let archive = archive_with(".hg/requires", b"revlogv1\n");
+        let err = StockBookmarks::from_tar(&archive[..], ".hg/bookmarks").unwrap_err();
+        assert_matches!(
+            err.downcast::<ErrorKind>().unwrap(),
+            ErrorKind::MissingArchiveEntry(_)
+        );
+    }
+}
@@ -0,0 +1,32 @@
+// Copyright (c) 2004-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+use mercurial_types::NodeHash;
+
+/// The differences between an earlier and a later set of bookmarks. Each list is sorted by
+/// bookmark name.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct BookmarksDiff {
+    /// Bookmarks that only exist in the later set, with their hashes.
+    pub added: Vec<(Vec<u8>, NodeHash)>,
+    /// Bookmarks that only exist in the earlier set, with the hashes they used to point at.
+    pub removed: Vec<(Vec<u8>, NodeHash)>,
+    /// Bookmarks that point somewhere else now, as (name, old hash, new hash).
+    pub changed: Vec<(Vec<u8>, NodeHash, NodeHash)>,
+}
+
+impl BookmarksDiff {
+    /// Whether the two sets of bookmarks were identical.
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty() && self.changed.is_empty()
+    }
+
+    pub(crate) fn sort(&mut self) {
+        self.added.sort_by(|a, b| a.0.cmp(&b.0));
+        self.removed.sort_by(|a, b| a.0.cmp(&b.0));
+        self.changed.sort_by(|a, b| a.0.cmp(&b.0));
+    }
+}
@@ -0,0 +1,130 @@
+// Copyright (c) 2004-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+//! Bookmarks as JSON, for tools that don't read the `.hg/bookmarks` format.
+//!
+//! The JSON is a list of `{"name": ..., "hash": ...}` objects. Bookmark names aren't always valid
+//! UTF-8, so the ones that aren't are base64-encoded under `name_b64` instead of `name`.
+
+use base64;
+use failure::{Result, ResultExt};
+use serde_json;
+
+use mercurial_types::NodeHash;
+
+use {ErrorKind, StockBookmarks};
+
+#[derive(Debug, Deserialize, Serialize)]
+struct JsonBookmark {
+    #[serde(default, skip_serializing_if = "Option::is_none")] name: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")] name_b64: Option<String>,
+    hash: String,
+}
+
+impl StockBookmarks {
+    /// The bookmarks as JSON, in the order `keys_ordered` yields them.
+    pub fn to_json(&self) -> Result<String> {
+        let entries: Vec<_> = self.order
+            .iter()
+            .map(|name| {
+                let hash = self.bookmarks[name].to_string();
+                match String::from_utf8(name.clone()) {
+                    Ok(name) => JsonBookmark {
+                        name: Some(name),
+                        name_b64: None,
+                        hash,
+                    },
+                    Err(_) => JsonBookmark {
+                        name: None,
+                        name_b64: Some(base64::encode(name)),
+                        hash,
+                    },
+                }
+            })
+            .collect();
+        Ok(serde_json::to_string(&entries)?)
+    }
+
+    /// Parse bookmarks written by `to_json`. Each entry must have exactly one of `name` and
+    /// `name_b64`. As with `from_entries`, if a name appears more than once the last entry wins.
+    pub fn from_json(json: &str) -> Result<Self> {
+        let entries: Vec<JsonBookmark> = serde_json::from_str(json)?;
+        let mut bookmarks = Self::empty();
+        for entry in entries {
+            let name = match (entry.name, entry.name_b64) {
+                (Some(name), None) => name.into_bytes(),
+                (None, Some(encoded)) => base64::decode(&encoded)
+                    .context(ErrorKind::InvalidJsonBookmark(format!("bad name_b64 {}", encoded)))?,
+                _ => {
+                    return Err(ErrorKind::InvalidJsonBookmark(format!(
+                        "bookmark for {} needs exactly one of name and name_b64",
+                        entry.hash
+                    )).into())
+                }
+            };
+            let hash: NodeHash = entry
+                .hash
+                .parse()
+                .context(ErrorKind::InvalidHash(entry.hash.clone()))?;
+            bookmarks.insert(name, hash);
+        }
+        Ok(bookmarks)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::{Future, Stream};
+    use mercurial_types_mocks::nodehash;
+
+    use bookmarks::Bookmarks;
+    use storage_types::Version;
+
+    use super::*;
+
+    #[test]
+    fn ascii_name_round_trip() {
+        let bookmarks =
+            StockBookmarks::from_entries(vec![(b"master".to_vec(), nodehash::ONES_HASH)]);
+        let json = bookmarks.to_json().unwrap();
+        assert_eq!(
+            json,
+            r#"[{"name":"master","hash":"1111111111111111111111111111111111111111"}]"#
+        );
+
+        let bookmarks = StockBookmarks::from_json(&json).unwrap();
+        assert_eq!(
+            bookmarks.get(&"master").wait().unwrap(),
+            Some((nodehash::ONES_HASH, Version::from(1)))
+        );
+    }
+
+    #[test]
+    fn non_utf8_name_round_trip() {
+        let name = b"release\xff\xfe".to_vec();
+        let bookmarks = StockBookmarks::from_entries(vec![
+            (b"master".to_vec(), nodehash::ONES_HASH),
+            (name.clone(), nodehash::TWOS_HASH),
+        ]);
+        let json = bookmarks.to_json().unwrap();
+        assert!(json.contains(r#"{"name_b64":"cmVsZWFzZf/+","hash":"2222"#), "{}", json);
+
+        let bookmarks = StockBookmarks::from_json(&json).unwrap();
+        let names = bookmarks.keys_ordered().collect().wait().unwrap();
+        assert_eq!(names, vec![b"master".to_vec(), name.clone()]);
+        assert_eq!(
+            bookmarks.get(&name).wait().unwrap(),
+            Some((nodehash::TWOS_HASH, Version::from(1)))
+        );
+
+        // Only one of the two can be given.
+        let both = concat!(
+            r#"[{"name":"a","name_b64":"YQ==","#,
+            r#""hash":"1111111111111111111111111111111111111111"}]"#
+        );
+        assert!(StockBookmarks::from_json(both).is_err());
+    }
+}
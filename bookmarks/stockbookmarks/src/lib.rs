@@ -10,37 +10,89 @@ extern crate ascii;
 #[macro_use]
 #[cfg(test)]
 extern crate assert_matches;
+extern crate base64;
+extern crate bytes;
 #[macro_use]
 extern crate failure_derive;
 extern crate failure_ext as failure;
 extern crate futures;
 extern crate futures_ext;
+extern crate serde;
+#[macro_use]
+extern crate serde_derive;
+extern crate serde_json;
 
 extern crate bookmarks;
 extern crate mercurial_types;
 #[cfg(test)]
 extern crate mercurial_types_mocks;
+#[cfg(feature = "notify")]
+extern crate notify;
 extern crate storage_types;
+#[cfg(feature = "tar")]
+extern crate tar;
+#[cfg(test)]
+extern crate tempdir;
+extern crate tokio_io;
 
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::fs;
 use std::io::{self, BufRead, BufReader, Read};
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
 
 use ascii::AsciiStr;
+use bytes::BytesMut;
 use failure::{Error, Result, ResultExt};
 use futures::future;
 use futures::stream::{self, Stream};
-use futures_ext::{BoxFuture, BoxStream, StreamExt};
+use futures_ext::{BoxFuture, BoxStream, FutureExt, StreamExt};
+use tokio_io::AsyncRead;
+use tokio_io::codec::{Decoder, FramedRead};
 
 use bookmarks::Bookmarks;
 use mercurial_types::NodeHash;
+use mercurial_types::hash::Sha1;
 use storage_types::Version;
 
+#[cfg(feature = "tar")]
+mod archive;
+mod diff;
+mod json;
+mod sorted;
+#[cfg(feature = "notify")]
+mod watch;
+pub use diff::BookmarksDiff;
+pub use sorted::SortedBookmarks;
+
 #[derive(Debug, Fail)]
 pub enum ErrorKind {
     #[fail(display = "invalid bookmarks line: {}", _0)] InvalidBookmarkLine(String),
     #[fail(display = "invalid hash: {}", _0)] InvalidHash(String),
+    #[fail(display = "duplicate bookmark: {}", _0)] DuplicateBookmark(String),
+    #[fail(display = "invalid JSON bookmark: {}", _0)] InvalidJsonBookmark(String),
+    #[fail(display = "bad bookmarks file at line {}", _0)] BadLineAt(usize),
+    #[fail(display = "no {} in archive", _0)] MissingArchiveEntry(String),
+    #[fail(display = "hash prefix {} is too short", _0)] HashPrefixTooShort(String),
+}
+
+/// The shortest hash prefix `names_for_hash_prefix` accepts.
+pub const MIN_HASH_PREFIX_LEN: usize = 4;
+
+/// How the `Version` that `get` returns is picked, for `read_with_version_scheme`. Caches in
+/// front of the bookmarks can compare versions to tell whether the file has changed since they
+/// last read it.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum VersionScheme {
+    /// Always 1, as for bookmarks read any other way. Free, but caches never see a change.
+    Constant,
+    /// The file's modification time. This only costs a stat, but it's as coarse as the filesystem
+    /// keeps mtimes, so two writes within one tick look the same, and touching the file without
+    /// changing it looks like a change.
+    Mtime,
+    /// A hash of the file's contents. This changes exactly when the contents do, but the file is
+    /// read in full before parsing so that it can be hashed.
+    ContentHash,
 }
 
 /// Implementation of bookmarks as they exist in stock Mercurial inside `.hg/bookmarks`.
@@ -59,6 +111,13 @@ pub enum ErrorKind {
 #[derive(Clone, Debug)]
 pub struct StockBookmarks {
     bookmarks: HashMap<Vec<u8>, NodeHash>,
+    // Bookmark names in the order they first appeared in, for keys_ordered.
+    order: Vec<Vec<u8>>,
+    // Only ever set by from_reader_hg_at.
+    active: Option<Vec<u8>>,
+    // What get returns alongside the hash. Only read_with_version_scheme sets this to anything
+    // but 1.
+    version: Version,
 }
 
 impl StockBookmarks {
@@ -71,50 +130,501 @@ impl StockBookmarks {
             Err(ref err) if err.kind() == io::ErrorKind::NotFound => {
                 // The .hg/bookmarks file is not guaranteed to exist. Treat it is empty if it
                 // doesn't.
-                Ok(StockBookmarks {
-                    bookmarks: HashMap::new(),
-                })
+                Ok(Self::empty())
+            }
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    /// Like `read`, but with `get` returning versions picked by `scheme`. A missing file has the
+    /// same version as an empty one, except that its mtime is taken to be 0.
+    pub fn read_with_version_scheme<P: Into<PathBuf>>(
+        base: P,
+        scheme: VersionScheme,
+    ) -> Result<Self> {
+        let path = base.into().join("bookmarks");
+
+        let file = match fs::File::open(&path) {
+            Ok(file) => Some(file),
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => None,
+            Err(err) => return Err(err.into()),
+        };
+        let (bookmarks, version) = match (file, scheme) {
+            (None, VersionScheme::Constant) => (Self::empty(), 1),
+            (None, VersionScheme::Mtime) => (Self::empty(), 0),
+            (None, VersionScheme::ContentHash) => (Self::empty(), content_version(b"")),
+            (Some(file), VersionScheme::Constant) => (Self::from_reader(file)?, 1),
+            (Some(file), VersionScheme::Mtime) => {
+                let modified = file.metadata()?.modified()?.duration_since(UNIX_EPOCH)?;
+                let mtime = modified.as_secs() * 1_000_000_000 + modified.subsec_nanos() as u64;
+                (Self::from_reader(file)?, mtime)
             }
+            (Some(mut file), VersionScheme::ContentHash) => {
+                let mut contents = Vec::new();
+                file.read_to_end(&mut contents)?;
+                (Self::from_reader(&contents[..])?, content_version(&contents))
+            }
+        };
+        Ok(StockBookmarks {
+            version: Version::from(version),
+            ..bookmarks
+        })
+    }
+
+    /// Read the `.hg/bookmarks.pending` file Mercurial writes while a transaction that moves
+    /// bookmarks is in progress, or `None` if there isn't one.
+    ///
+    /// Pending state is transient: the file is renamed over `.hg/bookmarks` when the
+    /// transaction commits and removed if it aborts, so it can disappear at any time.
+    pub fn read_pending<P: Into<PathBuf>>(base: P) -> Result<Option<Self>> {
+        let base = base.into();
+
+        let file = fs::File::open(base.join("bookmarks.pending"));
+        match file {
+            Ok(file) => Ok(Some(Self::from_reader(file)?)),
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
             Err(err) => Err(err.into()),
         }
     }
 
+    /// Read bookmarks in the `.hg/bookmarks` format from stdin.
+    pub fn read_stdin() -> Result<Self> {
+        let stdin = io::stdin();
+        let locked = stdin.lock();
+        Self::from_reader(locked)
+    }
+
+    /// Read bookmarks from a file in the `.hg/bookmarks` format. Unlike `read`, this takes the
+    /// path to the file itself rather than the `.hg` directory, and a missing file is an error.
+    /// A path of `-` reads from stdin, for use by command-line tools.
+    pub fn read_file<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+        if path == Path::new("-") {
+            Self::read_stdin()
+        } else {
+            Self::from_reader(fs::File::open(path)?)
+        }
+    }
+
+    /// Read several files in the `.hg/bookmarks` format and combine them in order, so that a
+    /// bookmark in a later file overrides one with the same name in an earlier file. As with
+    /// `read_file`, the paths are to the files themselves, and a missing or malformed file is an
+    /// error.
+    pub fn read_overlay<P: AsRef<Path>>(paths: &[P]) -> Result<Self> {
+        let mut overlay = Self::empty();
+        for path in paths {
+            let layer = Self::from_reader(fs::File::open(path.as_ref())?)?;
+            let StockBookmarks {
+                bookmarks,
+                order,
+                ..
+            } = layer;
+            overlay.extend(order.into_iter().map(|name| {
+                let hash = bookmarks[&name];
+                (name, hash)
+            }));
+        }
+        Ok(overlay)
+    }
+
+    /// Check that a file is in the `.hg/bookmarks` format, failing with `ErrorKind::BadLineAt` for
+    /// the first line that isn't. Nothing is kept around, so this is cheap even for huge files.
+    pub fn validate_file<P: Into<PathBuf>>(path: P) -> Result<()> {
+        let file = fs::File::open(path.into())?;
+        // Line numbers start from 1, as in editors.
+        for (idx, line) in BufReader::new(file).split(b'\n').enumerate() {
+            parse_line(&line?).context(ErrorKind::BadLineAt(idx + 1))?;
+        }
+        Ok(())
+    }
+
+    /// Like `read`, but skips malformed lines instead of failing, and returns a report of what
+    /// was skipped alongside the bookmarks that could be parsed. IO errors still fail.
+    pub fn read_with_report<P: Into<PathBuf>>(base: P) -> Result<(Self, ParseReport)> {
+        let base = base.into();
+        let mut report = ParseReport::default();
+
+        let file = fs::File::open(base.join("bookmarks"));
+        let bookmarks = match file {
+            Ok(file) => Self::from_reader_impl(file, ParseOptions::default(), Some(&mut report))?,
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => Self::empty(),
+            Err(err) => return Err(err.into()),
+        };
+        Ok((bookmarks, report))
+    }
+
+    /// What has changed relative to a `known` snapshot of the bookmarks, for when the earlier
+    /// state didn't come from a bookmarks file.
+    pub fn changed_since(&self, known: &HashMap<Vec<u8>, NodeHash>) -> BookmarksDiff {
+        let mut diff = BookmarksDiff::default();
+        for (name, hash) in &self.bookmarks {
+            match known.get(name) {
+                None => diff.added.push((name.clone(), *hash)),
+                Some(old) if old != hash => diff.changed.push((name.clone(), *old, *hash)),
+                Some(_) => {}
+            }
+        }
+        for (name, old) in known {
+            if !self.bookmarks.contains_key(name) {
+                diff.removed.push((name.clone(), *old));
+            }
+        }
+        diff.sort();
+        diff
+    }
+
+    /// Build bookmarks from (name, hash) pairs. As when parsing a file, if a name appears more
+    /// than once the last entry wins.
+    pub fn from_entries<I>(entries: I) -> Self
+    where
+        I: IntoIterator<Item = (Vec<u8>, NodeHash)>,
+    {
+        let mut bookmarks = Self::empty();
+        bookmarks.extend(entries);
+        bookmarks
+    }
+
+    /// The number of bookmarks.
+    pub fn len(&self) -> usize {
+        self.bookmarks.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.bookmarks.is_empty()
+    }
+
+    fn empty() -> Self {
+        StockBookmarks {
+            bookmarks: HashMap::new(),
+            order: Vec::new(),
+            active: None,
+            version: Version::from(1),
+        }
+    }
+
+    /// Add a bookmark, replacing any existing one with the same name. A replaced bookmark keeps
+    /// its original position in the ordering.
+    fn insert(&mut self, name: Vec<u8>, hash: NodeHash) {
+        if self.bookmarks.insert(name.clone(), hash).is_none() {
+            self.order.push(name);
+        }
+    }
+
+    /// Like `keys`, but yields the bookmark names in the order they appeared in the file. If a
+    /// name appears more than once, its first position is used.
+    pub fn keys_ordered(&self) -> BoxStream<Vec<u8>, Error> {
+        stream::iter_ok(self.order.clone()).boxify()
+    }
+
+    /// All the bookmarks pointing at a hash that starts with `prefix`, sorted by name, for
+    /// looking up abbreviated hashes. The prefix is case-insensitive, and has to be at least
+    /// `MIN_HASH_PREFIX_LEN` digits long. Prefixes of several hashes match all of them.
+    pub fn names_for_hash_prefix(&self, prefix: &AsciiStr) -> Result<Vec<(Vec<u8>, NodeHash)>> {
+        if prefix.len() < MIN_HASH_PREFIX_LEN {
+            return Err(ErrorKind::HashPrefixTooShort(prefix.to_string()).into());
+        }
+        let prefix = prefix.as_str().to_lowercase();
+        let mut matches: Vec<_> = self.bookmarks
+            .iter()
+            .filter(|&(_, hash)| hash.to_hex().as_str().starts_with(&prefix))
+            .map(|(name, hash)| (name.clone(), *hash))
+            .collect();
+        matches.sort();
+        Ok(matches)
+    }
+
+    /// Like `get`, for callers whose bookmark names are strings.
+    pub fn get_str(&self, name: &str) -> BoxFuture<Option<(NodeHash, Version)>, Error> {
+        self.get(&name)
+    }
+
+    /// Like `keys`, but as strings. Names that aren't valid UTF-8 are left out rather than
+    /// decoded lossily, so that every name yielded can be looked up again with `get_str`.
+    pub fn keys_str(&self) -> BoxStream<String, Error> {
+        let names: Vec<_> = self.bookmarks
+            .keys()
+            .filter_map(|name| String::from_utf8(name.clone()).ok())
+            .collect();
+        stream::iter_ok(names).boxify()
+    }
+
     fn from_reader<R: Read>(reader: R) -> Result<Self> {
-        let mut bookmarks = HashMap::new();
+        Self::from_reader_impl(reader, ParseOptions::default(), None)
+    }
+
+    /// Like `from_reader`, but reads from an async byte source such as a network stream. Each
+    /// line is parsed as soon as it has arrived, so the whole file is never buffered.
+    pub fn from_async_read<R>(reader: R) -> BoxFuture<Self, Error>
+    where
+        R: AsyncRead + Send + 'static,
+    {
+        FramedRead::new(reader, LineDecoder)
+            .from_err()
+            .fold(Self::empty(), |mut bookmarks, line| {
+                let (bmname, hash) = parse_line(&line)?;
+                bookmarks.insert(bmname, hash);
+                Ok::<_, Error>(bookmarks)
+            })
+            .boxify()
+    }
+
+    /// Like `from_reader`, but fails with `ErrorKind::DuplicateBookmark` if a name appears more
+    /// than once, instead of letting the last entry win the way Mercurial does.
+    pub fn from_reader_no_dups<R: Read>(reader: R) -> Result<Self> {
+        let options = ParseOptions {
+            reject_dups: true,
+            ..ParseOptions::default()
+        };
+        Self::from_reader_impl(reader, options, None)
+    }
+
+    /// Like `from_reader`, but skips blank lines and lines whose first non-whitespace byte is
+    /// `#`.
+    ///
+    /// Note that this deviates from stock Mercurial, which doesn't allow comments in
+    /// `.hg/bookmarks`. It's meant for bookmark files annotated by tooling.
+    pub fn from_reader_with_comments<R: Read>(reader: R) -> Result<Self> {
+        let options = ParseOptions {
+            allow_comments: true,
+            ..ParseOptions::default()
+        };
+        Self::from_reader_impl(reader, options, None)
+    }
+
+    /// Like `from_reader`, but allows one line to be marked as the active bookmark by starting
+    /// it with `@ `, as in `@ <hash> <name>`. The marker is stripped before the line is parsed,
+    /// and the name is then available from `active`. If more than one line is marked, the last
+    /// one wins.
+    ///
+    /// Stock Mercurial keeps the active bookmark in `.hg/bookmarks.current` instead; this is for
+    /// exported bookmark files that fold it into the one file.
+    pub fn from_reader_hg_at<R: Read>(reader: R) -> Result<Self> {
+        let options = ParseOptions {
+            allow_active_marker: true,
+            ..ParseOptions::default()
+        };
+        Self::from_reader_impl(reader, options, None)
+    }
+
+    /// The active bookmark, if one was marked in a file read by `from_reader_hg_at`.
+    pub fn active(&self) -> Option<&[u8]> {
+        self.active.as_ref().map(|name| name.as_slice())
+    }
+
+    /// If `report` is provided, malformed lines are recorded in it and skipped rather than
+    /// failing the whole parse.
+    fn from_reader_impl<R: Read>(
+        reader: R,
+        options: ParseOptions,
+        mut report: Option<&mut ParseReport>,
+    ) -> Result<Self> {
+        let ParseOptions {
+            allow_comments,
+            reject_dups,
+            allow_active_marker,
+        } = options;
+        let mut bookmarks = Self::empty();
 
         // Bookmark names might not be valid UTF-8, so use split() instead of lines().
         for line in BufReader::new(reader).split(b'\n') {
             let line = line?;
-            // <hash><space><bookmark name>, where hash is 40 bytes, the space is 1 byte
-            // and the bookmark name is at least 1 byte.
-            if line.len() < 42 || line[40] != b' ' {
+            if allow_comments && is_comment_or_blank(&line) {
+                continue;
+            }
+            let (line, is_active) = if allow_active_marker && line.starts_with(b"@ ") {
+                (&line[2..], true)
+            } else {
+                (&line[..], false)
+            };
+            let (bmname, hash) = match (parse_line(line), report.as_mut()) {
+                (Ok(entry), _) => entry,
+                (Err(_), Some(report)) => {
+                    report.record(LineProblem::classify(line), line);
+                    continue;
+                }
+                (Err(err), None) => return Err(err),
+            };
+            if reject_dups && bookmarks.bookmarks.contains_key(&bmname) {
                 return Err(
-                    ErrorKind::InvalidBookmarkLine(
-                        String::from_utf8_lossy(line.as_ref()).into_owned(),
-                    ).into(),
+                    ErrorKind::DuplicateBookmark(String::from_utf8_lossy(&bmname).into_owned())
+                        .into(),
                 );
             }
-            let bmname = &line[41..];
-            let hash_slice = &line[..40];
-            let hash = AsciiStr::from_ascii(&hash_slice).context(ErrorKind::InvalidHash(
-                String::from_utf8_lossy(hash_slice).into_owned(),
-            ))?;
-            bookmarks.insert(
-                bmname.into(),
-                NodeHash::from_ascii_str(hash).context(ErrorKind::InvalidHash(
-                    String::from_utf8_lossy(hash_slice).into_owned(),
-                ))?,
-            );
+            if is_active {
+                bookmarks.active = Some(bmname.clone());
+            }
+            bookmarks.insert(bmname, hash);
+        }
+
+        Ok(bookmarks)
+    }
+}
+
+/// The ways in which a bookmarks line can be malformed.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Hash)]
+pub enum LineProblem {
+    /// The line is too short to contain a hash and a separator.
+    ShortLine,
+    /// The hash isn't 40 hex digits followed by a space.
+    BadHash,
+    /// The hash contains non-ASCII bytes.
+    NonAscii,
+    /// There is nothing after the separator.
+    EmptyName,
+}
+
+impl LineProblem {
+    fn classify(line: &[u8]) -> Self {
+        if line.len() < 41 {
+            LineProblem::ShortLine
+        } else if line[..40].iter().any(|b| *b >= 0x80) {
+            LineProblem::NonAscii
+        } else if line[40] != b' ' {
+            LineProblem::BadHash
+        } else if line.len() == 41 {
+            LineProblem::EmptyName
+        } else {
+            LineProblem::BadHash
+        }
+    }
+}
+
+/// The variations on the `.hg/bookmarks` format that `from_reader_impl` accepts. The default is
+/// the plain format.
+#[derive(Clone, Copy, Debug, Default)]
+struct ParseOptions {
+    /// Skip `#` comment lines and blank lines.
+    allow_comments: bool,
+    /// Fail on a name that's given more than once, instead of keeping the last one.
+    reject_dups: bool,
+    /// Take a leading `@ ` as marking the active bookmark.
+    allow_active_marker: bool,
+}
+
+/// Number of example lines `ParseReport` keeps around.
+const MAX_REPORT_EXAMPLES: usize = 5;
+
+/// A tally of the malformed lines skipped by `read_with_report`.
+#[derive(Clone, Debug, Default)]
+pub struct ParseReport {
+    counts: HashMap<LineProblem, usize>,
+    examples: Vec<(LineProblem, String)>,
+}
+
+impl ParseReport {
+    fn record(&mut self, problem: LineProblem, line: &[u8]) {
+        *self.counts.entry(problem).or_insert(0) += 1;
+        if self.examples.len() < MAX_REPORT_EXAMPLES {
+            self.examples
+                .push((problem, String::from_utf8_lossy(line).into_owned()));
+        }
+    }
+
+    /// How many lines were skipped because of the given problem.
+    pub fn count(&self, problem: LineProblem) -> usize {
+        self.counts.get(&problem).cloned().unwrap_or(0)
+    }
+
+    /// How many lines were skipped in total.
+    pub fn skipped(&self) -> usize {
+        self.counts.values().sum()
+    }
+
+    /// The first few skipped lines, lossily converted to UTF-8.
+    pub fn examples(&self) -> &[(LineProblem, String)] {
+        &self.examples
+    }
+}
+
+fn is_comment_or_blank(line: &[u8]) -> bool {
+    let first = line.iter()
+        .find(|&&b| b != b' ' && b != b'\t' && b != b'\r');
+    match first {
+        None => true,
+        Some(&b) => b == b'#',
+    }
+}
+
+/// Splits bytes into lines on `\n`, the same way `from_reader` does. The terminator isn't
+/// included, and the last line doesn't need one.
+struct LineDecoder;
+
+impl Decoder for LineDecoder {
+    type Item = BytesMut;
+    type Error = io::Error;
+
+    fn decode(&mut self, buf: &mut BytesMut) -> io::Result<Option<BytesMut>> {
+        match buf.iter().position(|b| *b == b'\n') {
+            Some(pos) => {
+                let mut line = buf.split_to(pos + 1);
+                line.truncate(pos);
+                Ok(Some(line))
+            }
+            None => Ok(None),
         }
+    }
 
-        Ok(StockBookmarks { bookmarks })
+    fn decode_eof(&mut self, buf: &mut BytesMut) -> io::Result<Option<BytesMut>> {
+        match self.decode(buf)? {
+            Some(line) => Ok(Some(line)),
+            None if buf.is_empty() => Ok(None),
+            None => Ok(Some(buf.take())),
+        }
+    }
+}
+
+/// The version for bookmarks file contents under `VersionScheme::ContentHash`: the first 8 bytes
+/// of their SHA-1.
+fn content_version(contents: &[u8]) -> u64 {
+    Sha1::from(contents)
+        .as_ref()
+        .iter()
+        .take(8)
+        .fold(0, |version, byte| version << 8 | *byte as u64)
+}
+
+fn parse_line(line: &[u8]) -> Result<(Vec<u8>, NodeHash)> {
+    // <hash><space><bookmark name>, where hash is 40 bytes, the space is 1 byte
+    // and the bookmark name is at least 1 byte.
+    if line.len() < 42 || line[40] != b' ' {
+        return Err(
+            ErrorKind::InvalidBookmarkLine(String::from_utf8_lossy(line.as_ref()).into_owned())
+                .into(),
+        );
+    }
+    let bmname = &line[41..];
+    let hash_slice = &line[..40];
+    let hash = AsciiStr::from_ascii(&hash_slice).context(ErrorKind::InvalidHash(
+        String::from_utf8_lossy(hash_slice).into_owned(),
+    ))?;
+    let hash = NodeHash::from_ascii_str(hash).context(ErrorKind::InvalidHash(
+        String::from_utf8_lossy(hash_slice).into_owned(),
+    ))?;
+    Ok((bmname.into(), hash))
+}
+
+impl Default for StockBookmarks {
+    fn default() -> Self {
+        Self::empty()
+    }
+}
+
+/// As with `from_entries`, the last entry for a name wins.
+impl Extend<(Vec<u8>, NodeHash)> for StockBookmarks {
+    fn extend<I>(&mut self, entries: I)
+    where
+        I: IntoIterator<Item = (Vec<u8>, NodeHash)>,
+    {
+        for (name, hash) in entries {
+            self.insert(name, hash);
+        }
     }
 }
 
 impl Bookmarks for StockBookmarks {
     fn get(&self, name: &AsRef<[u8]>) -> BoxFuture<Option<(NodeHash, Version)>, Error> {
         let value = match self.bookmarks.get(name.as_ref()) {
-            Some(hash) => Some((*hash, Version::from(1))),
+            Some(hash) => Some((*hash, self.version)),
             None => None,
         };
         Box::new(future::result(Ok(value)))
@@ -130,15 +640,26 @@ impl Bookmarks for StockBookmarks {
         ).and_then(|x| x)
             .boxify()
     }
+
+    fn keys_set(&self) -> BoxFuture<HashSet<Vec<u8>>, Error> {
+        Box::new(future::ok(self.bookmarks.keys().cloned().collect()))
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use std::io::Cursor;
 
+    use std::fs::File;
+    use std::io::Write;
+
     use failure::Context;
     use futures::Future;
     use mercurial_types_mocks::nodehash;
+    use tempdir::TempDir;
+
+    use bookmarks::BookmarkOp;
+    use bookmarks::ErrorKind as BookmarksErrorKind;
 
     use super::*;
 
@@ -176,6 +697,460 @@ mod tests {
         assert_eq!(list, vec![&b"abc"[..], &b"def"[..], &b"test123"[..]]);
     }
 
+    /// Hands out its data at most `chunk` bytes at a time.
+    struct Chunked {
+        data: Vec<u8>,
+        pos: usize,
+        chunk: usize,
+    }
+
+    impl Read for Chunked {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let len = *[self.chunk, buf.len(), self.data.len() - self.pos]
+                .iter()
+                .min()
+                .unwrap();
+            buf[..len].copy_from_slice(&self.data[self.pos..self.pos + len]);
+            self.pos += len;
+            Ok(len)
+        }
+    }
+
+    impl AsyncRead for Chunked {}
+
+    #[test]
+    fn test_from_async_read() {
+        // The last line isn't terminated.
+        let disk_bookmarks = b"\
+            1111111111111111111111111111111111111111 abc\n\
+            2222222222222222222222222222222222222222 def\n\
+            3333333333333333333333333333333333333333 test123";
+
+        // Chunk sizes that split lines at the start, in the middle of the hash, right at the
+        // separator and across the terminator.
+        for &chunk in &[1, 7, 40, 41, 45, 1000] {
+            let reader = Chunked {
+                data: disk_bookmarks.to_vec(),
+                pos: 0,
+                chunk,
+            };
+            let bookmarks = StockBookmarks::from_async_read(reader).wait().unwrap();
+            assert_bookmark_get(&bookmarks, &"abc", Some(nodehash::ONES_HASH));
+            assert_bookmark_get(&bookmarks, &"def", Some(nodehash::TWOS_HASH));
+            assert_bookmark_get(&bookmarks, &"test123", Some(nodehash::THREES_HASH));
+
+            let mut list = bookmarks.keys().collect().wait().unwrap();
+            list.sort();
+            assert_eq!(list, vec![&b"abc"[..], &b"def"[..], &b"test123"[..]]);
+        }
+
+        let reader = Chunked {
+            data: b"111 abc\n".to_vec(),
+            pos: 0,
+            chunk: 3,
+        };
+        let err = StockBookmarks::from_async_read(reader).wait().unwrap_err();
+        assert_matches!(
+            err.downcast::<ErrorKind>().unwrap(),
+            ErrorKind::InvalidBookmarkLine(_)
+        );
+    }
+
+    #[test]
+    fn test_from_entries() {
+        let bookmarks = StockBookmarks::from_entries(vec![
+            (b"abc".to_vec(), nodehash::ONES_HASH),
+            (b"def".to_vec(), nodehash::TWOS_HASH),
+            (b"abc".to_vec(), nodehash::THREES_HASH),
+        ]);
+        assert_bookmark_get(&bookmarks, &"abc", Some(nodehash::THREES_HASH));
+        assert_bookmark_get(&bookmarks, &"def", Some(nodehash::TWOS_HASH));
+        assert_bookmark_get(&bookmarks, &"ghi", None);
+
+        let mut list = bookmarks.keys().collect().wait().unwrap();
+        list.sort();
+        assert_eq!(list, vec![&b"abc"[..], &b"def"[..]]);
+        let list = bookmarks.keys_ordered().collect().wait().unwrap();
+        assert_eq!(list, vec![&b"abc"[..], &b"def"[..]]);
+    }
+
+    #[test]
+    fn test_compare_and_set_unsupported() {
+        let bookmarks = StockBookmarks::from_entries(vec![(b"abc".to_vec(), nodehash::ONES_HASH)]);
+        let err = bookmarks
+            .compare_and_set(&"abc", Some(nodehash::ONES_HASH), nodehash::TWOS_HASH)
+            .wait()
+            .unwrap_err();
+        assert_matches!(
+            err.downcast::<BookmarksErrorKind>().unwrap(),
+            BookmarksErrorKind::Unsupported(_)
+        );
+        assert_bookmark_get(&bookmarks, &"abc", Some(nodehash::ONES_HASH));
+    }
+
+    #[test]
+    fn test_transaction_unsupported() {
+        let bookmarks = StockBookmarks::from_entries(vec![(b"abc".to_vec(), nodehash::ONES_HASH)]);
+        let ops = vec![
+            BookmarkOp::Delete {
+                name: b"abc".to_vec(),
+            },
+        ];
+        let err = bookmarks.apply_transaction(ops).wait().unwrap_err();
+        assert_matches!(
+            err.downcast::<BookmarksErrorKind>().unwrap(),
+            BookmarksErrorKind::Unsupported(_)
+        );
+        assert_bookmark_get(&bookmarks, &"abc", Some(nodehash::ONES_HASH));
+    }
+
+    #[test]
+    fn test_default_extend() {
+        let mut bookmarks = StockBookmarks::default();
+        assert!(bookmarks.is_empty());
+
+        bookmarks.extend(vec![
+            (b"abc".to_vec(), nodehash::ONES_HASH),
+            (b"def".to_vec(), nodehash::TWOS_HASH),
+        ]);
+        bookmarks.extend(vec![(b"abc".to_vec(), nodehash::THREES_HASH)]);
+
+        assert_eq!(bookmarks.len(), 2);
+        assert_bookmark_get(&bookmarks, &"abc", Some(nodehash::THREES_HASH));
+        assert_bookmark_get(&bookmarks, &"def", Some(nodehash::TWOS_HASH));
+    }
+
+    #[test]
+    fn test_from_reader_hg_at() {
+        let disk_bookmarks = b"\
+            1111111111111111111111111111111111111111 abc\n\
+            @ 2222222222222222222222222222222222222222 def\n\
+            3333333333333333333333333333333333333333 ghi\n";
+
+        let bookmarks = StockBookmarks::from_reader_hg_at(&disk_bookmarks[..]).unwrap();
+        assert_eq!(bookmarks.active(), Some(&b"def"[..]));
+        assert_bookmark_get(&bookmarks, &"abc", Some(nodehash::ONES_HASH));
+        assert_bookmark_get(&bookmarks, &"def", Some(nodehash::TWOS_HASH));
+        assert_bookmark_get(&bookmarks, &"ghi", Some(nodehash::THREES_HASH));
+        assert_eq!(bookmarks.len(), 3);
+
+        // Without the opt-in, the marker is a parse error.
+        assert!(StockBookmarks::from_reader(&disk_bookmarks[..]).is_err());
+        let unmarked = b"1111111111111111111111111111111111111111 abc\n";
+        let bookmarks = StockBookmarks::from_reader_hg_at(&unmarked[..]).unwrap();
+        assert_eq!(bookmarks.active(), None);
+    }
+
+    #[test]
+    fn test_read_file() {
+        let disk_bookmarks = b"\
+            1111111111111111111111111111111111111111 abc\n\
+            2222222222222222222222222222222222222222 def\n";
+        let tmp = TempDir::new("stockbookmarks_read_file").unwrap();
+        let path = tmp.path().join("generated-bookmarks");
+        File::create(&path)
+            .unwrap()
+            .write_all(disk_bookmarks)
+            .unwrap();
+
+        let bookmarks = StockBookmarks::read_file(&path).unwrap();
+        assert_bookmark_get(&bookmarks, &"abc", Some(nodehash::ONES_HASH));
+        assert_bookmark_get(&bookmarks, &"def", Some(nodehash::TWOS_HASH));
+
+        // read_stdin goes through from_reader too.
+        let bookmarks = StockBookmarks::from_reader(Cursor::new(&disk_bookmarks[..])).unwrap();
+        assert_bookmark_get(&bookmarks, &"abc", Some(nodehash::ONES_HASH));
+
+        // Unlike read, a missing file is an error.
+        assert!(StockBookmarks::read_file(tmp.path().join("missing")).is_err());
+    }
+
+    #[test]
+    fn test_read_overlay() {
+        let tmp = TempDir::new("stockbookmarks_read_overlay").unwrap();
+        let base = tmp.path().join("base");
+        File::create(&base)
+            .unwrap()
+            .write_all(
+                b"1111111111111111111111111111111111111111 shared\n\
+                  1111111111111111111111111111111111111111 base-only\n",
+            )
+            .unwrap();
+        let overrides = tmp.path().join("overrides");
+        File::create(&overrides)
+            .unwrap()
+            .write_all(
+                b"2222222222222222222222222222222222222222 shared\n\
+                  2222222222222222222222222222222222222222 override-only\n",
+            )
+            .unwrap();
+
+        let bookmarks = StockBookmarks::read_overlay(&[&base, &overrides]).unwrap();
+        assert_eq!(bookmarks.len(), 3);
+        assert_bookmark_get(&bookmarks, &"shared", Some(nodehash::TWOS_HASH));
+        assert_bookmark_get(&bookmarks, &"base-only", Some(nodehash::ONES_HASH));
+        assert_bookmark_get(&bookmarks, &"override-only", Some(nodehash::TWOS_HASH));
+
+        // Any file failing fails the whole overlay.
+        File::create(&overrides)
+            .unwrap()
+            .write_all(b"not a bookmark\n")
+            .unwrap();
+        assert!(StockBookmarks::read_overlay(&[&base, &overrides]).is_err());
+        assert!(StockBookmarks::read_overlay(&[base, tmp.path().join("missing")]).is_err());
+    }
+
+    #[test]
+    fn test_names_for_hash_prefix() {
+        let disk_bookmarks = b"\
+            1111111111111111111111111111111111111111 abc\n\
+            2222222222222222222222222222222222222222 def\n\
+            1111111111111111111111111111111111111111 test123\n";
+        let bookmarks = StockBookmarks::from_reader(Cursor::new(&disk_bookmarks[..])).unwrap();
+
+        let prefix = AsciiStr::from_ascii("11111").unwrap();
+        assert_eq!(
+            bookmarks.names_for_hash_prefix(prefix).unwrap(),
+            vec![
+                (b"abc".to_vec(), nodehash::ONES_HASH),
+                (b"test123".to_vec(), nodehash::ONES_HASH),
+            ]
+        );
+        let prefix = AsciiStr::from_ascii("3333").unwrap();
+        assert_eq!(bookmarks.names_for_hash_prefix(prefix).unwrap(), vec![]);
+
+        let prefix = AsciiStr::from_ascii("111").unwrap();
+        assert_matches!(
+            bookmarks
+                .names_for_hash_prefix(prefix)
+                .unwrap_err()
+                .downcast::<ErrorKind>()
+                .unwrap(),
+            ErrorKind::HashPrefixTooShort(_)
+        );
+    }
+
+    #[test]
+    fn test_str_names() {
+        let bookmarks = StockBookmarks::from_entries(vec![
+            (b"abc".to_vec(), nodehash::ONES_HASH),
+            (b"bad\xff".to_vec(), nodehash::TWOS_HASH),
+        ]);
+        assert_eq!(
+            bookmarks.get_str("abc").wait().unwrap(),
+            Some((nodehash::ONES_HASH, Version::from(1)))
+        );
+        assert_eq!(bookmarks.get_str("def").wait().unwrap(), None);
+
+        // The name that isn't UTF-8 is left out.
+        assert_eq!(
+            bookmarks.keys_str().collect().wait().unwrap(),
+            vec!["abc".to_string()]
+        );
+    }
+
+    #[test]
+    fn test_validate_file() {
+        let tmp = TempDir::new("stockbookmarks_validate_file").unwrap();
+        let good = tmp.path().join("good");
+        File::create(&good)
+            .unwrap()
+            .write_all(
+                b"1111111111111111111111111111111111111111 abc\n\
+                  2222222222222222222222222222222222222222 def\n",
+            )
+            .unwrap();
+        StockBookmarks::validate_file(&good).unwrap();
+
+        let bad = tmp.path().join("bad");
+        File::create(&bad)
+            .unwrap()
+            .write_all(
+                b"1111111111111111111111111111111111111111 abc\n\
+                  2222222222222222222222222222222222222222\n\
+                  3333333333333333333333333333333333333333\n",
+            )
+            .unwrap();
+        let err = StockBookmarks::validate_file(&bad).unwrap_err();
+        match err.downcast::<Context<ErrorKind>>() {
+            Ok(ctx) => assert_matches!(ctx.get_context(), &ErrorKind::BadLineAt(2)),
+            Err(err) => panic!("unexpected error: {}", err),
+        }
+    }
+
+    #[test]
+    fn test_changed_since() {
+        let bookmarks = StockBookmarks::from_entries(vec![
+            (b"same".to_vec(), nodehash::ONES_HASH),
+            (b"moved".to_vec(), nodehash::THREES_HASH),
+            (b"new".to_vec(), nodehash::TWOS_HASH),
+        ]);
+
+        // The snapshot doesn't know about "new", and has one bookmark that's since been deleted.
+        let mut known = HashMap::new();
+        known.insert(b"same".to_vec(), nodehash::ONES_HASH);
+        known.insert(b"moved".to_vec(), nodehash::TWOS_HASH);
+        known.insert(b"gone".to_vec(), nodehash::ONES_HASH);
+
+        let diff = bookmarks.changed_since(&known);
+        assert_eq!(
+            diff,
+            BookmarksDiff {
+                added: vec![(b"new".to_vec(), nodehash::TWOS_HASH)],
+                removed: vec![(b"gone".to_vec(), nodehash::ONES_HASH)],
+                changed: vec![
+                    (b"moved".to_vec(), nodehash::TWOS_HASH, nodehash::THREES_HASH),
+                ],
+            }
+        );
+
+        let known = bookmarks.bookmarks.clone();
+        assert!(bookmarks.changed_since(&known).is_empty());
+    }
+
+    #[test]
+    fn test_read_pending() {
+        let tmp = TempDir::new("stockbookmarks_read_pending").unwrap();
+        assert!(
+            StockBookmarks::read_pending(tmp.path())
+                .unwrap()
+                .is_none()
+        );
+
+        File::create(tmp.path().join("bookmarks"))
+            .unwrap()
+            .write_all(b"1111111111111111111111111111111111111111 abc\n")
+            .unwrap();
+        File::create(tmp.path().join("bookmarks.pending"))
+            .unwrap()
+            .write_all(
+                b"2222222222222222222222222222222222222222 abc\n\
+                  3333333333333333333333333333333333333333 def\n",
+            )
+            .unwrap();
+
+        let pending = StockBookmarks::read_pending(tmp.path())
+            .unwrap()
+            .expect("pending bookmarks should be present");
+        assert_bookmark_get(&pending, &"abc", Some(nodehash::TWOS_HASH));
+        assert_bookmark_get(&pending, &"def", Some(nodehash::THREES_HASH));
+
+        // The committed bookmarks are unaffected.
+        let bookmarks = StockBookmarks::read(tmp.path()).unwrap();
+        assert_bookmark_get(&bookmarks, &"abc", Some(nodehash::ONES_HASH));
+        assert_bookmark_get(&bookmarks, &"def", None);
+    }
+
+    #[test]
+    fn test_keys_ordered() {
+        let disk_bookmarks = b"\
+            1111111111111111111111111111111111111111 zzz\n\
+            2222222222222222222222222222222222222222 abc\n\
+            1111111111111111111111111111111111111111 mmm\n\
+            2222222222222222222222222222222222222222 zzz\n";
+        let reader = Cursor::new(&disk_bookmarks[..]);
+
+        let bookmarks = StockBookmarks::from_reader(reader).unwrap();
+        let list = bookmarks.keys_ordered().collect().wait().unwrap();
+        assert_eq!(list, vec![&b"zzz"[..], &b"abc"[..], &b"mmm"[..]]);
+        // The duplicate still wins for lookups.
+        assert_bookmark_get(&bookmarks, &"zzz", Some(nodehash::TWOS_HASH));
+    }
+
+    #[test]
+    fn test_keys_set() {
+        let disk_bookmarks = b"\
+            1111111111111111111111111111111111111111 abc\n\
+            2222222222222222222222222222222222222222 def\n\
+            1111111111111111111111111111111111111111 test123\n";
+        let reader = Cursor::new(&disk_bookmarks[..]);
+
+        let bookmarks = StockBookmarks::from_reader(reader).unwrap();
+        let expected: HashSet<_> = vec![b"abc".to_vec(), b"def".to_vec(), b"test123".to_vec()]
+            .into_iter()
+            .collect();
+        assert_eq!(bookmarks.keys_set().wait().unwrap(), expected);
+    }
+
+    #[test]
+    fn test_duplicates() {
+        let disk_bookmarks = b"\
+            1111111111111111111111111111111111111111 abc\n\
+            2222222222222222222222222222222222222222 def\n\
+            3333333333333333333333333333333333333333 abc\n";
+
+        // By default the last entry wins, like in Mercurial.
+        let bookmarks = StockBookmarks::from_reader(Cursor::new(&disk_bookmarks[..])).unwrap();
+        assert_bookmark_get(&bookmarks, &"abc", Some(nodehash::THREES_HASH));
+        assert_bookmark_get(&bookmarks, &"def", Some(nodehash::TWOS_HASH));
+
+        let bookmarks = StockBookmarks::from_reader_no_dups(Cursor::new(&disk_bookmarks[..]));
+        assert_matches!(
+            bookmarks.unwrap_err().downcast::<ErrorKind>().unwrap(),
+            ErrorKind::DuplicateBookmark(ref name) if name == "abc"
+        );
+    }
+
+    #[test]
+    fn test_parse_with_comments() {
+        let disk_bookmarks = b"\
+            # generated by some tool\n\
+            1111111111111111111111111111111111111111 abc\n\
+            \n\
+            \t  # indented comment\n\
+            2222222222222222222222222222222222222222 def\n\
+            \x20\x20\n";
+        let reader = Cursor::new(&disk_bookmarks[..]);
+
+        let bookmarks = StockBookmarks::from_reader_with_comments(reader).unwrap();
+        assert_bookmark_get(&bookmarks, &"abc", Some(nodehash::ONES_HASH));
+        assert_bookmark_get(&bookmarks, &"def", Some(nodehash::TWOS_HASH));
+
+        let mut list = bookmarks.keys().collect().wait().unwrap();
+        list.sort();
+        assert_eq!(list, vec![&b"abc"[..], &b"def"[..]]);
+
+        // The strict parser still rejects comments.
+        let reader = Cursor::new(&disk_bookmarks[..]);
+        let bookmarks = StockBookmarks::from_reader(reader);
+        assert_matches!(
+            bookmarks.unwrap_err().downcast::<ErrorKind>().unwrap(),
+            ErrorKind::InvalidBookmarkLine(_)
+        );
+    }
+
+    #[test]
+    fn test_read_with_report() {
+        let disk_bookmarks = b"\
+            1111111111111111111111111111111111111111 abc\n\
+            111\n\
+            1111111111111111111111111111111111111111 \n\
+            111111111111111111111111111111111111111\xff test\n\
+            abcdefgabcdefgabcdefgabcdefgabcdefgabcde test\n\
+            1111111111111111111111111111111111111111ab\n\
+            2222222222222222222222222222222222222222 def\n";
+        let tmp = TempDir::new("stockbookmarks_read_with_report").unwrap();
+        File::create(tmp.path().join("bookmarks"))
+            .unwrap()
+            .write_all(disk_bookmarks)
+            .unwrap();
+
+        let (bookmarks, report) = StockBookmarks::read_with_report(tmp.path()).unwrap();
+        assert_bookmark_get(&bookmarks, &"abc", Some(nodehash::ONES_HASH));
+        assert_bookmark_get(&bookmarks, &"def", Some(nodehash::TWOS_HASH));
+
+        assert_eq!(report.count(LineProblem::ShortLine), 1);
+        assert_eq!(report.count(LineProblem::EmptyName), 1);
+        assert_eq!(report.count(LineProblem::NonAscii), 1);
+        assert_eq!(report.count(LineProblem::BadHash), 2);
+        assert_eq!(report.skipped(), 5);
+        assert_eq!(report.examples().len(), 5);
+        assert_eq!(report.examples()[0], (LineProblem::ShortLine, "111".to_string()));
+
+        // The strict reader fails on the same file.
+        assert!(StockBookmarks::read(tmp.path()).is_err());
+    }
+
     /// Test a bunch of invalid bookmark lines
     #[test]
     fn test_invalid() {
@@ -244,4 +1219,63 @@ mod tests {
             Err(bad) => panic!("other error: {:?}", bad),
         };
     }
+
+    /// The version `get` returns for `name` in the bookmarks in `dir`, read with `scheme`.
+    fn version_of(dir: &Path, scheme: VersionScheme) -> Version {
+        let bookmarks = StockBookmarks::read_with_version_scheme(dir, scheme).unwrap();
+        bookmarks.get(&"abc").wait().unwrap().unwrap().1
+    }
+
+    fn write_bookmarks(dir: &Path, contents: &[u8]) {
+        File::create(dir.join("bookmarks"))
+            .unwrap()
+            .write_all(contents)
+            .unwrap();
+    }
+
+    #[test]
+    fn test_version_schemes() {
+        const FIRST: &[u8] = b"1111111111111111111111111111111111111111 abc\n";
+        const SECOND: &[u8] = b"2222222222222222222222222222222222222222 abc\n";
+        let tmp = TempDir::new("stockbookmarks_version_schemes").unwrap();
+        let dir = tmp.path();
+        write_bookmarks(dir, FIRST);
+
+        for scheme in &[VersionScheme::Constant, VersionScheme::Mtime, VersionScheme::ContentHash] {
+            // Unchanged files keep their version.
+            assert_eq!(version_of(dir, *scheme), version_of(dir, *scheme));
+        }
+        let content_first = version_of(dir, VersionScheme::ContentHash);
+
+        write_bookmarks(dir, SECOND);
+        assert_eq!(version_of(dir, VersionScheme::Constant), Version::from(1));
+        let content_second = version_of(dir, VersionScheme::ContentHash);
+        assert_ne!(content_first, content_second);
+        // Whatever the filesystem's mtime granularity, the version is the file's mtime.
+        let modified = fs::metadata(dir.join("bookmarks"))
+            .unwrap()
+            .modified()
+            .unwrap()
+            .duration_since(UNIX_EPOCH)
+            .unwrap();
+        let mtime = modified.as_secs() * 1_000_000_000 + modified.subsec_nanos() as u64;
+        assert_eq!(version_of(dir, VersionScheme::Mtime), Version::from(mtime));
+
+        // Writing the old contents back gets the old version back, whatever the mtime says.
+        write_bookmarks(dir, FIRST);
+        assert_eq!(version_of(dir, VersionScheme::ContentHash), content_first);
+    }
+
+    #[test]
+    fn test_version_scheme_missing_file() {
+        let tmp = TempDir::new("stockbookmarks_version_scheme_missing").unwrap();
+        let mtime = StockBookmarks::read_with_version_scheme(tmp.path(), VersionScheme::Mtime)
+            .unwrap();
+        assert!(mtime.is_empty());
+        let content =
+            StockBookmarks::read_with_version_scheme(tmp.path(), VersionScheme::ContentHash)
+                .unwrap();
+        assert_eq!(content.version, Version::from(content_version(b"")));
+        assert_eq!(mtime.version, Version::from(0));
+    }
 }
@@ -0,0 +1,139 @@
+// Copyright (c) 2004-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+use std::collections::BTreeMap;
+use std::io::Read;
+use std::path::PathBuf;
+
+use failure::{Error, Result};
+use futures::future;
+use futures::stream;
+use futures_ext::{BoxFuture, BoxStream, StreamExt};
+
+use bookmarks::Bookmarks;
+use mercurial_types::NodeHash;
+use storage_types::Version;
+
+use StockBookmarks;
+
+/// Stock Mercurial bookmarks kept sorted by name, so that ranges of names can be listed
+/// efficiently. Parses the same `.hg/bookmarks` format as `StockBookmarks`, and is read-only
+/// for the same reasons.
+#[derive(Clone, Debug)]
+pub struct SortedBookmarks {
+    bookmarks: BTreeMap<Vec<u8>, NodeHash>,
+}
+
+impl SortedBookmarks {
+    pub fn read<P: Into<PathBuf>>(base: P) -> Result<Self> {
+        StockBookmarks::read(base).map(Self::from)
+    }
+
+    pub fn from_reader<R: Read>(reader: R) -> Result<Self> {
+        StockBookmarks::from_reader(reader).map(Self::from)
+    }
+
+    /// Returns the bookmarks with names in `[lo, hi)`, in byte order of their names.
+    pub fn range(&self, lo: &[u8], hi: &[u8]) -> BoxStream<(Vec<u8>, NodeHash), Error> {
+        // BTreeMap::range panics if the range is backwards.
+        if lo >= hi {
+            return stream::empty().boxify();
+        }
+        let entries: Vec<_> = self.bookmarks
+            .range(lo.to_vec()..hi.to_vec())
+            .map(|(name, hash)| (name.clone(), *hash))
+            .collect();
+        stream::iter_ok(entries).boxify()
+    }
+}
+
+impl From<StockBookmarks> for SortedBookmarks {
+    fn from(stock: StockBookmarks) -> Self {
+        SortedBookmarks {
+            bookmarks: stock.bookmarks.into_iter().collect(),
+        }
+    }
+}
+
+impl Bookmarks for SortedBookmarks {
+    fn get(&self, name: &AsRef<[u8]>) -> BoxFuture<Option<(NodeHash, Version)>, Error> {
+        let value = self.bookmarks
+            .get(name.as_ref())
+            .map(|hash| (*hash, Version::from(1)));
+        Box::new(future::ok(value))
+    }
+
+    fn keys(&self) -> BoxStream<Vec<u8>, Error> {
+        // Collect up front so that the stream can outlive self. The keys come out sorted.
+        stream::iter_ok(self.bookmarks.keys().cloned().collect::<Vec<_>>()).boxify()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use futures::{Future, Stream};
+    use mercurial_types_mocks::nodehash;
+
+    use super::*;
+
+    fn sorted_bookmarks() -> SortedBookmarks {
+        let disk_bookmarks = b"\
+            1111111111111111111111111111111111111111 beta\n\
+            2222222222222222222222222222222222222222 alpha\n\
+            3333333333333333333333333333333333333333 delta\n\
+            1111111111111111111111111111111111111111 gamma\n";
+        SortedBookmarks::from_reader(Cursor::new(&disk_bookmarks[..])).unwrap()
+    }
+
+    #[test]
+    fn test_keys_sorted() {
+        let bookmarks = sorted_bookmarks();
+        let keys = bookmarks.keys().collect().wait().unwrap();
+        assert_eq!(
+            keys,
+            vec![&b"alpha"[..], &b"beta"[..], &b"delta"[..], &b"gamma"[..]]
+        );
+        assert_eq!(
+            bookmarks.get(&"delta").wait().unwrap(),
+            Some((nodehash::THREES_HASH, Version::from(1)))
+        );
+    }
+
+    #[test]
+    fn test_range() {
+        let bookmarks = sorted_bookmarks();
+        // The lower bound is included and the upper bound isn't.
+        let entries = bookmarks.range(b"beta", b"gamma").collect().wait().unwrap();
+        assert_eq!(
+            entries,
+            vec![
+                (b"beta".to_vec(), nodehash::ONES_HASH),
+                (b"delta".to_vec(), nodehash::THREES_HASH),
+            ]
+        );
+
+        // Bounds don't have to be bookmark names.
+        let entries = bookmarks.range(b"a", b"c").collect().wait().unwrap();
+        assert_eq!(
+            entries,
+            vec![
+                (b"alpha".to_vec(), nodehash::TWOS_HASH),
+                (b"beta".to_vec(), nodehash::ONES_HASH),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_range_empty() {
+        let bookmarks = sorted_bookmarks();
+        assert!(bookmarks.range(b"beta", b"beta").collect().wait().unwrap().is_empty());
+        assert!(bookmarks.range(b"e", b"f").collect().wait().unwrap().is_empty());
+        // A backwards range is empty rather than a panic.
+        assert!(bookmarks.range(b"gamma", b"alpha").collect().wait().unwrap().is_empty());
+    }
+}
@@ -0,0 +1,131 @@
+// Copyright (c) 2004-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+use std::ffi::OsStr;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc as std_mpsc;
+use std::thread;
+use std::time::Duration;
+
+use failure::Error;
+use futures::{Future, Sink};
+use futures::stream::{self, Stream};
+use futures::sync::mpsc;
+use notify::{self, DebouncedEvent, RecursiveMode, Watcher};
+
+use futures_ext::{BoxStream, StreamExt};
+
+use StockBookmarks;
+
+/// Changes to the bookmarks file that happen within this many milliseconds of each other are
+/// reported once.
+const WATCH_DEBOUNCE_MS: u64 = 100;
+
+impl StockBookmarks {
+    /// Watch the `.hg/bookmarks` file under `base`. The stream starts with the current
+    /// bookmarks, then yields a freshly read set each time the file changes.
+    ///
+    /// If the file can't be read or parsed, the error is yielded and the stream carries on with
+    /// the next change. The watch stops once the stream is dropped and the file changes again.
+    pub fn watch<P: Into<PathBuf>>(base: P) -> BoxStream<StockBookmarks, Error> {
+        let base = base.into();
+
+        let (notify_tx, notify_rx) = std_mpsc::channel();
+        // Mercurial replaces the file by renaming a new one over it, so watch the directory
+        // rather than the file itself.
+        let debounce = Duration::from_millis(WATCH_DEBOUNCE_MS);
+        let watcher = notify::watcher(notify_tx, debounce).and_then(|mut watcher| {
+            watcher.watch(&base, RecursiveMode::NonRecursive)?;
+            Ok(watcher)
+        });
+        let watcher = match watcher {
+            Ok(watcher) => watcher,
+            Err(err) => return stream::once(Err(err.into())).boxify(),
+        };
+
+        let (tx, rx) = mpsc::channel(1);
+        thread::spawn(move || {
+            // Keep the watcher alive for as long as the thread runs.
+            let _watcher = watcher;
+            let mut tx = match tx.send(StockBookmarks::read(&base)).wait() {
+                Ok(tx) => tx,
+                Err(_) => return,
+            };
+            for event in notify_rx {
+                let changed = match event {
+                    DebouncedEvent::Create(ref path)
+                    | DebouncedEvent::Write(ref path)
+                    | DebouncedEvent::Remove(ref path) => is_bookmarks(path),
+                    DebouncedEvent::Rename(ref from, ref to) => {
+                        is_bookmarks(from) || is_bookmarks(to)
+                    }
+                    DebouncedEvent::Rescan => true,
+                    _ => false,
+                };
+                if changed {
+                    tx = match tx.send(StockBookmarks::read(&base)).wait() {
+                        Ok(tx) => tx,
+                        // Nobody is listening any more.
+                        Err(_) => return,
+                    };
+                }
+            }
+        });
+
+        rx.then(|item| item.expect("bookmarks watch receiver failed"))
+            .boxify()
+    }
+}
+
+/// Whether an event path is the bookmarks file. Only the one directory is watched, so the name is
+/// enough, and it avoids worrying about how the watcher spells the directory.
+fn is_bookmarks(path: &Path) -> bool {
+    path.file_name() == Some(OsStr::new("bookmarks"))
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs::{self, File};
+    use std::io::Write;
+
+    use mercurial_types_mocks::nodehash;
+    use tempdir::TempDir;
+
+    use bookmarks::Bookmarks;
+    use storage_types::Version;
+
+    use super::*;
+
+    #[test]
+    fn emits_on_change() {
+        let tmp = TempDir::new("stockbookmarks_watch").unwrap();
+        File::create(tmp.path().join("bookmarks"))
+            .unwrap()
+            .write_all(b"1111111111111111111111111111111111111111 abc\n")
+            .unwrap();
+
+        let mut snapshots = StockBookmarks::watch(tmp.path()).wait();
+        let initial = snapshots.next().unwrap().unwrap();
+        assert_eq!(
+            initial.get(&"abc").wait().unwrap(),
+            Some((nodehash::ONES_HASH, Version::from(1)))
+        );
+
+        // Replace the file the way Mercurial does.
+        let new = tmp.path().join("bookmarks.new");
+        File::create(&new)
+            .unwrap()
+            .write_all(b"2222222222222222222222222222222222222222 abc\n")
+            .unwrap();
+        fs::rename(&new, tmp.path().join("bookmarks")).unwrap();
+
+        let updated = snapshots.next().unwrap().unwrap();
+        assert_eq!(
+            updated.get(&"abc").wait().unwrap(),
+            Some((nodehash::TWOS_HASH, Version::from(1)))
+        );
+    }
+}
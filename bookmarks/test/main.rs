@@ -28,7 +28,7 @@ use futures::Stream;
 use tempdir::TempDir;
 use tokio_core::reactor::Core;
 
-use bookmarks::BookmarksMut;
+use bookmarks::{BookmarkOp, Bookmarks, BookmarksMut};
 use dbbookmarks::DbBookmarks;
 use filebookmarks::FileBookmarks;
 use membookmarks::MemBookmarks;
@@ -195,3 +195,87 @@ bookmarks_test_impl! {
         persistent: true,
     }
 }
+
+#[test]
+fn membookmarks_compare_and_set() {
+    let mut core = Core::new().unwrap();
+    let bookmarks = MemBookmarks::new();
+    let foo = b"foo";
+    let one = nodehash::ONES_HASH;
+    let two = nodehash::TWOS_HASH;
+    let three = nodehash::THREES_HASH;
+
+    // Creating only works if the bookmark doesn't exist yet.
+    assert!(core.run(bookmarks.compare_and_set(&foo, None, one)).unwrap());
+    assert!(!core.run(bookmarks.compare_and_set(&foo, None, two)).unwrap());
+    assert_eq!(core.run(bookmarks.get(&foo)).unwrap().unwrap().0, one);
+
+    // Moving only works from the current target.
+    assert!(!core.run(bookmarks.compare_and_set(&foo, Some(three), two)).unwrap());
+    assert_eq!(core.run(bookmarks.get(&foo)).unwrap().unwrap().0, one);
+    assert!(core.run(bookmarks.compare_and_set(&foo, Some(one), two)).unwrap());
+    assert_eq!(core.run(bookmarks.get(&foo)).unwrap().unwrap().0, two);
+}
+
+#[test]
+fn membookmarks_transaction() {
+    let mut core = Core::new().unwrap();
+    let bookmarks = MemBookmarks::new();
+    let one = nodehash::ONES_HASH;
+    let two = nodehash::TWOS_HASH;
+    let three = nodehash::THREES_HASH;
+    core.run(bookmarks.create(&"moved", &one)).unwrap();
+    core.run(bookmarks.create(&"deleted", &one)).unwrap();
+
+    let ops = vec![
+        BookmarkOp::CompareAndSet {
+            name: b"moved".to_vec(),
+            expected: Some(one),
+            new: two,
+        },
+        BookmarkOp::Set {
+            name: b"created".to_vec(),
+            new: three,
+        },
+        BookmarkOp::Delete {
+            name: b"deleted".to_vec(),
+        },
+    ];
+    assert!(core.run(bookmarks.apply_transaction(ops)).unwrap());
+    assert_eq!(core.run(bookmarks.get(&"moved")).unwrap().unwrap().0, two);
+    assert_eq!(core.run(bookmarks.get(&"created")).unwrap().unwrap().0, three);
+    assert_eq!(core.run(bookmarks.get(&"deleted")).unwrap(), None);
+}
+
+#[test]
+fn membookmarks_transaction_failed_cas() {
+    let mut core = Core::new().unwrap();
+    let bookmarks = MemBookmarks::new();
+    let one = nodehash::ONES_HASH;
+    let two = nodehash::TWOS_HASH;
+    let three = nodehash::THREES_HASH;
+    core.run(bookmarks.create(&"first", &one)).unwrap();
+    core.run(bookmarks.create(&"second", &one)).unwrap();
+
+    // The first op would succeed on its own, but the second one doesn't match.
+    let ops = vec![
+        BookmarkOp::CompareAndSet {
+            name: b"first".to_vec(),
+            expected: Some(one),
+            new: two,
+        },
+        BookmarkOp::Set {
+            name: b"created".to_vec(),
+            new: two,
+        },
+        BookmarkOp::CompareAndSet {
+            name: b"second".to_vec(),
+            expected: Some(three),
+            new: two,
+        },
+    ];
+    assert!(!core.run(bookmarks.apply_transaction(ops)).unwrap());
+    assert_eq!(core.run(bookmarks.get(&"first")).unwrap().unwrap().0, one);
+    assert_eq!(core.run(bookmarks.get(&"second")).unwrap().unwrap().0, one);
+    assert_eq!(core.run(bookmarks.get(&"created")).unwrap(), None);
+}
@@ -0,0 +1,184 @@
+// Copyright (c) 2017-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+//! Restricting an import to an explicit set of blob keys, for re-importing just the blobs involved
+//! in a corruption.
+
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+use std::sync::atomic::Ordering;
+
+use bytes::Bytes;
+use failure::{Error, Result, ResultExt};
+use futures::IntoFuture;
+use stats::Timeseries;
+
+use blobstore::Blobstore;
+use futures_ext::{BoxFuture, BoxStream, FutureExt};
+
+use ALLOWLIST_SKIPPED;
+use BBlobstore;
+use STATS;
+
+/// Read a `--blob-key-allowlist` file, which has one key per line. Blank lines are ignored.
+pub(crate) fn read_key_allowlist<P: AsRef<Path>>(path: P) -> Result<HashSet<String>> {
+    let path = path.as_ref();
+    let mut contents = String::new();
+    File::open(path)
+        .and_then(|mut file| file.read_to_string(&mut contents))
+        .with_context(|_| format!("reading blob key allowlist {}", path.display()))?;
+    Ok(contents
+        .lines()
+        .map(str::trim)
+        .filter(|key| !key.is_empty())
+        .map(String::from)
+        .collect())
+}
+
+/// Blobstore that only inserts blobs whose keys are in the allowlist, and counts the rest as
+/// skipped. An empty allowlist lets everything through.
+pub(crate) struct AllowlistBlobstore {
+    blobstore: BBlobstore,
+    allowlist: HashSet<String>,
+}
+
+impl AllowlistBlobstore {
+    pub fn new(blobstore: BBlobstore, allowlist: HashSet<String>) -> Self {
+        AllowlistBlobstore {
+            blobstore,
+            allowlist,
+        }
+    }
+
+    /// Whether the blob with this key should be written. Counts it as skipped if not.
+    fn allowed(&self, key: &str) -> bool {
+        if self.allowlist.is_empty() || self.allowlist.contains(key) {
+            true
+        } else {
+            STATS::allowlist_skipped.add_value(1);
+            ALLOWLIST_SKIPPED.fetch_add(1, Ordering::Relaxed);
+            false
+        }
+    }
+}
+
+impl Blobstore for AllowlistBlobstore {
+    type GetBlob = BoxFuture<Option<Bytes>, Error>;
+    type PutBlob = BoxFuture<(), Error>;
+
+    fn get(&self, key: String) -> Self::GetBlob {
+        self.blobstore.get(key)
+    }
+
+    fn put(&self, key: String, val: Bytes) -> Self::PutBlob {
+        if self.allowed(&key) {
+            self.blobstore.put(key, val)
+        } else {
+            Ok(()).into_future().boxify()
+        }
+    }
+
+    fn get_batch(&self, keys: Vec<String>) -> BoxFuture<HashMap<String, Option<Bytes>>, Error> {
+        self.blobstore.get_batch(keys)
+    }
+
+    fn exists(&self, key: String) -> BoxFuture<bool, Error> {
+        self.blobstore.exists(key)
+    }
+
+    fn put_if_absent(&self, key: String, val: Bytes) -> BoxFuture<bool, Error> {
+        if self.allowed(&key) {
+            self.blobstore.put_if_absent(key, val)
+        } else {
+            Ok(false).into_future().boxify()
+        }
+    }
+
+    fn put_stream(
+        &self,
+        key: String,
+        body: BoxStream<Bytes, Error>,
+        len_hint: Option<usize>,
+    ) -> BoxFuture<(), Error> {
+        if self.allowed(&key) {
+            self.blobstore.put_stream(key, body, len_hint)
+        } else {
+            Ok(()).into_future().boxify()
+        }
+    }
+
+    fn copy(&self, src_key: String, dst_key: String) -> BoxFuture<(), Error> {
+        if self.allowed(&dst_key) {
+            self.blobstore.copy(src_key, dst_key)
+        } else {
+            Ok(()).into_future().boxify()
+        }
+    }
+
+    fn delete(&self, key: String) -> BoxFuture<(), Error> {
+        self.blobstore.delete(key)
+    }
+
+    fn keys(&self) -> BoxStream<String, Error> {
+        self.blobstore.keys()
+    }
+
+    fn flush(&self) -> BoxFuture<(), Error> {
+        self.blobstore.flush()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::io::Write;
+
+    use futures::{Future, Stream};
+    use tempdir::TempDir;
+
+    use memblob::Memblob;
+
+    #[test]
+    fn one_key_allowlist() {
+        let tmp = TempDir::new("blobimport_allowlist").unwrap();
+        let path = tmp.path().join("allowlist");
+        File::create(&path)
+            .unwrap()
+            .write_all(b"\nwanted\n\n")
+            .unwrap();
+        let allowlist = read_key_allowlist(&path).unwrap();
+        assert_eq!(allowlist.len(), 1);
+
+        let memblob = Memblob::new().arced();
+        let blobstore = AllowlistBlobstore::new(memblob.clone(), allowlist);
+
+        let before = ALLOWLIST_SKIPPED.load(Ordering::SeqCst);
+        for key in &["unwanted1", "wanted", "unwanted2"] {
+            blobstore
+                .put(key.to_string(), Bytes::from_static(b"value"))
+                .wait()
+                .unwrap();
+        }
+
+        let keys = memblob.keys().collect().wait().unwrap();
+        assert_eq!(keys, vec!["wanted".to_string()]);
+        assert_eq!(ALLOWLIST_SKIPPED.load(Ordering::SeqCst) - before, 2);
+    }
+
+    #[test]
+    fn empty_allowlist_writes_everything() {
+        let memblob = Memblob::new().arced();
+        let blobstore = AllowlistBlobstore::new(memblob.clone(), HashSet::new());
+        blobstore
+            .put("anything".to_string(), Bytes::from_static(b"value"))
+            .wait()
+            .unwrap();
+        assert!(memblob.get("anything".to_string()).wait().unwrap().is_some());
+    }
+}
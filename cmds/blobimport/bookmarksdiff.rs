@@ -0,0 +1,94 @@
+// Copyright (c) 2017-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+//! Comparing a source repo's bookmarks with the ones recorded in an existing store, to see what a
+//! re-import would change.
+
+use std::collections::HashMap;
+use std::io::Write;
+use std::sync::Arc;
+
+use failure::{Error, Result};
+use futures::{Future, Stream};
+
+use bookmarks::Bookmarks;
+use futures_ext::{BoxFuture, FutureExt};
+use mercurial_types::NodeHash;
+use stockbookmarks::BookmarksDiff;
+
+/// All the bookmarks in `bookmarks`, with the hashes they point at.
+pub(crate) fn recorded_bookmarks<B>(
+    bookmarks: Arc<B>,
+) -> BoxFuture<HashMap<Vec<u8>, NodeHash>, Error>
+where
+    B: Bookmarks,
+{
+    let getter = bookmarks.clone();
+    bookmarks
+        .keys()
+        .and_then(move |name| {
+            getter
+                .get(&name)
+                .map(move |entry| entry.map(|(hash, _version)| (name, hash)))
+        })
+        .filter_map(|entry| entry)
+        .collect()
+        .map(|entries| entries.into_iter().collect())
+        .boxify()
+}
+
+/// Write `diff` out with one line per bookmark, as `added NAME HASH`, `removed NAME HASH` or
+/// `changed NAME OLD NEW`.
+pub(crate) fn write_diff<W: Write>(diff: &BookmarksDiff, mut writer: W) -> Result<()> {
+    for &(ref name, ref hash) in &diff.added {
+        writeln!(writer, "added {} {}", String::from_utf8_lossy(name), hash)?;
+    }
+    for &(ref name, ref hash) in &diff.removed {
+        writeln!(writer, "removed {} {}", String::from_utf8_lossy(name), hash)?;
+    }
+    for &(ref name, ref old, ref new) in &diff.changed {
+        writeln!(writer, "changed {} {} {}", String::from_utf8_lossy(name), old, new)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use tempdir::TempDir;
+
+    use bookmarks::BookmarksMut;
+    use filebookmarks::FileBookmarks;
+    use mercurial_types_mocks::nodehash::*;
+    use stockbookmarks::StockBookmarks;
+
+    #[test]
+    fn known_divergence() {
+        let tmp = TempDir::new("blobimport_bookmarksdiff").unwrap();
+        let store = FileBookmarks::create(tmp.path()).unwrap();
+        for &(name, hash) in &[("gone", &ONES_HASH), ("moved", &TWOS_HASH), ("same", &AS_HASH)] {
+            store.create(&name, hash).wait().unwrap();
+        }
+        let source = StockBookmarks::from_entries(vec![
+            (b"moved".to_vec(), THREES_HASH),
+            (b"new".to_vec(), BS_HASH),
+            (b"same".to_vec(), AS_HASH),
+        ]);
+
+        let recorded = recorded_bookmarks(Arc::new(store)).wait().unwrap();
+        assert_eq!(recorded.len(), 3);
+        let mut out = Vec::new();
+        write_diff(&source.changed_since(&recorded), &mut out).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            format!(
+                "added new {}\nremoved gone {}\nchanged moved {} {}\n",
+                BS_HASH, ONES_HASH, TWOS_HASH, THREES_HASH
+            )
+        );
+    }
+}
@@ -0,0 +1,303 @@
+// Copyright (c) 2017-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+//! Backing off from manifold while it keeps failing, for `--manifold-breaker-threshold`.
+//!
+//! Once enough puts in a row have failed, new ones are held back for a cool-down instead of piling
+//! onto a service that's struggling, and then let through again to see if it's recovered. The
+//! cool-down is jittered, so that imports that tripped at the same time don't all come back at
+//! once.
+
+use std::collections::HashMap;
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use bytes::Bytes;
+use failure::Error;
+use futures::{future, Future};
+use futures::future::{loop_fn, Loop};
+use futures_cpupool::CpuPool;
+use slog::Logger;
+use stats::Timeseries;
+
+use blobstore::Blobstore;
+use futures_ext::{BoxFuture, BoxStream, FutureExt};
+
+use BBlobstore;
+use STATS;
+
+/// A cool-down somewhere between half of `cooldown` and all of it.
+fn jittered(cooldown: Duration) -> Duration {
+    // RandomState is keyed randomly, which is all the randomness this needs.
+    let random = RandomState::new().build_hasher().finish();
+    let millis = cooldown.as_secs() * 1000 + cooldown.subsec_nanos() as u64 / 1_000_000;
+    Duration::from_millis(millis / 2 + random % (millis / 2 + 1))
+}
+
+#[derive(Debug, Default)]
+struct BreakerState {
+    consecutive_failures: usize,
+    /// While the breaker is open, when puts can next be tried.
+    open_until: Option<Instant>,
+}
+
+/// Counts consecutive failures, and opens once there have been `threshold` of them.
+#[derive(Debug)]
+pub(crate) struct CircuitBreaker {
+    threshold: usize,
+    cooldown: Duration,
+    state: Mutex<BreakerState>,
+}
+
+impl CircuitBreaker {
+    pub fn new(threshold: usize, cooldown: Duration) -> Self {
+        CircuitBreaker {
+            threshold,
+            cooldown,
+            state: Mutex::new(BreakerState::default()),
+        }
+    }
+
+    /// How much longer anything has to wait at `now` before it can be tried, if the breaker is
+    /// open.
+    pub fn wait_at(&self, now: Instant) -> Option<Duration> {
+        let state = self.state.lock().expect("lock poisoned");
+        match state.open_until {
+            Some(until) if until > now => Some(until - now),
+            _ => None,
+        }
+    }
+
+    pub fn is_open(&self) -> bool {
+        self.wait_at(Instant::now()).is_some()
+    }
+
+    pub fn record_success(&self) {
+        let mut state = self.state.lock().expect("lock poisoned");
+        state.consecutive_failures = 0;
+        state.open_until = None;
+    }
+
+    /// Returns the cool-down if this failure opened the breaker. Once it's tripped, the first
+    /// failure after the cool-down opens it again straight away, while failures of operations
+    /// that were already in flight while it was open don't make it any longer.
+    pub fn record_failure(&self, now: Instant) -> Option<Duration> {
+        let mut state = self.state.lock().expect("lock poisoned");
+        state.consecutive_failures += 1;
+        let open = match state.open_until {
+            Some(until) => until > now,
+            None => false,
+        };
+        if state.consecutive_failures < self.threshold || open {
+            return None;
+        }
+        let cooldown = jittered(self.cooldown);
+        state.open_until = Some(now + cooldown);
+        Some(cooldown)
+    }
+}
+
+/// Blobstore whose puts go through a `CircuitBreaker`, waiting while it's open. Everything else
+/// goes straight to the underlying blobstore.
+#[derive(Clone)]
+pub(crate) struct BreakerBlobstore {
+    blobstore: BBlobstore,
+    breaker: Arc<CircuitBreaker>,
+    logger: Logger,
+    // Cool-downs are slept out on here, so as not to block whoever is polling.
+    delay_pool: CpuPool,
+}
+
+impl BreakerBlobstore {
+    pub fn new(blobstore: BBlobstore, breaker: Arc<CircuitBreaker>, logger: Logger) -> Self {
+        BreakerBlobstore {
+            blobstore,
+            breaker,
+            logger,
+            delay_pool: CpuPool::new(1),
+        }
+    }
+
+    /// Run `op` once the breaker is closed, and tell the breaker how it went.
+    fn guarded<F, T>(&self, op: F) -> BoxFuture<T, Error>
+    where
+        F: FnOnce(&BBlobstore) -> BoxFuture<T, Error> + Send + 'static,
+        T: Send + 'static,
+    {
+        let breaker = self.breaker.clone();
+        let delay_pool = self.delay_pool.clone();
+        // The breaker can open again while waiting, so it's checked again after each wait.
+        let closed = loop_fn((), move |()| match breaker.wait_at(Instant::now()) {
+            Some(delay) => delay_pool
+                .spawn_fn(move || {
+                    thread::sleep(delay);
+                    Ok::<_, Error>(Loop::Continue(()))
+                })
+                .boxify(),
+            None => future::ok(Loop::Break(())).boxify(),
+        });
+
+        let blobstore = self.blobstore.clone();
+        let breaker = self.breaker.clone();
+        let logger = self.logger.clone();
+        closed
+            .and_then(move |()| op(&blobstore))
+            .then(move |res| {
+                match res {
+                    Ok(_) => breaker.record_success(),
+                    Err(_) => if let Some(cooldown) = breaker.record_failure(Instant::now()) {
+                        STATS::manifold_breaker_opened.add_value(1);
+                        warn!(
+                            logger,
+                            "{} puts in a row failed, pausing puts for {}ms",
+                            breaker.threshold,
+                            cooldown.as_secs() * 1000 + cooldown.subsec_nanos() as u64 / 1_000_000
+                        );
+                    },
+                }
+                res
+            })
+            .boxify()
+    }
+}
+
+impl Blobstore for BreakerBlobstore {
+    type GetBlob = BoxFuture<Option<Bytes>, Error>;
+    type PutBlob = BoxFuture<(), Error>;
+
+    fn get(&self, key: String) -> Self::GetBlob {
+        self.blobstore.get(key)
+    }
+
+    fn put(&self, key: String, val: Bytes) -> Self::PutBlob {
+        self.guarded(move |blobstore| blobstore.put(key, val))
+    }
+
+    fn get_batch(&self, keys: Vec<String>) -> BoxFuture<HashMap<String, Option<Bytes>>, Error> {
+        self.blobstore.get_batch(keys)
+    }
+
+    fn exists(&self, key: String) -> BoxFuture<bool, Error> {
+        self.blobstore.exists(key)
+    }
+
+    fn put_if_absent(&self, key: String, val: Bytes) -> BoxFuture<bool, Error> {
+        self.guarded(move |blobstore| blobstore.put_if_absent(key, val))
+    }
+
+    fn put_stream(
+        &self,
+        key: String,
+        body: BoxStream<Bytes, Error>,
+        len_hint: Option<usize>,
+    ) -> BoxFuture<(), Error> {
+        self.guarded(move |blobstore| blobstore.put_stream(key, body, len_hint))
+    }
+
+    fn copy(&self, src_key: String, dst_key: String) -> BoxFuture<(), Error> {
+        self.blobstore.copy(src_key, dst_key)
+    }
+
+    fn delete(&self, key: String) -> BoxFuture<(), Error> {
+        self.blobstore.delete(key)
+    }
+
+    fn keys(&self) -> BoxStream<String, Error> {
+        self.blobstore.keys()
+    }
+
+    fn flush(&self) -> BoxFuture<(), Error> {
+        self.blobstore.flush()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use slog;
+
+    use memblob::Memblob;
+
+    /// Fails the first `failures` puts, and passes the rest through.
+    struct FlakyBlobstore {
+        inner: Memblob,
+        failures: AtomicUsize,
+    }
+
+    impl Blobstore for FlakyBlobstore {
+        type GetBlob = <Memblob as Blobstore>::GetBlob;
+        type PutBlob = BoxFuture<(), Error>;
+
+        fn get(&self, key: String) -> Self::GetBlob {
+            self.inner.get(key)
+        }
+
+        fn put(&self, key: String, value: Bytes) -> Self::PutBlob {
+            let remaining = self.failures.load(Ordering::SeqCst);
+            if remaining > 0 {
+                self.failures.store(remaining - 1, Ordering::SeqCst);
+                return future::err(format_err!("manifold is having a bad day")).boxify();
+            }
+            self.inner.put(key, value).boxify()
+        }
+    }
+
+    #[test]
+    fn breaker_threshold_and_cooldown() {
+        let cooldown = Duration::from_millis(100);
+        let breaker = CircuitBreaker::new(3, cooldown);
+        let start = Instant::now();
+        assert_eq!(breaker.record_failure(start), None);
+        assert_eq!(breaker.record_failure(start), None);
+        let opened = breaker.record_failure(start).expect("breaker didn't open");
+        assert!(opened >= cooldown / 2 && opened <= cooldown, "cool-down {:?}", opened);
+        assert!(breaker.wait_at(start).is_some());
+        // Failures of operations already in flight don't extend it.
+        assert_eq!(breaker.record_failure(start), None);
+        assert_eq!(breaker.wait_at(start + cooldown), None);
+        // A failed probe opens it again.
+        assert!(breaker.record_failure(start + cooldown).is_some());
+        assert!(breaker.wait_at(start + cooldown).is_some());
+        breaker.record_success();
+        assert_eq!(breaker.wait_at(start + cooldown), None);
+        assert_eq!(breaker.record_failure(start + cooldown), None);
+    }
+
+    #[test]
+    fn failure_burst_opens_and_closes() {
+        let cooldown = Duration::from_millis(50);
+        let breaker = Arc::new(CircuitBreaker::new(3, cooldown));
+        let flaky = FlakyBlobstore {
+            inner: Memblob::new(),
+            failures: AtomicUsize::new(3),
+        };
+        let logger = Logger::root(slog::Discard, o![]);
+        let blobstore = BreakerBlobstore::new(flaky.arced(), breaker.clone(), logger);
+        let put = |key: &str| {
+            blobstore
+                .put(key.to_string(), Bytes::from_static(b"blob"))
+                .wait()
+        };
+
+        for key in &["a", "b", "c"] {
+            assert!(put(key).is_err());
+        }
+        assert!(breaker.is_open());
+
+        // The next put waits out the cool-down, then goes through and closes the breaker.
+        let before = Instant::now();
+        put("d").unwrap();
+        assert!(before.elapsed() >= cooldown / 2 - Duration::from_millis(5));
+        assert!(!breaker.is_open());
+        assert!(blobstore.get("d".to_string()).wait().unwrap().is_some());
+    }
+}
@@ -0,0 +1,415 @@
+// Copyright (c) 2017-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+//! Writing an import out as a Mercurial bundle, for `--bundle-output`.
+//!
+//! The import goes into an in-memory blobstore and linknodes store like it would go into any
+//! other, so that the changeset filters and `--only-path` apply the same way, and the changegroup
+//! is put together from those once it's done. The flags that leave out single blobs,
+//! `--max-blob-size`, `--blob-key-allowlist` and `--skip-existing`, can't be used with it, as the
+//! bundle would then be missing revisions that its manifests refer to.
+//!
+//! Every revision goes in as a full text, which makes for a bigger bundle, but one that doesn't
+//! depend on what the repo it's applied to already has.
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs::File;
+use std::io::{Cursor, Write};
+use std::path::Path;
+
+use async_compression::CompressorType;
+use bincode;
+use bytes::Bytes;
+use failure::{Error, Result};
+use futures::{future, stream, Future, Stream};
+use tokio_core::reactor::Core;
+
+use blobrepo::{cs_blob_node, RawNodeBlob};
+use blobstore::Blobstore;
+use futures_ext::{BoxFuture, FutureExt};
+use linknodes::Linknodes;
+use mercurial_bundles::Bundle2EncodeBuilder;
+use mercurial_bundles::changegroup::{CgDeltaChunk, Part, Section};
+use mercurial_bundles::parts;
+use mercurial_types::{Delta, NodeHash, Parents, RepoPath, NULL_HASH};
+
+use KeyScheme;
+
+/// A changeset, manifest or file revision, as it goes into the changegroup.
+#[derive(Clone, Debug)]
+struct Revision {
+    node: NodeHash,
+    p1: NodeHash,
+    p2: NodeHash,
+    linknode: NodeHash,
+    text: Bytes,
+}
+
+impl Revision {
+    fn new(node: NodeHash, parents: &Parents, linknode: NodeHash, text: Bytes) -> Self {
+        let (p1, p2) = parents.get_nodes();
+        Revision {
+            node,
+            p1: p1.cloned().unwrap_or(NULL_HASH),
+            p2: p2.cloned().unwrap_or(NULL_HASH),
+            linknode,
+            text,
+        }
+    }
+
+    fn into_part(self, section: Section) -> Part {
+        let chunk = CgDeltaChunk {
+            node: self.node,
+            p1: self.p1,
+            p2: self.p2,
+            base: NULL_HASH,
+            linknode: self.linknode,
+            delta: Delta::new_fulltext(self.text.to_vec()),
+        };
+        Part::CgChunk(section, chunk)
+    }
+}
+
+/// Load the changeset `csid` from `blobstore`. It isn't there if it was skipped for being over
+/// --max-changeset-size.
+fn load_changeset<B>(
+    blobstore: &B,
+    key_scheme: &KeyScheme,
+    csid: NodeHash,
+) -> BoxFuture<Option<Revision>, Error>
+where
+    B: Blobstore,
+{
+    blobstore
+        .get(key_scheme.changeset_key(&csid))
+        .and_then(move |blob| match blob {
+            None => Ok(None),
+            Some(blob) => {
+                let node = cs_blob_node(blob.as_ref())?;
+                let text = node.as_blob()
+                    .as_slice()
+                    .map(Bytes::from)
+                    .ok_or_else(|| format_err!("changeset {} has no content", csid))?;
+                Ok(Some(Revision::new(csid, node.parents(), csid, text)))
+            }
+        })
+        .boxify()
+}
+
+/// Load the manifest or file revision `node` from `blobstore`. It isn't there if it was filtered
+/// out, for example for not being under --only-path.
+fn load_revision<B>(
+    blobstore: &B,
+    node: NodeHash,
+    linknode: NodeHash,
+) -> BoxFuture<Option<Revision>, Error>
+where
+    B: Blobstore + Clone,
+{
+    let content_blobstore = blobstore.clone();
+    blobstore
+        .get(format!("node-{}.bincode", node))
+        .and_then(move |blob| match blob {
+            None => future::ok(None).boxify(),
+            Some(blob) => {
+                let nodeblob: RawNodeBlob = match bincode::deserialize(blob.as_ref()) {
+                    Ok(nodeblob) => nodeblob,
+                    Err(err) => return future::err(err.into()).boxify(),
+                };
+                content_blobstore
+                    .get(format!("sha1-{}", nodeblob.blob.sha1()))
+                    .and_then(move |content| match content {
+                        Some(content) => Ok(Some(Revision::new(
+                            node,
+                            &nodeblob.parents,
+                            linknode,
+                            content,
+                        ))),
+                        None => bail!("no content for node {}", node),
+                    })
+                    .boxify()
+            }
+        })
+        .boxify()
+}
+
+/// Put `changesets` in an order where parents come before their children, like a changegroup
+/// needs them to be. Parents outside of `changesets` are left for the receiving repo to have.
+fn sort_changesets(changesets: Vec<Revision>) -> Vec<Revision> {
+    let mut by_node: HashMap<_, _> = changesets.into_iter().map(|cs| (cs.node, cs)).collect();
+    let mut nodes: Vec<_> = by_node.keys().cloned().collect();
+    nodes.sort();
+
+    let mut sorted = Vec::with_capacity(nodes.len());
+    let mut seen = HashSet::new();
+    for node in nodes {
+        // The second element is whether the node's parents have been dealt with.
+        let mut stack = vec![(node, false)];
+        while let Some((node, parents_done)) = stack.pop() {
+            if parents_done {
+                sorted.extend(by_node.remove(&node));
+                continue;
+            }
+            if !by_node.contains_key(&node) || !seen.insert(node) {
+                continue;
+            }
+            let (p1, p2) = {
+                let cs = &by_node[&node];
+                (cs.p1, cs.p2)
+            };
+            stack.push((node, true));
+            stack.push((p2, false));
+            stack.push((p1, false));
+        }
+    }
+    sorted
+}
+
+/// The changegroup parts for everything in `blobstore` and `linknodes`: the changesets in order,
+/// then the root manifests and then the filelogs, with each manifest and file revision after the
+/// ones its changeset comes after.
+fn changegroup_parts<B, L>(
+    blobstore: B,
+    linknodes: &L,
+    key_scheme: KeyScheme,
+) -> BoxFuture<Vec<Part>, Error>
+where
+    B: Blobstore + Clone,
+    L: Linknodes,
+{
+    linknodes
+        .iter()
+        .collect()
+        .and_then(move |linknodes| {
+            // Every changeset gives its root manifest a linknode.
+            let csids: HashSet<_> = linknodes
+                .iter()
+                .filter(|data| data.path == RepoPath::root())
+                .map(|data| data.linknode)
+                .collect();
+            let changesets: Vec<_> = csids
+                .into_iter()
+                .map(|csid| load_changeset(&blobstore, &key_scheme, csid))
+                .collect();
+            let revisions: Vec<_> = linknodes
+                .into_iter()
+                .map(|data| {
+                    let path = data.path;
+                    load_revision(&blobstore, data.node, data.linknode)
+                        .map(move |revision| revision.map(|revision| (path, revision)))
+                })
+                .collect();
+            future::join_all(changesets).join(future::join_all(revisions))
+        })
+        .and_then(|(changesets, revisions)| {
+            let changesets = sort_changesets(changesets.into_iter().flat_map(|cs| cs).collect());
+            let positions: HashMap<_, _> = changesets
+                .iter()
+                .enumerate()
+                .map(|(position, cs)| (cs.node, position))
+                .collect();
+
+            let mut manifests = Vec::new();
+            let mut filelogs = BTreeMap::new();
+            for (path, revision) in revisions.into_iter().flat_map(|revision| revision) {
+                // Revisions of changesets that weren't written are left out along with them.
+                if !positions.contains_key(&revision.linknode) {
+                    continue;
+                }
+                match path {
+                    RepoPath::RootPath => manifests.push(revision),
+                    RepoPath::FilePath(path) => {
+                        filelogs.entry(path).or_insert_with(Vec::new).push(revision)
+                    }
+                    RepoPath::DirectoryPath(path) => {
+                        bail!("tree manifest for {} can't go in a changegroup", path)
+                    }
+                }
+            }
+            let sort = |revisions: &mut Vec<Revision>| {
+                revisions.sort_by_key(|revision| (positions[&revision.linknode], revision.node))
+            };
+
+            let mut parts: Vec<_> = changesets
+                .into_iter()
+                .map(|cs| cs.into_part(Section::Changeset))
+                .collect();
+            parts.push(Part::SectionEnd(Section::Changeset));
+            sort(&mut manifests);
+            parts.extend(manifests.into_iter().map(|mf| mf.into_part(Section::Manifest)));
+            parts.push(Part::SectionEnd(Section::Manifest));
+            for (path, mut revisions) in filelogs {
+                sort(&mut revisions);
+                let section = Section::Filelog(path);
+                parts.extend(
+                    revisions
+                        .into_iter()
+                        .map(|revision| revision.into_part(section.clone())),
+                );
+                parts.push(Part::SectionEnd(section));
+            }
+            parts.push(Part::End);
+            Ok(parts)
+        })
+        .boxify()
+}
+
+/// Write everything imported into `blobstore` and `linknodes` to an uncompressed bundle at
+/// `path`, which `hg unbundle` can apply. Returns the number of changesets in it.
+pub(crate) fn write_bundle<B, L, P>(
+    blobstore: B,
+    linknodes: &L,
+    key_scheme: KeyScheme,
+    path: P,
+    core: &mut Core,
+) -> Result<usize>
+where
+    B: Blobstore + Clone,
+    L: Linknodes,
+    P: AsRef<Path>,
+{
+    let parts = core.run(changegroup_parts(blobstore, linknodes, key_scheme))?;
+    let changesets = parts
+        .iter()
+        .filter(|part| match **part {
+            Part::CgChunk(Section::Changeset, _) => true,
+            _ => false,
+        })
+        .count();
+
+    let mut bundle = Bundle2EncodeBuilder::new(Cursor::new(Vec::new()));
+    bundle.set_compressor_type(CompressorType::Uncompressed);
+    bundle.add_part(parts::changegroup_part(stream::iter_ok(parts))?);
+    let encoded = core.run(bundle.build())?;
+
+    File::create(path.as_ref())?.write_all(&encoded.into_inner())?;
+    Ok(changesets)
+}
+
+#[cfg(test)]
+pub(crate) mod test {
+    use super::*;
+
+    use std::io::Read;
+
+    use slog::{self, Logger};
+    use tempdir::TempDir;
+
+    use blobrepo::BlobChangeset;
+    use memblob::Memblob;
+    use memlinknodes::MemLinknodes;
+    use mercurial::revlogrepo::RevlogChangeset;
+    use mercurial_bundles::{Bundle2Item, InnerPart};
+    use mercurial_bundles::bundle2::Bundle2Stream;
+    use mercurial_types::{Blob, BlobHash, BlobNode, MPath};
+    use mercurial_types_mocks::nodehash::{ONES_HASH, THREES_HASH, TWOS_HASH};
+
+    fn put_changeset(blobstore: &Memblob, parent: Option<&NodeHash>, comment: &str) -> NodeHash {
+        let text = format!(
+            "{}\nuser <user@example.com>\n1500000000 0 \na\n\n{}",
+            ONES_HASH, comment
+        );
+        let node = BlobNode::new(Blob::from(text.into_bytes()), parent, None);
+        let csid = node.nodeid().unwrap();
+        BlobChangeset::new(&csid, RevlogChangeset::new(node).unwrap())
+            .save_with_key(blobstore.clone(), KeyScheme::Default.changeset_key(&csid))
+            .wait()
+            .unwrap();
+        csid
+    }
+
+    fn put_revision(blobstore: &Memblob, node: &NodeHash, parents: Parents, content: &[u8]) {
+        let nodeblob = RawNodeBlob {
+            parents,
+            blob: BlobHash::from(content),
+        };
+        let key = format!("sha1-{}", nodeblob.blob.sha1());
+        blobstore
+            .put(key, Bytes::from(content))
+            .wait()
+            .unwrap();
+        let nodeblob = bincode::serialize(&nodeblob, bincode::Infinite).unwrap();
+        blobstore
+            .put(format!("node-{}.bincode", node), Bytes::from(nodeblob))
+            .wait()
+            .unwrap();
+    }
+
+    /// The changegroup parts of the bundle at `path`.
+    pub(crate) fn read_bundle(path: &Path, core: &mut Core) -> Vec<Part> {
+        let mut bytes = Vec::new();
+        File::open(path).unwrap().read_to_end(&mut bytes).unwrap();
+        let logger = Logger::root(slog::Discard, o![]);
+        let items = core.run(Bundle2Stream::new(Cursor::new(bytes), logger).collect())
+            .unwrap();
+        items
+            .into_iter()
+            .filter_map(|item| match item {
+                Bundle2Item::Inner(InnerPart::Cg2(part)) => Some(part),
+                _ => None,
+            })
+            .collect()
+    }
+
+    pub(crate) fn chunk(part: &Part) -> &CgDeltaChunk {
+        match *part {
+            Part::CgChunk(_, ref chunk) => chunk,
+            ref other => panic!("expected a chunk, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn bundle_round_trip() {
+        let blobstore = Memblob::new();
+        let linknodes = MemLinknodes::new();
+        let first = put_changeset(&blobstore, None, "first");
+        let second = put_changeset(&blobstore, Some(&first), "second");
+        let file = RepoPath::file(MPath::new("a").unwrap()).unwrap();
+        // The second root manifest is added first, so that it has to be sorted after the first.
+        for &(ref path, node, parents, linknode, content) in &[
+            (RepoPath::root(), TWOS_HASH, Parents::One(ONES_HASH), second, &b"a\0...\n"[..]),
+            (RepoPath::root(), ONES_HASH, Parents::None, first, &b"a\0\n"[..]),
+            (file.clone(), THREES_HASH, Parents::None, first, &b"contents"[..]),
+        ] {
+            put_revision(&blobstore, &node, parents, content);
+            linknodes.add(path.clone(), &node, &linknode).wait().unwrap();
+        }
+        // A file that was filtered out of the import, so only its linknode is there.
+        let filtered = RepoPath::file(MPath::new("b").unwrap()).unwrap();
+        linknodes.add(filtered, &ONES_HASH, &first).wait().unwrap();
+
+        let tmp = TempDir::new("blobimport_bundle_round_trip").unwrap();
+        let path = tmp.path().join("out.hg");
+        let mut core = Core::new().unwrap();
+        let count =
+            write_bundle(blobstore, &linknodes, KeyScheme::Default, &path, &mut core).unwrap();
+        assert_eq!(count, 2);
+
+        let parts = read_bundle(&path, &mut core);
+
+        assert_eq!(parts.len(), 9, "unexpected parts: {:?}", parts);
+        assert_eq!(chunk(&parts[0]).node, first);
+        assert_eq!(chunk(&parts[1]).node, second);
+        assert_eq!(chunk(&parts[1]).p1, first);
+        assert_eq!(parts[2], Part::SectionEnd(Section::Changeset));
+        assert_eq!(chunk(&parts[3]).node, ONES_HASH);
+        assert_eq!(chunk(&parts[4]).node, TWOS_HASH);
+        assert_eq!(chunk(&parts[4]).linknode, second);
+        assert_eq!(parts[5], Part::SectionEnd(Section::Manifest));
+        let a = Section::Filelog(MPath::new("a").unwrap());
+        match parts[6] {
+            Part::CgChunk(ref section, ref chunk) => {
+                assert_eq!(*section, a);
+                assert_eq!(chunk.node, THREES_HASH);
+                assert_eq!(chunk.base, NULL_HASH);
+                assert_eq!(chunk.delta, Delta::new_fulltext(&b"contents"[..]));
+            }
+            ref other => panic!("expected a filelog chunk, got {:?}", other),
+        }
+        assert_eq!(parts[7], Part::SectionEnd(a));
+        assert_eq!(parts[8], Part::End);
+    }
+}
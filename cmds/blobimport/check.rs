@@ -0,0 +1,275 @@
+// Copyright (c) 2017-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+//! Consistency checks between the stores written by an import.
+
+use futures::{stream, Future, Stream};
+use slog::Logger;
+use stats::Timeseries;
+
+use blobrepo::cs_content_hash;
+use blobstore::Blobstore;
+use failure::{Error, Result};
+use futures_ext::{BoxFuture, FutureExt};
+use linknodes::{LinknodeData, Linknodes};
+use mercurial_types::NodeHash;
+
+use KeyScheme;
+use STATS;
+
+/// Number of changesets to look up in the blobstore at once.
+const CHECK_BATCH_SIZE: usize = 100;
+/// Number of dangling linknodes to log individually.
+const MAX_REPORTED_DANGLING: usize = 10;
+/// Number of bad changeset hashes to log individually.
+const MAX_REPORTED_MISMATCHES: usize = 10;
+
+/// The result of `check_linknodes`.
+#[derive(Default)]
+pub(crate) struct LinknodesCheck {
+    /// Number of linknodes pointing at a changeset that is in the blobstore.
+    pub ok: usize,
+    /// Linknodes pointing at a changeset that isn't in the blobstore.
+    pub dangling: Vec<LinknodeData>,
+}
+
+/// Check that the changeset every linknode points at is present in the blobstore, under the key
+/// given by `key_scheme`.
+pub(crate) fn check_linknodes<L, B>(
+    linknodes: &L,
+    blobstore: B,
+    key_scheme: KeyScheme,
+) -> BoxFuture<LinknodesCheck, Error>
+where
+    L: Linknodes,
+    B: Blobstore,
+{
+    linknodes
+        .iter()
+        .chunks(CHECK_BATCH_SIZE)
+        .and_then(move |entries| {
+            let keys: Vec<_> = entries
+                .iter()
+                .map(|data| key_scheme.changeset_key(&data.linknode))
+                .collect();
+            blobstore
+                .get_batch(keys.clone())
+                .map(move |found| (entries, keys, found))
+        })
+        .fold(LinknodesCheck::default(), |mut check, (entries, keys, found)| {
+            for (data, key) in entries.into_iter().zip(keys) {
+                match found.get(&key) {
+                    Some(&Some(_)) => check.ok += 1,
+                    _ => check.dangling.push(data),
+                }
+            }
+            Ok::<_, Error>(check)
+        })
+        .boxify()
+}
+
+/// Log the outcome of `check_linknodes`, failing if anything was dangling.
+pub(crate) fn report_linknodes_check(check: &LinknodesCheck, logger: &Logger) -> Result<()> {
+    for data in check.dangling.iter().take(MAX_REPORTED_DANGLING) {
+        warn!(
+            logger,
+            "dangling linknode: {}, node {} -> missing changeset {}",
+            data.path,
+            data.node,
+            data.linknode
+        );
+    }
+    info!(
+        logger,
+        "checked {} linknodes: {} ok, {} dangling",
+        check.ok + check.dangling.len(),
+        check.ok,
+        check.dangling.len()
+    );
+    if !check.dangling.is_empty() {
+        bail!("{} dangling linknodes found", check.dangling.len());
+    }
+    Ok(())
+}
+
+/// A changeset whose stored blob doesn't hash to its key.
+pub(crate) struct HashMismatch {
+    pub expected: NodeHash,
+    /// The hash of the stored blob, or `None` if the blob didn't even deserialize.
+    pub actual: Option<NodeHash>,
+}
+
+/// The result of `verify_changeset_hashes`.
+#[derive(Default)]
+pub(crate) struct HashCheck {
+    /// Number of changesets whose blob hashes to its key.
+    pub ok: usize,
+    /// Changesets that weren't in the blobstore at all.
+    pub missing: Vec<NodeHash>,
+    pub mismatched: Vec<HashMismatch>,
+}
+
+/// Read back each of `csids` from the blobstore, under the key given by `key_scheme`, and check
+/// that the blob's content still hashes to the changeset id. Every mismatch is counted in the
+/// `hash_mismatches` stat.
+pub(crate) fn verify_changeset_hashes<B>(
+    blobstore: B,
+    csids: Vec<NodeHash>,
+    key_scheme: KeyScheme,
+) -> BoxFuture<HashCheck, Error>
+where
+    B: Blobstore,
+{
+    stream::iter_ok(csids)
+        .chunks(CHECK_BATCH_SIZE)
+        .and_then(move |csids| {
+            let keys: Vec<_> = csids
+                .iter()
+                .map(|csid| key_scheme.changeset_key(csid))
+                .collect();
+            blobstore
+                .get_batch(keys.clone())
+                .map(move |found| (csids, keys, found))
+        })
+        .fold(HashCheck::default(), |mut check, (csids, keys, found)| {
+            for (csid, key) in csids.into_iter().zip(keys) {
+                match found.get(&key) {
+                    Some(&Some(ref blob)) => match cs_content_hash(blob) {
+                        Ok(ref actual) if *actual == csid => check.ok += 1,
+                        actual => {
+                            STATS::hash_mismatches.add_value(1);
+                            check.mismatched.push(HashMismatch {
+                                expected: csid,
+                                actual: actual.ok(),
+                            });
+                        }
+                    },
+                    _ => check.missing.push(csid),
+                }
+            }
+            Ok::<_, Error>(check)
+        })
+        .boxify()
+}
+
+/// Log the outcome of `verify_changeset_hashes`, failing if any hash didn't match. Missing
+/// changesets are only warned about, as `--blob-key-allowlist` and `--max-blob-size` can leave
+/// them out on purpose.
+pub(crate) fn report_hash_check(check: &HashCheck, logger: &Logger) -> Result<()> {
+    for mismatch in check.mismatched.iter().take(MAX_REPORTED_MISMATCHES) {
+        match mismatch.actual {
+            Some(ref actual) => warn!(
+                logger,
+                "changeset {} is stored with content hashing to {}", mismatch.expected, actual
+            ),
+            None => warn!(
+                logger,
+                "changeset {} is stored as a blob that doesn't deserialize", mismatch.expected
+            ),
+        }
+    }
+    if !check.missing.is_empty() {
+        warn!(
+            logger,
+            "{} changesets were not in the blobstore to verify, e.g. {}",
+            check.missing.len(),
+            check.missing[0]
+        );
+    }
+    info!(
+        logger,
+        "verified {} changeset hashes: {} ok, {} mismatched, {} missing",
+        check.ok + check.mismatched.len() + check.missing.len(),
+        check.ok,
+        check.mismatched.len(),
+        check.missing.len()
+    );
+    if !check.mismatched.is_empty() {
+        bail!("{} changeset hash mismatches found", check.mismatched.len());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use bytes::Bytes;
+
+    use blobrepo::{cskey, BlobChangeset};
+    use mercurial::revlogrepo::RevlogChangeset;
+
+    use memblob::Memblob;
+    use memlinknodes::MemLinknodes;
+    use mercurial_types::{Blob, BlobNode, RepoPath};
+    use mercurial_types_mocks::nodehash::*;
+
+    #[test]
+    fn one_dangling() {
+        let blobstore = Memblob::new();
+        let linknodes = MemLinknodes::new();
+        let path = RepoPath::file("abc".as_ref()).unwrap();
+
+        blobstore
+            .put(cskey(&ONES_HASH), Bytes::from_static(b"changeset"))
+            .wait()
+            .unwrap();
+        linknodes
+            .add(path.clone(), &NULL_HASH, &ONES_HASH)
+            .wait()
+            .unwrap();
+        linknodes.add(path, &AS_HASH, &TWOS_HASH).wait().unwrap();
+
+        let check = check_linknodes(&linknodes, blobstore, KeyScheme::Default)
+            .wait()
+            .unwrap();
+        assert_eq!(check.ok, 1);
+        assert_eq!(check.dangling.len(), 1);
+        assert_eq!(check.dangling[0].node, AS_HASH);
+        assert_eq!(check.dangling[0].linknode, TWOS_HASH);
+    }
+
+    #[test]
+    fn flipped_byte() {
+        let blobstore = Memblob::new();
+        // `generate` always puts a space after the timezone, so this is what gets saved.
+        let text = format!(
+            "{}\nuser <user@example.com>\n1500000000 0 \nabc\n\ncomment",
+            ONES_HASH
+        );
+        let node = BlobNode::new(Blob::from(text.into_bytes()), Some(&TWOS_HASH), None);
+        let csid = node.nodeid().unwrap();
+        BlobChangeset::new(&csid, RevlogChangeset::new(node).unwrap())
+            .save(blobstore.clone())
+            .wait()
+            .unwrap();
+
+        let check = verify_changeset_hashes(blobstore.clone(), vec![csid], KeyScheme::Default)
+            .wait()
+            .unwrap();
+        assert_eq!(check.ok, 1);
+        assert!(check.mismatched.is_empty());
+
+        // The last byte is in the comment, so the blob still deserializes after this.
+        let stored = blobstore.get(cskey(&csid)).wait().unwrap().unwrap();
+        let mut corrupted = stored.to_vec();
+        *corrupted.last_mut().unwrap() ^= 1;
+        blobstore
+            .put(cskey(&csid), Bytes::from(corrupted))
+            .wait()
+            .unwrap();
+
+        let check = verify_changeset_hashes(blobstore, vec![csid, THREES_HASH], KeyScheme::Default)
+            .wait()
+            .unwrap();
+        assert_eq!(check.ok, 0);
+        assert_eq!(check.missing, vec![THREES_HASH]);
+        assert_eq!(check.mismatched.len(), 1);
+        assert_eq!(check.mismatched[0].expected, csid);
+        assert!(check.mismatched[0].actual.is_some());
+        assert_ne!(check.mismatched[0].actual, Some(csid));
+    }
+}
@@ -0,0 +1,149 @@
+// Copyright (c) 2017-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+//! Checking a reimport against an existing store instead of writing it, for `--compare-with`.
+//!
+//! Each blob the import would have written is looked up in the comparison store as it comes, so
+//! only the keys are kept around, not the blobs. Once the import is done, the comparison store's
+//! keys that the import never got to are the extra ones.
+
+use std::collections::HashSet;
+use std::sync::{Arc, Mutex};
+
+use bytes::Bytes;
+use failure::Error;
+use futures::{Future, IntoFuture, Stream};
+
+use blobstore::{Blobstore, ScrubDifference};
+use futures_ext::{BoxFuture, FutureExt};
+
+use BBlobstore;
+
+/// Blobstore whose puts are compared against `other` rather than written. As with `scrub`, the
+/// import is blobstore A and `other` is B, so a blob the import made that `other` doesn't have is
+/// `MissingInB`, and one only `other` has is `MissingInA`.
+///
+/// Gets go to `other`, as nothing is written anywhere else. Clones share what they've seen.
+#[derive(Clone)]
+pub(crate) struct CompareBlobstore {
+    other: BBlobstore,
+    seen: Arc<Mutex<HashSet<String>>>,
+    differences: Arc<Mutex<Vec<(String, ScrubDifference)>>>,
+}
+
+impl CompareBlobstore {
+    pub fn new(other: BBlobstore) -> Self {
+        CompareBlobstore {
+            other,
+            seen: Arc::new(Mutex::new(HashSet::new())),
+            differences: Arc::new(Mutex::new(Vec::new())),
+        }
+    }
+
+    /// Every difference found, sorted by key, including the keys in `other` that weren't put.
+    /// Only call this once all the puts are done.
+    pub fn differences(&self) -> BoxFuture<Vec<(String, ScrubDifference)>, Error> {
+        let seen = self.seen.clone();
+        let differences = self.differences.clone();
+        self.other
+            .keys()
+            .filter(move |key| !seen.lock().expect("lock poisoned").contains(key))
+            .collect()
+            .map(move |extra| {
+                let mut differences = differences.lock().expect("lock poisoned").clone();
+                differences.extend(
+                    extra
+                        .into_iter()
+                        .map(|key| (key, ScrubDifference::MissingInA)),
+                );
+                differences.sort_by(|a, b| a.0.cmp(&b.0));
+                differences
+            })
+            .boxify()
+    }
+}
+
+impl Blobstore for CompareBlobstore {
+    type GetBlob = BoxFuture<Option<Bytes>, Error>;
+    type PutBlob = BoxFuture<(), Error>;
+
+    fn get(&self, key: String) -> Self::GetBlob {
+        self.other.get(key)
+    }
+
+    fn put(&self, key: String, value: Bytes) -> Self::PutBlob {
+        // The same blob can be sent more than once, but it only needs checking the first time.
+        if !self.seen.lock().expect("lock poisoned").insert(key.clone()) {
+            return Ok(()).into_future().boxify();
+        }
+        let differences = self.differences.clone();
+        self.other
+            .get(key.clone())
+            .map(move |found| {
+                let difference = match found {
+                    None => Some(ScrubDifference::MissingInB),
+                    Some(ref found) if *found != value => Some(ScrubDifference::Differs),
+                    Some(_) => None,
+                };
+                if let Some(difference) = difference {
+                    differences
+                        .lock()
+                        .expect("lock poisoned")
+                        .push((key, difference));
+                }
+            })
+            .boxify()
+    }
+
+    fn exists(&self, key: String) -> BoxFuture<bool, Error> {
+        self.other.exists(key)
+    }
+
+    fn flush(&self) -> BoxFuture<(), Error> {
+        Ok(()).into_future().boxify()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use memblob::Memblob;
+
+    #[test]
+    fn altered_store_differences() {
+        let other = Memblob::new();
+        for &(key, value) in &[("same", "blob"), ("altered", "tampered"), ("extra", "blob")] {
+            other
+                .put(key.to_string(), Bytes::from(value))
+                .wait()
+                .unwrap();
+        }
+        let compare = CompareBlobstore::new(other.clone().arced());
+        for key in &["same", "altered", "missing", "same"] {
+            compare
+                .put(key.to_string(), Bytes::from_static(b"blob"))
+                .wait()
+                .unwrap();
+        }
+
+        let differences = compare.differences().wait().unwrap();
+        assert_eq!(
+            differences,
+            vec![
+                ("altered".to_string(), ScrubDifference::Differs),
+                ("extra".to_string(), ScrubDifference::MissingInA),
+                ("missing".to_string(), ScrubDifference::MissingInB),
+            ]
+        );
+        // Nothing was written to the comparison store.
+        assert_eq!(other.get("missing".to_string()).wait().unwrap(), None);
+        assert_eq!(
+            other.get("altered".to_string()).wait().unwrap(),
+            Some(Bytes::from_static(b"tampered"))
+        );
+    }
+}
@@ -4,27 +4,37 @@
 // This software may be used and distributed according to the terms of the
 // GNU General Public License version 2 or any later version.
 
+use std::collections::HashSet;
+use std::fs::File;
+use std::io::{BufWriter, Write};
 use std::sync::Arc;
+use std::sync::atomic::Ordering;
 use std::sync::mpsc::SyncSender;
 
-use futures::{Future, IntoFuture, Stream};
+use futures::{stream, Future, IntoFuture, Stream};
 use futures_cpupool::CpuPool;
 use slog::Logger;
 use tokio_core::reactor::Core;
 
 use blobrepo::BlobChangeset;
+use bookmarks::Bookmarks;
 use failure::{Error, Result};
 use futures_ext::{BoxStream, FutureExt, StreamExt};
 use heads::Heads;
 use linknodes::Linknodes;
-use mercurial::{self, RevlogManifest, RevlogRepo};
-use mercurial::revlog::RevIdx;
+use mercurial::{RevlogManifest, RevlogRepo};
+use mercurial::revlog::{RevIdx, Revlog};
 use mercurial_types::{Changeset, Manifest, NodeHash, RepoPath};
 use stats::Timeseries;
 
 use BlobstoreEntry;
 use STATS;
+use TOTALS;
+use send_entry;
 use manifest;
+use parentindex::ParentIndex;
+use skippedout::SkippedOut;
+use trace::BlobTrace;
 
 pub(crate) struct ConvertContext<H> {
     pub repo: RevlogRepo,
@@ -33,8 +43,48 @@ pub(crate) struct ConvertContext<H> {
     pub core: Core,
     pub cpupool: Arc<CpuPool>,
     pub logger: Logger,
+    /// Total number of changesets in the source repo, before `skip` and `commits_limit` apply.
+    pub total_changesets: usize,
     pub skip: Option<u64>,
     pub commits_limit: Option<u64>,
+    /// If set, only import this fraction of the changesets, as picked by `in_sample`.
+    pub sample_rate: Option<f64>,
+    pub sample_seed: u64,
+    /// Fail the import if not all of the source repo's heads end up in the headstore.
+    pub strict: bool,
+    /// Add the source repo's heads to `headstore`, once their changesets have been converted.
+    /// Heads whose changesets the filters leave out aren't added. Without this the import is
+    /// head-less, and there's nothing for `strict` to check.
+    pub write_heads: bool,
+    /// Also record the changesets the source repo's bookmarks point at as heads. These are added
+    /// on top of the repo's native heads, and count towards the `strict` check.
+    pub heads_from_bookmarks: bool,
+    /// If set, record each converted changeset's parents here.
+    pub parent_index: Option<Arc<ParentIndex<BufWriter<File>>>>,
+    /// If set, only import the descendants of this changeset, for incremental imports on top of
+    /// an earlier one that got as far as it. This applies before `skip` and `commits_limit`.
+    pub since: Option<NodeHash>,
+    /// Finish changesets in revlog order rather than as soon as they're done, so that the
+    /// progress through the import is the same from run to run. This costs some throughput, as
+    /// one slow changeset holds up the ones behind it.
+    pub ordered: bool,
+    /// If set, record each changeset the filters above leave out here.
+    pub skipped_out: Option<Arc<SkippedOut<BufWriter<File>>>>,
+    /// Logs the stages of the blob picked with `--trace-blob`, if any.
+    pub trace: BlobTrace,
+    /// Only send each changeset once all of its manifest and file entries have been sent, for
+    /// `--two-phase`.
+    pub two_phase: bool,
+}
+
+/// The filters on which of the source changesets get imported, applied in this order.
+struct ChangesetFilters {
+    /// Only the changesets in this set.
+    since: Option<HashSet<NodeHash>>,
+    skip: Option<u64>,
+    commits_limit: Option<u64>,
+    /// The sample rate and seed, for `in_sample`.
+    sample: Option<(f64, u64)>,
 }
 
 impl<H> ConvertContext<H>
@@ -49,18 +99,39 @@ where
         let headstore = self.headstore;
         let skip = self.skip;
         let commits_limit = self.commits_limit;
+        let total_changesets = self.total_changesets;
 
-        let changesets: BoxStream<NodeHash, mercurial::Error> = if let Some(skip) = skip {
-            self.repo.changesets().skip(skip).boxify()
-        } else {
-            self.repo.changesets().boxify()
+        let since = match self.since {
+            Some(ref since) => {
+                let wanted = descendants(self.repo.get_changelog(), since)?;
+                info!(
+                    logger,
+                    "Importing the {} changesets after {}",
+                    wanted.len(),
+                    since
+                );
+                Some(wanted)
+            }
+            None => None,
         };
-
-        let changesets: BoxStream<NodeHash, mercurial::Error> = if let Some(limit) = commits_limit {
-            changesets.take(limit).boxify()
-        } else {
-            changesets.boxify()
+        if let Some(rate) = self.sample_rate {
+            warn!(
+                logger,
+                "Importing a {} sample of changesets, the resulting store will be incomplete",
+                rate
+            );
+        }
+        let filters = ChangesetFilters {
+            since,
+            skip,
+            commits_limit,
+            sample: self.sample_rate.map(|rate| (rate, self.sample_seed)),
         };
+        let changesets = filter_changesets(
+            self.repo.changesets().boxify(),
+            filters,
+            self.skipped_out.clone(),
+        );
         let linknodes_store = Arc::new(linknodes_store);
 
         // Generate stream of changesets. For each changeset, save the cs blob, and the manifest
@@ -71,22 +142,52 @@ where
             .map({
                 let repo = self.repo.clone();
                 let sender = self.sender.clone();
+                let parent_index = self.parent_index.clone();
+                let trace = self.trace.clone();
+                let two_phase = self.two_phase;
+                let logger = logger.clone();
                 move |(seq, csid)| {
-                    debug!(logger, "{}: changeset {}", seq, csid);
+                    debug!(logger, "{} of {}: changeset {}", seq, total_changesets, csid);
                     STATS::changesets.add_value(1);
-                    copy_changeset(repo.clone(), sender.clone(), linknodes_store.clone(), csid)
+                    TOTALS.changesets.fetch_add(1, Ordering::Relaxed);
+                    copy_changeset(
+                        repo.clone(),
+                        sender.clone(),
+                        linknodes_store.clone(),
+                        parent_index.clone(),
+                        trace.clone(),
+                        two_phase,
+                        csid,
+                    ).map(move |()| csid)
                 }
-            }) // Stream<Future<()>>
-            .map(|copy| cpupool.spawn(copy))
-            .buffer_unordered(100);
+            }); // Stream<Future<NodeHash>>
+        // Only the heads whose changesets get through the filters are added, so that the check
+        // at the end sees the ones that were dropped.
+        let source_heads = if self.write_heads {
+            let heads = source_heads(&self.repo, self.heads_from_bookmarks)?;
+            core.run(heads.collect())
+                .map_err(|err| Error::from(err.context("Failed get heads")))?
+        } else {
+            Vec::new()
+        };
+        let wanted_heads: HashSet<_> = source_heads.iter().cloned().collect();
+        let changesets = spawn_changesets(cpupool.clone(), changesets, self.ordered).fold(
+            HashSet::new(),
+            |mut imported_heads, csid| {
+                debug!(logger, "changeset {} done", csid);
+                if wanted_heads.contains(&csid) {
+                    imported_heads.insert(csid);
+                }
+                Ok::<_, Error>(imported_heads)
+            },
+        );
+        let imported_heads = core.run(changesets)?;
 
-        let heads = self.repo
-            .get_heads()
-            .map_err(Error::from)
-            .map_err(|err| err.context("Failed get heads").into())
+        let heads = stream::iter_ok(imported_heads)
             .map(|h| {
                 debug!(logger, "head {}", h);
                 STATS::heads.add_value(1);
+                TOTALS.heads.fetch_add(1, Ordering::Relaxed);
                 headstore.add(&h).map_err({
                     move |err| {
                         err.context(format_err!("Failed to create head {}", h))
@@ -94,17 +195,126 @@ where
                     }
                 })
             })
-            .buffer_unordered(100);
+            .buffer_unordered(100)
+            .for_each(|_| Ok(()));
+        core.run(heads)?;
 
-        let convert = changesets.select(heads).for_each(|_| Ok(()));
+        if let Some(ref parent_index) = self.parent_index {
+            parent_index.flush()?;
+        }
+        if let Some(ref skipped_out) = self.skipped_out {
+            skipped_out.flush()?;
+        }
 
-        core.run(convert)?;
+        if self.write_heads {
+            let stored_heads = core.run(headstore.heads().collect())?;
+            let missing = missing_heads(&source_heads, &stored_heads);
+            if missing.is_empty() {
+                info!(logger, "all {} heads imported", source_heads.len());
+            } else {
+                warn!(
+                    logger,
+                    "source repo has {} heads, but only {} were imported; missing: {:?}",
+                    source_heads.len(),
+                    source_heads.len() - missing.len(),
+                    missing
+                );
+                if self.strict {
+                    bail!("{} heads missing from the headstore", missing.len());
+                }
+            }
+        } else {
+            info!(logger, "not writing heads");
+        }
 
         info!(logger, "parsed everything, waiting for io");
         Ok(())
     }
 }
 
+/// Apply `filters` to `changesets`. Each changeset that gets left out is recorded in
+/// `skipped_out`, along with the name of the filter that dropped it.
+fn filter_changesets<W>(
+    changesets: BoxStream<NodeHash, Error>,
+    filters: ChangesetFilters,
+    skipped_out: Option<Arc<SkippedOut<W>>>,
+) -> BoxStream<NodeHash, Error>
+where
+    W: Write + Send + 'static,
+{
+    let ChangesetFilters {
+        since,
+        skip,
+        commits_limit,
+        sample,
+    } = filters;
+
+    let changesets = match since {
+        Some(wanted) => exclude(changesets, &skipped_out, "since", move |csid| {
+            wanted.contains(csid)
+        }),
+        None => changesets,
+    };
+
+    let changesets = match skip {
+        Some(skip) => {
+            let mut seen = 0;
+            exclude(changesets, &skipped_out, "skip", move |_| {
+                seen += 1;
+                seen > skip
+            })
+        }
+        None => changesets,
+    };
+
+    let changesets = match (commits_limit, skipped_out.is_some()) {
+        // Everything after the limit has to be read to be recorded, otherwise the stream can
+        // stop there.
+        (Some(limit), true) => {
+            let mut seen = 0;
+            exclude(changesets, &skipped_out, "commits-limit", move |_| {
+                seen += 1;
+                seen <= limit
+            })
+        }
+        (Some(limit), false) => changesets.take(limit).boxify(),
+        (None, _) => changesets,
+    };
+
+    match sample {
+        Some((rate, seed)) => exclude(changesets, &skipped_out, "sample", move |csid| {
+            in_sample(csid, seed, rate)
+        }),
+        None => changesets,
+    }
+}
+
+/// Keep the changesets that `keep` returns true for, recording the others in `skipped_out`.
+fn exclude<W, F>(
+    changesets: BoxStream<NodeHash, Error>,
+    skipped_out: &Option<Arc<SkippedOut<W>>>,
+    reason: &'static str,
+    mut keep: F,
+) -> BoxStream<NodeHash, Error>
+where
+    W: Write + Send + 'static,
+    F: FnMut(&NodeHash) -> bool + Send + 'static,
+{
+    let skipped_out = skipped_out.clone();
+    changesets
+        .and_then(move |csid| {
+            if keep(&csid) {
+                return Ok(Some(csid));
+            }
+            if let Some(ref skipped_out) = skipped_out {
+                skipped_out.add(&csid, reason)?;
+            }
+            Ok(None)
+        })
+        .filter_map(|csid| csid)
+        .boxify()
+}
+
 /// Copy a changeset and its manifest into the blobstore
 ///
 /// The changeset and the manifest are straightforward - we just make literal copies of the
@@ -113,10 +323,16 @@ where
 /// The files are more complex. For each manifest, we generate a stream of entries, then flatten
 /// the entry streams from all changesets into a single stream. Then each entry is filtered
 /// against a set of entries that have already been copied, and any remaining are actually copied.
+///
+/// Normally the changeset is sent as soon as it's read. If `two_phase` is set, it's only sent once
+/// the manifest and file entries have all been.
 fn copy_changeset<L>(
     revlog_repo: RevlogRepo,
     sender: SyncSender<BlobstoreEntry>,
     linknodes_store: L,
+    parent_index: Option<Arc<ParentIndex<BufWriter<File>>>>,
+    trace: BlobTrace,
+    two_phase: bool,
     csid: NodeHash,
 ) -> impl Future<Item = (), Error = Error> + Send + 'static
 where
@@ -125,16 +341,19 @@ where
 {
     let put = {
         let sender = sender.clone();
+        let trace = trace.clone();
         let csid = csid;
 
         revlog_repo
             .get_changeset_by_nodeid(&csid)
             .from_err()
             .and_then(move |cs| {
+                if let Some(parent_index) = parent_index {
+                    parent_index.add(&csid, cs.parents())?;
+                }
                 let bcs = BlobChangeset::new(&csid, cs);
-                sender
-                    .send(BlobstoreEntry::Changeset(bcs))
-                    .map_err(Error::from)
+                trace.changeset(&csid, "sent");
+                send_entry(&sender, BlobstoreEntry::Changeset(bcs)).map_err(Error::from)
             })
     };
 
@@ -145,7 +364,7 @@ where
         .and_then(move |(cs, entry)| {
             let mfid = *cs.manifestid();
             let linkrev = entry.linkrev;
-            put_blobs(revlog_repo, sender, linknodes_store, mfid, linkrev)
+            put_blobs(revlog_repo, sender, linknodes_store, trace, mfid, linkrev)
         })
         .map_err(move |err| {
             err.context(format_err!("Can't copy manifest for cs {}", csid))
@@ -154,7 +373,11 @@ where
     _assert_sized(&put);
     _assert_sized(&manifest);
 
-    put.join(manifest).map(|_| ())
+    if two_phase {
+        manifest.and_then(move |()| put).boxify()
+    } else {
+        put.join(manifest).map(|_| ()).boxify()
+    }
 }
 
 /// Copy manifest and filelog entries into the blob store.
@@ -164,6 +387,7 @@ fn put_blobs<L>(
     revlog_repo: RevlogRepo,
     sender: SyncSender<BlobstoreEntry>,
     linknodes_store: L,
+    trace: BlobTrace,
     mfid: NodeHash,
     linkrev: RevIdx,
 ) -> impl Future<Item = (), Error = Error> + Send + 'static
@@ -179,9 +403,11 @@ where
         .and_then(move |(blob, cs_entry)| {
             let putmf = manifest::put_entry(
                 sender.clone(),
+                trace.clone(),
                 mfid,
                 blob.as_blob().clone(),
                 blob.parents().clone(),
+                RepoPath::root(),
             );
 
             let linknode = cs_entry.nodeid;
@@ -213,7 +439,8 @@ where
                             let linknode_future = linknodes_store
                                 .add(entry.get_path().clone(), entry.get_hash(), &linknode)
                                 .from_err();
-                            let copy_future = manifest::copy_entry(entry, sender.clone());
+                            let copy_future =
+                                manifest::copy_entry(entry, sender.clone(), trace.clone());
                             copy_future.join(linknode_future).map(|_| ())
                         })
                 })
@@ -228,4 +455,300 @@ where
         })
 }
 
+/// How many changesets are converted at once.
+const PARALLEL_CHANGESETS: usize = 100;
+
+/// Run the changeset copies from `copies` on `cpupool`, `PARALLEL_CHANGESETS` at a time. The
+/// results come out in the order of `copies` if `ordered`, or as they finish otherwise.
+fn spawn_changesets<S, F>(
+    cpupool: Arc<CpuPool>,
+    copies: S,
+    ordered: bool,
+) -> BoxStream<F::Item, Error>
+where
+    S: Stream<Item = F, Error = Error> + Send + 'static,
+    F: Future<Error = Error> + Send + 'static,
+    F::Item: Send + 'static,
+{
+    let spawned = copies.map(move |copy| cpupool.spawn(copy));
+    if ordered {
+        spawned.buffered(PARALLEL_CHANGESETS).boxify()
+    } else {
+        spawned.buffer_unordered(PARALLEL_CHANGESETS).boxify()
+    }
+}
+
+/// The heads to import: the repo's own heads, followed by its bookmark targets if
+/// `heads_from_bookmarks` is set. A bookmark on a native head shows up twice, which is harmless
+/// as adding a head is idempotent.
+fn source_heads(
+    repo: &RevlogRepo,
+    heads_from_bookmarks: bool,
+) -> Result<BoxStream<NodeHash, Error>> {
+    let heads = repo.get_heads().map_err(Error::from);
+    if heads_from_bookmarks {
+        Ok(heads.chain(bookmark_heads(repo.bookmarks()?)).boxify())
+    } else {
+        Ok(heads.boxify())
+    }
+}
+
+/// The changesets that `bookmarks` point at.
+fn bookmark_heads<B: Bookmarks>(bookmarks: B) -> BoxStream<NodeHash, Error> {
+    let bookmarks = Arc::new(bookmarks);
+    bookmarks
+        .keys()
+        .and_then({
+            let bookmarks = bookmarks.clone();
+            move |key| bookmarks.get(&key)
+        })
+        .filter_map(|value| value.map(|(hash, _version)| hash))
+        .boxify()
+}
+
+/// All the changesets descended from `since` in `changelog`, not including `since` itself. Fails
+/// if `since` isn't in the changelog.
+fn descendants(changelog: &Revlog, since: &NodeHash) -> Result<HashSet<NodeHash>> {
+    let since_idx = changelog
+        .get_idx_by_nodeid(since)
+        .map_err(|_| format_err!("changeset {} to import after is not in the repo", since))?;
+
+    // Parents always come before their children in a revlog, so a single pass in index order
+    // sees every parent's status before it's needed.
+    let mut descendant_idxs = HashSet::new();
+    descendant_idxs.insert(since_idx);
+    let mut descendants = HashSet::new();
+    for idx in since_idx.succ().range_to(RevIdx::from(changelog.len())) {
+        let entry = changelog.get_entry(idx)?;
+        let is_descendant = entry
+            .p1
+            .into_iter()
+            .chain(entry.p2)
+            .any(|parent| descendant_idxs.contains(&parent));
+        if is_descendant {
+            descendant_idxs.insert(idx);
+            descendants.insert(entry.nodeid);
+        }
+    }
+    Ok(descendants)
+}
+
+/// The heads in `source` that aren't in `stored`.
+fn missing_heads(source: &[NodeHash], stored: &[NodeHash]) -> Vec<NodeHash> {
+    let stored: HashSet<_> = stored.iter().collect();
+    source
+        .iter()
+        .filter(|head| !stored.contains(head))
+        .cloned()
+        .collect()
+}
+
+/// Decide whether a changeset is part of the sample selected by `rate` and `seed`.
+///
+/// Changeset hashes are already uniformly distributed, so mixing the seed into the leading
+/// bytes is enough to get a selection that's pseudo-random but stable across runs.
+fn in_sample(csid: &NodeHash, seed: u64, rate: f64) -> bool {
+    let x = csid.as_ref()[..8]
+        .iter()
+        .fold(0u64, |acc, b| (acc << 8) | u64::from(*b));
+
+    // splitmix64 finalizer
+    let mut x = x ^ seed;
+    x = (x ^ (x >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    x = (x ^ (x >> 27)).wrapping_mul(0x94d049bb133111eb);
+    x ^= x >> 31;
+
+    rate >= 1.0 || (x as f64) < rate * (u64::max_value() as f64)
+}
+
 fn _assert_sized<T: Sized>(_: &T) {}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::str::FromStr;
+    use std::thread;
+    use std::time::Duration;
+
+    use futures::{future, stream};
+
+    use stockbookmarks::StockBookmarks;
+
+    fn hashes() -> Vec<NodeHash> {
+        (0..100u32)
+            .map(|i| {
+                let hex = format!("{:016x}{:024x}", (i as u64).wrapping_mul(0x9e3779b97f4a7c15), i);
+                NodeHash::from_str(&hex).unwrap()
+            })
+            .collect()
+    }
+
+    fn sample(seed: u64, rate: f64) -> Vec<NodeHash> {
+        hashes()
+            .into_iter()
+            .filter(|csid| in_sample(csid, seed, rate))
+            .collect()
+    }
+
+    #[test]
+    fn sample_stable() {
+        let first = sample(42, 0.3);
+        assert_eq!(first, sample(42, 0.3));
+        assert!(!first.is_empty() && first.len() < 100);
+
+        // A different seed usually picks a different subset.
+        assert_ne!(first, sample(43, 0.3));
+    }
+
+    #[test]
+    fn heads_comparison() {
+        let source = hashes()[..3].to_vec();
+        assert!(missing_heads(&source, &source).is_empty());
+
+        // A filter that dropped a head.
+        let stored = vec![source[0], source[2]];
+        assert_eq!(missing_heads(&source, &stored), vec![source[1]]);
+
+        // Heads left over from an earlier import don't hide the missing one.
+        let stored = vec![source[0], source[2], hashes()[50]];
+        assert_eq!(missing_heads(&source, &stored), vec![source[1]]);
+    }
+
+    #[test]
+    fn bookmark_targets() {
+        let hashes = hashes();
+        let bookmarks = StockBookmarks::from_entries(vec![
+            (b"master".to_vec(), hashes[0]),
+            (b"stable".to_vec(), hashes[1]),
+        ]);
+        let mut heads = bookmark_heads(bookmarks).collect().wait().unwrap();
+        heads.sort();
+        let mut expected = vec![hashes[0], hashes[1]];
+        expected.sort();
+        assert_eq!(heads, expected);
+
+        // A head that's only reachable through a bookmark is reported missing if it isn't stored.
+        assert_eq!(missing_heads(&heads, &hashes[..1]), vec![hashes[1]]);
+    }
+
+    /// An in-memory changelog index with the given parents for each revision, identified by
+    /// `hashes()[rev]`. Only the graph is there, there's no data.
+    fn changelog(parents: &[(Option<u32>, Option<u32>)]) -> Revlog {
+        let hashes = hashes();
+        let mut idx = Vec::new();
+        for (rev, &(p1, p2)) in parents.iter().enumerate() {
+            let rev = rev as u32;
+            let mut entry = Vec::new();
+            // offset and flags, the start of which doubles as the revlogng header for the
+            // first entry.
+            entry.extend_from_slice(if rev == 0 {
+                &[0, 0, 0, 1, 0, 0, 0, 0]
+            } else {
+                &[0; 8]
+            });
+            // compressed and uncompressed lengths
+            entry.extend_from_slice(&[0; 8]);
+            for field in &[rev, rev, p1.unwrap_or(!0), p2.unwrap_or(!0)] {
+                entry.extend_from_slice(&[
+                    (field >> 24) as u8,
+                    (field >> 16) as u8,
+                    (field >> 8) as u8,
+                    *field as u8,
+                ]);
+            }
+            entry.extend_from_slice(hashes[rev as usize].as_ref());
+            entry.extend_from_slice(&[0; 12]);
+            idx.extend(entry);
+        }
+        Revlog::new(idx, None).unwrap()
+    }
+
+    #[test]
+    fn since_descendants() {
+        // 0 - 1 - 3 - 4
+        //  \         /
+        //   2 ------
+        let changelog = changelog(&[
+            (None, None),
+            (Some(0), None),
+            (Some(0), None),
+            (Some(1), None),
+            (Some(2), Some(3)),
+        ]);
+        let hashes = hashes();
+
+        let after = descendants(&changelog, &hashes[1]).unwrap();
+        let expected: HashSet<_> = vec![hashes[3], hashes[4]].into_iter().collect();
+        assert_eq!(after, expected);
+
+        let after = descendants(&changelog, &hashes[0]).unwrap();
+        assert_eq!(after.len(), 4);
+        assert!(descendants(&changelog, &hashes[4]).unwrap().is_empty());
+
+        let err = descendants(&changelog, &hashes[50]).unwrap_err();
+        assert!(format!("{}", err).contains("not in the repo"));
+    }
+
+    /// The order the copies come out of `spawn_changesets` in, when the earlier ones take longer.
+    fn finish_order(ordered: bool) -> Vec<usize> {
+        let cpupool = Arc::new(CpuPool::new(4));
+        let copies = stream::iter_ok((0..8).map(|i| {
+            future::lazy(move || {
+                thread::sleep(Duration::from_millis((8 - i as u64) * 5));
+                Ok(i)
+            })
+        }));
+        spawn_changesets(cpupool, copies, ordered)
+            .collect()
+            .wait()
+            .unwrap()
+    }
+
+    #[test]
+    fn ordered_changesets() {
+        let first = finish_order(true);
+        assert_eq!(first, (0..8).collect::<Vec<_>>());
+        assert_eq!(first, finish_order(true));
+
+        // Unordered, everything still gets done.
+        let mut unordered = finish_order(false);
+        unordered.sort();
+        assert_eq!(unordered, first);
+    }
+
+    #[test]
+    fn skipped_out_commits_limit() {
+        let hashes = hashes();
+        let skipped_out = Arc::new(SkippedOut::new(Vec::new()));
+        let filters = ChangesetFilters {
+            since: None,
+            skip: Some(2),
+            commits_limit: Some(5),
+            sample: None,
+        };
+        let imported = filter_changesets(
+            stream::iter_ok(hashes[..10].to_vec()).boxify(),
+            filters,
+            Some(skipped_out.clone()),
+        ).collect()
+            .wait()
+            .unwrap();
+        assert_eq!(imported, hashes[2..7].to_vec());
+
+        let out = Arc::try_unwrap(skipped_out).ok().unwrap().into_inner();
+        let out = String::from_utf8(out).unwrap();
+        let mut expected: Vec<_> = hashes[..2]
+            .iter()
+            .map(|csid| format!("{} skip", csid))
+            .collect();
+        expected.extend(hashes[7..10].iter().map(|csid| format!("{} commits-limit", csid)));
+        assert_eq!(out.lines().collect::<Vec<_>>(), expected);
+    }
+
+    #[test]
+    fn sample_extremes() {
+        assert!(sample(42, 0.0).is_empty());
+        assert_eq!(sample(42, 1.0).len(), 100);
+    }
+}
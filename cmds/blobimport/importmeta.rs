@@ -0,0 +1,125 @@
+// Copyright (c) 2017-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+//! A record, kept next to a local blobstore's blobs, of where the first import into it came from.
+//! Later imports check it so that blobs from different repos don't end up mixed in one store.
+//!
+//! The record is an `IMPORT_META` file with one `name value` line each for the source repo, the
+//! time the import started in seconds since the epoch, and the blobstore type.
+
+use std::fs::{self, File};
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+
+use failure::{Result, ResultExt};
+
+pub(crate) const IMPORT_META: &str = "IMPORT_META";
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub(crate) struct ImportMeta {
+    pub source: PathBuf,
+    pub started: u64,
+    pub blobstore: String,
+}
+
+impl ImportMeta {
+    fn parse(contents: &str) -> Result<Self> {
+        let (mut source, mut started, mut blobstore) = (None, None, None);
+        for line in contents.lines() {
+            let mut parts = line.splitn(2, ' ');
+            match (parts.next(), parts.next()) {
+                (Some("source"), Some(value)) => source = Some(PathBuf::from(value)),
+                (Some("started"), Some(value)) => started = Some(value.parse()?),
+                (Some("blobstore"), Some(value)) => blobstore = Some(value.to_string()),
+                // Leave room for more fields.
+                _ => {}
+            }
+        }
+        match (source, started, blobstore) {
+            (Some(source), Some(started), Some(blobstore)) => Ok(ImportMeta {
+                source,
+                started,
+                blobstore,
+            }),
+            _ => bail!("incomplete import record"),
+        }
+    }
+
+    fn write<W: Write>(&self, mut writer: W) -> Result<()> {
+        writeln!(writer, "source {}", self.source.display())?;
+        writeln!(writer, "started {}", self.started)?;
+        writeln!(writer, "blobstore {}", self.blobstore)?;
+        Ok(())
+    }
+}
+
+/// What `check_import_meta` found.
+#[derive(Debug, Eq, PartialEq)]
+pub(crate) enum MetaCheck {
+    /// There was no record, so this import's was written.
+    Written,
+    /// The store was first imported from the same source.
+    Matched,
+    /// The store was first imported from somewhere else. The record is left as it was.
+    Mismatched(ImportMeta),
+}
+
+/// Compare `meta` with the record in the blobstore directory `dir`, writing it there if there
+/// isn't one yet.
+pub(crate) fn check_import_meta(dir: &Path, meta: &ImportMeta) -> Result<MetaCheck> {
+    let path = dir.join(IMPORT_META);
+    if !path.exists() {
+        fs::create_dir_all(dir)?;
+        let mut file = File::create(&path)?;
+        meta.write(&mut file)
+            .with_context(|_| format!("writing import record {}", path.display()))?;
+        return Ok(MetaCheck::Written);
+    }
+
+    let mut contents = String::new();
+    File::open(&path)
+        .and_then(|mut file| file.read_to_string(&mut contents))
+        .with_context(|_| format!("reading import record {}", path.display()))?;
+    let recorded = ImportMeta::parse(&contents)
+        .with_context(|_| format!("parsing import record {}", path.display()))?;
+    if recorded.source == meta.source {
+        Ok(MetaCheck::Matched)
+    } else {
+        Ok(MetaCheck::Mismatched(recorded))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use tempdir::TempDir;
+
+    fn meta(source: &str, started: u64) -> ImportMeta {
+        ImportMeta {
+            source: PathBuf::from(source),
+            started,
+            blobstore: "rocksdb".to_string(),
+        }
+    }
+
+    #[test]
+    fn different_source() {
+        let tmp = TempDir::new("blobimport_importmeta").unwrap();
+        let dir = tmp.path().join("blobs");
+
+        let first = meta("/repos/first", 1500000000);
+        assert_eq!(check_import_meta(&dir, &first).unwrap(), MetaCheck::Written);
+        assert_eq!(
+            check_import_meta(&dir, &meta("/repos/first", 1500001000)).unwrap(),
+            MetaCheck::Matched
+        );
+        assert_eq!(
+            check_import_meta(&dir, &meta("/repos/second", 1500002000)).unwrap(),
+            MetaCheck::Mismatched(first)
+        );
+    }
+}
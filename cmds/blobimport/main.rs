@@ -16,15 +16,25 @@ extern crate futures;
 extern crate futures_cpupool;
 #[macro_use]
 extern crate lazy_static;
+extern crate num_cpus;
 #[macro_use]
 extern crate slog;
 extern crate slog_glog_fmt;
 extern crate slog_term;
+extern crate memblob;
+extern crate memlinknodes;
+#[cfg(test)]
+extern crate mercurial_types_mocks;
+#[cfg(test)]
+extern crate tempdir;
 extern crate tokio_core;
 
+extern crate async_compression;
 extern crate blobrepo;
 extern crate blobstore;
+extern crate bookmarks;
 extern crate fileblob;
+extern crate filebookmarks;
 extern crate fileheads;
 extern crate filekv;
 extern crate filelinknodes;
@@ -34,20 +44,48 @@ extern crate linknodes;
 extern crate manifoldblob;
 extern crate memheads;
 extern crate mercurial;
+extern crate mercurial_bundles;
 extern crate mercurial_types;
 extern crate rocksblob;
 extern crate rocksdb;
 extern crate services;
+extern crate stockbookmarks;
 #[macro_use]
 extern crate stats;
 
+mod allowlist;
+mod bookmarksdiff;
+mod breaker;
+mod bundle;
+mod check;
+mod compare;
 mod convert;
+mod importmeta;
 mod manifest;
+mod obsmarkers;
+mod parentindex;
+mod selftest;
+mod skipexisting;
+mod skippedout;
+mod toppaths;
+mod trace;
+mod twophase;
+mod uri;
+mod warm;
 
+use std::cmp;
+use std::collections::{HashMap, HashSet};
+use std::env;
+use std::ffi::OsString;
+use std::fs;
+use std::io::{self, Read, Write};
 use std::path::{Path, PathBuf};
-use std::sync::Arc;
-use std::sync::mpsc::sync_channel;
+use std::result::Result as StdResult;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering, ATOMIC_USIZE_INIT};
+use std::sync::mpsc::{channel, sync_channel, SendError, SyncSender, TrySendError};
 use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
 use bytes::Bytes;
 use clap::{App, Arg, ArgMatches};
@@ -59,17 +97,50 @@ use slog_glog_fmt::default_drain as glog_drain;
 use stats::Timeseries;
 use tokio_core::reactor::{Core, Remote};
 
-use blobrepo::BlobChangeset;
-use blobstore::Blobstore;
+use blobrepo::{cskey, BlobChangeset};
+use blobstore::{collect_body, Blobstore, CompressingBlobstore, PrefixBlobstore,
+                ScrubDifference, TieredBlobstore, DEFAULT_COMPRESSION_LEVEL};
+use bookmarks::Bookmarks;
 use fileblob::Fileblob;
-use filelinknodes::FileLinknodes;
-use futures_ext::{BoxFuture, FutureExt};
-use linknodes::NoopLinknodes;
+use filebookmarks::FileBookmarks;
+use filelinknodes::{FileLinknodes, ShardedFileLinknodes};
+use futures_ext::{BoxFuture, BoxStream, FutureExt};
+use linknodes::{Linknodes, NoopLinknodes};
+use memblob::Memblob;
+use memlinknodes::MemLinknodes;
 use manifoldblob::ManifoldBlob;
 use mercurial::RevlogRepo;
+use mercurial::revlog::Version as RevlogVersion;
+use mercurial_types::{MPath, NodeHash, RepoPath};
 use rocksblob::Rocksblob;
+use stockbookmarks::StockBookmarks;
 
 const DEFAULT_MANIFOLD_BUCKET: &str = "mononoke_prod";
+const MANIFOLD_BUCKET_ENV: &str = "MONONOKE_MANIFOLD_BUCKET";
+
+/// Channel size to fall back to if the available memory can't be determined.
+const DEFAULT_CHANNEL_SIZE: usize = 1000;
+/// Default channel sizes for each kind of blobstore, before the available memory is taken into
+/// account. Local files are disk-bound and gain little from a deep buffer, while manifold has
+/// network latency to hide.
+const FILES_CHANNEL_SIZE: usize = 100;
+const ROCKSDB_CHANNEL_SIZE: usize = 1000;
+const MANIFOLD_CHANNEL_SIZE: usize = 5000;
+/// Fraction of the available memory that in-flight entries are allowed to take up.
+const CHANNEL_MEMORY_FRACTION: u64 = 8;
+/// Pessimistic estimate of the size of an entry in the channel. Most are much smaller, but large
+/// file blobs are what cause trouble.
+const CHANNEL_ENTRY_SIZE: u64 = 1024 * 1024;
+const MIN_CHANNEL_SIZE: usize = 100;
+const MAX_CHANNEL_SIZE: usize = 10000;
+/// Size at which `--blobstore tiered` sends blobs to the large tier, if `--tier-threshold` isn't
+/// given.
+const DEFAULT_TIER_THRESHOLD: usize = 1024 * 1024;
+/// How long manifold puts are paused for once --manifold-breaker-threshold is reached, if
+/// --manifold-breaker-cooldown-ms isn't given.
+const DEFAULT_BREAKER_COOLDOWN_MS: u64 = 1000;
+/// Number of keys --scrub fetches from each blobstore at a time.
+const SCRUB_BATCH_SIZE: usize = 100;
 
 define_stats! {
     prefix = "blobimport";
@@ -78,6 +149,89 @@ define_stats! {
     duplicates: timeseries(RATE, SUM),
     failures: timeseries(RATE, SUM),
     successes: timeseries(RATE, SUM),
+    path_filtered: timeseries(RATE, SUM),
+    channel_full_waits: timeseries(RATE, SUM),
+    allowlist_skipped: timeseries(RATE, SUM),
+    existing_skipped: timeseries(RATE, SUM),
+    hash_mismatches: timeseries(RATE, SUM),
+    obsmarkers: timeseries(RATE, SUM),
+    oversized_changesets: timeseries(RATE, SUM),
+    manifold_breaker_opened: timeseries(RATE, SUM),
+}
+
+// Running totals of the channel_full_waits stat, for the summary at the end of the import.
+static CHANNEL_FULL_WAITS: AtomicUsize = ATOMIC_USIZE_INIT;
+static CHANNEL_FULL_WAIT_MICROS: AtomicUsize = ATOMIC_USIZE_INIT;
+// Running total of the allowlist_skipped stat.
+static ALLOWLIST_SKIPPED: AtomicUsize = ATOMIC_USIZE_INIT;
+// Running total of the existing_skipped stat.
+static EXISTING_SKIPPED: AtomicUsize = ATOMIC_USIZE_INIT;
+// Running total of the oversized_changesets stat.
+static OVERSIZED_CHANGESETS: AtomicUsize = ATOMIC_USIZE_INIT;
+
+/// Running totals of the main stats, for the `BLOBIMPORT_RESULT` line. The stats themselves can't
+/// be read back.
+struct Totals {
+    changesets: AtomicUsize,
+    heads: AtomicUsize,
+    duplicates: AtomicUsize,
+    failures: AtomicUsize,
+    successes: AtomicUsize,
+}
+
+static TOTALS: Totals = Totals {
+    changesets: ATOMIC_USIZE_INIT,
+    heads: ATOMIC_USIZE_INIT,
+    duplicates: ATOMIC_USIZE_INIT,
+    failures: ATOMIC_USIZE_INIT,
+    successes: ATOMIC_USIZE_INIT,
+};
+
+/// The final counts of an import run.
+#[derive(Debug, Eq, PartialEq)]
+struct ImportResult {
+    /// The --repo-name of the import, if it was given one.
+    repo_name: Option<String>,
+    changesets: usize,
+    heads: usize,
+    duplicates: usize,
+    failures: usize,
+    successes: usize,
+    elapsed: Duration,
+}
+
+impl ImportResult {
+    fn from_totals(repo_name: Option<String>, elapsed: Duration) -> Self {
+        ImportResult {
+            repo_name,
+            changesets: TOTALS.changesets.load(Ordering::Relaxed),
+            heads: TOTALS.heads.load(Ordering::Relaxed),
+            duplicates: TOTALS.duplicates.load(Ordering::Relaxed),
+            failures: TOTALS.failures.load(Ordering::Relaxed),
+            successes: TOTALS.successes.load(Ordering::Relaxed),
+            elapsed,
+        }
+    }
+
+    /// A single line of space-separated `key=value` fields, for orchestration tools to parse.
+    /// There's a `repo` field first if the import was given a repo name.
+    fn summary_line(&self) -> String {
+        let repo = match self.repo_name {
+            Some(ref name) => format!(" repo={}", name),
+            None => String::new(),
+        };
+        format!(
+            "BLOBIMPORT_RESULT{} changesets={} heads={} duplicates={} failures={} successes={} \
+             elapsed_secs={:.3}",
+            repo,
+            self.changesets,
+            self.heads,
+            self.duplicates,
+            self.failures,
+            self.successes,
+            self.elapsed.as_secs() as f64 + self.elapsed.subsec_nanos() as f64 / 1e9
+        )
+    }
 }
 
 #[derive(Debug, Eq, PartialEq)]
@@ -85,6 +239,81 @@ enum BlobstoreType {
     Files,
     Rocksdb,
     Manifold(String),
+    /// Blobs of `threshold` bytes or more go in `large`, the rest in `small`.
+    Tiered {
+        small: Box<BlobstoreType>,
+        large: Box<BlobstoreType>,
+        threshold: usize,
+    },
+}
+
+/// How changeset blobs are keyed in the blobstore.
+#[derive(Clone, Debug, Eq, PartialEq)]
+enum KeyScheme {
+    /// `changeset-<hash>.bincode`, which is what the rest of Mononoke expects.
+    Default,
+    /// `<prefix><hash>`, for interop with other stores.
+    Prefix(String),
+}
+
+impl KeyScheme {
+    /// Parse a `--key-scheme` value, which is either `default` or `prefix:<PREFIX>`.
+    fn parse(scheme: &str) -> Result<Self> {
+        if scheme == "default" {
+            Ok(KeyScheme::Default)
+        } else if scheme.starts_with("prefix:") {
+            Ok(KeyScheme::Prefix(scheme["prefix:".len()..].to_string()))
+        } else {
+            bail!(
+                "invalid key scheme '{}', expected 'default' or 'prefix:<PREFIX>'",
+                scheme
+            )
+        }
+    }
+
+    fn changeset_key(&self, nodeid: &NodeHash) -> String {
+        match *self {
+            KeyScheme::Default => cskey(nodeid),
+            KeyScheme::Prefix(ref prefix) => format!("{}{}", prefix, nodeid),
+        }
+    }
+}
+
+/// Which revlog format the input repo is expected to be in.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum InputFormat {
+    /// Whatever the revlog headers say.
+    Auto,
+    Forced(RevlogVersion),
+}
+
+impl InputFormat {
+    /// Parse an `--input-format` value: `auto`, `revlogv0` or `revlogv1`.
+    fn parse(format: &str) -> Result<Self> {
+        match format {
+            "auto" => Ok(InputFormat::Auto),
+            "revlogv0" => Ok(InputFormat::Forced(RevlogVersion::Revlog0)),
+            "revlogv1" => Ok(InputFormat::Forced(RevlogVersion::RevlogNG)),
+            _ => bail!(
+                "invalid input format '{}', expected 'auto', 'revlogv0' or 'revlogv1'",
+                format
+            ),
+        }
+    }
+
+    /// Fail if the repo isn't in the forced format. Mercurial uses one format for the whole
+    /// store, so checking the changelog is enough.
+    fn check(&self, repo: &RevlogRepo) -> Result<()> {
+        let actual = repo.get_changelog().get_header().version;
+        match *self {
+            InputFormat::Forced(expected) if expected != actual => bail!(
+                "input format was given as {:?}, but the changelog is {:?}",
+                expected,
+                actual
+            ),
+            _ => Ok(()),
+        }
+    }
 }
 
 type BBlobstore = Arc<
@@ -97,90 +326,591 @@ fn _assert_static<T: 'static>(_: &T) {}
 fn _assert_blobstore<T: Blobstore>(_: &T) {}
 
 pub(crate) enum BlobstoreEntry {
-    ManifestEntry((String, Bytes)),
+    /// A blob for a manifest or file entry, and the path of the entry it belongs to.
+    ManifestEntry((String, Bytes), RepoPath),
     Changeset(BlobChangeset),
+    /// An obsolescence marker, encoded as it was in the obsstore.
+    Obsmarker((String, Bytes)),
+}
+
+/// Send an entry to the io thread, keeping track of how often and for how long senders are held up
+/// because the channel is full. Frequent waits mean the io thread is the bottleneck.
+pub(crate) fn send_entry<T>(sender: &SyncSender<T>, entry: T) -> StdResult<(), SendError<T>> {
+    match sender.try_send(entry) {
+        Ok(()) => Ok(()),
+        Err(TrySendError::Disconnected(entry)) => Err(SendError(entry)),
+        Err(TrySendError::Full(entry)) => {
+            STATS::channel_full_waits.add_value(1);
+            let start = Instant::now();
+            let res = sender.send(entry);
+            let waited = start.elapsed();
+            CHANNEL_FULL_WAITS.fetch_add(1, Ordering::Relaxed);
+            CHANNEL_FULL_WAIT_MICROS.fetch_add(
+                waited.as_secs() as usize * 1_000_000 + waited.subsec_nanos() as usize / 1000,
+                Ordering::Relaxed,
+            );
+            res
+        }
+    }
 }
 
-fn run_blobimport<In, Out>(
-    input: In,
-    output: Option<Out>,
+/// The fully resolved settings for an import run.
+#[derive(Debug)]
+struct BlobimportConfig {
+    input: PathBuf,
+    /// Where the repo is inside `input`, if it isn't at the top.
+    input_subdir: Option<PathBuf>,
+    input_format: InputFormat,
+    output: Option<PathBuf>,
     blobtype: BlobstoreType,
+    /// Where a local blobstore goes: the `blobs` directory of the output, unless
+    /// `--blobstore-uri` says otherwise.
+    blobstore_location: Option<PathBuf>,
     write_linknodes: bool,
-    logger: &Logger,
+    linknodes_shards: Option<usize>,
     postpone_compaction: bool,
     channel_size: usize,
+    /// How many blobstore operations the io thread runs at once.
+    blobstore_concurrency: usize,
     skip: Option<u64>,
     commits_limit: Option<u64>,
     max_blob_size: Option<usize>,
-) -> Result<()>
-where
-    In: Into<PathBuf>,
-    Out: Into<PathBuf> + Clone + std::fmt::Debug + Send + 'static,
-{
-    let input = input.into();
+    /// If set, changesets whose blobs are this size or bigger aren't written, the same cutoff as
+    /// `max_blob_size`'s. Unlike `max_blob_size`, this doesn't apply to file and tree blobs.
+    /// The heads and linknodes of a changeset left out are still written, and are logged.
+    max_changeset_size: Option<usize>,
+    key_prefix: Option<String>,
+    /// If set, zstd-compress blobs at this level.
+    compression_level: Option<i32>,
+    sample_rate: Option<f64>,
+    sample_seed: u64,
+    /// Fail, rather than warn, if not all of the heads were imported or if a local blobstore was
+    /// first imported from a different source.
+    strict: bool,
+    /// If set, only import file and tree blobs for paths under this one.
+    only_path: Option<MPath>,
+    key_scheme: KeyScheme,
+    heads_from_bookmarks: bool,
+    /// Don't record any heads, leaving a store with only the blobs.
+    no_heads: bool,
+    /// If set, write a child -> parents index of the imported changesets to this file.
+    parent_index: Option<PathBuf>,
+    /// If set, write the changesets left out by the filters to this file.
+    skipped_out: Option<PathBuf>,
+    /// If set and not empty, only write blobs with these keys.
+    key_allowlist: Option<HashSet<String>>,
+    /// If set, only import the changesets descended from this one.
+    since: Option<SinceChangeset>,
+    ordered: bool,
+    /// Read back every changeset written and check that it hashes to its id.
+    verify_hashes: bool,
+    /// Once everything is written, read the heads' changeset blobs back, to get them into any
+    /// cache in front of the blobstore.
+    warm_heads: bool,
+    /// Also read back the heads' root manifests.
+    warm_manifests: bool,
+    /// Copy the source repo's obsolescence markers into the blobstore.
+    import_obsmarkers: bool,
+    /// Log this many of the paths with the most manifest entry bytes at the end.
+    report_top_paths: Option<usize>,
+    /// Number of threads in the pool that conversion and the headstore and linknodes run on.
+    cpu_pool_size: usize,
+    /// If set, log each stage the blob with this key goes through.
+    trace_blob: Option<String>,
+    /// Write and flush everything sent before each changeset before the changeset itself, so
+    /// that a changeset's manifest is always in the blobstore before it is.
+    two_phase: bool,
+    /// Don't write blobs that are already in the blobstore.
+    skip_existing: bool,
+    /// If set, write the imported changesets to a Mercurial bundle here instead of to the
+    /// blobstore.
+    bundle_output: Option<PathBuf>,
+    /// With manifold, pause puts for the cool-down once this many in a row have failed.
+    manifold_breaker: Option<(usize, Duration)>,
+    /// If set, compare the blobs the import makes against this store instead of writing them.
+    compare_with: Option<uri::BlobstoreUri>,
+    /// Which repo this is, for telling imports apart in logs and the summary.
+    repo_name: Option<String>,
+}
+
+/// Where an incremental import starts from.
+#[derive(Debug, Eq, PartialEq)]
+enum SinceChangeset {
+    Hash(NodeHash),
+    /// The changeset the bookmark currently points at in the source repo.
+    Bookmark(String),
+}
+
+impl SinceChangeset {
+    fn resolve(&self, repo: &RevlogRepo) -> Result<NodeHash> {
+        match *self {
+            SinceChangeset::Hash(hash) => Ok(hash),
+            SinceChangeset::Bookmark(ref name) => {
+                match repo.bookmarks()?.get(&name.as_str()).wait()? {
+                    Some((hash, _version)) => Ok(hash),
+                    None => bail!("bookmark {} to import after is not in the repo", name),
+                }
+            }
+        }
+    }
+}
+
+/// The settings an import runs with, defaults included, for --print-config.
+fn describe_config(config: &BlobimportConfig) -> String {
+    format!("Effective config: {:?}", config)
+}
+
+/// Run an import, then print a `BLOBIMPORT_RESULT` summary whether or not it succeeded.
+fn run_blobimport(config: BlobimportConfig, logger: &Logger) -> Result<()> {
+    let start = Instant::now();
+    let repo_name = config.repo_name.clone();
+    // Tag everything the import logs with the repo, so that imports of different repos can be
+    // told apart when their logs end up in the same place.
+    let res = match repo_name {
+        Some(ref name) => import(config, &logger.new(o!("repo" => name.clone()))),
+        None => import(config, logger),
+    };
+    // This goes straight to stderr rather than through the logger, so that it's there whatever
+    // the log level.
+    let result = ImportResult::from_totals(repo_name, start.elapsed());
+    eprintln!("{}", result.summary_line());
+    res
+}
+
+fn import(config: BlobimportConfig, logger: &Logger) -> Result<()> {
+    let BlobimportConfig {
+        input,
+        input_subdir,
+        input_format,
+        output,
+        blobtype,
+        blobstore_location,
+        write_linknodes,
+        linknodes_shards,
+        postpone_compaction,
+        channel_size,
+        blobstore_concurrency,
+        skip,
+        commits_limit,
+        max_blob_size,
+        max_changeset_size,
+        key_prefix,
+        compression_level,
+        sample_rate,
+        sample_seed,
+        strict,
+        only_path,
+        key_scheme,
+        heads_from_bookmarks,
+        no_heads,
+        parent_index,
+        skipped_out,
+        key_allowlist,
+        since,
+        ordered,
+        verify_hashes,
+        warm_heads,
+        warm_manifests,
+        import_obsmarkers,
+        report_top_paths,
+        cpu_pool_size,
+        trace_blob,
+        two_phase,
+        skip_existing,
+        bundle_output,
+        manifold_breaker,
+        compare_with,
+        repo_name,
+    } = config;
+    let drops_blobs = max_blob_size.is_some() || key_allowlist.is_some() || skip_existing;
+    if bundle_output.is_some() && drops_blobs {
+        bail!(
+            "--bundle-output can't be used with --max-blob-size, --blob-key-allowlist or \
+             --skip-existing, which would leave revisions out of the bundle"
+        );
+    }
+    if compare_with.is_some() && skip_existing {
+        bail!(
+            "--compare-with can't be used with --skip-existing, which would leave out the blobs \
+             the comparison store already has"
+        );
+    }
+    let input = repo_dir(&input, input_subdir.as_ref().map(PathBuf::as_path))?;
     let core = Core::new()?;
-    let cpupool = Arc::new(CpuPool::new_num_cpus());
+    if let Some(ref name) = repo_name {
+        info!(logger, "Importing repo {}", name);
+    }
+    info!(logger, "Using a CPU pool of {} threads", cpu_pool_size);
+    let cpupool = Arc::new(CpuPool::new(cpu_pool_size));
+
+    // --compare-with only checks the blobs, so nothing else is written anywhere either.
+    let comparing = compare_with.is_some();
+    let headstore: Box<heads::Heads> = if no_heads || comparing {
+        Box::new(heads::NoopHeads::new())
+    } else {
+        info!(logger, "Opening headstore: {:?}", output);
+        open_headstore(output.clone(), &cpupool)?
+    };
 
-    info!(logger, "Opening headstore: {:?}", output);
-    let headstore = open_headstore(output.clone(), &cpupool)?;
+    if let Some(ref only_path) = only_path {
+        warn!(
+            logger,
+            "Only importing files and trees under {}, the resulting store will be a narrow repo \
+             with some blobs missing",
+            only_path
+        );
+    }
+
+    // With --bundle-output, the import goes into memory like it would into any other store, and
+    // the bundle is made from that once it's done.
+    let bundle_stores =
+        bundle_output.map(|path| (path, Memblob::new(), Arc::new(MemLinknodes::new())));
 
-    if let BlobstoreType::Manifold(ref bucket) = blobtype {
+    if let Some((ref path, _, _)) = bundle_stores {
+        info!(logger, "Writing a bundle to {}", path.display());
+    } else if let Some(ref uri) = compare_with {
+        info!(logger, "Comparing the blobs against {:?}, not writing them", uri);
+    } else if let BlobstoreType::Manifold(ref bucket) = blobtype {
         info!(logger, "Using ManifoldBlob with bucket: {:?}", bucket);
     } else {
-        info!(logger, "Opening blobstore: {:?}", output);
+        info!(logger, "Opening blobstore: {:?}", blobstore_location);
     }
 
+    // The breaker is only for manifold, where a struggling service is worth backing off from.
+    let manifold_breaker = match blobtype {
+        BlobstoreType::Manifold(_) => manifold_breaker.map(|(threshold, cooldown)| {
+            info!(
+                logger,
+                "Pausing manifold puts for {:?} after {} failures in a row", cooldown, threshold
+            );
+            Arc::new(breaker::CircuitBreaker::new(threshold, cooldown))
+        }),
+        _ => None,
+    };
+
+    // Local stores remember which repo they were first imported from.
+    let import_meta_location = match blobtype {
+        _ if bundle_stores.is_some() || comparing => None,
+        BlobstoreType::Files => blobstore_location.clone().map(|location| (location, "files")),
+        BlobstoreType::Rocksdb => blobstore_location.clone().map(|location| (location, "rocksdb")),
+        _ => None,
+    };
+
+    let trace = trace::BlobTrace::new(trace_blob, key_scheme.clone(), logger.clone());
     let (sender, recv) = sync_channel::<BlobstoreEntry>(channel_size);
+    // The keys of the blobs to warm, which the io thread waits for once it's written everything.
+    // It only gets them if the import succeeded, and nothing is warmed if the sender is dropped.
+    let (warm_sender, warm_recv) = channel::<Vec<String>>();
+    let warm_key_scheme = key_scheme.clone();
+    let bundle_key_scheme = key_scheme.clone();
+    let io_memblob = bundle_stores
+        .as_ref()
+        .map(|&(_, ref memblob, _)| memblob.clone());
+    let io_trace = trace.clone();
     // Separate thread that does all blobstore operations. Other worker threads send parsed revlog
     // data to this thread.
     let iothread = thread::Builder::new()
         .name("iothread".to_owned())
         .spawn({
-            let output = output.clone();
+            let logger = logger.clone();
             move || {
-                let receiverstream = stream::iter_ok::<_, ()>(recv);
                 let mut core = Core::new().expect("cannot create core in iothread");
-                let blobstore = open_blobstore(
-                    output,
-                    blobtype,
-                    &core.remote(),
-                    postpone_compaction,
-                    max_blob_size,
-                )?;
+                // The comparison store is read through the same prefix and compression as the
+                // store the import would have gone to.
+                let compare_blobstore = match compare_with {
+                    Some(uri) => {
+                        let other = open_blobstore(
+                            uri.path,
+                            uri.ty,
+                            &core.remote(),
+                            false,
+                            None,
+                            key_prefix.clone(),
+                            compression_level,
+                            None,
+                            false,
+                        )?;
+                        Some(compare::CompareBlobstore::new(other))
+                    }
+                    None => None,
+                };
+                let blobstore = match (io_memblob, compare_blobstore.clone()) {
+                    (Some(memblob), _) => memblob.arced(),
+                    (None, Some(compare_blobstore)) => filter_compared(
+                        compare_blobstore.arced(),
+                        max_blob_size,
+                        key_prefix.as_ref(),
+                        key_allowlist,
+                    ),
+                    (None, None) => open_blobstore(
+                        blobstore_location,
+                        blobtype,
+                        &core.remote(),
+                        postpone_compaction,
+                        max_blob_size,
+                        key_prefix,
+                        compression_level,
+                        key_allowlist,
+                        skip_existing,
+                    )?,
+                };
+                let blobstore = match manifold_breaker {
+                    Some(manifold_breaker) => {
+                        breaker::BreakerBlobstore::new(blobstore, manifold_breaker, logger.clone())
+                            .arced()
+                    }
+                    None => blobstore,
+                };
                 // Filter only manifest entries, because changeset entries should be unique
                 let mut inserted_manifest_entries = std::collections::HashSet::new();
-                let stream = receiverstream
-                    .map(move |sender_helper| match sender_helper {
-                        BlobstoreEntry::Changeset(bcs) => {
-                            bcs.save(blobstore.clone()).from_err().boxify()
-                        }
-                        BlobstoreEntry::ManifestEntry((key, value)) => {
-                            if inserted_manifest_entries.insert(key.clone()) {
-                                blobstore.put(key.clone(), value).boxify()
-                            } else {
-                                STATS::duplicates.add_value(1);
-                                Ok(()).into_future().boxify()
+                let flush_blobstore = blobstore.clone();
+                let verify_blobstore = blobstore.clone();
+                let warm_blobstore = blobstore.clone();
+                let verify_key_scheme = key_scheme.clone();
+                let path_sizes = Arc::new(Mutex::new(toppaths::PathSizes::new()));
+                let report_path_sizes = path_sizes.clone();
+                let changeset_logger = logger.clone();
+                let received_trace = io_trace.clone();
+                let received = recv.into_iter()
+                    .inspect(move |entry| received_trace.entry(entry, "received"));
+                let phases_blobstore = blobstore.clone();
+                // Each entry written yields the changeset it was, if it was one.
+                let write_entry = move |sender_helper| match sender_helper {
+                    BlobstoreEntry::Changeset(bcs) => {
+                        let csid = *bcs.nodeid();
+                        let key = key_scheme.changeset_key(&csid);
+                        let trace = io_trace.clone();
+                        let save = match max_changeset_size {
+                            Some(max_changeset_size) => {
+                                let oversized = Arc::new(AtomicBool::new(false));
+                                let limited = ChangesetLimitBlobstore {
+                                    blobstore: blobstore.clone(),
+                                    max_changeset_size,
+                                    oversized: oversized.clone(),
+                                };
+                                let logger = changeset_logger.clone();
+                                bcs.save_with_key(limited, key)
+                                    .from_err()
+                                    .map(move |()| {
+                                        if !oversized.load(Ordering::Relaxed) {
+                                            return Some(csid);
+                                        }
+                                        STATS::oversized_changesets.add_value(1);
+                                        OVERSIZED_CHANGESETS.fetch_add(1, Ordering::Relaxed);
+                                        warn!(
+                                            logger,
+                                            "Skipped changeset {}, which is over \
+                                             --max-changeset-size. Any heads and linknodes \
+                                             pointing at it are still written",
+                                            csid
+                                        );
+                                        None
+                                    })
+                                    .boxify()
                             }
-                        }
-                    })
-                    .map_err(|_| failure::err_msg("failure happened").into())
-                    .buffer_unordered(channel_size)
-                    .then(move |res| {
-                        if res.is_err() {
-                            STATS::failures.add_value(1);
+                            None => bcs.save_with_key(blobstore.clone(), key)
+                                .from_err()
+                                .map(move |()| Some(csid))
+                                .boxify(),
+                        };
+                        save.map(move |written| {
+                            let stage = match written {
+                                Some(_) => "written",
+                                None => "skipped, over --max-changeset-size",
+                            };
+                            trace.changeset(&csid, stage);
+                            written
+                        }).boxify()
+                    }
+                    BlobstoreEntry::ManifestEntry((ref key, _), ref path)
+                        if !path_wanted(path, only_path.as_ref()) =>
+                    {
+                        STATS::path_filtered.add_value(1);
+                        io_trace.key(key, "skipped, not under --only-path");
+                        Ok(None).into_future().boxify()
+                    }
+                    BlobstoreEntry::ManifestEntry((key, value), path) => {
+                        if inserted_manifest_entries.insert(key.clone()) {
+                            if report_top_paths.is_some() {
+                                path_sizes
+                                    .lock()
+                                    .expect("lock poisoned")
+                                    .add(&path, value.len());
+                            }
+                            let trace = io_trace.clone();
+                            blobstore
+                                .put(key.clone(), value)
+                                .map(move |()| {
+                                    trace.key(&key, "written");
+                                    None
+                                })
+                                .boxify()
                         } else {
-                            STATS::successes.add_value(1);
+                            STATS::duplicates.add_value(1);
+                            TOTALS.duplicates.fetch_add(1, Ordering::Relaxed);
+                            io_trace.key(&key, "skipped, already written");
+                            Ok(None).into_future().boxify()
                         }
-                        res
-                    });
-                core.run(stream.for_each(|_| Ok(())))
+                    }
+                    BlobstoreEntry::Obsmarker((key, value)) => {
+                        let trace = io_trace.clone();
+                        blobstore
+                            .put(key.clone(), value)
+                            .map(move |()| {
+                                trace.key(&key, "written");
+                                None
+                            })
+                            .boxify()
+                    }
+                };
+                let writes = if two_phase {
+                    twophase::write_in_phases(
+                        received,
+                        phases_blobstore,
+                        write_entry,
+                        blobstore_concurrency,
+                    )
+                } else {
+                    stream::iter_ok::<_, ()>(received)
+                        .map(write_entry)
+                        .map_err(|_| failure::err_msg("failure happened").into())
+                        .buffer_unordered(blobstore_concurrency)
+                        .boxify()
+                };
+                let stream = writes.then(move |res| {
+                    if res.is_err() {
+                        STATS::failures.add_value(1);
+                        TOTALS.failures.fetch_add(1, Ordering::Relaxed);
+                    } else {
+                        STATS::successes.add_value(1);
+                        TOTALS.successes.fetch_add(1, Ordering::Relaxed);
+                    }
+                    res
+                });
+                let written = stream.fold(Vec::new(), move |mut written, csid| {
+                    // Only keep the changesets around if they're going to be verified.
+                    if verify_hashes {
+                        written.extend(csid);
+                    }
+                    Ok::<_, Error>(written)
+                });
+                // Everything has been written, make sure it's durable before reporting success.
+                let csids = core.run(
+                    written.and_then(move |written| flush_blobstore.flush().map(|()| written)),
+                )?;
+
+                if let Some(compare_blobstore) = compare_blobstore {
+                    let differences = core.run(compare_blobstore.differences())?;
+                    let stdout = io::stdout();
+                    write_differences(&differences, stdout.lock())?;
+                    if !differences.is_empty() {
+                        bail!("{} keys differ from the comparison store", differences.len());
+                    }
+                    info!(logger, "Every blob matches the comparison store");
+                }
+
+                if verify_hashes {
+                    info!(logger, "Verifying the hashes of {} changesets", csids.len());
+                    let check = core.run(check::verify_changeset_hashes(
+                        verify_blobstore,
+                        csids,
+                        verify_key_scheme,
+                    ))?;
+                    check::report_hash_check(&check, &logger)?;
+                }
+
+                if let Some(n) = report_top_paths {
+                    let top = report_path_sizes.lock().expect("lock poisoned").top(n);
+                    info!(logger, "Top {} paths by manifest entry bytes:", top.len());
+                    for (path, bytes) in top {
+                        info!(logger, "  {}: {} bytes", path, bytes);
+                    }
+                }
+
+                if let Ok(keys) = warm_recv.recv() {
+                    let total = keys.len();
+                    let warmed = core.run(warm::warm_blobs(warm_blobstore, keys))?;
+                    info!(logger, "Warmed {} of {} head blobs", warmed, total);
+                }
+                Ok(())
             }
         })
         .expect("cannot start iothread");
 
-    let repo = open_repo(&input)?;
+    let parent_index = match parent_index {
+        Some(path) => {
+            info!(logger, "Writing parent index to {}", path.display());
+            Some(Arc::new(parentindex::ParentIndex::create(path)?))
+        }
+        None => None,
+    };
+    let skipped_out = match skipped_out {
+        Some(path) => {
+            info!(logger, "Writing skipped changesets to {}", path.display());
+            Some(Arc::new(skippedout::SkippedOut::create(path)?))
+        }
+        None => None,
+    };
+
+    let repo = open_repo(&input, input_format)?;
+    if let Some((location, blobstore)) = import_meta_location {
+        let meta = importmeta::ImportMeta {
+            source: fs::canonicalize(&input).unwrap_or_else(|_| input.clone()),
+            started: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or(0),
+            blobstore: blobstore.to_string(),
+        };
+        if let importmeta::MetaCheck::Mismatched(recorded) =
+            importmeta::check_import_meta(&location, &meta)?
+        {
+            let msg = format!(
+                "blobstore {} was first imported from {}, not {}",
+                location.display(),
+                recorded.source.display(),
+                meta.source.display()
+            );
+            if strict {
+                bail!("{}", msg);
+            }
+            warn!(logger, "{}", msg);
+        }
+    }
+    let total_changesets = repo.changeset_count();
+    if import_obsmarkers {
+        // Markers can refer to changesets the repo doesn't have, so they're copied even if it's
+        // empty.
+        let count = obsmarkers::send_obsmarkers(&repo, &sender, &trace)?;
+        info!(logger, "Importing {} obsolescence markers", count);
+    }
+    if total_changesets == 0 {
+        info!(logger, "repository has no changesets, nothing to import");
+        // The io thread still creates the blobstore, so that the output is a valid empty store.
+        drop(sender);
+        drop(warm_sender);
+        iothread.join().expect("failed to join io thread")?;
+        if let Some((path, memblob, linknodes)) = bundle_stores {
+            finish_bundle(&path, memblob, &linknodes, bundle_key_scheme, logger)?;
+        }
+        return Ok(());
+    }
+    let since = match since {
+        Some(since) => Some(since.resolve(&repo)?),
+        None => None,
+    };
 
-    info!(logger, "Converting: {}", input.display());
+    info!(
+        logger,
+        "Converting: {} ({} changesets)",
+        input.display(),
+        total_changesets
+    );
+    let warm_repo = if warm_heads {
+        Some(repo.clone())
+    } else {
+        None
+    };
     let convert_context = convert::ConvertContext {
         repo,
         sender,
@@ -188,31 +918,151 @@ where
         core,
         cpupool: cpupool.clone(),
         logger: logger.clone(),
+        total_changesets,
         skip: skip,
         commits_limit: commits_limit,
+        sample_rate,
+        sample_seed,
+        strict,
+        write_heads: !no_heads && !comparing,
+        heads_from_bookmarks,
+        parent_index,
+        since,
+        ordered,
+        skipped_out,
+        trace,
+        two_phase,
     };
-    let res = if write_linknodes {
+    let res = if let Some((_, _, ref linknodes)) = bundle_stores {
+        convert_context.convert(linknodes.clone())
+    } else if comparing {
+        info!(logger, "Comparing the blobs only, not writing linknodes");
+        convert_context.convert(NoopLinknodes::new())
+    } else if write_linknodes {
         info!(logger, "Opening linknodes store: {:?}", output);
         let output = output.expect("output path is not provided");
-        let output = output.into();
-        let linknodes_store = open_linknodes_store(&output, &cpupool)?;
-        convert_context.convert(linknodes_store)
+        match linknodes_shards {
+            Some(shards) => {
+                info!(logger, "Sharding linknodes {} ways", shards);
+                let linknodes_store = open_sharded_linknodes_store(&output, shards, &cpupool)?;
+                convert_context.convert(linknodes_store)
+            }
+            None => {
+                let linknodes_store = open_linknodes_store(&output, &cpupool)?;
+                convert_context.convert(linknodes_store)
+            }
+        }
     } else {
         info!(logger, "--linknodes not specified, not writing linknodes");
         convert_context.convert(NoopLinknodes::new())
     };
+    let res = res.and_then(|()| {
+        if let Some(repo) = warm_repo {
+            let keys = warm::head_keys(&repo, &warm_key_scheme, warm_manifests)?;
+            info!(logger, "Warming {} head blobs", keys.len());
+            // The io thread only goes away early if it failed, which joining it reports.
+            let _ = warm_sender.send(keys);
+        }
+        Ok(())
+    });
+    drop(warm_sender);
     iothread.join().expect("failed to join io thread")?;
+    let res = res.and_then(|()| match bundle_stores {
+        Some((path, memblob, linknodes)) => {
+            finish_bundle(&path, memblob, &linknodes, bundle_key_scheme, logger)
+        }
+        None => Ok(()),
+    });
+    info!(
+        logger,
+        "io thread backpressure: senders waited {} times, for {}ms in total",
+        CHANNEL_FULL_WAITS.load(Ordering::Relaxed),
+        CHANNEL_FULL_WAIT_MICROS.load(Ordering::Relaxed) / 1000
+    );
+    let allowlist_skipped = ALLOWLIST_SKIPPED.load(Ordering::Relaxed);
+    if allowlist_skipped > 0 {
+        info!(
+            logger,
+            "skipped {} blobs not in the blob key allowlist", allowlist_skipped
+        );
+    }
+    let existing_skipped = EXISTING_SKIPPED.load(Ordering::Relaxed);
+    if existing_skipped > 0 {
+        info!(
+            logger,
+            "skipped {} blobs already in the blobstore", existing_skipped
+        );
+    }
+    let oversized_changesets = OVERSIZED_CHANGESETS.load(Ordering::Relaxed);
+    if oversized_changesets > 0 {
+        warn!(
+            logger,
+            "skipped {} changesets over --max-changeset-size, which heads and linknodes may \
+             still point at",
+            oversized_changesets
+        );
+    }
     res
 }
 
-fn open_repo<P: Into<PathBuf>>(input: P) -> Result<RevlogRepo> {
+/// Write the --bundle-output bundle to `path`, from what the import put in `blobstore` and
+/// `linknodes`.
+fn finish_bundle<L: Linknodes>(
+    path: &Path,
+    blobstore: Memblob,
+    linknodes: &L,
+    key_scheme: KeyScheme,
+    logger: &Logger,
+) -> Result<()> {
+    let mut core = Core::new()?;
+    let count = bundle::write_bundle(blobstore, linknodes, key_scheme, path, &mut core)?;
+    info!(logger, "Wrote {} changesets to {}", count, path.display());
+    Ok(())
+}
+
+/// The directory the repo is in: `input` itself, or `subdir` of it for --input-subdir.
+fn repo_dir(input: &Path, subdir: Option<&Path>) -> Result<PathBuf> {
+    let subdir = match subdir {
+        Some(subdir) => subdir,
+        None => return Ok(input.to_path_buf()),
+    };
+    if subdir.is_absolute() {
+        bail!("input subdir {} must be relative to the input", subdir.display());
+    }
+    let dir = input.join(subdir);
+    if !dir.is_dir() {
+        bail!("input subdir {} isn't a dir in {}", subdir.display(), input.display());
+    }
+    Ok(dir)
+}
+
+fn open_repo<P: Into<PathBuf>>(input: P, format: InputFormat) -> Result<RevlogRepo> {
     let mut input = input.into();
-    if !input.exists() || !input.is_dir() {
-        bail!("input {} doesn't exist or isn't a dir", input.display());
+    if !input.exists() {
+        bail!("input {} doesn't exist", input.display());
+    }
+    if !input.is_dir() {
+        bail!("input {} isn't a dir", input.display());
     }
     input.push(".hg");
 
-    let revlog = RevlogRepo::open(input)?;
+    let store = input.join("store");
+    if !store.exists() {
+        bail!(
+            "input {} is not a Mercurial repo: {} is missing",
+            input.display(),
+            store.display()
+        );
+    }
+    fs::read_dir(&store)
+        .map_err(Error::from)
+        .with_context(|_| format!("{} is present but unreadable", store.display()))?;
+
+    let revlog = RevlogRepo::open(&input)
+        .with_context(|_| format!("opening revlog repo at {}", input.display()))?;
+    format
+        .check(&revlog)
+        .with_context(|_| format!("opening revlog repo at {}", input.display()))?;
 
     Ok(revlog)
 }
@@ -233,6 +1083,53 @@ fn open_headstore<P: Into<PathBuf>>(
     }
 }
 
+/// Write each of the heads in `headstore` to `out` on a line of its own, as they're listed, for
+/// --list-heads. Returns the number of heads.
+fn write_heads<H, W>(headstore: &H, mut out: W) -> Result<usize>
+where
+    H: heads::Heads,
+    W: Write,
+{
+    let mut count = 0;
+    for head in headstore.heads().wait() {
+        writeln!(out, "{}", head?)?;
+        count += 1;
+    }
+    Ok(count)
+}
+
+/// Write the keys whose blobs differ between `blobstore` and `other` to `out`, one per line with
+/// how they differ, for --scrub. Every key in either blobstore is checked. Returns the number of
+/// keys written.
+fn write_scrub<W: Write>(
+    blobstore: BBlobstore,
+    other: BBlobstore,
+    core: &mut Core,
+    mut out: W,
+) -> Result<usize> {
+    let keys = blobstore.keys().chain(other.keys()).boxify();
+    let scrub = blobstore::scrub(blobstore, other, keys, SCRUB_BATCH_SIZE);
+    let differences = core.run(scrub.collect())?;
+    write_differences(&differences, &mut out)?;
+    Ok(differences.len())
+}
+
+/// Write each of `differences` to `out` on a line of its own: how the key differs, then the key.
+fn write_differences<W: Write>(
+    differences: &[(String, ScrubDifference)],
+    mut out: W,
+) -> Result<()> {
+    for &(ref key, difference) in differences {
+        let difference = match difference {
+            ScrubDifference::MissingInA => "missing-in-a",
+            ScrubDifference::MissingInB => "missing-in-b",
+            ScrubDifference::Differs => "differs",
+        };
+        writeln!(out, "{} {}", difference, key)?;
+    }
+    Ok(())
+}
+
 fn open_linknodes_store<P: Into<PathBuf>>(path: P, pool: &Arc<CpuPool>) -> Result<FileLinknodes> {
     let mut linknodes_path = path.into();
     linknodes_path.push("linknodes");
@@ -240,31 +1137,105 @@ fn open_linknodes_store<P: Into<PathBuf>>(path: P, pool: &Arc<CpuPool>) -> Resul
     Ok(linknodes_store)
 }
 
+fn open_sharded_linknodes_store<P: Into<PathBuf>>(
+    path: P,
+    shards: usize,
+    pool: &Arc<CpuPool>,
+) -> Result<ShardedFileLinknodes> {
+    let mut linknodes_path = path.into();
+    linknodes_path.push("linknodes");
+    let linknodes_store =
+        ShardedFileLinknodes::create_with_pool(linknodes_path, shards, pool.clone())?;
+    Ok(linknodes_store)
+}
+
+/// Open the blobstore for an import. `location` is where a local store goes, which is usually the
+/// `blobs` directory of the output.
 fn open_blobstore<P: Into<PathBuf>>(
-    output: Option<P>,
+    location: Option<P>,
     ty: BlobstoreType,
     remote: &Remote,
     postpone_compaction: bool,
     max_blob_size: Option<usize>,
+    key_prefix: Option<String>,
+    compression_level: Option<i32>,
+    key_allowlist: Option<HashSet<String>>,
+    skip_existing: bool,
+) -> Result<BBlobstore> {
+    let location: Option<PathBuf> = location.map(Into::into);
+    let location = location.as_ref().map(PathBuf::as_path);
+    let blobstore = open_backend(location, ty, remote, postpone_compaction)?;
+
+    // Compression goes right on top of the backend, so that everything else deals in the
+    // uncompressed blobs.
+    let blobstore = if let Some(level) = compression_level {
+        CompressingBlobstore::with_level(blobstore, level).arced()
+    } else {
+        blobstore
+    };
+
+    // The allowlist sits under the prefix, so that it's checked against the keys as they end up in
+    // the store.
+    let blobstore = match key_allowlist {
+        Some(allowlist) => Arc::new(allowlist::AllowlistBlobstore::new(blobstore, allowlist)),
+        None => blobstore,
+    };
+
+    // Checking for existing blobs also goes under the prefix, for the same reason.
+    let blobstore = if skip_existing {
+        skipexisting::SkipExistingBlobstore::new(blobstore).arced()
+    } else {
+        blobstore
+    };
+
+    let blobstore = if let Some(key_prefix) = key_prefix {
+        PrefixBlobstore::new(blobstore, key_prefix).arced()
+    } else {
+        blobstore
+    };
+
+    let blobstore = if let Some(max_blob_size) = max_blob_size {
+        Arc::new(LimitedBlobstore {
+            blobstore,
+            max_blob_size,
+        })
+    } else {
+        blobstore
+    };
+
+    _assert_clone(&blobstore);
+    _assert_send(&blobstore);
+    _assert_static(&blobstore);
+    _assert_blobstore(&blobstore);
+
+    Ok(blobstore)
+}
+
+/// Open the backend blobstore, with any local store in the `location` directory.
+fn open_backend(
+    location: Option<&Path>,
+    ty: BlobstoreType,
+    remote: &Remote,
+    postpone_compaction: bool,
 ) -> Result<BBlobstore> {
     let blobstore: BBlobstore = match ty {
         BlobstoreType::Files => {
-            let output = output.expect("output path is not specified");
-            let mut output = output.into();
-            output.push("blobs");
-            Fileblob::create(output)
+            let location = location.ok_or_else(|| {
+                failure::err_msg("--blobstore files needs OUTPUT or --blobstore-uri")
+            })?;
+            Fileblob::create(location)
                 .map_err(Error::from)
                 .context("Failed to open file blob store")?
                 .arced()
         }
         BlobstoreType::Rocksdb => {
-            let output = output.expect("output path is not specified");
-            let mut output = output.into();
-            output.push("blobs");
+            let location = location.ok_or_else(|| {
+                failure::err_msg("--blobstore rocksdb needs OUTPUT or --blobstore-uri")
+            })?;
             let options = rocksdb::Options::new()
                 .create_if_missing(true)
                 .disable_auto_compaction(postpone_compaction);
-            Rocksblob::open_with_options(output, options)
+            Rocksblob::open_with_options(location, options)
                 .map_err(Error::from)
                 .context("Failed to open rocksdb blob store")?
                 .arced()
@@ -273,26 +1244,75 @@ fn open_blobstore<P: Into<PathBuf>>(
             let mb: ManifoldBlob = ManifoldBlob::new_may_panic(bucket, remote);
             mb.arced()
         }
+        BlobstoreType::Tiered {
+            small,
+            large,
+            threshold,
+        } => {
+            // The small tier is where a plain store would be, the large one goes alongside it.
+            let large_location = location.map(|location| {
+                let mut large_location = OsString::from(location);
+                large_location.push("-large");
+                PathBuf::from(large_location)
+            });
+            let large_location = large_location.as_ref().map(PathBuf::as_path);
+            let small = open_backend(location, *small, remote, postpone_compaction)?;
+            let large = open_backend(large_location, *large, remote, postpone_compaction)?;
+            TieredBlobstore::new(small, large, threshold).arced()
+        }
     };
+    Ok(blobstore)
+}
 
-    let blobstore = if let Some(max_blob_size) = max_blob_size {
-        Arc::new(LimitedBlobstore {
-            blobstore,
-            max_blob_size,
-        })
-    } else {
-        blobstore
+/// Whether blobs for the entry at `path` should be imported given the `--only-path` prefix. Paths
+/// count as under the prefix if they are the prefix itself or inside it.
+fn path_wanted(path: &RepoPath, only_path: Option<&MPath>) -> bool {
+    let prefix = match only_path {
+        Some(prefix) => prefix,
+        None => return true,
     };
+    match path.mpath() {
+        Some(mpath) => {
+            let mut elements = mpath.into_iter();
+            prefix.into_iter().all(|prefix_elem| elements.next() == Some(prefix_elem))
+        }
+        None => false,
+    }
+}
 
-    _assert_clone(&blobstore);
-    _assert_send(&blobstore);
-    _assert_static(&blobstore);
-    _assert_blobstore(&blobstore);
-
-    Ok(blobstore)
+/// Apply the filters of `open_blobstore` that leave blobs out to `compare`, for --compare-with, so
+/// that the blobs an import with the same flags wouldn't have written aren't looked for. The
+/// prefix and compression are already on the comparison store underneath, so unlike with
+/// `open_blobstore` the allowlist sees the keys without the prefix, and its own keys are stripped
+/// of it to match.
+fn filter_compared(
+    compare: BBlobstore,
+    max_blob_size: Option<usize>,
+    key_prefix: Option<&String>,
+    key_allowlist: Option<HashSet<String>>,
+) -> BBlobstore {
+    let blobstore: BBlobstore = match key_allowlist {
+        Some(allowlist) => {
+            let prefix = key_prefix.map(String::as_str).unwrap_or("");
+            let allowlist = allowlist
+                .into_iter()
+                .filter(|key| key.starts_with(prefix))
+                .map(|key| key[prefix.len()..].to_string())
+                .collect();
+            Arc::new(allowlist::AllowlistBlobstore::new(compare, allowlist))
+        }
+        None => compare,
+    };
+    match max_blob_size {
+        Some(max_blob_size) => Arc::new(LimitedBlobstore {
+            blobstore,
+            max_blob_size,
+        }),
+        None => blobstore,
+    }
 }
 
-/// Blobstore that doesn't inserts blobs that are bigger than max_blob_size
+/// Blobstore that doesn't insert blobs of `max_blob_size` bytes or more
 struct LimitedBlobstore {
     blobstore: BBlobstore,
     max_blob_size: usize,
@@ -313,60 +1333,510 @@ impl Blobstore for LimitedBlobstore {
             self.blobstore.put(key, val)
         }
     }
-}
 
-fn setup_app<'a, 'b>() -> App<'a, 'b> {
-    App::new("revlog to blob importer")
-        .version("0.0.0")
-        .about("make blobs")
-        .args_from_usage(
-            r#"
-            <INPUT>                  'input revlog repo'
-            [OUTPUT]                 'output blobstore RepoCtx'
+    fn get_batch(&self, keys: Vec<String>) -> BoxFuture<HashMap<String, Option<Bytes>>, Error> {
+        self.blobstore.get_batch(keys)
+    }
 
-            -p, --port [PORT]        'if provided the thrift server will start on this port'
+    fn exists(&self, key: String) -> BoxFuture<bool, Error> {
+        self.blobstore.exists(key)
+    }
 
-            --postpone-compaction    '(rocksdb only) postpone auto compaction while importing'
+    fn put_if_absent(&self, key: String, val: Bytes) -> BoxFuture<bool, Error> {
+        if val.len() >= self.max_blob_size {
+            Ok(false).into_future().boxify()
+        } else {
+            self.blobstore.put_if_absent(key, val)
+        }
+    }
 
-            -d, --debug              'print debug level output'
-            --linknodes              'also generate linknodes'
-            --channel-size [SIZE]    'channel size between worker and io threads. Default: 1000'
-            --skip [SKIP]            'skips commits from the beginning'
-            --commits-limit [LIMIT]  'import only LIMIT first commits from revlog repo'
-            --max-blob-size [LIMIT]  'max size of the blob to be inserted'
-        "#,
-        )
-        .arg(
-            Arg::with_name("blobstore")
-                .long("blobstore")
-                .short("B")
-                .takes_value(true)
-                .possible_values(&["files", "rocksdb", "manifold"])
-                .required(true)
-                .help("blobstore type"),
-        )
-        .arg(
-            Arg::with_name("bucket")
-                .long("bucket")
-                .takes_value(true)
-                .help("bucket to use for manifold blobstore"),
-        )
-}
+    fn put_stream(
+        &self,
+        key: String,
+        body: BoxStream<Bytes, Error>,
+        len_hint: Option<usize>,
+    ) -> BoxFuture<(), Error> {
+        match len_hint {
+            Some(len) if len >= self.max_blob_size => Ok(()).into_future().boxify(),
+            Some(_) => self.blobstore.put_stream(key, body, len_hint),
+            // The size is only known once the whole blob is in, so it's collected and then put
+            // like any other.
+            None => {
+                let blobstore = self.blobstore.clone();
+                let max_blob_size = self.max_blob_size;
+                collect_body(body, len_hint)
+                    .and_then(move |val| {
+                        if val.len() >= max_blob_size {
+                            Ok(()).into_future().boxify()
+                        } else {
+                            blobstore.put(key, val)
+                        }
+                    })
+                    .boxify()
+            }
+        }
+    }
 
-fn start_thrift_service<'a>(logger: &Logger, matches: &ArgMatches<'a>) -> Result<()> {
-    let port = match matches.value_of("port") {
-        None => return Ok(()),
-        Some(port) => port.parse().expect("Failed to parse port as number"),
-    };
+    // A copy is no bigger than the blob it's copied from, which is already in.
+    fn copy(&self, src_key: String, dst_key: String) -> BoxFuture<(), Error> {
+        self.blobstore.copy(src_key, dst_key)
+    }
 
-    info!(logger, "Initializing thrift server on port {}", port);
+    fn delete(&self, key: String) -> BoxFuture<(), Error> {
+        self.blobstore.delete(key)
+    }
 
-    thread::Builder::new()
-        .name("thrift_service".to_owned())
-        .spawn(move || {
-            services::run_service_framework(
-                "mononoke_server",
-                port,
+    fn keys(&self) -> BoxStream<String, Error> {
+        self.blobstore.keys()
+    }
+
+    fn flush(&self) -> BoxFuture<(), Error> {
+        self.blobstore.flush()
+    }
+}
+
+/// Blobstore for saving a single changeset, which leaves the changeset's blob out if it's
+/// `max_changeset_size` bytes or more, and records that in `oversized`. That's the same cutoff as
+/// `LimitedBlobstore`'s, so that the two size flags mean the same thing.
+struct ChangesetLimitBlobstore {
+    blobstore: BBlobstore,
+    max_changeset_size: usize,
+    oversized: Arc<AtomicBool>,
+}
+
+impl Blobstore for ChangesetLimitBlobstore {
+    type GetBlob = BoxFuture<Option<Bytes>, Error>;
+    type PutBlob = BoxFuture<(), Error>;
+
+    fn get(&self, key: String) -> Self::GetBlob {
+        self.blobstore.get(key)
+    }
+
+    fn put(&self, key: String, val: Bytes) -> Self::PutBlob {
+        if val.len() >= self.max_changeset_size {
+            self.oversized.store(true, Ordering::Relaxed);
+            Ok(()).into_future().boxify()
+        } else {
+            self.blobstore.put(key, val)
+        }
+    }
+}
+
+/// Pick the manifold bucket to use. An explicit `--bucket` wins, then the
+/// `MONONOKE_MANIFOLD_BUCKET` environment variable, then the compiled-in default. Also returns a
+/// description of where the bucket came from, for logging.
+fn resolve_bucket(flag: Option<&str>, env: Option<String>) -> (String, &'static str) {
+    match (flag, env) {
+        (Some(bucket), _) => (bucket.to_string(), "--bucket"),
+        (None, Some(bucket)) => (bucket, MANIFOLD_BUCKET_ENV),
+        (None, None) => (DEFAULT_MANIFOLD_BUCKET.to_string(), "default"),
+    }
+}
+
+/// Pick a channel size so that a full channel of large entries fits in a fraction of the available
+/// memory.
+fn channel_size_for_memory(available: Option<u64>) -> usize {
+    match available {
+        Some(available) => {
+            let size = available / CHANNEL_MEMORY_FRACTION / CHANNEL_ENTRY_SIZE;
+            cmp::max(MIN_CHANNEL_SIZE, cmp::min(MAX_CHANNEL_SIZE, size as usize))
+        }
+        None => DEFAULT_CHANNEL_SIZE,
+    }
+}
+
+/// The default channel size for the kind of blobstore being imported into.
+fn channel_size_for_blobstore(blobtype: &BlobstoreType) -> usize {
+    match *blobtype {
+        BlobstoreType::Files => FILES_CHANNEL_SIZE,
+        BlobstoreType::Rocksdb => ROCKSDB_CHANNEL_SIZE,
+        BlobstoreType::Manifold(_) => MANIFOLD_CHANNEL_SIZE,
+        // The deeper of the two tiers' buffers.
+        BlobstoreType::Tiered {
+            ref small,
+            ref large,
+            ..
+        } => cmp::max(
+            channel_size_for_blobstore(small),
+            channel_size_for_blobstore(large),
+        ),
+    }
+}
+
+/// The channel size to use when --channel-size isn't given: the blobstore type's default, lowered
+/// to what `channel_size_for_memory` allows if the available memory is known. The memory limit
+/// only ever lowers the size, so that a deep manifold buffer doesn't run a small machine out of
+/// memory.
+fn default_channel_size(blobtype: &BlobstoreType, available: Option<u64>) -> usize {
+    let size = channel_size_for_blobstore(blobtype);
+    match available {
+        Some(_) => cmp::min(size, channel_size_for_memory(available)),
+        None => size,
+    }
+}
+
+/// The number of blobstore operations to run at once. Unless --blobstore-concurrency says
+/// otherwise, there can be as many as there are entries in the channel.
+fn blobstore_concurrency(matches: &ArgMatches, channel_size: usize) -> usize {
+    match matches.value_of("blobstore-concurrency") {
+        Some(n) => {
+            let n: usize = n.parse()
+                .expect("blobstore-concurrency must be positive integer");
+            if n == 0 {
+                panic!("blobstore-concurrency must be positive integer");
+            }
+            n
+        }
+        None => channel_size,
+    }
+}
+
+/// --repo-name ends up in blob keys and in the space-separated summary line, so it is kept to
+/// letters, digits, `.`, `_` and `-`.
+fn validate_repo_name(name: String) -> ::std::result::Result<(), String> {
+    let valid = |c| match c {
+        'a'...'z' | 'A'...'Z' | '0'...'9' | '.' | '_' | '-' => true,
+        _ => false,
+    };
+    if name.is_empty() || !name.chars().all(valid) {
+        Err(format!(
+            "repo name {:?} must be made of letters, digits, '.', '_' and '-'",
+            name
+        ))
+    } else {
+        Ok(())
+    }
+}
+
+/// The prefix for blob keys: --key-prefix, followed by the repo name and a `.` with
+/// --prefix-keys-with-repo.
+fn key_prefix(matches: &ArgMatches) -> Option<String> {
+    let prefix = matches.value_of("key-prefix");
+    match matches.value_of("repo-name") {
+        Some(repo) if matches.is_present("prefix-keys-with-repo") => {
+            Some(format!("{}{}.", prefix.unwrap_or(""), repo))
+        }
+        _ => prefix.map(|prefix| prefix.to_string()),
+    }
+}
+
+/// The number of threads asked for with --cpu-pool-size, or one per CPU.
+fn cpu_pool_size(matches: &ArgMatches) -> usize {
+    match matches.value_of("cpu-pool-size") {
+        Some(size) => {
+            let size: usize = size.parse().expect("cpu-pool-size must be positive integer");
+            if size == 0 {
+                panic!("cpu-pool-size must be positive integer");
+            }
+            size
+        }
+        None => num_cpus::get(),
+    }
+}
+
+/// The memory available on this machine in bytes, if it can be found out.
+fn available_memory() -> Option<u64> {
+    let mut contents = String::new();
+    match fs::File::open("/proc/meminfo").and_then(|mut file| file.read_to_string(&mut contents)) {
+        Ok(_) => parse_meminfo(&contents),
+        Err(_) => None,
+    }
+}
+
+fn parse_meminfo(contents: &str) -> Option<u64> {
+    // The line looks like "MemAvailable:   12345678 kB".
+    contents
+        .lines()
+        .find(|line| line.starts_with("MemAvailable:"))
+        .and_then(|line| line.split_whitespace().nth(1))
+        .and_then(|kb| kb.parse::<u64>().ok())
+        .map(|kb| kb * 1024)
+}
+
+fn setup_app<'a, 'b>() -> App<'a, 'b> {
+    App::new("revlog to blob importer")
+        .version("0.0.0")
+        .about("make blobs")
+        // The positional arguments are declared here, rather than in the usage string, as the
+        // INPUT help doesn't fit there.
+        .arg(
+            Arg::with_name("INPUT")
+                .index(1)
+                .help(
+                    "input revlog repo. Not used by --selftest, --check-linknodes, --list-heads \
+                     or --scrub",
+                ),
+        )
+        .arg(
+            Arg::with_name("OUTPUT")
+                .index(2)
+                .help("output blobstore RepoCtx"),
+        )
+        .args_from_usage(
+            r#"
+            --selftest               'round-trip some blobs through the blobstore and exit'
+            --check-linknodes        'check that linknode changesets are in the blobstore and exit'
+            --bookmarks-diff         'print how the bookmarks in INPUT differ from OUTPUT and exit'
+            --list-heads             'print the heads recorded in OUTPUT and exit'
+
+            -p, --port [PORT]        'if provided the thrift server will start on this port'
+
+            --input-format [FORMAT]  'auto, revlogv0 or revlogv1, to override detection'
+            --input-subdir [REL]     'import the repo in this subdirectory of INPUT'
+
+            --postpone-compaction    '(rocksdb only) postpone auto compaction while importing'
+
+            -d, --debug              'print debug level output'
+            --linknodes              'also generate linknodes'
+            --linknodes-shards [N]   'split linknodes across N stores by top-level directory'
+            --channel-size [SIZE]    'channel size between worker and io threads. Default: auto'
+            --skip [SKIP]            'skips commits from the beginning'
+            --commits-limit [LIMIT]  'import only LIMIT first commits from revlog repo'
+            --max-blob-size [LIMIT]  'max size of the blob to be inserted'
+            --key-prefix [PREFIX]    'prefix to prepend to every blob key'
+            --compress               'zstd-compress blobs before storing them'
+            --sample-rate [RATE]     'import only this fraction (0.0-1.0) of the changesets'
+            --sample-seed [SEED]     'seed for picking the --sample-rate changesets. Default: 0'
+            --strict                 'fail if heads are missing or the store came from another repo'
+            --only-path [PREFIX]     'only import file and tree blobs under PREFIX'
+            --key-scheme [SCHEME]    'changeset keys: default or prefix:PREFIX. Default: default'
+            --heads-from-bookmarks   'also import the bookmarked changesets as heads'
+            --no-heads               'import only the blobs, without recording any heads'
+            --ordered                'finish changesets in revlog order, for reproducible runs'
+            --verify-hashes          'read back each changeset written and check its hash'
+            --warm-heads             'read back the heads once imported, to warm caches'
+            --import-obsmarkers      'also import the obsolescence markers from the obsstore'
+            --report-top-paths [N]   'log the N paths with the most manifest entry bytes'
+            --cpu-pool-size [N]      'threads in the worker pool. Default: one per CPU'
+            --print-config           'log the settings the import runs with before starting'
+            --trace-blob [KEY]       'log when the blob with this key is sent, received and written'
+            --two-phase              'write and flush the manifests of each changeset before it'
+            --skip-existing          'skip writing blobs that are already in the blobstore'
+            --scrub [URI]            'list the keys that differ from the blobstore at URI and exit'
+            --bundle-output [PATH]   'write the import to a Mercurial bundle at PATH instead'
+            --compare-with [URI]     'list how the blobs differ from the blobstore at URI instead'
+        "#,
+        )
+        .arg(
+            Arg::with_name("blobstore")
+                .long("blobstore")
+                .short("B")
+                .takes_value(true)
+                .possible_values(&["files", "rocksdb", "manifold", "tiered"])
+                .required_unless_one(&[
+                    "blobstore-uri",
+                    "bundle-output",
+                    "compare-with",
+                    "bookmarks-diff",
+                    "list-heads",
+                ])
+                .help("blobstore type"),
+        )
+        .arg(
+            Arg::with_name("blobstore-uri")
+                .long("blobstore-uri")
+                .takes_value(true)
+                .value_name("URI")
+                .conflicts_with_all(&["blobstore", "bucket"])
+                .help("file:///PATH, rocksdb:///PATH or manifold://BUCKET, instead of --blobstore"),
+        )
+        .arg(
+            Arg::with_name("small-blobstore")
+                .long("small-blobstore")
+                .takes_value(true)
+                .possible_values(&["files", "rocksdb", "manifold"])
+                .required_if("blobstore", "tiered")
+                .help("with --blobstore tiered, where to store blobs under the threshold"),
+        )
+        .arg(
+            Arg::with_name("large-blobstore")
+                .long("large-blobstore")
+                .takes_value(true)
+                .possible_values(&["files", "rocksdb", "manifold"])
+                .required_if("blobstore", "tiered")
+                .help("with --blobstore tiered, where to store blobs over the threshold"),
+        )
+        .arg(
+            Arg::with_name("tier-threshold")
+                .long("tier-threshold")
+                .takes_value(true)
+                .value_name("BYTES")
+                .help("with --blobstore tiered, the size of the smallest large blob. Default: 1M"),
+        )
+        .arg(
+            Arg::with_name("manifold-breaker-threshold")
+                .long("manifold-breaker-threshold")
+                .takes_value(true)
+                .value_name("K")
+                .help("with manifold, pause puts once K in a row have failed, before trying again"),
+        )
+        .arg(
+            Arg::with_name("manifold-breaker-cooldown-ms")
+                .long("manifold-breaker-cooldown-ms")
+                .takes_value(true)
+                .value_name("MS")
+                .requires("manifold-breaker-threshold")
+                .help("longest pause of manifold puts, jittered down to half. Default: 1000"),
+        )
+        .arg(
+            Arg::with_name("repo-name")
+                .long("repo-name")
+                .takes_value(true)
+                .value_name("NAME")
+                .validator(validate_repo_name)
+                .help("name of the repo, for the logs and the summary line"),
+        )
+        .arg(
+            Arg::with_name("prefix-keys-with-repo")
+                .long("prefix-keys-with-repo")
+                .requires("repo-name")
+                .help("prefix every blob key with the repo name, after any --key-prefix"),
+        )
+        .arg(
+            Arg::with_name("quiet")
+                .long("quiet")
+                .short("q")
+                .conflicts_with("debug")
+                .help("only print warnings and errors"),
+        )
+        .arg(
+            Arg::with_name("build-parent-index")
+                .long("build-parent-index")
+                .takes_value(true)
+                .value_name("PATH")
+                .help("write an NDJSON index of each changeset's parents to PATH"),
+        )
+        .arg(
+            Arg::with_name("blobstore-concurrency")
+                .long("blobstore-concurrency")
+                .takes_value(true)
+                .value_name("N")
+                .help("run at most N blobstore operations at once. Default: the channel size"),
+        )
+        .arg(
+            Arg::with_name("max-changeset-size")
+                .long("max-changeset-size")
+                .takes_value(true)
+                .value_name("BYTES")
+                .help("leave out the changesets whose blobs are BYTES or bigger"),
+        )
+        .arg(
+            Arg::with_name("warm-manifests")
+                .long("warm-manifests")
+                .requires("warm-heads")
+                .help("with --warm-heads, also read back the heads' root manifests"),
+        )
+        .arg(
+            Arg::with_name("skipped-out")
+                .long("skipped-out")
+                .takes_value(true)
+                .value_name("PATH")
+                .help("write each changeset left out by the filters, and why, to PATH"),
+        )
+        .arg(
+            Arg::with_name("since-hash")
+                .long("since-hash")
+                .takes_value(true)
+                .value_name("HASH")
+                .help("only import the changesets descended from HASH"),
+        )
+        .arg(
+            Arg::with_name("since-bookmark")
+                .long("since-bookmark")
+                .takes_value(true)
+                .value_name("NAME")
+                .conflicts_with("since-hash")
+                .help("like --since-hash, with the changeset the bookmark NAME points at"),
+        )
+        .arg(
+            Arg::with_name("blob-key-allowlist")
+                .long("blob-key-allowlist")
+                .takes_value(true)
+                .value_name("FILE")
+                .help("only write the blobs whose keys are listed, one per line, in FILE"),
+        )
+        .arg(
+            Arg::with_name("compression-level")
+                .long("compression-level")
+                .takes_value(true)
+                .value_name("LEVEL")
+                .requires("compress")
+                .help("zstd level to --compress blobs at, from 1 to 22. Default: 3"),
+        )
+        .arg(
+            Arg::with_name("log-file")
+                .long("log-file")
+                .takes_value(true)
+                .value_name("PATH")
+                .help("also append the log to PATH. With --quiet, log only to PATH"),
+        )
+        .arg(
+            Arg::with_name("bucket")
+                .long("bucket")
+                .takes_value(true)
+                .help(
+                    "bucket to use for manifold blobstore. Defaults to $MONONOKE_MANIFOLD_BUCKET \
+                     if set, or mononoke_prod otherwise",
+                ),
+        )
+}
+
+/// Pick the log level from the --debug and --quiet flags. clap stops both being passed, but
+/// --debug wins if they somehow are.
+fn log_level(debug: bool, quiet: bool) -> Level {
+    if debug {
+        Level::Debug
+    } else if quiet {
+        Level::Warning
+    } else {
+        Level::Info
+    }
+}
+
+/// Log to stderr, and to `log_file` too if it's given. With `quiet`, a log file replaces stderr
+/// instead, and gets the regular level of output.
+fn setup_logger(debug: bool, quiet: bool, log_file: Option<&Path>) -> Result<Logger> {
+    let level = log_level(debug, quiet);
+    let log_file = match log_file {
+        Some(path) => fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .with_context(|_| format!("opening log file {}", path.display()))?,
+        None => {
+            let drain = glog_drain().filter_level(level).fuse();
+            return Ok(Logger::root(drain, o![]));
+        }
+    };
+
+    // The file isn't buffered, and the decorator flushes after every record, so nothing is lost
+    // if the process exits without dropping the logger.
+    let decorator = slog_term::PlainSyncDecorator::new(log_file);
+    let file_drain = slog_term::FullFormat::new(decorator).build();
+    let logger = if quiet {
+        let level = log_level(debug, false);
+        Logger::root(file_drain.filter_level(level).fuse(), o![])
+    } else {
+        let drain = slog::Duplicate::new(glog_drain(), file_drain);
+        Logger::root(drain.filter_level(level).fuse(), o![])
+    };
+    Ok(logger)
+}
+
+fn start_thrift_service<'a>(logger: &Logger, matches: &ArgMatches<'a>) -> Result<()> {
+    let port = match matches.value_of("port") {
+        None => return Ok(()),
+        Some(port) => port.parse().expect("Failed to parse port as number"),
+    };
+
+    info!(logger, "Initializing thrift server on port {}", port);
+
+    thread::Builder::new()
+        .name("thrift_service".to_owned())
+        .spawn(move || {
+            services::run_service_framework(
+                "mononoke_server",
+                port,
                 0, // Disables separate status http server
             ).expect("failure while running thrift service framework")
         })
@@ -391,72 +1861,404 @@ fn start_stats() -> Result<()> {
 fn main() {
     let matches = setup_app().get_matches();
 
-    let root_log = {
-        let level = if matches.is_present("debug") {
-            Level::Debug
-        } else {
-            Level::Info
-        };
-
-        let drain = glog_drain().filter_level(level).fuse();
-        slog::Logger::root(drain, o![])
-    };
+    let root_log = setup_logger(
+        matches.is_present("debug"),
+        matches.is_present("quiet"),
+        matches.value_of("log-file").map(Path::new),
+    ).expect("failed to open log file");
 
     fn run<'a>(root_log: &Logger, matches: ArgMatches<'a>) -> Result<()> {
         start_thrift_service(&root_log, &matches)?;
         start_stats()?;
 
-        let input = matches.value_of("INPUT").unwrap();
-        let output = matches.value_of("OUTPUT");
-        let bucket = matches
-            .value_of("bucket")
-            .unwrap_or(DEFAULT_MANIFOLD_BUCKET);
-
-        let blobtype = match matches.value_of("blobstore").unwrap() {
+        let backend_type = |name: &str| match name {
             "files" => BlobstoreType::Files,
             "rocksdb" => BlobstoreType::Rocksdb,
-            "manifold" => BlobstoreType::Manifold(bucket.to_string()),
+            "manifold" => {
+                let (bucket, source) = resolve_bucket(
+                    matches.value_of("bucket"),
+                    env::var(MANIFOLD_BUCKET_ENV).ok(),
+                );
+                info!(root_log, "Manifold bucket {} (from {})", bucket, source);
+                BlobstoreType::Manifold(bucket)
+            }
             bad => panic!("unexpected blobstore type {}", bad),
         };
+        let blobstore_type = || match matches.value_of("blobstore").unwrap() {
+            "tiered" => {
+                // clap makes sure both tiers are given with --blobstore tiered.
+                let small = backend_type(matches.value_of("small-blobstore").unwrap());
+                let large = backend_type(matches.value_of("large-blobstore").unwrap());
+                let threshold = matches
+                    .value_of("tier-threshold")
+                    .map(|size| size.parse().expect("tier-threshold must be positive integer"))
+                    .unwrap_or(DEFAULT_TIER_THRESHOLD);
+                info!(root_log, "Blobs of {} bytes or more go to the large tier", threshold);
+                BlobstoreType::Tiered {
+                    small: Box::new(small),
+                    large: Box::new(large),
+                    threshold,
+                }
+            }
+            name => backend_type(name),
+        };
+        // clap makes sure that exactly one of --blobstore and --blobstore-uri is given, unless
+        // there's --bundle-output or --compare-with instead, or it's --bookmarks-diff or
+        // --list-heads, which don't look at the blobs.
+        let (blobtype, uri_location) = match matches.value_of("blobstore-uri") {
+            Some(uri) => {
+                let uri = uri::parse_blobstore_uri(uri)?;
+                (uri.ty, uri.path)
+            }
+            // Nothing is written to a blobstore, so its type doesn't matter.
+            None if !matches.is_present("blobstore") => (BlobstoreType::Files, None),
+            None => (blobstore_type(), None),
+        };
+        // A local store goes where its URI says, or in the output otherwise.
+        let blobstore_location = |output: Option<&str>| {
+            uri_location
+                .clone()
+                .or_else(|| output.map(|output| Path::new(output).join("blobs")))
+        };
+
+        let linknodes_shards = matches.value_of("linknodes-shards").map(|shards| {
+            let shards: usize = shards
+                .parse()
+                .expect("linknodes-shards must be positive integer");
+            if shards == 0 {
+                panic!("linknodes-shards must be positive integer");
+            }
+            shards
+        });
+
+        let compression_level = if matches.is_present("compress") {
+            Some(
+                matches
+                    .value_of("compression-level")
+                    .map(|level| level.parse().expect("compression-level must be an integer"))
+                    .unwrap_or(DEFAULT_COMPRESSION_LEVEL),
+            )
+        } else {
+            None
+        };
+
+        let key_scheme = match matches.value_of("key-scheme") {
+            Some(scheme) => KeyScheme::parse(scheme)?,
+            None => KeyScheme::Default,
+        };
+
+        if matches.is_present("selftest") {
+            // There's no input repo, so the one positional argument is the output.
+            let output = matches.value_of("OUTPUT").or(matches.value_of("INPUT"));
+            let mut core = Core::new()?;
+            let blobstore = open_blobstore(
+                blobstore_location(output),
+                blobtype,
+                &core.remote(),
+                false,
+                None,
+                None,
+                compression_level,
+                None,
+                false,
+            )?;
+            let results = selftest::run_selftest(&blobstore, &mut core, &root_log)?;
+            if results.iter().any(|res| !res.deleted) {
+                warn!(root_log, "selftest blobs could not be deleted and were left behind");
+            }
+            return Ok(());
+        }
+
+        if matches.is_present("check-linknodes") {
+            // As with --selftest, the one positional argument is the output.
+            let output = matches
+                .value_of("OUTPUT")
+                .or(matches.value_of("INPUT"))
+                .ok_or_else(|| failure::err_msg("--check-linknodes needs OUTPUT"))?;
+            let key_prefix = key_prefix(&matches);
+            let mut core = Core::new()?;
+            let blobstore = open_blobstore(
+                blobstore_location(Some(output)),
+                blobtype,
+                &core.remote(),
+                false,
+                None,
+                key_prefix,
+                compression_level,
+                None,
+                false,
+            )?;
+            let linknodes_path = Path::new(output).join("linknodes");
+            info!(root_log, "Checking linknodes in {}", linknodes_path.display());
+            let check = match linknodes_shards {
+                Some(shards) => {
+                    let linknodes = ShardedFileLinknodes::open(linknodes_path, shards)?;
+                    core.run(check::check_linknodes(&linknodes, blobstore, key_scheme))?
+                }
+                None => {
+                    let linknodes = FileLinknodes::open(linknodes_path)?;
+                    core.run(check::check_linknodes(&linknodes, blobstore, key_scheme))?
+                }
+            };
+            return check::report_linknodes_check(&check, &root_log);
+        }
+
+        if let Some(other) = matches.value_of("scrub") {
+            // As with --selftest, the one positional argument is the output. That's blobstore A
+            // in the report, and the one at the URI is B.
+            let output = matches.value_of("OUTPUT").or(matches.value_of("INPUT"));
+            let other = uri::parse_blobstore_uri(other)?;
+            let mut core = Core::new()?;
+            let blobstore = open_blobstore(
+                blobstore_location(output),
+                blobtype,
+                &core.remote(),
+                false,
+                None,
+                None,
+                compression_level,
+                None,
+                false,
+            ).context("opening the blobstore to scrub, in OUTPUT or at --blobstore-uri")?;
+            let other = open_blobstore(
+                other.path,
+                other.ty,
+                &core.remote(),
+                false,
+                None,
+                None,
+                compression_level,
+                None,
+                false,
+            ).context("opening the blobstore at the --scrub URI")?;
+            let stdout = io::stdout();
+            let count = write_scrub(blobstore, other, &mut core, stdout.lock())?;
+            if count > 0 {
+                bail!("{} keys differ between the blobstores", count);
+            }
+            info!(root_log, "The blobstores have the same blobs");
+            return Ok(());
+        }
+
+        if matches.is_present("list-heads") {
+            // As with --selftest, the one positional argument is the output.
+            let output = matches
+                .value_of("OUTPUT")
+                .or(matches.value_of("INPUT"))
+                .ok_or_else(|| failure::err_msg("--list-heads needs OUTPUT"))?;
+            let headstore = open_headstore(Some(output), &Arc::new(CpuPool::new(1)))?;
+            let stdout = io::stdout();
+            let count = write_heads(&headstore, stdout.lock())?;
+            info!(root_log, "Listed {} heads", count);
+            return Ok(());
+        }
+
+        if matches.is_present("bookmarks-diff") {
+            let (input, output) = match (matches.value_of("INPUT"), matches.value_of("OUTPUT")) {
+                (Some(input), Some(output)) => (input, output),
+                _ => bail!("--bookmarks-diff needs INPUT and OUTPUT"),
+            };
+            let input = repo_dir(
+                Path::new(input),
+                matches.value_of("input-subdir").map(Path::new),
+            )?;
+            let source = StockBookmarks::read(input.join(".hg"))?;
+            // A store that has never had bookmarks written to it has no bookmarks directory.
+            let books = Path::new(output).join("books");
+            let recorded = if books.exists() {
+                let store = FileBookmarks::open(books)?;
+                bookmarksdiff::recorded_bookmarks(Arc::new(store)).wait()?
+            } else {
+                HashMap::new()
+            };
+            let diff = source.changed_since(&recorded);
+            info!(
+                root_log,
+                "Bookmarks: {} added, {} removed, {} changed",
+                diff.added.len(),
+                diff.removed.len(),
+                diff.changed.len()
+            );
+            let stdout = io::stdout();
+            return bookmarksdiff::write_diff(&diff, stdout.lock());
+        }
+
+        let input = matches.value_of("INPUT").ok_or_else(|| {
+            failure::err_msg(
+                "INPUT is required unless --selftest, --check-linknodes, --list-heads or --scrub \
+                 is given",
+            )
+        })?;
+        let output = matches.value_of("OUTPUT");
 
         let postpone_compaction = matches.is_present("postpone-compaction");
 
-        let channel_size: usize = matches
-            .value_of("channel-size")
-            .map(|size| {
-                size.parse().expect("channel-size must be positive integer")
-            })
-            .unwrap_or(1000);
+        let channel_size: usize = match matches.value_of("channel-size") {
+            Some(size) => size.parse().expect("channel-size must be positive integer"),
+            None => {
+                let available = available_memory();
+                let type_default = channel_size_for_blobstore(&blobtype);
+                let size = default_channel_size(&blobtype, available);
+                match available {
+                    Some(available) => info!(
+                        root_log,
+                        "Channel size {} (default of {} for this blobstore type, limited to {} \
+                         by {} MiB of available memory)",
+                        size,
+                        type_default,
+                        channel_size_for_memory(Some(available)),
+                        available / 1024 / 1024
+                    ),
+                    None => info!(
+                        root_log,
+                        "Channel size {} (default for this blobstore type, available memory \
+                         unknown)",
+                        size
+                    ),
+                }
+                size
+            }
+        };
 
         let write_linknodes = matches.is_present("linknodes");
 
-        run_blobimport(
-            input,
-            output.map(|path| path.to_string()),
+        let sample_rate = matches.value_of("sample-rate").map(|rate| {
+            let rate: f64 = rate.parse().expect("sample-rate must be a number");
+            if rate < 0.0 || rate > 1.0 {
+                panic!("sample-rate must be between 0.0 and 1.0");
+            }
+            rate
+        });
+
+        let key_allowlist = match matches.value_of("blob-key-allowlist") {
+            Some(path) => {
+                let allowlist = allowlist::read_key_allowlist(path)?;
+                if allowlist.is_empty() {
+                    warn!(root_log, "Blob key allowlist {} is empty, writing all blobs", path);
+                    None
+                } else {
+                    info!(root_log, "Only writing the {} allowlisted blob keys", allowlist.len());
+                    Some(allowlist)
+                }
+            }
+            None => None,
+        };
+
+        let since = match (matches.value_of("since-hash"), matches.value_of("since-bookmark")) {
+            (Some(hash), _) => {
+                let hash = hash.parse()
+                    .map_err(|_| format_err!("--since-hash {} is not a valid hash", hash))?;
+                Some(SinceChangeset::Hash(hash))
+            }
+            (None, Some(name)) => Some(SinceChangeset::Bookmark(name.to_string())),
+            (None, None) => None,
+        };
+
+        let input_format = match matches.value_of("input-format") {
+            Some(format) => InputFormat::parse(format)?,
+            None => InputFormat::Auto,
+        };
+
+        // The compaction is done once the import has finished with the store.
+        let compact_location = match blobtype {
+            BlobstoreType::Rocksdb if postpone_compaction => blobstore_location(output),
+            _ => None,
+        };
+
+        let config = BlobimportConfig {
+            input: input.into(),
+            input_subdir: matches.value_of("input-subdir").map(PathBuf::from),
+            input_format,
+            output: output.map(PathBuf::from),
             blobtype,
+            blobstore_location: blobstore_location(output),
             write_linknodes,
-            &root_log,
+            linknodes_shards,
             postpone_compaction,
             channel_size,
-            matches.value_of("skip").map(|size| {
+            blobstore_concurrency: blobstore_concurrency(&matches, channel_size),
+            skip: matches.value_of("skip").map(|size| {
                 size.parse()
                     .expect("skip must be positive integer")
             }),
-            matches.value_of("commits-limit").map(|size| {
+            commits_limit: matches.value_of("commits-limit").map(|size| {
                 size.parse()
                     .expect("commits-limit must be positive integer")
             }),
-            matches.value_of("max-blob-size").map(|size| {
+            max_blob_size: matches.value_of("max-blob-size").map(|size| {
                 size.parse()
                     .expect("max-blob-size must be positive integer")
             }),
-        )?;
+            max_changeset_size: matches.value_of("max-changeset-size").map(|size| {
+                size.parse()
+                    .expect("max-changeset-size must be positive integer")
+            }),
+            key_prefix: key_prefix(&matches),
+            compression_level,
+            sample_rate,
+            sample_seed: matches
+                .value_of("sample-seed")
+                .map(|seed| seed.parse().expect("sample-seed must be positive integer"))
+                .unwrap_or(0),
+            strict: matches.is_present("strict"),
+            only_path: matches
+                .value_of("only-path")
+                .map(|path| MPath::new(path).expect("only-path must be a valid path")),
+            key_scheme,
+            heads_from_bookmarks: matches.is_present("heads-from-bookmarks"),
+            no_heads: matches.is_present("no-heads"),
+            parent_index: matches.value_of("build-parent-index").map(PathBuf::from),
+            skipped_out: matches.value_of("skipped-out").map(PathBuf::from),
+            key_allowlist,
+            since,
+            ordered: matches.is_present("ordered"),
+            verify_hashes: matches.is_present("verify-hashes"),
+            warm_heads: matches.is_present("warm-heads"),
+            warm_manifests: matches.is_present("warm-manifests"),
+            import_obsmarkers: matches.is_present("import-obsmarkers"),
+            report_top_paths: matches.value_of("report-top-paths").map(|n| {
+                n.parse()
+                    .expect("report-top-paths must be positive integer")
+            }),
+            cpu_pool_size: cpu_pool_size(&matches),
+            trace_blob: matches.value_of("trace-blob").map(String::from),
+            two_phase: matches.is_present("two-phase"),
+            skip_existing: matches.is_present("skip-existing"),
+            bundle_output: matches.value_of("bundle-output").map(PathBuf::from),
+            compare_with: match matches.value_of("compare-with") {
+                Some(_) if matches.is_present("bundle-output") => {
+                    bail!("--compare-with and --bundle-output can't be used together")
+                }
+                Some(uri) => Some(uri::parse_blobstore_uri(uri)?),
+                None => None,
+            },
+            manifold_breaker: matches.value_of("manifold-breaker-threshold").map(|threshold| {
+                let threshold: usize = threshold
+                    .parse()
+                    .expect("manifold-breaker-threshold must be positive integer");
+                if threshold == 0 {
+                    panic!("manifold-breaker-threshold must be positive integer");
+                }
+                let cooldown_ms = matches
+                    .value_of("manifold-breaker-cooldown-ms")
+                    .map(|ms| {
+                        ms.parse()
+                            .expect("manifold-breaker-cooldown-ms must be positive integer")
+                    })
+                    .unwrap_or(DEFAULT_BREAKER_COOLDOWN_MS);
+                (threshold, Duration::from_millis(cooldown_ms))
+            }),
+            repo_name: matches.value_of("repo-name").map(String::from),
+        };
+        if matches.is_present("print-config") {
+            info!(root_log, "{}", describe_config(&config));
+        }
 
+        run_blobimport(config, &root_log)?;
 
-        if matches.value_of("blobstore").unwrap() == "rocksdb" && postpone_compaction {
+        if let Some(location) = compact_location {
             let options = rocksdb::Options::new().create_if_missing(false);
-            let rocksdb = rocksdb::Db::open(Path::new(output.unwrap()).join("blobs"), options)
-                .expect("can't open rocksdb");
+            let rocksdb = rocksdb::Db::open(location, options).expect("can't open rocksdb");
             info!(root_log, "compaction started");
             rocksdb.compact_range(&[], &[]);
             info!(root_log, "compaction finished");
@@ -470,3 +2272,1023 @@ fn main() {
         std::process::exit(1);
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::fs::File;
+    use std::io::Write;
+    use std::process::Command;
+    use std::time::Duration;
+
+    use tempdir::TempDir;
+
+    use futures_ext::StreamExt;
+    use heads::Heads;
+    use mercurial::revlogrepo::RevlogChangeset;
+    use mercurial_bundles::changegroup::{Part, Section};
+    use mercurial_types::{Blob, BlobNode};
+    use mercurial_types_mocks::nodehash::{ONES_HASH, TWOS_HASH};
+    use trace::test::Collect;
+
+    fn open_repo_err<P: Into<PathBuf>>(input: P) -> String {
+        match open_repo(input, InputFormat::Auto) {
+            Ok(_) => panic!("open_repo unexpectedly succeeded"),
+            Err(err) => format!("{}", err),
+        }
+    }
+
+    #[test]
+    fn open_repo_nonexistent() {
+        let tmp = TempDir::new("blobimport_open_repo_nonexistent").unwrap();
+        let msg = open_repo_err(tmp.path().join("missing"));
+        assert!(msg.contains("doesn't exist"), "unexpected message: {}", msg);
+    }
+
+    #[test]
+    fn open_repo_not_a_dir() {
+        let tmp = TempDir::new("blobimport_open_repo_not_a_dir").unwrap();
+        let path = tmp.path().join("file");
+        File::create(&path).unwrap();
+        let msg = open_repo_err(path);
+        assert!(msg.contains("isn't a dir"), "unexpected message: {}", msg);
+    }
+
+    #[test]
+    fn open_repo_missing_store() {
+        let tmp = TempDir::new("blobimport_open_repo_missing_store").unwrap();
+        fs::create_dir(tmp.path().join(".hg")).unwrap();
+        let msg = open_repo_err(tmp.path());
+        assert!(msg.contains("is missing"), "unexpected message: {}", msg);
+    }
+
+    #[test]
+    fn open_repo_changeset_count() {
+        let tmp = TempDir::new("blobimport_open_repo_changeset_count").unwrap();
+        let repo = open_repo(linear_repo(tmp.path()), InputFormat::Auto).unwrap();
+        assert_eq!(repo.changeset_count(), 10);
+    }
+
+    #[test]
+    fn import_reports_dropped_head() {
+        let tmp = TempDir::new("blobimport_import_reports_dropped_head").unwrap();
+        let input = linear_repo(tmp.path());
+        // The limit leaves out the second half of the line, and with it the only head.
+        let config = |output: &str, strict| BlobimportConfig {
+            commits_limit: Some(5),
+            strict,
+            ..test_config(input.clone(), &tmp.path().join(output))
+        };
+        let lines = Arc::new(Mutex::new(Vec::new()));
+        let logger = Logger::root(Collect(lines.clone()).fuse(), o![]);
+
+        import(config("lenient", false), &logger).unwrap();
+        let warned = lines
+            .lock()
+            .unwrap()
+            .iter()
+            .any(|line| line.starts_with("source repo has 1 heads, but only 0 were imported"));
+        assert!(warned, "no warning in {:?}", *lines.lock().unwrap());
+
+        let err = import(config("strict", true), &logger).unwrap_err();
+        assert_eq!(format!("{}", err), "1 heads missing from the headstore");
+    }
+
+    /// Unpack the `linear` fixture repo into `dir`: ten changesets in a line, so one head.
+    fn linear_repo(dir: &Path) -> PathBuf {
+        let tarball = dir.join("linear.tar.gz");
+        File::create(&tarball)
+            .unwrap()
+            .write_all(include_bytes!("../../tests/fixtures/linear.tar.gz"))
+            .unwrap();
+        let status = Command::new("tar")
+            .arg("-xzf")
+            .arg(&tarball)
+            .arg("-C")
+            .arg(dir)
+            .status()
+            .unwrap();
+        assert!(status.success(), "unpacking {} failed", tarball.display());
+        dir.join("linear")
+    }
+
+    /// Make `path` a repo like a fresh `hg init` would, with no changesets.
+    fn empty_repo(path: &Path) {
+        let hg = path.join(".hg");
+        fs::create_dir_all(hg.join("store")).unwrap();
+        File::create(hg.join("requires"))
+            .unwrap()
+            .write_all(b"revlogv1\nstore\n")
+            .unwrap();
+        // Empty inline RevlogNG revlogs, which are just a header.
+        for name in &["00changelog.i", "00manifest.i"] {
+            File::create(hg.join("store").join(name))
+                .unwrap()
+                .write_all(&[0, 1, 0, 1])
+                .unwrap();
+        }
+    }
+
+    #[test]
+    fn input_format_mismatch() {
+        let tmp = TempDir::new("blobimport_input_format_mismatch").unwrap();
+        empty_repo(tmp.path());
+
+        assert!(open_repo(tmp.path(), InputFormat::Auto).is_ok());
+        assert!(open_repo(tmp.path(), InputFormat::parse("revlogv1").unwrap()).is_ok());
+
+        let err = open_repo(tmp.path(), InputFormat::parse("revlogv0").unwrap()).unwrap_err();
+        assert!(
+            err.causes()
+                .any(|cause| cause.to_string().contains("input format")),
+            "unexpected error: {:?}",
+            err
+        );
+
+        assert!(InputFormat::parse("revlogv2").is_err());
+    }
+
+    /// A config for importing `input` into a file blobstore in `output`, with nothing extra
+    /// enabled.
+    fn test_config(input: PathBuf, output: &Path) -> BlobimportConfig {
+        BlobimportConfig {
+            input,
+            input_subdir: None,
+            input_format: InputFormat::Auto,
+            output: Some(output.to_path_buf()),
+            blobtype: BlobstoreType::Files,
+            blobstore_location: Some(output.join("blobs")),
+            write_linknodes: false,
+            linknodes_shards: None,
+            postpone_compaction: false,
+            channel_size: MIN_CHANNEL_SIZE,
+            blobstore_concurrency: MIN_CHANNEL_SIZE,
+            skip: None,
+            commits_limit: None,
+            max_blob_size: None,
+            max_changeset_size: None,
+            key_prefix: None,
+            compression_level: None,
+            sample_rate: None,
+            sample_seed: 0,
+            strict: true,
+            only_path: None,
+            key_scheme: KeyScheme::Default,
+            heads_from_bookmarks: false,
+            no_heads: false,
+            parent_index: None,
+            skipped_out: None,
+            key_allowlist: None,
+            since: None,
+            ordered: false,
+            verify_hashes: false,
+            warm_heads: false,
+            warm_manifests: false,
+            import_obsmarkers: false,
+            report_top_paths: None,
+            cpu_pool_size: 2,
+            trace_blob: None,
+            two_phase: false,
+            skip_existing: false,
+            bundle_output: None,
+            manifold_breaker: None,
+            compare_with: None,
+            repo_name: None,
+        }
+    }
+
+    #[test]
+    fn import_empty_repo() {
+        let tmp = TempDir::new("blobimport_import_empty_repo").unwrap();
+        let input = tmp.path().join("input");
+        empty_repo(&input);
+        let output = tmp.path().join("output");
+
+        let config = BlobimportConfig {
+            verify_hashes: true,
+            warm_heads: true,
+            warm_manifests: true,
+            ..test_config(input, &output)
+        };
+        let logger = Logger::root(slog::Discard, o![]);
+        import(config, &logger).unwrap();
+
+        // The stores are there, just empty.
+        assert!(output.join("heads").is_dir());
+        let blobs = Fileblob::open(output.join("blobs")).unwrap();
+        assert!(blobs.keys().collect().wait().unwrap().is_empty());
+    }
+
+    #[test]
+    fn list_heads() {
+        let tmp = TempDir::new("blobimport_list_heads").unwrap();
+        let pool = Arc::new(CpuPool::new(1));
+        let headstore = open_headstore(Some(tmp.path()), &pool).unwrap();
+        let mut out = Vec::new();
+        assert_eq!(write_heads(&headstore, &mut out).unwrap(), 0);
+        assert!(out.is_empty());
+
+        headstore.add(&ONES_HASH).wait().unwrap();
+        headstore.add(&TWOS_HASH).wait().unwrap();
+        // Reopened, as --list-heads would after an import.
+        let headstore = open_headstore(Some(tmp.path()), &pool).unwrap();
+        let mut out = Vec::new();
+        assert_eq!(write_heads(&headstore, &mut out).unwrap(), 2);
+        let mut listed: Vec<_> = String::from_utf8(out)
+            .unwrap()
+            .lines()
+            .map(String::from)
+            .collect();
+        listed.sort();
+        assert_eq!(listed, vec![ONES_HASH.to_string(), TWOS_HASH.to_string()]);
+    }
+
+    #[test]
+    fn scrub_report() {
+        let mut core = Core::new().unwrap();
+        let (a, b) = (Memblob::new(), Memblob::new());
+        for &(blobstore, key, value) in &[
+            (&a, "same", "value"),
+            (&b, "same", "value"),
+            (&a, "divergent", "a"),
+            (&b, "divergent", "b"),
+        ] {
+            blobstore
+                .put(key.to_string(), Bytes::from(value))
+                .wait()
+                .unwrap();
+        }
+
+        let mut out = Vec::new();
+        let count = write_scrub(a.arced(), b.arced(), &mut core, &mut out).unwrap();
+        assert_eq!(count, 1);
+        assert_eq!(String::from_utf8(out).unwrap(), "differs divergent\n");
+    }
+
+    #[test]
+    fn import_from_subdir() {
+        let tmp = TempDir::new("blobimport_import_from_subdir").unwrap();
+        let input = tmp.path().join("checkout");
+        empty_repo(&input.join("nested/repo"));
+        let output = tmp.path().join("output");
+        let logger = Logger::root(slog::Discard, o![]);
+
+        let config = BlobimportConfig {
+            input_subdir: Some(PathBuf::from("nested/repo")),
+            ..test_config(input.clone(), &output)
+        };
+        import(config, &logger).unwrap();
+        assert!(output.join("heads").is_dir());
+
+        let config = BlobimportConfig {
+            input_subdir: Some(PathBuf::from("nested/missing")),
+            ..test_config(input, &output)
+        };
+        let err = import(config, &logger).unwrap_err();
+        assert!(
+            err.to_string().contains("input subdir nested/missing isn't a dir"),
+            "unexpected error: {}",
+            err
+        );
+    }
+
+    #[test]
+    fn import_from_different_source() {
+        let tmp = TempDir::new("blobimport_import_from_different_source").unwrap();
+        let (first, second) = (tmp.path().join("first"), tmp.path().join("second"));
+        empty_repo(&first);
+        empty_repo(&second);
+        let output = tmp.path().join("output");
+        let logger = Logger::root(slog::Discard, o![]);
+
+        import(test_config(first.clone(), &output), &logger).unwrap();
+        // Importing from the same place again is fine.
+        import(test_config(first, &output), &logger).unwrap();
+
+        let err = import(test_config(second.clone(), &output), &logger).unwrap_err();
+        assert!(
+            err.to_string().contains("was first imported from"),
+            "unexpected error: {}",
+            err
+        );
+        // Without --strict it's only a warning.
+        let config = BlobimportConfig {
+            strict: false,
+            ..test_config(second, &output)
+        };
+        import(config, &logger).unwrap();
+    }
+
+    /// A changeset whose blob is a little over `comment_len` bytes.
+    fn changeset_with_comment(comment_len: usize) -> BlobChangeset {
+        let text = format!(
+            "{}\nuser <user@example.com>\n1500000000 0 \nabc\n\n{}",
+            ONES_HASH,
+            "x".repeat(comment_len)
+        );
+        let node = BlobNode::new(Blob::from(text.into_bytes()), None, None);
+        let csid = node.nodeid().unwrap();
+        BlobChangeset::new(&csid, RevlogChangeset::new(node).unwrap())
+    }
+
+    #[test]
+    fn max_changeset_size() {
+        let memblob = Memblob::new().arced();
+        let save = |bcs: &BlobChangeset| {
+            let oversized = Arc::new(AtomicBool::new(false));
+            let limited = ChangesetLimitBlobstore {
+                blobstore: memblob.clone(),
+                max_changeset_size: 1024,
+                oversized: oversized.clone(),
+            };
+            bcs.save(limited).wait().unwrap();
+            oversized.load(Ordering::SeqCst)
+        };
+
+        let huge = changeset_with_comment(4096);
+        assert!(save(&huge));
+        assert_eq!(memblob.get(cskey(huge.nodeid())).wait().unwrap(), None);
+
+        let small = changeset_with_comment(16);
+        assert!(!save(&small));
+        assert!(memblob.get(cskey(small.nodeid())).wait().unwrap().is_some());
+
+        // The cutoff is the same as --max-blob-size's: a blob of exactly the limit is left out.
+        let oversized = Arc::new(AtomicBool::new(false));
+        let limited = ChangesetLimitBlobstore {
+            blobstore: memblob.clone(),
+            max_changeset_size: 1024,
+            oversized: oversized.clone(),
+        };
+        let at_limit = Bytes::from(vec![b'c'; 1024]);
+        limited.put("at-limit".to_string(), at_limit.clone()).wait().unwrap();
+        assert!(oversized.load(Ordering::SeqCst));
+        let limited = LimitedBlobstore {
+            blobstore: memblob.clone(),
+            max_blob_size: 1024,
+        };
+        limited.put("at-limit".to_string(), at_limit).wait().unwrap();
+        assert_eq!(memblob.get("at-limit".to_string()).wait().unwrap(), None);
+
+        // File blobs bigger than that are only up to --max-blob-size.
+        let limited = LimitedBlobstore {
+            blobstore: memblob.clone(),
+            max_blob_size: 8192,
+        };
+        let file = Bytes::from(vec![b'a'; 4096]);
+        limited.put("file".to_string(), file.clone()).wait().unwrap();
+        assert_eq!(memblob.get("file".to_string()).wait().unwrap(), Some(file));
+
+        // Streamed blobs are limited too, whether or not their size is given up front.
+        let chunks = || stream::iter_ok(vec![Bytes::from(vec![b'b'; 8192])]).boxify();
+        for (key, len_hint) in vec![("hinted", Some(8192)), ("unhinted", None)] {
+            limited
+                .put_stream(key.to_string(), chunks(), len_hint)
+                .wait()
+                .unwrap();
+            assert_eq!(memblob.get(key.to_string()).wait().unwrap(), None);
+        }
+        let small = stream::iter_ok(vec![Bytes::from_static(b"small")]).boxify();
+        limited
+            .put_stream("streamed".to_string(), small, None)
+            .wait()
+            .unwrap();
+        assert!(memblob.get("streamed".to_string()).wait().unwrap().is_some());
+
+        // Copies go through to the store underneath.
+        limited
+            .copy("streamed".to_string(), "copied".to_string())
+            .wait()
+            .unwrap();
+        assert_eq!(
+            memblob.get("copied".to_string()).wait().unwrap(),
+            Some(Bytes::from_static(b"small"))
+        );
+    }
+
+    #[test]
+    fn import_obsmarkers() {
+        const OBSSTORE: &[u8] = include_bytes!("fixtures/obsstore.bin");
+
+        let tmp = TempDir::new("blobimport_import_obsmarkers").unwrap();
+        let input = tmp.path().join("input");
+        empty_repo(&input);
+        File::create(input.join(".hg/store/obsstore"))
+            .unwrap()
+            .write_all(OBSSTORE)
+            .unwrap();
+        let output = tmp.path().join("output");
+
+        let config = BlobimportConfig {
+            import_obsmarkers: true,
+            ..test_config(input, &output)
+        };
+        let logger = Logger::root(slog::Discard, o![]);
+        import(config, &logger).unwrap();
+
+        // The fixture has two markers, each stored as it was encoded, after the version byte.
+        let blobs = Fileblob::open(output.join("blobs")).unwrap();
+        let keys = blobs.keys().collect().wait().unwrap();
+        assert_eq!(keys.len(), 2);
+        let mut total_size = 0;
+        for key in keys {
+            let blob = blobs.get(key.clone()).wait().unwrap().unwrap();
+            assert_eq!(key, obsmarkers::obsmarker_key(&blob));
+            total_size += blob.len();
+        }
+        assert_eq!(total_size, OBSSTORE.len() - 1);
+    }
+
+    #[test]
+    fn import_without_heads() {
+        let tmp = TempDir::new("blobimport_import_without_heads").unwrap();
+        let input = tmp.path().join("input");
+        empty_repo(&input);
+        File::create(input.join(".hg/store/obsstore"))
+            .unwrap()
+            .write_all(include_bytes!("fixtures/obsstore.bin"))
+            .unwrap();
+        let output = tmp.path().join("output");
+
+        let config = BlobimportConfig {
+            no_heads: true,
+            import_obsmarkers: true,
+            ..test_config(input, &output)
+        };
+        let logger = Logger::root(slog::Discard, o![]);
+        import(config, &logger).unwrap();
+
+        assert!(!output.join("heads").exists());
+        let blobs = Fileblob::open(output.join("blobs")).unwrap();
+        assert_eq!(blobs.keys().collect().wait().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn import_to_bundle() {
+        let tmp = TempDir::new("blobimport_import_to_bundle").unwrap();
+        let input = tmp.path().join("input");
+        empty_repo(&input);
+        let bundle = tmp.path().join("out.hg");
+
+        let config = BlobimportConfig {
+            output: None,
+            blobstore_location: None,
+            bundle_output: Some(bundle.clone()),
+            ..test_config(input, &tmp.path().join("output"))
+        };
+        let logger = Logger::root(slog::Discard, o![]);
+        import(config, &logger).unwrap();
+
+        // Nothing goes to the blobstore, and the bundle is there even with no changesets.
+        assert!(!tmp.path().join("output").exists());
+        let mut header = [0; 4];
+        File::open(&bundle)
+            .unwrap()
+            .read_exact(&mut header)
+            .unwrap();
+        assert_eq!(&header, b"HG20");
+    }
+
+    #[test]
+    fn import_filtered_to_bundle() {
+        let tmp = TempDir::new("blobimport_import_filtered_to_bundle").unwrap();
+        let input = linear_repo(tmp.path());
+        let bundle = tmp.path().join("out.hg");
+        let config = || BlobimportConfig {
+            commits_limit: Some(3),
+            // The limit leaves out the head, which is what the bundle is for.
+            strict: false,
+            bundle_output: Some(bundle.clone()),
+            ..test_config(input.clone(), &tmp.path().join("output"))
+        };
+        let logger = Logger::root(slog::Discard, o![]);
+        import(config(), &logger).unwrap();
+
+        // Only the first three changesets of the line are in the bundle, in order.
+        let mut core = Core::new().unwrap();
+        let parts = bundle::test::read_bundle(&bundle, &mut core);
+        let changesets: Vec<_> = parts
+            .iter()
+            .take_while(|part| **part != Part::SectionEnd(Section::Changeset))
+            .map(|part| bundle::test::chunk(part).node)
+            .collect();
+        let expected: Vec<NodeHash> = vec![
+            "2d7d4ba9ce0a6ffd222de7785b249ead9c51c536",
+            "3e0e761030db6e479a7fb58b12881883f9f8c63f",
+            "607314ef579bd2407752361ba1b0c1729d08b281",
+        ].into_iter()
+            .map(|hash| hash.parse().unwrap())
+            .collect();
+        assert_eq!(changesets, expected);
+        assert_eq!(parts.last(), Some(&Part::End));
+
+        // Flags that would leave single blobs out of it are turned down.
+        let config = BlobimportConfig {
+            max_blob_size: Some(10),
+            ..config()
+        };
+        assert!(import(config, &logger).is_err());
+    }
+
+    #[test]
+    fn import_compare_with() {
+        let tmp = TempDir::new("blobimport_import_compare_with").unwrap();
+        let input = tmp.path().join("input");
+        empty_repo(&input);
+        File::create(input.join(".hg/store/obsstore"))
+            .unwrap()
+            .write_all(include_bytes!("fixtures/obsstore.bin"))
+            .unwrap();
+        let output = tmp.path().join("output");
+        let logger = Logger::root(slog::Discard, o![]);
+        let first = BlobimportConfig {
+            import_obsmarkers: true,
+            ..test_config(input.clone(), &output)
+        };
+        import(first, &logger).unwrap();
+
+        // The reimports are given an output of their own, to check that nothing is written there.
+        let compare_output = tmp.path().join("compare_output");
+        let config = |compare_with: Option<uri::BlobstoreUri>| BlobimportConfig {
+            import_obsmarkers: true,
+            write_linknodes: true,
+            compare_with,
+            ..test_config(input.clone(), &compare_output)
+        };
+
+        let location = output.join("blobs");
+        let uri = || {
+            let uri = format!("file://{}", location.display());
+            Some(uri::parse_blobstore_uri(&uri).unwrap())
+        };
+        // A reimport of the same repo matches.
+        import(config(uri()), &logger).unwrap();
+
+        let blobs = Fileblob::open(&location).unwrap();
+        let keys = blobs.keys().collect().wait().unwrap();
+        blobs
+            .put(keys[0].clone(), Bytes::from_static(b"altered"))
+            .wait()
+            .unwrap();
+        let err = import(config(uri()), &logger).unwrap_err();
+        assert_eq!(format!("{}", err), "1 keys differ from the comparison store");
+        // Comparing doesn't write anything: not blobs, heads or linknodes.
+        assert_eq!(
+            blobs.get(keys[0].clone()).wait().unwrap(),
+            Some(Bytes::from_static(b"altered"))
+        );
+        assert!(!compare_output.exists());
+    }
+
+    #[test]
+    fn import_compare_with_filters() {
+        let tmp = TempDir::new("blobimport_import_compare_with_filters").unwrap();
+        let input = linear_repo(tmp.path());
+        let output = tmp.path().join("output");
+        // Small enough to leave out the bigger file blobs, but not all of them.
+        let config = |output: &Path| BlobimportConfig {
+            max_blob_size: Some(10),
+            ..test_config(input.clone(), output)
+        };
+        let logger = Logger::root(slog::Discard, o![]);
+        import(config(&output), &logger).unwrap();
+
+        // The blobs left out the first time are left out of the comparison too, rather than being
+        // reported missing from the comparison store.
+        let uri = || {
+            let uri = format!("file://{}", output.join("blobs").display());
+            Some(uri::parse_blobstore_uri(&uri).unwrap())
+        };
+        let compare = BlobimportConfig {
+            compare_with: uri(),
+            ..config(&tmp.path().join("compare_output"))
+        };
+        import(compare, &logger).unwrap();
+
+        let compare = BlobimportConfig {
+            compare_with: uri(),
+            skip_existing: true,
+            ..config(&tmp.path().join("compare_output"))
+        };
+        assert!(import(compare, &logger).is_err());
+    }
+
+    #[test]
+    fn channel_size_sizing() {
+        const GIB: u64 = 1024 * 1024 * 1024;
+        assert_eq!(channel_size_for_memory(None), DEFAULT_CHANNEL_SIZE);
+        // An eighth of the memory, in 1MiB entries.
+        assert_eq!(channel_size_for_memory(Some(16 * GIB)), 2048);
+        assert_eq!(channel_size_for_memory(Some(64 * GIB)), 8192);
+        // Clamped at both ends.
+        assert_eq!(channel_size_for_memory(Some(GIB / 2)), MIN_CHANNEL_SIZE);
+        assert_eq!(channel_size_for_memory(Some(0)), MIN_CHANNEL_SIZE);
+        assert_eq!(channel_size_for_memory(Some(1024 * GIB)), MAX_CHANNEL_SIZE);
+    }
+
+    #[test]
+    fn channel_size_per_blobstore() {
+        const GIB: u64 = 1024 * 1024 * 1024;
+        let manifold = BlobstoreType::Manifold("bucket".to_string());
+        assert_eq!(channel_size_for_blobstore(&BlobstoreType::Files), 100);
+        assert_eq!(channel_size_for_blobstore(&BlobstoreType::Rocksdb), 1000);
+        assert_eq!(channel_size_for_blobstore(&manifold), 5000);
+
+        // Without a memory reading, the type's default is used as it is.
+        assert_eq!(default_channel_size(&manifold, None), 5000);
+        // The memory limit lowers it, but never raises it.
+        assert_eq!(default_channel_size(&manifold, Some(16 * GIB)), 2048);
+        assert_eq!(default_channel_size(&manifold, Some(64 * GIB)), 5000);
+        assert_eq!(default_channel_size(&BlobstoreType::Files, Some(64 * GIB)), 100);
+    }
+
+    #[test]
+    fn meminfo_parsing() {
+        let meminfo = "MemTotal:       32768000 kB\n\
+                       MemFree:         1024000 kB\n\
+                       MemAvailable:   16384000 kB\n\
+                       Buffers:          512000 kB\n";
+        assert_eq!(parse_meminfo(meminfo), Some(16384000 * 1024));
+        // Old kernels don't have MemAvailable.
+        assert_eq!(parse_meminfo("MemTotal:       32768000 kB\n"), None);
+    }
+
+    #[test]
+    fn log_file() {
+        let tmp = TempDir::new("blobimport_log_file").unwrap();
+        let path = tmp.path().join("blobimport.log");
+        File::create(&path)
+            .unwrap()
+            .write_all(b"earlier run\n")
+            .unwrap();
+
+        for &quiet in &[false, true] {
+            let logger = setup_logger(false, quiet, Some(&path)).unwrap();
+            info!(logger, "Converting: test repo (quiet: {})", quiet);
+            debug!(logger, "not logged");
+        }
+
+        let mut contents = String::new();
+        File::open(&path)
+            .unwrap()
+            .read_to_string(&mut contents)
+            .unwrap();
+        // The file is appended to rather than truncated.
+        assert!(contents.starts_with("earlier run\n"), "contents: {}", contents);
+        assert!(contents.contains("Converting: test repo (quiet: false)"));
+        assert!(contents.contains("Converting: test repo (quiet: true)"));
+        assert!(!contents.contains("not logged"));
+    }
+
+    #[test]
+    fn only_path_filter() {
+        let prefix = MPath::new("dir/sub").unwrap();
+        let wanted = |path: RepoPath| path_wanted(&path, Some(&prefix));
+
+        assert!(wanted(RepoPath::dir("dir/sub".as_ref()).unwrap()));
+        assert!(wanted(RepoPath::file("dir/sub/file".as_ref()).unwrap()));
+        assert!(wanted(RepoPath::file("dir/sub/deeper/file".as_ref()).unwrap()));
+
+        assert!(!wanted(RepoPath::root()));
+        assert!(!wanted(RepoPath::dir("dir".as_ref()).unwrap()));
+        assert!(!wanted(RepoPath::file("dir/other".as_ref()).unwrap()));
+        // Prefixes are matched by path component, not byte.
+        assert!(!wanted(RepoPath::file("dir/subway".as_ref()).unwrap()));
+
+        // Without --only-path everything is imported.
+        assert!(path_wanted(&RepoPath::root(), None));
+    }
+
+    #[test]
+    fn channel_full_waits() {
+        let (sender, recv) = sync_channel(1);
+        let consumer = thread::spawn(move || {
+            for _ in recv {
+                thread::sleep(Duration::from_millis(10));
+            }
+        });
+
+        let before = CHANNEL_FULL_WAITS.load(Ordering::SeqCst);
+        for i in 0..5 {
+            send_entry(&sender, i).unwrap();
+        }
+        drop(sender);
+        consumer.join().unwrap();
+
+        // The consumer is much slower than the sender, so most sends have to wait.
+        assert!(CHANNEL_FULL_WAITS.load(Ordering::SeqCst) - before >= 2);
+    }
+
+    #[test]
+    fn key_schemes() {
+        let hash = mercurial_types_mocks::nodehash::ONES_HASH;
+        let hex = "1111111111111111111111111111111111111111";
+
+        let default = KeyScheme::parse("default").unwrap();
+        assert_eq!(default, KeyScheme::Default);
+        assert_eq!(
+            default.changeset_key(&hash),
+            format!("changeset-{}.bincode", hex)
+        );
+
+        let dotted = KeyScheme::parse("prefix:changeset.").unwrap();
+        assert_eq!(dotted.changeset_key(&hash), format!("changeset.{}", hex));
+        let bare = KeyScheme::parse("prefix:").unwrap();
+        assert_eq!(bare.changeset_key(&hash), hex);
+
+        assert!(KeyScheme::parse("changeset.").is_err());
+    }
+
+    #[test]
+    fn log_level_precedence() {
+        assert_eq!(log_level(false, false), Level::Info);
+        assert_eq!(log_level(true, false), Level::Debug);
+        assert_eq!(log_level(false, true), Level::Warning);
+        assert_eq!(log_level(true, true), Level::Debug);
+        // Errors are still printed in quiet mode.
+        assert!(Level::Error.is_at_least(log_level(false, true)));
+    }
+
+    #[test]
+    fn quiet_conflicts_with_debug() {
+        let args = vec!["blobimport", "--blobstore", "files", "--quiet", "--debug", "input"];
+        assert!(setup_app().get_matches_from_safe(args).is_err());
+        let args = vec!["blobimport", "--blobstore", "files", "--quiet", "input"];
+        assert!(setup_app().get_matches_from_safe(args).is_ok());
+    }
+
+    #[test]
+    fn print_config() {
+        let config = BlobimportConfig {
+            commits_limit: Some(7),
+            ..test_config(PathBuf::from("input"), Path::new("output"))
+        };
+        let description = describe_config(&config);
+        assert!(
+            description.contains("commits_limit: Some(7)"),
+            "unexpected config: {}",
+            description
+        );
+        // Defaults show up too.
+        assert!(description.contains("skip: None"), "unexpected config: {}", description);
+    }
+
+    #[test]
+    fn blobstore_concurrency_flag() {
+        let args = vec![
+            "blobimport",
+            "--blobstore",
+            "manifold",
+            "--channel-size",
+            "4096",
+            "--blobstore-concurrency",
+            "64",
+            "input",
+        ];
+        let matches = setup_app().get_matches_from(args);
+        assert_eq!(matches.value_of("channel-size"), Some("4096"));
+        assert_eq!(blobstore_concurrency(&matches, 4096), 64);
+
+        // Without the flag, it follows the channel size.
+        let args = vec!["blobimport", "--blobstore", "manifold", "input"];
+        assert_eq!(
+            blobstore_concurrency(&setup_app().get_matches_from(args), 4096),
+            4096
+        );
+    }
+
+    #[test]
+    fn cpu_pool_size_flag() {
+        let args = vec!["blobimport", "--blobstore", "files", "--cpu-pool-size", "3", "input"];
+        assert_eq!(cpu_pool_size(&setup_app().get_matches_from(args)), 3);
+        let args = vec!["blobimport", "--blobstore", "files", "input"];
+        assert_eq!(
+            cpu_pool_size(&setup_app().get_matches_from(args)),
+            num_cpus::get()
+        );
+    }
+
+    #[test]
+    fn compression_level_requires_compress() {
+        let args = vec![
+            "blobimport",
+            "--blobstore",
+            "files",
+            "--compression-level",
+            "10",
+            "input",
+        ];
+        assert!(setup_app().get_matches_from_safe(args).is_err());
+        let args = vec!["blobimport", "--blobstore", "files", "--compress", "input"];
+        assert!(setup_app().get_matches_from_safe(args).is_ok());
+    }
+
+    #[test]
+    fn result_line() {
+        let result = ImportResult {
+            repo_name: None,
+            changesets: 10,
+            heads: 2,
+            duplicates: 3,
+            failures: 0,
+            successes: 42,
+            elapsed: Duration::from_millis(1500),
+        };
+        let line = result.summary_line();
+        assert!(!line.contains('\n'));
+
+        let mut fields = line.split(' ');
+        assert_eq!(fields.next(), Some("BLOBIMPORT_RESULT"));
+        let fields: HashMap<_, _> = fields
+            .map(|field| {
+                let mut kv = field.splitn(2, '=');
+                (kv.next().unwrap(), kv.next().expect("field without a value"))
+            })
+            .collect();
+        assert_eq!(fields["changesets"], "10");
+        assert_eq!(fields["heads"], "2");
+        assert_eq!(fields["duplicates"], "3");
+        assert_eq!(fields["failures"], "0");
+        assert_eq!(fields["successes"], "42");
+        assert_eq!(fields["elapsed_secs"].parse::<f64>().unwrap(), 1.5);
+    }
+
+    #[test]
+    fn result_line_repo_name() {
+        let result = ImportResult {
+            repo_name: Some("fbsource".to_string()),
+            changesets: 1,
+            heads: 1,
+            duplicates: 0,
+            failures: 0,
+            successes: 1,
+            elapsed: Duration::from_secs(1),
+        };
+        let line = result.summary_line();
+        assert!(line.starts_with("BLOBIMPORT_RESULT repo=fbsource changesets=1 "));
+    }
+
+    #[test]
+    fn repo_name_key_prefix() {
+        let prefix = |args: Vec<&str>| {
+            let mut full = vec!["blobimport", "--blobstore", "files"];
+            full.extend(args);
+            full.push("input");
+            key_prefix(&setup_app().get_matches_from(full))
+        };
+        assert_eq!(prefix(vec!["--repo-name", "fbsource"]), None);
+        assert_eq!(
+            prefix(vec!["--repo-name", "fbsource", "--prefix-keys-with-repo"]),
+            Some("fbsource.".to_string())
+        );
+        assert_eq!(
+            prefix(vec![
+                "--key-prefix",
+                "shared/",
+                "--repo-name",
+                "fbsource",
+                "--prefix-keys-with-repo",
+            ]),
+            Some("shared/fbsource.".to_string())
+        );
+        assert_eq!(
+            prefix(vec!["--key-prefix", "shared/"]),
+            Some("shared/".to_string())
+        );
+
+        let args = vec!["blobimport", "--blobstore", "files", "--prefix-keys-with-repo", "input"];
+        assert!(setup_app().get_matches_from_safe(args).is_err());
+    }
+
+    #[test]
+    fn repo_name_rejects_bad_chars() {
+        let parse = |name: &str| {
+            let args = vec!["blobimport", "--blobstore", "files", "--repo-name", name, "input"];
+            setup_app().get_matches_from_safe(args)
+        };
+        for name in &["fbsource", "www-2.hg", "a_b"] {
+            assert!(parse(name).is_ok(), "{}", name);
+        }
+        for name in &["", "fb source", "repo=x", "a/b", "caf\u{e9}"] {
+            assert!(parse(name).is_err(), "{:?}", name);
+        }
+    }
+
+    #[test]
+    fn tiered_needs_both_tiers() {
+        let args = vec![
+            "blobimport",
+            "--blobstore",
+            "tiered",
+            "--small-blobstore",
+            "rocksdb",
+            "input",
+        ];
+        assert!(setup_app().get_matches_from_safe(args).is_err());
+    }
+
+    #[test]
+    fn blobstore_uri_args() {
+        let args = vec!["blobimport", "--blobstore-uri", "rocksdb:///blobs", "input"];
+        assert!(setup_app().get_matches_from_safe(args).is_ok());
+        let args = vec![
+            "blobimport",
+            "--blobstore-uri",
+            "rocksdb:///blobs",
+            "--blobstore",
+            "files",
+            "input",
+        ];
+        assert!(setup_app().get_matches_from_safe(args).is_err());
+        // One or the other is needed.
+        assert!(setup_app().get_matches_from_safe(vec!["blobimport", "input"]).is_err());
+        // Except by --bookmarks-diff, which only reads the bookmarks.
+        let args = vec!["blobimport", "--bookmarks-diff", "input", "output"];
+        assert!(setup_app().get_matches_from_safe(args).is_ok());
+        // And --list-heads, which only reads the headstore.
+        let args = vec!["blobimport", "--list-heads", "output"];
+        assert!(setup_app().get_matches_from_safe(args).is_ok());
+    }
+
+    #[test]
+    fn tiered_files() {
+        let tmp = TempDir::new("blobimport_tiered").unwrap();
+        let core = Core::new().unwrap();
+        let ty = BlobstoreType::Tiered {
+            small: Box::new(BlobstoreType::Files),
+            large: Box::new(BlobstoreType::Files),
+            threshold: 4,
+        };
+        let blobstore = open_blobstore(
+            Some(tmp.path().join("blobs")),
+            ty,
+            &core.remote(),
+            false,
+            None,
+            None,
+            None,
+            None,
+            false,
+        ).unwrap();
+        blobstore
+            .put("small".to_string(), Bytes::from_static(b"abc"))
+            .wait()
+            .unwrap();
+        blobstore
+            .put("large".to_string(), Bytes::from_static(b"abcdef"))
+            .wait()
+            .unwrap();
+
+        let large = Fileblob::open(tmp.path().join("blobs-large")).unwrap();
+        assert!(large.get("small".to_string()).wait().unwrap().is_none());
+        assert_eq!(
+            large.get("large".to_string()).wait().unwrap(),
+            Some(Bytes::from_static(b"abcdef"))
+        );
+        assert_eq!(
+            blobstore.get("small".to_string()).wait().unwrap(),
+            Some(Bytes::from_static(b"abc"))
+        );
+        assert_eq!(
+            blobstore.get("large".to_string()).wait().unwrap(),
+            Some(Bytes::from_static(b"abcdef"))
+        );
+    }
+
+    #[test]
+    fn local_blobstore_needs_location() {
+        let core = Core::new().unwrap();
+        let tiered = BlobstoreType::Tiered {
+            small: Box::new(BlobstoreType::Rocksdb),
+            large: Box::new(BlobstoreType::Files),
+            threshold: 4,
+        };
+        for ty in vec![BlobstoreType::Files, BlobstoreType::Rocksdb, tiered] {
+            match open_backend(None, ty, &core.remote(), false) {
+                Ok(_) => panic!("opened a local blobstore without a location"),
+                Err(err) => assert!(format!("{}", err).contains("needs OUTPUT"), "{}", err),
+            }
+        }
+    }
+
+    #[test]
+    fn bucket_precedence() {
+        assert_eq!(
+            resolve_bucket(None, None),
+            (DEFAULT_MANIFOLD_BUCKET.to_string(), "default")
+        );
+        assert_eq!(
+            resolve_bucket(None, Some("from_env".to_string())),
+            ("from_env".to_string(), MANIFOLD_BUCKET_ENV)
+        );
+        assert_eq!(
+            resolve_bucket(Some("from_flag"), None),
+            ("from_flag".to_string(), "--bucket")
+        );
+        assert_eq!(
+            resolve_bucket(Some("from_flag"), Some("from_env".to_string())),
+            ("from_flag".to_string(), "--bucket")
+        );
+    }
+}
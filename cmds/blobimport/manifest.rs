@@ -15,15 +15,22 @@ use blobrepo::RawNodeBlob;
 use futures_ext::StreamExt;
 use mercurial::RevlogRepo;
 use mercurial::revlog::RevIdx;
-use mercurial_types::{self, Blob, BlobHash, Entry, NodeHash, Parents, Type};
+use mercurial_types::{self, Blob, BlobHash, Entry, NodeHash, Parents, RepoPath, Type};
 
 use BlobstoreEntry;
+use send_entry;
+use trace::BlobTrace;
 
+/// Send the node and content blobs for a manifest entry. Files' executable and symlink flags are
+/// in their parent tree's manifest text, which goes into the store as is, so they need nothing
+/// extra here.
 pub(crate) fn put_entry(
     sender: SyncSender<BlobstoreEntry>,
+    trace: BlobTrace,
     entry_hash: NodeHash,
     blob: Blob<Vec<u8>>,
     parents: Parents,
+    path: RepoPath,
 ) -> impl Future<Item = (), Error = Error> + Send + 'static
 where
     Error: Send + 'static,
@@ -44,10 +51,16 @@ where
         let nodeblob = bincode::serialize(&nodeblob, bincode::Bounded(4096))
             .expect("bincode serialize failed");
 
-        let res1 = sender.send(BlobstoreEntry::ManifestEntry(
-            (nodekey, Bytes::from(nodeblob)),
-        ));
-        let res2 = sender.send(BlobstoreEntry::ManifestEntry((blobkey, bytes)));
+        trace.key(&nodekey, "sent");
+        let res1 = send_entry(
+            &sender,
+            BlobstoreEntry::ManifestEntry((nodekey, Bytes::from(nodeblob)), path.clone()),
+        );
+        trace.key(&blobkey, "sent");
+        let res2 = send_entry(
+            &sender,
+            BlobstoreEntry::ManifestEntry((blobkey, bytes), path),
+        );
 
         res1.and(res2).map_err(Error::from)
     })
@@ -58,15 +71,17 @@ where
 pub(crate) fn copy_entry(
     entry: Box<Entry>,
     sender: SyncSender<BlobstoreEntry>,
+    trace: BlobTrace,
 ) -> impl Future<Item = (), Error = Error> + Send + 'static {
     let hash = *entry.get_hash();
+    let path = entry.get_path().clone();
 
     let blobfuture = entry.get_raw_content().map_err(Error::from);
 
     blobfuture
         .join(entry.get_parents().map_err(Error::from))
         .and_then(move |(blob, parents)| {
-            put_entry(sender, hash, blob, parents)
+            put_entry(sender, trace, hash, blob, parents, path)
         })
 }
 
@@ -114,3 +129,57 @@ pub(crate) fn get_entry_stream(
             .boxify(),
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::sync::mpsc::sync_channel;
+
+    use slog::{Discard, Logger};
+
+    use blobrepo::BlobManifest;
+    use blobstore::Blobstore;
+    use memblob::Memblob;
+    use mercurial_types::{MPath, Manifest};
+    use mercurial_types_mocks::nodehash::{ONES_HASH, THREES_HASH, TWOS_HASH};
+
+    use KeyScheme;
+
+    #[test]
+    fn file_flags_survive() {
+        let (sender, receiver) = sync_channel(10);
+        let trace = BlobTrace::new(None, KeyScheme::Default, Logger::root(Discard, o![]));
+        let text = format!("plain\0{}\nrun\0{}x\n", TWOS_HASH, THREES_HASH);
+        let blob = Blob::from(text.into_bytes());
+        put_entry(sender, trace, ONES_HASH, blob, Parents::None, RepoPath::root())
+            .wait()
+            .unwrap();
+
+        let blobstore = Memblob::new();
+        for entry in receiver {
+            match entry {
+                BlobstoreEntry::ManifestEntry((key, value), _) => {
+                    blobstore.put(key, value).wait().unwrap()
+                }
+                _ => panic!("unexpected entry sent"),
+            }
+        }
+
+        // Read back the way the tree is served.
+        let manifest = BlobManifest::load(&blobstore, &ONES_HASH)
+            .wait()
+            .unwrap()
+            .expect("manifest not stored");
+        let ty = |path: &str| {
+            manifest
+                .lookup(&MPath::new(path).unwrap())
+                .wait()
+                .unwrap()
+                .expect("entry missing from manifest")
+                .get_type()
+        };
+        assert_eq!(ty("plain"), Type::File);
+        assert_eq!(ty("run"), Type::Executable);
+    }
+}
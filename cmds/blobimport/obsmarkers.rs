@@ -0,0 +1,47 @@
+// Copyright (c) 2017-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+//! Copying a repo's obsolescence markers into the blobstore.
+//!
+//! Each marker is stored as it was encoded in the obsstore, under `obsmarker-sha1-<hash>` where
+//! `<hash>` is the SHA-1 of that encoding. Identical markers end up in the same blob.
+
+use std::sync::mpsc::SyncSender;
+
+use bytes::Bytes;
+use failure::{Error, Result};
+use stats::Timeseries;
+
+use mercurial::RevlogRepo;
+use mercurial_types::BlobHash;
+
+use BlobstoreEntry;
+use STATS;
+use send_entry;
+use trace::BlobTrace;
+
+pub(crate) fn obsmarker_key(raw: &[u8]) -> String {
+    format!("obsmarker-sha1-{}", BlobHash::from(raw).sha1())
+}
+
+/// Send all of `repo`'s obsmarkers to the io thread. Returns the number of markers sent.
+pub(crate) fn send_obsmarkers(
+    repo: &RevlogRepo,
+    sender: &SyncSender<BlobstoreEntry>,
+    trace: &BlobTrace,
+) -> Result<usize> {
+    let markers = repo.get_obsmarkers()?;
+    for marker in &markers {
+        let entry = BlobstoreEntry::Obsmarker((
+            obsmarker_key(&marker.raw),
+            Bytes::from(marker.raw.clone()),
+        ));
+        trace.entry(&entry, "sent");
+        send_entry(sender, entry).map_err(Error::from)?;
+        STATS::obsmarkers.add_value(1);
+    }
+    Ok(markers.len())
+}
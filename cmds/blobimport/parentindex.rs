@@ -0,0 +1,106 @@
+// Copyright (c) 2017-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+//! A sidecar index from each imported changeset to its parents, so that ancestry queries don't
+//! need to parse the changesets in the blobstore.
+//!
+//! The index is newline-delimited JSON, one `{"child": ..., "parents": [...]}` object per
+//! changeset, in the order the changesets were converted.
+
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+use std::sync::Mutex;
+
+use failure::{Result, ResultExt};
+use mercurial_types::{NodeHash, Parents};
+
+pub(crate) struct ParentIndex<W> {
+    writer: Mutex<W>,
+}
+
+impl ParentIndex<BufWriter<File>> {
+    pub fn create<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+        let file = File::create(path)
+            .with_context(|_| format!("creating parent index {}", path.display()))?;
+        Ok(Self::new(BufWriter::new(file)))
+    }
+}
+
+impl<W: Write> ParentIndex<W> {
+    pub fn new(writer: W) -> Self {
+        ParentIndex {
+            writer: Mutex::new(writer),
+        }
+    }
+
+    pub fn add(&self, child: &NodeHash, parents: &Parents) -> Result<()> {
+        let line = format_entry(child, parents);
+        let mut writer = self.writer.lock().expect("lock poisoned");
+        writer.write_all(line.as_bytes())?;
+        Ok(())
+    }
+
+    pub fn flush(&self) -> Result<()> {
+        self.writer.lock().expect("lock poisoned").flush()?;
+        Ok(())
+    }
+}
+
+fn format_entry(child: &NodeHash, parents: &Parents) -> String {
+    // Hashes are plain hex, so there's nothing that needs escaping.
+    let parents: Vec<_> = parents
+        .into_iter()
+        .map(|parent| format!("\"{}\"", parent))
+        .collect();
+    format!(
+        "{{\"child\":\"{}\",\"parents\":[{}]}}\n",
+        child,
+        parents.join(",")
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use mercurial_types_mocks::nodehash::*;
+
+    #[test]
+    fn known_parents() {
+        // A root, two children of it, and a merge of those.
+        let index = ParentIndex::new(Vec::new());
+        index.add(&ONES_HASH, &Parents::None).unwrap();
+        index.add(&TWOS_HASH, &Parents::One(ONES_HASH)).unwrap();
+        index.add(&THREES_HASH, &Parents::One(ONES_HASH)).unwrap();
+        index
+            .add(&FOURS_HASH, &Parents::Two(TWOS_HASH, THREES_HASH))
+            .unwrap();
+
+        index.flush().unwrap();
+        let out = String::from_utf8(index.writer.into_inner().unwrap()).unwrap();
+        let lines: Vec<_> = out.lines().collect();
+        assert_eq!(
+            lines,
+            vec![
+                format!("{{\"child\":\"{}\",\"parents\":[]}}", ONES_HASH),
+                format!(
+                    "{{\"child\":\"{}\",\"parents\":[\"{}\"]}}",
+                    TWOS_HASH, ONES_HASH
+                ),
+                format!(
+                    "{{\"child\":\"{}\",\"parents\":[\"{}\"]}}",
+                    THREES_HASH, ONES_HASH
+                ),
+                format!(
+                    "{{\"child\":\"{}\",\"parents\":[\"{}\",\"{}\"]}}",
+                    FOURS_HASH, TWOS_HASH, THREES_HASH
+                ),
+            ]
+        );
+    }
+}
@@ -0,0 +1,164 @@
+// Copyright (c) 2017-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+//! A round-trip check of a blobstore, for trying out a blobstore configuration before
+//! importing anything into it.
+
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use bytes::Bytes;
+use slog::Logger;
+use tokio_core::reactor::Core;
+
+use blobstore::Blobstore;
+use failure::{Result, ResultExt};
+
+/// Sizes of the synthetic blobs written by the self-test.
+const BLOB_SIZES: &[usize] = &[0, 1, 1024, 1024 * 1024];
+
+/// The outcome for a single synthetic blob.
+#[derive(Debug)]
+pub(crate) struct BlobResult {
+    pub size: usize,
+    pub put_latency: Duration,
+    pub get_latency: Duration,
+    /// Whether the blob read back was byte-for-byte what was written.
+    pub matched: bool,
+    /// Whether the blob was removed again. This is false if the blobstore doesn't support
+    /// deletes.
+    pub deleted: bool,
+}
+
+/// Write, read back and delete a few synthetic blobs, logging the latencies of each step. Fails
+/// if a blob doesn't read back identically, or if any put or get fails.
+pub(crate) fn run_selftest<B: Blobstore>(
+    blobstore: &B,
+    core: &mut Core,
+    logger: &Logger,
+) -> Result<Vec<BlobResult>> {
+    // Make the keys unique so that the test doesn't trip over blobs left behind by an earlier
+    // run against a store without delete support.
+    let run_id = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs() * 1_000_000_000 + d.subsec_nanos() as u64)
+        .unwrap_or(0);
+
+    let mut results = Vec::new();
+    for (idx, &size) in BLOB_SIZES.iter().enumerate() {
+        let key = format!("blobimport-selftest-{}-{}", run_id, idx);
+        let value: Bytes = (0..size)
+            .map(|i| (i * 31 + idx) as u8)
+            .collect::<Vec<_>>()
+            .into();
+
+        let start = Instant::now();
+        core.run(blobstore.put(key.clone(), value.clone()))
+            .with_context(|_| format!("selftest put of {} failed", key))?;
+        let put_latency = start.elapsed();
+
+        let start = Instant::now();
+        let fetched = core.run(blobstore.get(key.clone()))
+            .with_context(|_| format!("selftest get of {} failed", key))?;
+        let get_latency = start.elapsed();
+
+        let matched = fetched.as_ref() == Some(&value);
+
+        let deleted = match core.run(blobstore.delete(key.clone())) {
+            Ok(()) => true,
+            Err(err) => {
+                warn!(logger, "selftest: could not delete {}: {}", key, err);
+                false
+            }
+        };
+
+        info!(
+            logger,
+            "selftest: {} byte blob: {}, put {}, get {}",
+            size,
+            if matched { "ok" } else { "MISMATCH" },
+            format_duration(put_latency),
+            format_duration(get_latency)
+        );
+
+        results.push(BlobResult {
+            size,
+            put_latency,
+            get_latency,
+            matched,
+            deleted,
+        });
+    }
+
+    let mismatches = results.iter().filter(|res| !res.matched).count();
+    if mismatches > 0 {
+        bail!(
+            "selftest failed: {} of {} blobs did not read back correctly",
+            mismatches,
+            results.len()
+        );
+    }
+    info!(logger, "selftest passed");
+    Ok(results)
+}
+
+fn format_duration(duration: Duration) -> String {
+    format!(
+        "{:.3}ms",
+        duration.as_secs() as f64 * 1000.0 + duration.subsec_nanos() as f64 / 1_000_000.0
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use futures::{Future, Stream};
+    use futures::future::{self, FutureResult};
+    use futures_ext::{BoxFuture, FutureExt};
+    use slog::Discard;
+
+    use failure::Error;
+    use memblob::Memblob;
+
+    fn logger() -> Logger {
+        Logger::root(Discard, o![])
+    }
+
+    #[test]
+    fn memblob_passes() {
+        let blobstore = Memblob::new();
+        let mut core = Core::new().unwrap();
+        let results = run_selftest(&blobstore, &mut core, &logger()).unwrap();
+
+        assert_eq!(results.len(), BLOB_SIZES.len());
+        assert!(results.iter().all(|res| res.matched && res.deleted));
+        // Everything was cleaned up afterwards.
+        assert!(blobstore.keys().collect().wait().unwrap().is_empty());
+    }
+
+    /// A blobstore that hands back something other than what was put.
+    struct Corrupting;
+
+    impl Blobstore for Corrupting {
+        type GetBlob = FutureResult<Option<Bytes>, Error>;
+        type PutBlob = BoxFuture<(), Error>;
+
+        fn get(&self, _key: String) -> Self::GetBlob {
+            future::ok(Some(Bytes::from_static(b"garbage")))
+        }
+
+        fn put(&self, _key: String, _value: Bytes) -> Self::PutBlob {
+            future::ok(()).boxify()
+        }
+    }
+
+    #[test]
+    fn mismatch_fails() {
+        let mut core = Core::new().unwrap();
+        let err = run_selftest(&Corrupting, &mut core, &logger()).unwrap_err();
+        assert!(format!("{}", err).contains("did not read back correctly"));
+    }
+}
@@ -0,0 +1,234 @@
+// Copyright (c) 2017-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+//! Not rewriting blobs that are already in the blobstore, for `--skip-existing`.
+//!
+//! Unlike the in-memory dedup, which only knows about the blobs sent during this import, this asks
+//! the blobstore itself, so that re-running an import into a store that already has most of the
+//! repo only writes what's missing.
+
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use bytes::Bytes;
+use failure::Error;
+use futures::{Future, IntoFuture};
+use stats::Timeseries;
+
+use blobstore::{self, Blobstore};
+use futures_ext::{BoxFuture, BoxStream, FutureExt};
+
+use BBlobstore;
+use EXISTING_SKIPPED;
+use STATS;
+
+fn count_skipped() {
+    STATS::existing_skipped.add_value(1);
+    EXISTING_SKIPPED.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Blobstore that doesn't write blobs whose keys are already in the underlying blobstore, and
+/// counts them as skipped. Puts go through `put_if_absent`, so that the check and the write happen
+/// together; once the underlying blobstore turns out not to support it, they're an `exists` check
+/// followed by a `put` instead. Other `put_if_absent` failures are passed on as they are.
+#[derive(Clone)]
+pub(crate) struct SkipExistingBlobstore {
+    blobstore: BBlobstore,
+    /// Set once `put_if_absent` has failed with `ErrorKind::Unsupported`.
+    no_put_if_absent: Arc<AtomicBool>,
+}
+
+impl SkipExistingBlobstore {
+    pub fn new(blobstore: BBlobstore) -> Self {
+        SkipExistingBlobstore {
+            blobstore,
+            no_put_if_absent: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    fn check_then_put(&self, key: String, val: Bytes) -> BoxFuture<(), Error> {
+        let blobstore = self.blobstore.clone();
+        self.blobstore
+            .exists(key.clone())
+            .and_then(move |exists| {
+                if exists {
+                    count_skipped();
+                    Ok(()).into_future().boxify()
+                } else {
+                    blobstore.put(key, val)
+                }
+            })
+            .boxify()
+    }
+}
+
+impl Blobstore for SkipExistingBlobstore {
+    type GetBlob = BoxFuture<Option<Bytes>, Error>;
+    type PutBlob = BoxFuture<(), Error>;
+
+    fn get(&self, key: String) -> Self::GetBlob {
+        self.blobstore.get(key)
+    }
+
+    fn put(&self, key: String, val: Bytes) -> Self::PutBlob {
+        if self.no_put_if_absent.load(Ordering::Relaxed) {
+            return self.check_then_put(key, val);
+        }
+        let this = self.clone();
+        self.blobstore
+            .put_if_absent(key.clone(), val.clone())
+            .then(move |res| match res {
+                Ok(true) => Ok(()).into_future().boxify(),
+                Ok(false) => {
+                    count_skipped();
+                    Ok(()).into_future().boxify()
+                }
+                Err(err) => {
+                    let unsupported = match err.downcast_ref::<blobstore::ErrorKind>() {
+                        Some(&blobstore::ErrorKind::Unsupported(_)) => true,
+                        _ => false,
+                    };
+                    if unsupported {
+                        this.no_put_if_absent.store(true, Ordering::Relaxed);
+                        this.check_then_put(key, val)
+                    } else {
+                        Err(err).into_future().boxify()
+                    }
+                }
+            })
+            .boxify()
+    }
+
+    fn get_batch(&self, keys: Vec<String>) -> BoxFuture<HashMap<String, Option<Bytes>>, Error> {
+        self.blobstore.get_batch(keys)
+    }
+
+    fn exists(&self, key: String) -> BoxFuture<bool, Error> {
+        self.blobstore.exists(key)
+    }
+
+    fn put_if_absent(&self, key: String, val: Bytes) -> BoxFuture<bool, Error> {
+        self.blobstore.put_if_absent(key, val)
+    }
+
+    fn put_stream(
+        &self,
+        key: String,
+        body: BoxStream<Bytes, Error>,
+        len_hint: Option<usize>,
+    ) -> BoxFuture<(), Error> {
+        self.blobstore.put_stream(key, body, len_hint)
+    }
+
+    fn copy(&self, src_key: String, dst_key: String) -> BoxFuture<(), Error> {
+        self.blobstore.copy(src_key, dst_key)
+    }
+
+    fn delete(&self, key: String) -> BoxFuture<(), Error> {
+        self.blobstore.delete(key)
+    }
+
+    fn keys(&self) -> BoxStream<String, Error> {
+        self.blobstore.keys()
+    }
+
+    fn flush(&self) -> BoxFuture<(), Error> {
+        self.blobstore.flush()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use memblob::Memblob;
+
+    /// A blobstore with only the required methods, so that `put_if_absent` isn't supported.
+    struct PlainBlobstore(Memblob);
+
+    impl Blobstore for PlainBlobstore {
+        type GetBlob = <Memblob as Blobstore>::GetBlob;
+        type PutBlob = <Memblob as Blobstore>::PutBlob;
+
+        fn get(&self, key: String) -> Self::GetBlob {
+            self.0.get(key)
+        }
+
+        fn put(&self, key: String, value: Bytes) -> Self::PutBlob {
+            self.0.put(key, value)
+        }
+    }
+
+    /// Fails `put_if_absent` the way a backend that supports it can, with a storage error.
+    struct BrokenBlobstore(Memblob);
+
+    impl Blobstore for BrokenBlobstore {
+        type GetBlob = <Memblob as Blobstore>::GetBlob;
+        type PutBlob = <Memblob as Blobstore>::PutBlob;
+
+        fn get(&self, key: String) -> Self::GetBlob {
+            self.0.get(key)
+        }
+
+        fn put(&self, key: String, value: Bytes) -> Self::PutBlob {
+            self.0.put(key, value)
+        }
+
+        fn put_if_absent(&self, key: String, _value: Bytes) -> BoxFuture<bool, Error> {
+            let msg = format!("disk full writing {}", key);
+            Err(blobstore::ErrorKind::StorageError(msg).into())
+                .into_future()
+                .boxify()
+        }
+    }
+
+    fn put_old_and_new(backend: BBlobstore) {
+        backend
+            .put("old".to_string(), Bytes::from_static(b"seeded"))
+            .wait()
+            .unwrap();
+        let blobstore = SkipExistingBlobstore::new(backend.clone());
+
+        let before = EXISTING_SKIPPED.load(Ordering::SeqCst);
+        for key in &["old", "new"] {
+            blobstore
+                .put(key.to_string(), Bytes::from_static(b"imported"))
+                .wait()
+                .unwrap();
+        }
+
+        let get = |key: &str| backend.get(key.to_string()).wait().unwrap();
+        assert_eq!(get("old"), Some(Bytes::from_static(b"seeded")));
+        assert_eq!(get("new"), Some(Bytes::from_static(b"imported")));
+        assert_eq!(EXISTING_SKIPPED.load(Ordering::SeqCst) - before, 1);
+    }
+
+    // Both cases are in one test, as they share the counter.
+    #[test]
+    fn existing_key_skipped() {
+        // Memblob supports put_if_absent.
+        put_old_and_new(Memblob::new().arced());
+        // This one falls back to exists and put.
+        put_old_and_new(PlainBlobstore(Memblob::new()).arced());
+    }
+
+    #[test]
+    fn storage_error_not_treated_as_unsupported() {
+        let backend = Memblob::new();
+        let blobstore = SkipExistingBlobstore::new(BrokenBlobstore(backend.clone()).arced());
+        let put = || {
+            blobstore
+                .put("key".to_string(), Bytes::from_static(b"blob"))
+                .wait()
+        };
+        // The failure is passed on rather than papered over with a non-atomic put, every time.
+        assert!(put().is_err());
+        assert!(put().is_err());
+        assert!(!blobstore.no_put_if_absent.load(Ordering::SeqCst));
+        assert_eq!(backend.get("key".to_string()).wait().unwrap(), None);
+    }
+}
@@ -0,0 +1,56 @@
+// Copyright (c) 2017-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+//! A record of the source changesets that an import's filters left out, so that its coverage can
+//! be audited.
+//!
+//! Each line is a changeset hash and the filter that dropped it, such as `skip` or
+//! `commits-limit`, separated by a space.
+
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+use std::sync::Mutex;
+
+use failure::{Result, ResultExt};
+use mercurial_types::NodeHash;
+
+pub(crate) struct SkippedOut<W> {
+    writer: Mutex<W>,
+}
+
+impl SkippedOut<BufWriter<File>> {
+    pub fn create<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+        let file = File::create(path)
+            .with_context(|_| format!("creating skipped changesets file {}", path.display()))?;
+        Ok(Self::new(BufWriter::new(file)))
+    }
+}
+
+impl<W: Write> SkippedOut<W> {
+    pub fn new(writer: W) -> Self {
+        SkippedOut {
+            writer: Mutex::new(writer),
+        }
+    }
+
+    pub fn add(&self, csid: &NodeHash, reason: &str) -> Result<()> {
+        let mut writer = self.writer.lock().expect("lock poisoned");
+        writeln!(writer, "{} {}", csid, reason)?;
+        Ok(())
+    }
+
+    pub fn flush(&self) -> Result<()> {
+        self.writer.lock().expect("lock poisoned").flush()?;
+        Ok(())
+    }
+
+    #[cfg(test)]
+    pub fn into_inner(self) -> W {
+        self.writer.into_inner().expect("lock poisoned")
+    }
+}
@@ -0,0 +1,82 @@
+// Copyright (c) 2017-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+//! Keeping track of which paths the manifest entry bytes written by an import belong to, to find
+//! the paths that make up most of a repo.
+
+use std::collections::HashMap;
+
+use mercurial_types::RepoPath;
+
+#[derive(Default)]
+pub(crate) struct PathSizes {
+    sizes: HashMap<RepoPath, u64>,
+}
+
+impl PathSizes {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn add(&mut self, path: &RepoPath, bytes: usize) {
+        if let Some(total) = self.sizes.get_mut(path) {
+            *total += bytes as u64;
+            return;
+        }
+        self.sizes.insert(path.clone(), bytes as u64);
+    }
+
+    /// The `n` paths with the most bytes, biggest first. Paths with the same number of bytes are
+    /// in path order, so that the report is stable.
+    pub fn top(&self, n: usize) -> Vec<(RepoPath, u64)> {
+        let mut sizes: Vec<_> = self.sizes
+            .iter()
+            .map(|(path, total)| (path.clone(), *total))
+            .collect();
+        sizes.sort_by(|a, b| {
+            b.1
+                .cmp(&a.1)
+                .then_with(|| a.0.to_string().cmp(&b.0.to_string()))
+        });
+        sizes.truncate(n);
+        sizes
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn top_n() {
+        let file = |path: &str| RepoPath::file(path.as_ref()).unwrap();
+        let mut sizes = PathSizes::new();
+        for &(path, bytes) in &[
+            ("small", 10),
+            ("big", 600),
+            ("medium", 200),
+            ("big", 400),
+            ("tie-b", 300),
+            ("tie-a", 300),
+            ("medium", 150),
+        ] {
+            sizes.add(&file(path), bytes);
+        }
+        sizes.add(&RepoPath::dir("big".as_ref()).unwrap(), 5000);
+
+        assert_eq!(
+            sizes.top(4),
+            vec![
+                (RepoPath::dir("big".as_ref()).unwrap(), 5000),
+                (file("big"), 1000),
+                (file("medium"), 350),
+                (file("tie-a"), 300),
+            ]
+        );
+        assert_eq!(sizes.top(100).len(), 6);
+        assert!(sizes.top(0).is_empty());
+    }
+}
@@ -0,0 +1,143 @@
+// Copyright (c) 2017-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+//! Following a single blob through an import, for `--trace-blob`.
+//!
+//! Each stage the traced blob goes through is logged at the normal level, with the time since the
+//! import started, so that it shows up without `--debug`. Nothing is logged for other blobs.
+
+use std::sync::Arc;
+use std::time::Instant;
+
+use mercurial_types::NodeHash;
+use slog::Logger;
+
+use BlobstoreEntry;
+use KeyScheme;
+
+struct TracedBlob {
+    key: String,
+    key_scheme: KeyScheme,
+    logger: Logger,
+    start: Instant,
+}
+
+/// Cheap to clone, so that every part of the pipeline can have one.
+#[derive(Clone)]
+pub(crate) struct BlobTrace {
+    traced: Option<Arc<TracedBlob>>,
+}
+
+impl BlobTrace {
+    /// Trace the blob with this key, if there is one. Changeset keys are the ones `key_scheme`
+    /// gives.
+    pub fn new(key: Option<String>, key_scheme: KeyScheme, logger: Logger) -> Self {
+        BlobTrace {
+            traced: key.map(|key| {
+                Arc::new(TracedBlob {
+                    key,
+                    key_scheme,
+                    logger,
+                    start: Instant::now(),
+                })
+            }),
+        }
+    }
+
+    /// Log that the blob with this key has reached `stage`, if it's the traced one.
+    pub fn key(&self, key: &str, stage: &str) {
+        if let Some(ref traced) = self.traced {
+            if traced.key == key {
+                let elapsed = traced.start.elapsed();
+                let elapsed_ms =
+                    elapsed.as_secs() * 1000 + elapsed.subsec_nanos() as u64 / 1_000_000;
+                info!(
+                    traced.logger,
+                    "trace-blob {}: {} (+{}ms)", key, stage, elapsed_ms
+                );
+            }
+        }
+    }
+
+    /// Like `key`, with the key of the blob for this changeset.
+    pub fn changeset(&self, csid: &NodeHash, stage: &str) {
+        if let Some(ref traced) = self.traced {
+            self.key(&traced.key_scheme.changeset_key(csid), stage);
+        }
+    }
+
+    /// Like `key`, with the key of the blob this entry is for.
+    pub fn entry(&self, entry: &BlobstoreEntry, stage: &str) {
+        match *entry {
+            BlobstoreEntry::ManifestEntry((ref key, _), _)
+            | BlobstoreEntry::Obsmarker((ref key, _)) => self.key(key, stage),
+            BlobstoreEntry::Changeset(ref bcs) => self.changeset(bcs.nodeid(), stage),
+        }
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod test {
+    use super::*;
+
+    use std::sync::Mutex;
+
+    use bytes::Bytes;
+    use slog::{Drain, Never, OwnedKVList, Record};
+
+    use mercurial_types::RepoPath;
+    use mercurial_types_mocks::nodehash::ONES_HASH;
+
+    /// Keeps the messages logged through it.
+    pub(crate) struct Collect(pub Arc<Mutex<Vec<String>>>);
+
+    impl Drain for Collect {
+        type Ok = ();
+        type Err = Never;
+
+        fn log(&self, record: &Record, _values: &OwnedKVList) -> Result<(), Never> {
+            self.0.lock().unwrap().push(format!("{}", record.msg()));
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn only_traced_key() {
+        let lines = Arc::new(Mutex::new(Vec::new()));
+        let logger = Logger::root(Collect(lines.clone()).fuse(), o![]);
+        let trace = BlobTrace::new(
+            Some("sha1-wanted".to_string()),
+            KeyScheme::Default,
+            logger,
+        );
+
+        let entry = |key: &str| {
+            BlobstoreEntry::ManifestEntry(
+                (key.to_string(), Bytes::from_static(b"blob")),
+                RepoPath::root(),
+            )
+        };
+        trace.entry(&entry("sha1-wanted"), "sent");
+        trace.entry(&entry("sha1-other"), "sent");
+        trace.key("sha1-other", "written");
+        trace.changeset(&ONES_HASH, "written");
+        trace.key("sha1-wanted", "written");
+
+        let lines = lines.lock().unwrap();
+        assert_eq!(lines.len(), 2, "unexpected trace: {:?}", *lines);
+        assert!(lines[0].starts_with("trace-blob sha1-wanted: sent"));
+        assert!(lines[1].starts_with("trace-blob sha1-wanted: written"));
+    }
+
+    #[test]
+    fn not_tracing() {
+        let lines = Arc::new(Mutex::new(Vec::new()));
+        let logger = Logger::root(Collect(lines.clone()).fuse(), o![]);
+        let trace = BlobTrace::new(None, KeyScheme::Default, logger);
+        trace.key("sha1-wanted", "sent");
+        assert!(lines.lock().unwrap().is_empty());
+    }
+}
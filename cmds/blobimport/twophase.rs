@@ -0,0 +1,215 @@
+// Copyright (c) 2017-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+//! Writing entries for `--two-phase`, where everything sent before a changeset is written and
+//! flushed before the changeset's own blob is. The conversion side only sends a changeset once
+//! all of its manifest and file entries have been sent, so this makes sure that a changeset in the
+//! blobstore always has its manifest there too.
+
+use futures::{stream, Future, Stream};
+
+use blobstore::Blobstore;
+use failure::Error;
+use futures_ext::{BoxFuture, BoxStream, FutureExt, StreamExt};
+use mercurial_types::NodeHash;
+
+use BlobstoreEntry;
+
+/// A step of a two-phase import.
+enum Phase {
+    /// Entries that can be written in any order, as long as they're all done before the next
+    /// changeset.
+    Entries(Vec<BlobstoreEntry>),
+    Changeset(BlobstoreEntry),
+}
+
+/// Splits the entries received at each changeset.
+struct Phases<I> {
+    entries: I,
+    /// A changeset that ended the last batch of entries, to come after it.
+    changeset: Option<BlobstoreEntry>,
+}
+
+impl<I> Iterator for Phases<I>
+where
+    I: Iterator<Item = BlobstoreEntry>,
+{
+    type Item = Phase;
+
+    fn next(&mut self) -> Option<Phase> {
+        if let Some(changeset) = self.changeset.take() {
+            return Some(Phase::Changeset(changeset));
+        }
+        let mut batch = Vec::new();
+        for entry in &mut self.entries {
+            if let BlobstoreEntry::Changeset(_) = entry {
+                if batch.is_empty() {
+                    return Some(Phase::Changeset(entry));
+                }
+                self.changeset = Some(entry);
+                break;
+            }
+            batch.push(entry);
+        }
+        if batch.is_empty() {
+            None
+        } else {
+            Some(Phase::Entries(batch))
+        }
+    }
+}
+
+/// Write `entries` with `write`, flushing `blobstore` before each changeset and writing the
+/// entries in between `concurrency` at a time. Like the normal io stream, this yields what
+/// `write` does for each entry.
+pub(crate) fn write_in_phases<I, B, F>(
+    entries: I,
+    blobstore: B,
+    mut write: F,
+    concurrency: usize,
+) -> BoxStream<Option<NodeHash>, Error>
+where
+    I: Iterator<Item = BlobstoreEntry> + Send + 'static,
+    B: Blobstore + Clone,
+    F: FnMut(BlobstoreEntry) -> BoxFuture<Option<NodeHash>, Error> + Send + 'static,
+{
+    let phases = Phases {
+        entries,
+        changeset: None,
+    };
+    // `and_then` only starts on the next phase once the last one is done, and the writes are
+    // only started when their phase is reached.
+    stream::iter_ok(phases)
+        .and_then(move |phase| match phase {
+            Phase::Entries(batch) => {
+                let writes: Vec<_> = batch.into_iter().map(&mut write).collect();
+                let blobstore = blobstore.clone();
+                stream::iter_ok(writes)
+                    .buffer_unordered(concurrency)
+                    .collect()
+                    .and_then(move |written| blobstore.flush().map(|()| written))
+                    .boxify()
+            }
+            Phase::Changeset(changeset) => write(changeset).map(|csid| vec![csid]).boxify(),
+        })
+        .map(|written| stream::iter_ok::<_, Error>(written))
+        .flatten()
+        .boxify()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::sync::{Arc, Mutex};
+
+    use bytes::Bytes;
+
+    use blobrepo::BlobChangeset;
+    use memblob::Memblob;
+    use mercurial::revlogrepo::RevlogChangeset;
+    use mercurial_types::{Blob, BlobNode, RepoPath};
+    use mercurial_types_mocks::nodehash::ONES_HASH;
+
+    /// Records the puts and flushes that go through it, in order.
+    #[derive(Clone)]
+    struct RecordingBlobstore {
+        inner: Memblob,
+        ops: Arc<Mutex<Vec<String>>>,
+    }
+
+    impl Blobstore for RecordingBlobstore {
+        type GetBlob = <Memblob as Blobstore>::GetBlob;
+        type PutBlob = <Memblob as Blobstore>::PutBlob;
+
+        fn get(&self, key: String) -> Self::GetBlob {
+            self.inner.get(key)
+        }
+
+        fn put(&self, key: String, value: Bytes) -> Self::PutBlob {
+            self.ops.lock().unwrap().push(key.clone());
+            self.inner.put(key, value)
+        }
+
+        fn flush(&self) -> BoxFuture<(), Error> {
+            self.ops.lock().unwrap().push("flush".to_string());
+            self.inner.flush()
+        }
+    }
+
+    fn changeset(comment: &str) -> BlobChangeset {
+        let text = format!(
+            "{}\nuser <user@example.com>\n1500000000 0 \nabc\n\n{}",
+            ONES_HASH, comment
+        );
+        let node = BlobNode::new(Blob::from(text.into_bytes()), None, None);
+        let csid = node.nodeid().unwrap();
+        BlobChangeset::new(&csid, RevlogChangeset::new(node).unwrap())
+    }
+
+    fn manifest_entry(key: &str) -> BlobstoreEntry {
+        BlobstoreEntry::ManifestEntry(
+            (key.to_string(), Bytes::from_static(b"blob")),
+            RepoPath::root(),
+        )
+    }
+
+    #[test]
+    fn manifests_before_changesets() {
+        let blobstore = RecordingBlobstore {
+            inner: Memblob::new(),
+            ops: Arc::new(Mutex::new(Vec::new())),
+        };
+        let (first, second) = (changeset("first"), changeset("second"));
+        let (first_id, second_id) = (*first.nodeid(), *second.nodeid());
+        let entries = vec![
+            manifest_entry("node-1"),
+            manifest_entry("sha1-1"),
+            BlobstoreEntry::Changeset(first),
+            manifest_entry("node-2"),
+            manifest_entry("sha1-2"),
+            BlobstoreEntry::Changeset(second),
+        ];
+
+        let write = {
+            let blobstore = blobstore.clone();
+            move |entry| match entry {
+                BlobstoreEntry::Changeset(bcs) => {
+                    let csid = *bcs.nodeid();
+                    bcs.save_with_key(blobstore.clone(), format!("changeset-{}", csid))
+                        .from_err()
+                        .map(move |()| Some(csid))
+                        .boxify()
+                }
+                BlobstoreEntry::ManifestEntry((key, value), _)
+                | BlobstoreEntry::Obsmarker((key, value)) => {
+                    blobstore.put(key, value).map(|()| None).boxify()
+                }
+            }
+        };
+        let written = write_in_phases(entries.into_iter(), blobstore.clone(), write, 10)
+            .collect()
+            .wait()
+            .unwrap();
+        let csids: Vec<_> = written.into_iter().filter_map(|csid| csid).collect();
+        assert_eq!(csids, vec![first_id, second_id]);
+
+        let ops = blobstore.ops.lock().unwrap();
+        let position = |op: &str| ops.iter().position(|o| o == op).unwrap();
+        let first_cs = position(&format!("changeset-{}", first_id));
+        let second_cs = position(&format!("changeset-{}", second_id));
+        for key in &["node-1", "sha1-1"] {
+            assert!(position(key) < first_cs, "{} after its changeset: {:?}", key, *ops);
+        }
+        for key in &["node-2", "sha1-2"] {
+            assert!(position(key) > first_cs, "{} written too early: {:?}", key, *ops);
+            assert!(position(key) < second_cs, "{} after its changeset: {:?}", key, *ops);
+        }
+        // Each batch is flushed before its changeset.
+        assert_eq!(ops[first_cs - 1], "flush");
+        assert_eq!(ops[second_cs - 1], "flush");
+    }
+}
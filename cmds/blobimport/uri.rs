@@ -0,0 +1,164 @@
+// Copyright (c) 2017-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+//! Naming a blobstore with a single URI, instead of a type, a bucket and an output directory.
+//!
+//! The supported URIs are `file:///path` and `rocksdb:///path`, for a local store in the
+//! directory `/path`, and `manifold://bucket`.
+
+use std::path::PathBuf;
+
+use failure::Result;
+
+use BlobstoreType;
+
+/// A parsed blobstore URI.
+#[derive(Debug, Eq, PartialEq)]
+pub(crate) struct BlobstoreUri {
+    pub ty: BlobstoreType,
+    /// Where a local store lives. `None` for remote stores.
+    pub path: Option<PathBuf>,
+}
+
+pub(crate) fn parse_blobstore_uri(uri: &str) -> Result<BlobstoreUri> {
+    let sep = match uri.find("://") {
+        Some(sep) => sep,
+        None => bail!("blobstore URI '{}' has no scheme", uri),
+    };
+    let (scheme, rest) = (&uri[..sep], &uri[sep + "://".len()..]);
+    let parsed = match scheme {
+        "file" | "rocksdb" => {
+            // Only absolute paths, so that there's no ambiguity about a host part.
+            if !rest.starts_with('/') || rest.len() == 1 {
+                bail!(
+                    "blobstore URI '{}' needs an absolute path, as in {}:///path",
+                    uri,
+                    scheme
+                );
+            }
+            let ty = if scheme == "file" {
+                BlobstoreType::Files
+            } else {
+                BlobstoreType::Rocksdb
+            };
+            BlobstoreUri {
+                ty,
+                path: Some(PathBuf::from(rest)),
+            }
+        }
+        "manifold" => {
+            if rest.is_empty() || rest.contains('/') {
+                bail!(
+                    "blobstore URI '{}' should be a bucket name, as in manifold://bucket",
+                    uri
+                );
+            }
+            BlobstoreUri {
+                ty: BlobstoreType::Manifold(rest.to_string()),
+                path: None,
+            }
+        }
+        _ => bail!(
+            "unknown scheme '{}' in blobstore URI '{}', expected file, rocksdb or manifold",
+            scheme,
+            uri
+        ),
+    };
+    Ok(parsed)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use bytes::Bytes;
+    use futures::Future;
+    use tempdir::TempDir;
+    use tokio_core::reactor::Core;
+
+    use blobstore::Blobstore;
+    use fileblob::Fileblob;
+    use rocksblob::Rocksblob;
+
+    use open_blobstore;
+
+    fn put_via_uri(uri: &str, value: &'static [u8]) {
+        let core = Core::new().unwrap();
+        let BlobstoreUri { ty, path } = parse_blobstore_uri(uri).unwrap();
+        open_blobstore(path, ty, &core.remote(), false, None, None, None, None, false)
+            .unwrap()
+            .put("key".to_string(), Bytes::from_static(value))
+            .wait()
+            .unwrap();
+    }
+
+    #[test]
+    fn schemes() {
+        assert_eq!(
+            parse_blobstore_uri("file:///data/blobs").unwrap(),
+            BlobstoreUri {
+                ty: BlobstoreType::Files,
+                path: Some(PathBuf::from("/data/blobs")),
+            }
+        );
+        assert_eq!(
+            parse_blobstore_uri("rocksdb:///data/blobs").unwrap(),
+            BlobstoreUri {
+                ty: BlobstoreType::Rocksdb,
+                path: Some(PathBuf::from("/data/blobs")),
+            }
+        );
+        assert_eq!(
+            parse_blobstore_uri("manifold://mononoke_test").unwrap(),
+            BlobstoreUri {
+                ty: BlobstoreType::Manifold("mononoke_test".to_string()),
+                path: None,
+            }
+        );
+    }
+
+    #[test]
+    fn bad_uris() {
+        for uri in &[
+            "/data/blobs",
+            "file://data/blobs",
+            "rocksdb:///",
+            "manifold://",
+            "manifold://bucket/path",
+        ] {
+            assert!(parse_blobstore_uri(uri).is_err(), "{} parsed", uri);
+        }
+        let msg = format!("{}", parse_blobstore_uri("s3://bucket").unwrap_err());
+        assert!(msg.contains("unknown scheme 's3'"), "unexpected message: {}", msg);
+    }
+
+    #[test]
+    fn open_local_uris() {
+        let tmp = TempDir::new("blobimport_uri").unwrap();
+
+        let files = tmp.path().join("files");
+        put_via_uri(&format!("file://{}", files.display()), b"file");
+        assert_eq!(
+            Fileblob::open(&files)
+                .unwrap()
+                .get("key".to_string())
+                .wait()
+                .unwrap(),
+            Some(Bytes::from_static(b"file"))
+        );
+
+        let rocks = tmp.path().join("rocks");
+        put_via_uri(&format!("rocksdb://{}", rocks.display()), b"rocks");
+        assert_eq!(
+            Rocksblob::open(&rocks)
+                .unwrap()
+                .get("key".to_string())
+                .wait()
+                .unwrap(),
+            Some(Bytes::from_static(b"rocks"))
+        );
+    }
+}
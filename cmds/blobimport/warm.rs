@@ -0,0 +1,111 @@
+// Copyright (c) 2017-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+//! Reading back the blobs for the heads after an import, so that any cache in front of the
+//! blobstore has them by the time the repo is first served.
+
+use futures::{stream, Future, Stream};
+
+use blobstore::Blobstore;
+use failure::{Error, Result};
+use futures_ext::{BoxFuture, FutureExt};
+use mercurial::RevlogRepo;
+use mercurial_types::Changeset;
+
+use KeyScheme;
+
+/// Number of blobs to read at once.
+const WARM_CONCURRENCY: usize = 100;
+
+/// The keys of the changeset blobs for the heads of `repo`, and of the root manifests of those
+/// changesets if `manifests` is set.
+pub(crate) fn head_keys(
+    repo: &RevlogRepo,
+    key_scheme: &KeyScheme,
+    manifests: bool,
+) -> Result<Vec<String>> {
+    let heads = repo.get_heads().collect().wait()?;
+    let mut keys = Vec::new();
+    for head in heads {
+        keys.push(key_scheme.changeset_key(&head));
+        if manifests {
+            let cs = repo.get_changeset_by_nodeid(&head).wait()?;
+            keys.push(format!("node-{}.bincode", cs.manifestid()));
+        }
+    }
+    Ok(keys)
+}
+
+/// Get each of `keys` from `blobstore`, throwing the contents away. Resolves to the number of
+/// blobs that were there.
+pub(crate) fn warm_blobs<B>(blobstore: B, keys: Vec<String>) -> BoxFuture<usize, Error>
+where
+    B: Blobstore,
+{
+    stream::iter_ok(keys)
+        .map(move |key| blobstore.get(key))
+        .buffer_unordered(WARM_CONCURRENCY)
+        .fold(0, |warmed, blob| {
+            Ok::<_, Error>(warmed + blob.map_or(0, |_| 1))
+        })
+        .boxify()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use bytes::Bytes;
+
+    use memblob::Memblob;
+
+    /// Counts the gets that go through it.
+    #[derive(Clone)]
+    struct CountingBlobstore {
+        inner: Memblob,
+        gets: Arc<AtomicUsize>,
+    }
+
+    impl Blobstore for CountingBlobstore {
+        type GetBlob = <Memblob as Blobstore>::GetBlob;
+        type PutBlob = <Memblob as Blobstore>::PutBlob;
+
+        fn get(&self, key: String) -> Self::GetBlob {
+            self.gets.fetch_add(1, Ordering::SeqCst);
+            self.inner.get(key)
+        }
+
+        fn put(&self, key: String, value: Bytes) -> Self::PutBlob {
+            self.inner.put(key, value)
+        }
+    }
+
+    #[test]
+    fn one_get_per_key() {
+        let blobstore = CountingBlobstore {
+            inner: Memblob::new(),
+            gets: Arc::new(AtomicUsize::new(0)),
+        };
+        for key in &["changeset-1", "node-1", "changeset-2"] {
+            blobstore
+                .put(key.to_string(), Bytes::from_static(b"blob"))
+                .wait()
+                .unwrap();
+        }
+
+        // The root manifest of the second head is missing.
+        let keys = vec!["changeset-1", "node-1", "changeset-2", "node-2"]
+            .into_iter()
+            .map(String::from)
+            .collect();
+        let warmed = warm_blobs(blobstore.clone(), keys).wait().unwrap();
+        assert_eq!(warmed, 3);
+        assert_eq!(blobstore.gets.load(Ordering::SeqCst), 4);
+    }
+}
@@ -37,6 +37,20 @@ impl MemHeads {
             heads: Mutex::new(HashSet::new()),
         }
     }
+
+    /// The same as `is_head`, for tests that read better with set-like names.
+    pub fn contains(&self, head: &NodeHash) -> BoxFuture<bool, Error> {
+        self.is_head(head)
+    }
+
+    /// The number of heads.
+    pub fn len(&self) -> usize {
+        self.heads.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.heads.lock().unwrap().is_empty()
+    }
 }
 
 impl Heads for MemHeads {
@@ -11,7 +11,8 @@ extern crate futures_ext;
 extern crate mercurial_types;
 
 use failure::Error;
-use futures_ext::{BoxFuture, BoxStream};
+use futures::{future, stream};
+use futures_ext::{BoxFuture, BoxStream, FutureExt, StreamExt};
 
 use mercurial_types::NodeHash;
 
@@ -27,6 +28,35 @@ pub trait Heads: Send + 'static {
     fn heads(&self) -> BoxStream<NodeHash, Error>;
 }
 
+/// A heads store that drops every head added to it, and so never has any.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct NoopHeads;
+
+impl NoopHeads {
+    #[inline]
+    pub fn new() -> Self {
+        NoopHeads
+    }
+}
+
+impl Heads for NoopHeads {
+    fn add(&self, _head: &NodeHash) -> BoxFuture<(), Error> {
+        future::ok(()).boxify()
+    }
+
+    fn remove(&self, _head: &NodeHash) -> BoxFuture<(), Error> {
+        future::ok(()).boxify()
+    }
+
+    fn is_head(&self, _hash: &NodeHash) -> BoxFuture<bool, Error> {
+        future::ok(false).boxify()
+    }
+
+    fn heads(&self) -> BoxStream<NodeHash, Error> {
+        stream::empty().boxify()
+    }
+}
+
 impl Heads for Box<Heads> {
     fn add(&self, head: &NodeHash) -> BoxFuture<(), Error> {
         self.as_ref().add(head)
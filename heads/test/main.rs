@@ -21,7 +21,7 @@ use futures::{Future, Stream};
 use tempdir::TempDir;
 
 use fileheads::FileHeads;
-use heads::Heads;
+use heads::{Heads, NoopHeads};
 use memheads::MemHeads;
 use mercurial_types::NodeHash;
 
@@ -84,6 +84,38 @@ fn save_node_hash<H: Heads>(heads: H) {
     assert_eq!(result, vec![head]);
 }
 
+#[test]
+fn memheads_transitions() {
+    let heads = MemHeads::new();
+    let foo = mercurial_types_mocks::nodehash::ONES_HASH;
+    let bar = mercurial_types_mocks::nodehash::TWOS_HASH;
+    assert!(heads.is_empty());
+
+    heads.add(&foo).wait().unwrap();
+    heads.add(&bar).wait().unwrap();
+    // Adding a head twice doesn't count it twice.
+    heads.add(&foo).wait().unwrap();
+    assert_eq!(heads.len(), 2);
+    assert!(heads.contains(&foo).wait().unwrap());
+
+    heads.remove(&foo).wait().unwrap();
+    assert_eq!(heads.len(), 1);
+    assert!(!heads.contains(&foo).wait().unwrap());
+    assert!(heads.contains(&bar).wait().unwrap());
+
+    heads.remove(&bar).wait().unwrap();
+    assert!(heads.is_empty());
+}
+
+#[test]
+fn noopheads_drops_heads() {
+    let heads = NoopHeads::new();
+    let foo = mercurial_types_mocks::nodehash::ONES_HASH;
+    heads.add(&foo).wait().unwrap();
+    assert!(!heads.is_head(&foo).wait().unwrap());
+    assert!(heads.heads().collect().wait().unwrap().is_empty());
+}
+
 macro_rules! heads_test_impl {
     ($mod_name: ident => {
         state: $state: expr,
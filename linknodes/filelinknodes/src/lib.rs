@@ -16,21 +16,33 @@ extern crate filekv;
 extern crate futures_ext;
 extern crate linknodes;
 extern crate mercurial_types;
+#[cfg(test)]
+extern crate mercurial_types_mocks;
 extern crate storage_types;
 
+use std::collections::HashMap;
+use std::collections::hash_map::Entry;
+use std::io::{BufReader, Read};
 use std::path::PathBuf;
 use std::sync::Arc;
 
-use futures::Future;
+use futures::{future, Future, Stream};
+use futures::future::{loop_fn, Loop};
 use futures_cpupool::CpuPool;
 
 use failure::Result;
 use filekv::FileKV;
-use futures_ext::{BoxFuture, FutureExt};
+use futures_ext::{BoxFuture, BoxStream, FutureExt, StreamExt};
 use linknodes::{Error as LinknodeError, ErrorKind as LinknodeErrorKind, LinknodeData, Linknodes,
                 OptionNodeHash};
 use mercurial_types::{NodeHash, RepoPath};
 use mercurial_types::hash::Sha1;
+use storage_types::Version;
+
+mod retrying;
+mod sharded;
+pub use retrying::RetryingLinknodes;
+pub use sharded::ShardedFileLinknodes;
 
 static PREFIX: &str = "linknode-";
 
@@ -38,38 +50,82 @@ static PREFIX: &str = "linknode-";
 ///
 /// Linknodes are stored as files in the specified base directory.
 pub struct FileLinknodes {
-    kv: FileKV<LinknodeData>,
+    kv: Arc<FileKV<LinknodeData>>,
 }
 
 impl FileLinknodes {
     #[inline]
     pub fn open<P: Into<PathBuf>>(path: P) -> Result<Self> {
         Ok(FileLinknodes {
-            kv: FileKV::open(path, PREFIX)?,
+            kv: Arc::new(FileKV::open(path, PREFIX)?),
         })
     }
 
     #[inline]
     pub fn open_with_pool<P: Into<PathBuf>>(path: P, pool: Arc<CpuPool>) -> Result<Self> {
         Ok(FileLinknodes {
-            kv: FileKV::open_with_pool(path, PREFIX, pool)?,
+            kv: Arc::new(FileKV::open_with_pool(path, PREFIX, pool)?),
         })
     }
 
     #[inline]
     pub fn create<P: Into<PathBuf>>(path: P) -> Result<Self> {
         Ok(FileLinknodes {
-            kv: FileKV::create(path, PREFIX)?,
+            kv: Arc::new(FileKV::create(path, PREFIX)?),
         })
     }
 
     #[inline]
     pub fn create_with_pool<P: Into<PathBuf>>(path: P, pool: Arc<CpuPool>) -> Result<Self> {
         Ok(FileLinknodes {
-            kv: FileKV::create_with_pool(path, PREFIX, pool)?,
+            kv: Arc::new(FileKV::create_with_pool(path, PREFIX, pool)?),
         })
     }
 
+    /// Create a store at `path` holding the entries in an NDJSON dump written by
+    /// `linknodes::dump_ndjson`. Everything is written in one pass, without the locking that
+    /// `add` goes through, so this is much faster than adding the entries one at a time. `path`
+    /// must not already have linknodes in it.
+    ///
+    /// A dump that maps the same path and node to two different linknodes fails with
+    /// `AlreadyExists`. Identical duplicates are fine.
+    pub fn load_ndjson<P, R>(path: P, reader: R) -> Result<Self>
+    where
+        P: Into<PathBuf>,
+        R: Read + 'static,
+    {
+        let linknodes = Self::create(path)?;
+
+        let mut entries = HashMap::new();
+        for data in linknodes::read_ndjson(BufReader::new(reader)) {
+            let data = data?;
+            let key: String = hash(&data.path, &data.node).to_hex().into();
+            match entries.entry(key) {
+                Entry::Occupied(occupied) => {
+                    let existing: &LinknodeData = occupied.get();
+                    if existing.linknode != data.linknode {
+                        return Err(LinknodeErrorKind::AlreadyExists {
+                            path: data.path,
+                            node: data.node,
+                            old_linknode: OptionNodeHash(Some(existing.linknode)),
+                            new_linknode: data.linknode,
+                        }.into());
+                    }
+                }
+                Entry::Vacant(vacant) => {
+                    vacant.insert(data);
+                }
+            }
+        }
+
+        // The same fixed version as `add` uses.
+        linknodes
+            .kv
+            .bulk_load(entries, 1.into())
+            .map_err(|err| err.context(LinknodeErrorKind::StorageError))?;
+        Ok(linknodes)
+    }
+
     pub fn get_data(
         &self,
         path: RepoPath,
@@ -107,9 +163,10 @@ impl Linknodes for FileLinknodes {
             node,
             linknode,
         };
+        let kv = self.kv.clone();
         self.kv
             .set_new(
-                hash,
+                hash.clone(),
                 &linknode_data,
                 Some(1.into()), // Set a fixed version so that the bytes on disk are deterministic
             )
@@ -117,17 +174,30 @@ impl Linknodes for FileLinknodes {
                 match res {
                     Ok(Some(_)) => {
                         // Versions are irrelevant as linknodes don't support replacement.
-                        Ok(())
+                        future::ok(()).boxify()
                     }
-                    Ok(None) => Err(
-                        LinknodeErrorKind::AlreadyExists {
-                            path,
-                            node,
-                            old_linknode: OptionNodeHash(None),
-                            new_linknode: linknode,
-                        }.into(),
-                    ),
-                    Err(err) => Err(err.context(LinknodeErrorKind::StorageError).into()),
+                    Ok(None) => kv.get(hash)
+                        .then(move |existing| {
+                            // If the existing entry can't be read, report the conflict anyway.
+                            let old_linknode = match existing {
+                                Ok(Some((data, _version))) => Some(data.linknode),
+                                _ => None,
+                            };
+                            let err = LinknodeErrorKind::AlreadyExists {
+                                path,
+                                node,
+                                old_linknode: OptionNodeHash(old_linknode),
+                                new_linknode: linknode,
+                            };
+                            if err.is_benign_conflict() {
+                                Ok(())
+                            } else {
+                                Err(err.into())
+                            }
+                        })
+                        .boxify(),
+                    Err(err) => future::err(err.context(LinknodeErrorKind::StorageError).into())
+                        .boxify(),
                 }
             })
             .boxify()
@@ -136,4 +206,47 @@ impl Linknodes for FileLinknodes {
     fn get(&self, path: RepoPath, node: &NodeHash) -> Self::Get {
         self.get_data(path, node).map(|data| data.linknode).boxify()
     }
+
+    fn upsert(&self, path: RepoPath, node: &NodeHash, linknode: &NodeHash) -> Self::Effect {
+        let hash = hash(&path, node).to_hex();
+        let linknode_data = LinknodeData {
+            path,
+            node: *node,
+            linknode: *linknode,
+        };
+        let kv = self.kv.clone();
+
+        // Read the current version and replace the value if it hasn't changed in the meantime.
+        // Another writer racing with us makes the set fail with a version mismatch, in which
+        // case just try again.
+        loop_fn((), move |()| {
+            let hash = hash.clone();
+            let linknode_data = linknode_data.clone();
+            let kv_set = kv.clone();
+            kv.get(hash.clone())
+                .and_then(move |existing| {
+                    let version = match existing {
+                        Some((_data, version)) => version,
+                        None => Version::absent(),
+                    };
+                    kv_set.set(hash, &linknode_data, &version, Some(1.into()))
+                })
+                .map(|res| match res {
+                    Some(_) => Loop::Break(()),
+                    None => Loop::Continue(()),
+                })
+        }).map_err(|err| err.context(LinknodeErrorKind::StorageError).into())
+            .boxify()
+    }
+
+    fn iter(&self) -> BoxStream<LinknodeData, LinknodeError> {
+        let kv = self.kv.clone();
+        self.kv
+            .keys()
+            .and_then(move |key| kv.get(key))
+            // Entries deleted since the directory listing started just get skipped.
+            .filter_map(|entry| entry.map(|(data, _version)| data))
+            .map_err(|err| err.context(LinknodeErrorKind::StorageError).into())
+            .boxify()
+    }
 }
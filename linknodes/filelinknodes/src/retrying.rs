@@ -0,0 +1,196 @@
+// Copyright (c) 2017-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+use std::io;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use futures::{future, Future};
+use futures::future::{loop_fn, Loop};
+use futures_cpupool::CpuPool;
+
+use futures_ext::{BoxFuture, BoxStream, FutureExt};
+use linknodes::{Error as LinknodeError, LinknodeData, Linknodes};
+use mercurial_types::{NodeHash, RepoPath};
+
+/// `ESTALE` on Linux. NFS returns it for file handles that went away under us, and it usually
+/// goes away on retry. It doesn't have an `io::ErrorKind` of its own.
+const ESTALE: i32 = 116;
+
+/// A wrapper around a linknodes store that retries operations that failed with a transient IO
+/// error, such as the spurious `EINTR` and `ESTALE` errors seen on network filesystems. Other
+/// errors are passed through straight away.
+///
+/// If an `add` actually went through before failing with a transient error, the retry finds the
+/// identical entry and succeeds.
+pub struct RetryingLinknodes<L> {
+    inner: Arc<L>,
+    retries: u32,
+    backoff: Duration,
+    // Backoff delays are slept out on here, so as not to block whoever is polling.
+    delay_pool: CpuPool,
+}
+
+impl<L: Linknodes> RetryingLinknodes<L> {
+    /// Retry up to `retries` times, waiting `backoff` longer before each successive attempt.
+    pub fn new(inner: L, retries: u32, backoff: Duration) -> Self {
+        RetryingLinknodes {
+            inner: Arc::new(inner),
+            retries,
+            backoff,
+            delay_pool: CpuPool::new(1),
+        }
+    }
+
+    fn retry<F, Fut, T>(&self, op: F) -> BoxFuture<T, LinknodeError>
+    where
+        F: Fn(&L) -> Fut + Send + 'static,
+        Fut: Future<Item = T, Error = LinknodeError> + Send + 'static,
+        T: Send + 'static,
+    {
+        let inner = self.inner.clone();
+        let retries = self.retries;
+        let backoff = self.backoff;
+        let delay_pool = self.delay_pool.clone();
+
+        loop_fn(0, move |attempt| {
+            let delay_pool = delay_pool.clone();
+            op(&inner).then(move |res| match res {
+                Ok(value) => future::ok(Loop::Break(value)).boxify(),
+                Err(ref err) if attempt < retries && is_transient(err) => {
+                    let delay = backoff * (attempt + 1);
+                    delay_pool
+                        .spawn_fn(move || {
+                            thread::sleep(delay);
+                            Ok::<_, LinknodeError>(Loop::Continue(attempt + 1))
+                        })
+                        .boxify()
+                }
+                Err(err) => future::err(err).boxify(),
+            })
+        }).boxify()
+    }
+}
+
+/// Whether anything in the chain of causes is an IO error that's worth retrying.
+fn is_transient(err: &LinknodeError) -> bool {
+    err.causes()
+        .filter_map(|cause| cause.downcast_ref::<io::Error>())
+        .any(|err| match err.kind() {
+            io::ErrorKind::Interrupted | io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut => {
+                true
+            }
+            _ => err.raw_os_error() == Some(ESTALE),
+        })
+}
+
+impl<L: Linknodes> Linknodes for RetryingLinknodes<L> {
+    type Get = BoxFuture<NodeHash, LinknodeError>;
+    type Effect = BoxFuture<(), LinknodeError>;
+
+    fn add(&self, path: RepoPath, node: &NodeHash, linknode: &NodeHash) -> Self::Effect {
+        let node = *node;
+        let linknode = *linknode;
+        self.retry(move |inner| inner.add(path.clone(), &node, &linknode))
+    }
+
+    fn get(&self, path: RepoPath, node: &NodeHash) -> Self::Get {
+        let node = *node;
+        self.retry(move |inner| inner.get(path.clone(), &node))
+    }
+
+    fn upsert(&self, path: RepoPath, node: &NodeHash, linknode: &NodeHash) -> Self::Effect {
+        let node = *node;
+        let linknode = *linknode;
+        self.retry(move |inner| inner.upsert(path.clone(), &node, &linknode))
+    }
+
+    fn iter(&self) -> BoxStream<LinknodeData, LinknodeError> {
+        self.inner.iter()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use futures::stream;
+    use futures_ext::StreamExt;
+
+    use linknodes::ErrorKind;
+    use mercurial_types_mocks::nodehash::*;
+
+    /// Fails the first `failures` gets with the given IO error kind, then returns ONES_HASH.
+    struct Flaky {
+        failures: usize,
+        kind: io::ErrorKind,
+        attempts: Arc<AtomicUsize>,
+    }
+
+    impl Linknodes for Flaky {
+        type Get = BoxFuture<NodeHash, LinknodeError>;
+        type Effect = BoxFuture<(), LinknodeError>;
+
+        fn add(&self, _path: RepoPath, _node: &NodeHash, _linknode: &NodeHash) -> Self::Effect {
+            future::ok(()).boxify()
+        }
+
+        fn get(&self, _path: RepoPath, _node: &NodeHash) -> Self::Get {
+            let attempt = self.attempts.fetch_add(1, Ordering::SeqCst);
+            if attempt < self.failures {
+                let err = LinknodeError::from(io::Error::new(self.kind, "flaky"));
+                future::err(err.context(ErrorKind::StorageError).into()).boxify()
+            } else {
+                future::ok(ONES_HASH).boxify()
+            }
+        }
+
+        fn upsert(&self, _path: RepoPath, _node: &NodeHash, _linknode: &NodeHash) -> Self::Effect {
+            future::ok(()).boxify()
+        }
+
+        fn iter(&self) -> BoxStream<LinknodeData, LinknodeError> {
+            stream::empty().boxify()
+        }
+    }
+
+    fn flaky(failures: usize, kind: io::ErrorKind) -> (RetryingLinknodes<Flaky>, Arc<AtomicUsize>) {
+        let attempts = Arc::new(AtomicUsize::new(0));
+        let linknodes = Flaky {
+            failures,
+            kind,
+            attempts: attempts.clone(),
+        };
+        let retrying = RetryingLinknodes::new(linknodes, 3, Duration::from_millis(1));
+        (retrying, attempts)
+    }
+
+    #[test]
+    fn transient_error_retried() {
+        let (linknodes, attempts) = flaky(1, io::ErrorKind::Interrupted);
+        let linknode = linknodes.get(RepoPath::root(), &NULL_HASH).wait().unwrap();
+        assert_eq!(linknode, ONES_HASH);
+        assert_eq!(attempts.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn retries_run_out() {
+        let (linknodes, attempts) = flaky(10, io::ErrorKind::Interrupted);
+        assert!(linknodes.get(RepoPath::root(), &NULL_HASH).wait().is_err());
+        // The first attempt plus three retries.
+        assert_eq!(attempts.load(Ordering::SeqCst), 4);
+    }
+
+    #[test]
+    fn permanent_error_not_retried() {
+        let (linknodes, attempts) = flaky(1, io::ErrorKind::PermissionDenied);
+        assert!(linknodes.get(RepoPath::root(), &NULL_HASH).wait().is_err());
+        assert_eq!(attempts.load(Ordering::SeqCst), 1);
+    }
+}
@@ -0,0 +1,134 @@
+// Copyright (c) 2017-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use futures::Stream;
+use futures::stream;
+use futures_cpupool::CpuPool;
+
+use failure::{self, Result};
+use futures_ext::{BoxFuture, BoxStream, StreamExt};
+use linknodes::{Error as LinknodeError, LinknodeData, Linknodes};
+use mercurial_types::{NodeHash, RepoPath};
+use mercurial_types::hash::Sha1;
+
+use FileLinknodes;
+
+/// A file-based linknode store split across a fixed number of `FileLinknodes` shards.
+///
+/// Each shard lives in a `shard-N` subdirectory of the base directory. Entries are routed by a
+/// hash of the first component of their path, so everything under one top-level directory ends
+/// up in the same shard. The shard count isn't recorded on disk, so a store must always be opened
+/// with the count it was created with.
+pub struct ShardedFileLinknodes {
+    shards: Vec<FileLinknodes>,
+}
+
+impl ShardedFileLinknodes {
+    pub fn open<P: Into<PathBuf>>(path: P, shards: usize) -> Result<Self> {
+        Self::open_with_pool(path, shards, Arc::new(CpuPool::new_num_cpus()))
+    }
+
+    pub fn open_with_pool<P: Into<PathBuf>>(
+        path: P,
+        shards: usize,
+        pool: Arc<CpuPool>,
+    ) -> Result<Self> {
+        Self::new(path.into(), shards, |path| {
+            FileLinknodes::open_with_pool(path, pool.clone())
+        })
+    }
+
+    pub fn create<P: Into<PathBuf>>(path: P, shards: usize) -> Result<Self> {
+        Self::create_with_pool(path, shards, Arc::new(CpuPool::new_num_cpus()))
+    }
+
+    pub fn create_with_pool<P: Into<PathBuf>>(
+        path: P,
+        shards: usize,
+        pool: Arc<CpuPool>,
+    ) -> Result<Self> {
+        Self::new(path.into(), shards, |path| {
+            FileLinknodes::create_with_pool(path, pool.clone())
+        })
+    }
+
+    fn new<F>(base: PathBuf, shards: usize, mut open_shard: F) -> Result<Self>
+    where
+        F: FnMut(PathBuf) -> Result<FileLinknodes>,
+    {
+        if shards == 0 {
+            return Err(failure::err_msg("sharded linknodes need at least one shard"));
+        }
+        let shards = (0..shards)
+            .map(|idx| open_shard(base.join(format!("shard-{}", idx))))
+            .collect::<Result<_>>()?;
+        Ok(ShardedFileLinknodes { shards })
+    }
+
+    /// The number of shards this store is split into.
+    pub fn shard_count(&self) -> usize {
+        self.shards.len()
+    }
+
+    /// The index of the shard that entries for `path` are stored in.
+    pub fn shard_for(&self, path: &RepoPath) -> usize {
+        shard_index(path, self.shards.len())
+    }
+
+    fn shard(&self, path: &RepoPath) -> &FileLinknodes {
+        &self.shards[self.shard_for(path)]
+    }
+}
+
+fn shard_index(path: &RepoPath, shards: usize) -> usize {
+    // The root path has no components, so it's hashed as an empty first component.
+    let first = path.mpath()
+        .and_then(|mpath| mpath.into_iter().next())
+        .map(|elem| elem.as_bytes())
+        .unwrap_or(&[][..]);
+    let hash = Sha1::from(first);
+    let bytes = hash.as_ref();
+    let prefix = ((bytes[0] as u32) << 24) | ((bytes[1] as u32) << 16) | ((bytes[2] as u32) << 8)
+        | (bytes[3] as u32);
+    prefix as usize % shards
+}
+
+impl Linknodes for ShardedFileLinknodes {
+    type Get = <FileLinknodes as Linknodes>::Get;
+    type Effect = <FileLinknodes as Linknodes>::Effect;
+
+    fn add(&self, path: RepoPath, node: &NodeHash, linknode: &NodeHash) -> Self::Effect {
+        self.shard(&path).add(path, node, linknode)
+    }
+
+    fn get(&self, path: RepoPath, node: &NodeHash) -> Self::Get {
+        self.shard(&path).get(path, node)
+    }
+
+    fn contains(&self, path: RepoPath, node: &NodeHash) -> BoxFuture<bool, LinknodeError> {
+        self.shard(&path).contains(path, node)
+    }
+
+    fn upsert(&self, path: RepoPath, node: &NodeHash, linknode: &NodeHash) -> Self::Effect {
+        self.shard(&path).upsert(path, node, linknode)
+    }
+
+    fn count_for_path(&self, path: RepoPath) -> BoxFuture<usize, LinknodeError> {
+        // All the entries for a path are in the same shard, so there's no need to scan the rest.
+        self.shard(&path).count_for_path(path)
+    }
+
+    fn iter(&self) -> BoxStream<LinknodeData, LinknodeError> {
+        self.shards
+            .iter()
+            .fold(stream::empty().boxify(), |acc, shard| {
+                acc.chain(shard.iter()).boxify()
+            })
+    }
+}
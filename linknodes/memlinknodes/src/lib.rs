@@ -22,6 +22,8 @@ use std::ptr;
 use std::sync::Mutex;
 
 use futures::future::{err, ok, FutureResult, IntoFuture};
+use futures::stream::iter_ok;
+use futures_ext::{BoxFuture, BoxStream, FutureExt, StreamExt};
 
 use linknodes::{Error as LinknodeError, ErrorKind as LinknodeErrorKind, LinknodeData, Linknodes,
                 OptionNodeHash, Result as LinknodeResult, ResultExt};
@@ -43,14 +45,19 @@ impl MemLinknodes {
     pub fn add_data(&self, data: LinknodeData) -> LinknodeResult<()> {
         let mut linknodes = self.linknodes.lock().unwrap();
         match linknodes.entry((data.path.clone(), data.node)) {
-            Entry::Occupied(occupied) => Err(
-                LinknodeErrorKind::AlreadyExists {
+            Entry::Occupied(occupied) => {
+                let err = LinknodeErrorKind::AlreadyExists {
                     path: data.path,
                     node: data.node,
                     old_linknode: OptionNodeHash(Some(*occupied.get())),
                     new_linknode: data.linknode,
-                }.into(),
-            ),
+                };
+                if err.is_benign_conflict() {
+                    Ok(())
+                } else {
+                    Err(err.into())
+                }
+            }
             Entry::Vacant(vacant) => {
                 vacant.insert(data.linknode);
                 Ok(())
@@ -77,6 +84,25 @@ impl Linknodes for MemLinknodes {
         self.add_data(data).into_future()
     }
 
+    fn upsert(&self, path: RepoPath, node: &NodeHash, linknode: &NodeHash) -> Self::Effect {
+        let mut linknodes = self.linknodes.lock().unwrap();
+        linknodes.insert((path, *node), *linknode);
+        ok(())
+    }
+
+    fn iter(&self) -> BoxStream<LinknodeData, LinknodeError> {
+        let linknodes = self.linknodes.lock().unwrap();
+        let entries: Vec<_> = linknodes
+            .iter()
+            .map(|(&(ref path, node), linknode)| LinknodeData {
+                path: path.clone(),
+                node,
+                linknode: *linknode,
+            })
+            .collect();
+        iter_ok(entries).boxify()
+    }
+
     fn get(&self, path: RepoPath, node: &NodeHash) -> Self::Get {
         let linknodes = self.linknodes.lock().unwrap();
         match get_pair(&linknodes, &path, node) {
@@ -84,6 +110,11 @@ impl Linknodes for MemLinknodes {
             None => err(LinknodeErrorKind::NotFound(path.clone(), *node).into()),
         }
     }
+
+    fn contains(&self, path: RepoPath, node: &NodeHash) -> BoxFuture<bool, LinknodeError> {
+        let linknodes = self.linknodes.lock().unwrap();
+        ok(get_pair(&linknodes, &path, node).is_some()).boxify()
+    }
 }
 
 // Turns (&T, &U) into &(T, U) as cheaply as possible.
@@ -0,0 +1,261 @@
+// Copyright (c) 2017-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+#![deny(warnings)]
+
+extern crate futures;
+extern crate rusqlite;
+
+extern crate failure_ext as failure;
+extern crate futures_ext;
+extern crate linknodes;
+extern crate mercurial_types;
+
+use std::path::Path;
+use std::sync::Mutex;
+
+use futures::future::{FutureResult, IntoFuture};
+use futures::stream;
+use rusqlite::{Connection, Row};
+
+use failure::Result;
+use futures_ext::{BoxFuture, BoxStream, FutureExt, StreamExt};
+use linknodes::{Error as LinknodeError, ErrorKind as LinknodeErrorKind, LinknodeData, Linknodes,
+                OptionNodeHash, ResultExt};
+use mercurial_types::{MPath, NodeHash, RepoPath};
+
+// `kind` records which kind of `RepoPath` an entry is for, which `path` on its own doesn't say.
+// `path` is the plain path, so that entries under a directory sort together for prefix queries.
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS linknodes (
+        kind INTEGER NOT NULL,
+        path BLOB NOT NULL,
+        node BLOB NOT NULL,
+        linknode BLOB NOT NULL,
+        PRIMARY KEY (kind, path, node)
+    );
+    CREATE INDEX IF NOT EXISTS linknodes_path ON linknodes (path);
+    CREATE INDEX IF NOT EXISTS linknodes_linknode ON linknodes (linknode);
+";
+
+const KIND_ROOT: i64 = 0;
+const KIND_DIR: i64 = 1;
+const KIND_FILE: i64 = 2;
+
+/// A persistent linknode store in a SQLite database. Unlike the other stores, it has indexes by
+/// path and by linknode, so it can answer `iter_prefix`, `find_by_linknode` and `count_for_path`
+/// without scanning everything.
+pub struct SqliteLinknodes {
+    conn: Mutex<Connection>,
+}
+
+impl SqliteLinknodes {
+    /// Open the database at `path`, creating it if it doesn't exist.
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self> {
+        Self::from_connection(Connection::open(path)?)
+    }
+
+    /// A database that only lives as long as this store, for tests.
+    pub fn open_in_memory() -> Result<Self> {
+        Self::from_connection(Connection::open_in_memory()?)
+    }
+
+    fn from_connection(conn: Connection) -> Result<Self> {
+        conn.execute_batch(SCHEMA)?;
+        Ok(SqliteLinknodes {
+            conn: Mutex::new(conn),
+        })
+    }
+
+    /// Add several entries at once, in a single transaction. If any of them conflicts with an
+    /// existing entry, none of them are added.
+    pub fn add_many<I>(&self, entries: I) -> BoxFuture<(), LinknodeError>
+    where
+        I: IntoIterator<Item = LinknodeData>,
+    {
+        let mut conn = self.conn.lock().expect("lock poisoned");
+        let res = conn.transaction()
+            .context(LinknodeErrorKind::StorageError)
+            .map_err(LinknodeError::from)
+            .and_then(|tx| {
+                for data in entries {
+                    add_data(&tx, data)?;
+                }
+                tx.commit().context(LinknodeErrorKind::StorageError)?;
+                Ok(())
+            });
+        res.into_future().boxify()
+    }
+
+    /// All the entries for `prefix` itself and for everything under it.
+    pub fn iter_prefix(&self, prefix: &MPath) -> BoxStream<LinknodeData, LinknodeError> {
+        let prefix = prefix.to_vec();
+        // Everything under the prefix sorts between `prefix/` and `prefix0`, as '0' comes right
+        // after '/'.
+        let mut lower = prefix.clone();
+        lower.push(b'/');
+        let mut upper = prefix.clone();
+        upper.push(b'0');
+        self.query(
+            "SELECT kind, path, node, linknode FROM linknodes \
+             WHERE kind != ?1 AND (path = ?2 OR (path >= ?3 AND path < ?4))",
+            &[&KIND_ROOT, &prefix, &lower, &upper],
+        )
+    }
+
+    /// All the entries whose linknode is `linknode`, which are the file and tree revisions
+    /// introduced by that changeset.
+    pub fn find_by_linknode(&self, linknode: &NodeHash) -> BoxStream<LinknodeData, LinknodeError> {
+        self.query(
+            "SELECT kind, path, node, linknode FROM linknodes WHERE linknode = ?1",
+            &[&linknode.as_ref()],
+        )
+    }
+
+    fn query(
+        &self,
+        sql: &str,
+        params: &[&rusqlite::types::ToSql],
+    ) -> BoxStream<LinknodeData, LinknodeError> {
+        let conn = self.conn.lock().expect("lock poisoned");
+        // Collect everything up front, so that the stream doesn't hold on to the connection.
+        let res = conn.prepare(sql)
+            .and_then(|mut stmt| {
+                let rows = stmt.query_map(params, row_to_data)?;
+                rows.collect::<::std::result::Result<Vec<_>, _>>()
+            })
+            .context(LinknodeErrorKind::StorageError)
+            .map_err(LinknodeError::from)
+            .and_then(|rows| rows.into_iter().collect::<Result<Vec<_>>>());
+        match res {
+            Ok(entries) => stream::iter_ok(entries).boxify(),
+            Err(err) => stream::once(Err(err)).boxify(),
+        }
+    }
+}
+
+fn encode_path(path: &RepoPath) -> (i64, Vec<u8>) {
+    match *path {
+        RepoPath::RootPath => (KIND_ROOT, Vec::new()),
+        RepoPath::DirectoryPath(ref path) => (KIND_DIR, path.to_vec()),
+        RepoPath::FilePath(ref path) => (KIND_FILE, path.to_vec()),
+    }
+}
+
+fn decode_path(kind: i64, path: &[u8]) -> Result<RepoPath> {
+    let path = match kind {
+        KIND_ROOT => RepoPath::root(),
+        KIND_DIR => RepoPath::dir(path)?,
+        KIND_FILE => RepoPath::file(path)?,
+        bad => return Err(failure::err_msg(format!("bad path kind {} in linknodes", bad))),
+    };
+    Ok(path)
+}
+
+fn row_to_data(row: &Row) -> Result<LinknodeData> {
+    let path: Vec<u8> = row.get(1);
+    let node: Vec<u8> = row.get(2);
+    let linknode: Vec<u8> = row.get(3);
+    Ok(LinknodeData {
+        path: decode_path(row.get(0), &path)?,
+        node: NodeHash::from_bytes(&node)?,
+        linknode: NodeHash::from_bytes(&linknode)?,
+    })
+}
+
+fn get_linknode(conn: &Connection, path: &RepoPath, node: &NodeHash) -> Result<Option<NodeHash>> {
+    let (kind, path) = encode_path(path);
+    let mut stmt = conn.prepare(
+        "SELECT linknode FROM linknodes WHERE kind = ?1 AND path = ?2 AND node = ?3",
+    ).context(LinknodeErrorKind::StorageError)?;
+    let mut rows = stmt.query(&[&kind, &path, &node.as_ref()])
+        .context(LinknodeErrorKind::StorageError)?;
+    let linknode: Option<Vec<u8>> = match rows.next() {
+        Some(row) => Some(row.context(LinknodeErrorKind::StorageError)?.get(0)),
+        None => None,
+    };
+    match linknode {
+        Some(linknode) => Ok(Some(NodeHash::from_bytes(&linknode)?)),
+        None => Ok(None),
+    }
+}
+
+fn add_data(conn: &Connection, data: LinknodeData) -> Result<()> {
+    if let Some(old_linknode) = get_linknode(conn, &data.path, &data.node)? {
+        let err = LinknodeErrorKind::AlreadyExists {
+            path: data.path,
+            node: data.node,
+            old_linknode: OptionNodeHash(Some(old_linknode)),
+            new_linknode: data.linknode,
+        };
+        return if err.is_benign_conflict() {
+            Ok(())
+        } else {
+            Err(err.into())
+        };
+    }
+    let (kind, path) = encode_path(&data.path);
+    conn.execute(
+        "INSERT INTO linknodes (kind, path, node, linknode) VALUES (?1, ?2, ?3, ?4)",
+        &[&kind, &path, &data.node.as_ref(), &data.linknode.as_ref()],
+    ).context(LinknodeErrorKind::StorageError)?;
+    Ok(())
+}
+
+impl Linknodes for SqliteLinknodes {
+    type Get = FutureResult<NodeHash, LinknodeError>;
+    type Effect = FutureResult<(), LinknodeError>;
+
+    fn add(&self, path: RepoPath, node: &NodeHash, linknode: &NodeHash) -> Self::Effect {
+        let conn = self.conn.lock().expect("lock poisoned");
+        let data = LinknodeData {
+            path,
+            node: *node,
+            linknode: *linknode,
+        };
+        add_data(&conn, data).into_future()
+    }
+
+    fn get(&self, path: RepoPath, node: &NodeHash) -> Self::Get {
+        let conn = self.conn.lock().expect("lock poisoned");
+        let res = match get_linknode(&conn, &path, node) {
+            Ok(Some(linknode)) => Ok(linknode),
+            Ok(None) => Err(LinknodeErrorKind::NotFound(path, *node).into()),
+            Err(err) => Err(err),
+        };
+        res.into_future()
+    }
+
+    fn upsert(&self, path: RepoPath, node: &NodeHash, linknode: &NodeHash) -> Self::Effect {
+        let conn = self.conn.lock().expect("lock poisoned");
+        let (kind, path) = encode_path(&path);
+        conn.execute(
+            "INSERT OR REPLACE INTO linknodes (kind, path, node, linknode) \
+             VALUES (?1, ?2, ?3, ?4)",
+            &[&kind, &path, &node.as_ref(), &linknode.as_ref()],
+        ).map(|_| ())
+            .context(LinknodeErrorKind::StorageError)
+            .map_err(LinknodeError::from)
+            .into_future()
+    }
+
+    fn iter(&self) -> BoxStream<LinknodeData, LinknodeError> {
+        self.query("SELECT kind, path, node, linknode FROM linknodes", &[])
+    }
+
+    fn count_for_path(&self, path: RepoPath) -> BoxFuture<usize, LinknodeError> {
+        let conn = self.conn.lock().expect("lock poisoned");
+        let (kind, path) = encode_path(&path);
+        conn.query_row(
+            "SELECT COUNT(*) FROM linknodes WHERE kind = ?1 AND path = ?2",
+            &[&kind, &path],
+            |row| row.get::<_, i64>(0) as usize,
+        ).context(LinknodeErrorKind::StorageError)
+            .map_err(LinknodeError::from)
+            .into_future()
+            .boxify()
+    }
+}
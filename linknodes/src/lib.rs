@@ -6,22 +6,28 @@
 
 #![deny(warnings)]
 
+extern crate bincode;
 #[macro_use]
 extern crate failure_derive;
 extern crate failure_ext as failure;
 extern crate futures;
+extern crate futures_ext;
 extern crate serde;
 #[macro_use]
 extern crate serde_derive;
+extern crate serde_json;
 
 extern crate mercurial_types;
 
 use std::fmt;
+use std::io::{BufRead, Write};
 use std::sync::Arc;
 
-use futures::{Future, IntoFuture};
+use futures::{future, stream, Future, IntoFuture, Stream};
 use futures::future::FutureResult;
 
+use futures_ext::{BoxFuture, BoxStream, FutureExt, StreamExt};
+
 use mercurial_types::{NodeHash, RepoPath};
 
 mod errors {
@@ -54,6 +60,21 @@ mod errors {
         },
         #[fail(display = "linknode storage error")] StorageError,
     }
+
+    impl ErrorKind {
+        /// Whether this is an `AlreadyExists` for an entry that already had the linknode being
+        /// added, so the store is in the state the caller wanted anyway.
+        pub fn is_benign_conflict(&self) -> bool {
+            match self {
+                &ErrorKind::AlreadyExists {
+                    ref old_linknode,
+                    ref new_linknode,
+                    ..
+                } => old_linknode.0 == Some(*new_linknode),
+                _ => false,
+            }
+        }
+    }
 }
 
 pub use errors::*;
@@ -70,17 +91,108 @@ pub trait Linknodes: Send + Sync + 'static {
     type Get: Future<Item = NodeHash, Error = Error> + Send + 'static;
     type Effect: Future<Item = (), Error = Error> + Send + 'static;
 
+    /// Adding an entry that's already there with the same linknode succeeds. A different
+    /// linknode fails with `AlreadyExists`.
     fn add(&self, path: RepoPath, node: &NodeHash, linknode: &NodeHash) -> Self::Effect;
     fn get(&self, path: RepoPath, node: &NodeHash) -> Self::Get;
+
+    /// Like `get`, but resolves to `None` instead of failing with `NotFound` if there's no
+    /// linknode for this path and node. Other errors, such as `StorageError`, are passed through.
+    fn try_get(&self, path: RepoPath, node: &NodeHash) -> BoxFuture<Option<NodeHash>, Error> {
+        self.get(path, node)
+            .then(|res| match res {
+                Ok(linknode) => Ok(Some(linknode)),
+                Err(err) => match err.downcast::<ErrorKind>() {
+                    Ok(ErrorKind::NotFound(..)) => Ok(None),
+                    Ok(kind) => Err(kind.into()),
+                    Err(err) => Err(err),
+                },
+            })
+            .boxify()
+    }
+
+    /// Whether there's a linknode for this path and node, for callers that don't need the
+    /// linknode itself. The default goes through `try_get`, so backends that can check for an
+    /// entry more cheaply than fetching it should override it.
+    fn contains(&self, path: RepoPath, node: &NodeHash) -> BoxFuture<bool, Error> {
+        self.try_get(path, node)
+            .map(|linknode| linknode.is_some())
+            .boxify()
+    }
+
+    /// Like `get`, but resolves to `default` instead of failing with `NotFound` if there's no
+    /// linknode for this path and node. Other errors are passed through.
+    fn get_or(
+        &self,
+        path: RepoPath,
+        node: &NodeHash,
+        default: NodeHash,
+    ) -> BoxFuture<NodeHash, Error> {
+        self.try_get(path, node)
+            .map(move |linknode| linknode.unwrap_or(default))
+            .boxify()
+    }
+
+    /// Like `add`, but replaces any existing linknode for this path and node instead of failing
+    /// with `AlreadyExists`. Intended for re-import tools that need to fix up stale mappings.
+    fn upsert(&self, path: RepoPath, node: &NodeHash, linknode: &NodeHash) -> Self::Effect;
+
+    /// Iterate over all the entries in the store, in no particular order.
+    fn iter(&self) -> BoxStream<LinknodeData, Error>;
+
+    /// Like `iter`, but sorted by path and then node, so that two stores with the same contents
+    /// always yield them the same way. Every entry is read into memory and sorted before the
+    /// first one is yielded.
+    fn iter_sorted(&self) -> BoxStream<LinknodeData, Error> {
+        self.iter()
+            .collect()
+            .map(|mut entries| {
+                entries.sort_by_key(|data| (data.path.serialize(), data.node));
+                stream::iter_ok(entries)
+            })
+            .flatten_stream()
+            .boxify()
+    }
+
+    /// The number of node entries stored for `path`. The default scans the whole store, so
+    /// backends with an index by path should override it.
+    fn count_for_path(&self, path: RepoPath) -> BoxFuture<usize, Error> {
+        self.iter()
+            .filter(move |data| data.path == path)
+            .fold(0, |count, _| Ok::<_, Error>(count + 1))
+            .boxify()
+    }
+
+    /// All the entries in the store, bincode-encoded as a list of `LinknodeData` the same way
+    /// the file stores encode each entry. The entries are in `iter_sorted` order, so two stores
+    /// with the same contents have the same snapshot. Load one into a store with `restore`.
+    fn snapshot(&self) -> BoxFuture<Vec<u8>, Error> {
+        self.iter_sorted()
+            .collect()
+            .and_then(|entries| Ok(bincode::serialize(&entries, bincode::Infinite)?))
+            .boxify()
+    }
 }
 
 /// A linknodes implementation that never stores anything.
-pub struct NoopLinknodes;
+pub struct NoopLinknodes {
+    // What `get` resolves to for every path and node, if anything.
+    found: Option<NodeHash>,
+}
 
 impl NoopLinknodes {
     #[inline]
     pub fn new() -> Self {
-        NoopLinknodes
+        NoopLinknodes { found: None }
+    }
+
+    /// A store whose `get` finds `linknode` for any path and node, for testing code that needs
+    /// lookups to succeed. Adds are still dropped.
+    #[inline]
+    pub fn always_found(linknode: NodeHash) -> Self {
+        NoopLinknodes {
+            found: Some(linknode),
+        }
     }
 }
 
@@ -90,13 +202,33 @@ impl Linknodes for NoopLinknodes {
 
     #[inline]
     fn get(&self, path: RepoPath, node: &NodeHash) -> Self::Get {
-        Err(ErrorKind::NotFound(path, *node).into()).into_future()
+        let res = match self.found {
+            Some(linknode) => Ok(linknode),
+            None => Err(ErrorKind::NotFound(path, *node).into()),
+        };
+        res.into_future()
+    }
+
+    /// As with `get`, only stores made with `always_found` contain anything.
+    #[inline]
+    fn contains(&self, _path: RepoPath, _node: &NodeHash) -> BoxFuture<bool, Error> {
+        future::ok(self.found.is_some()).boxify()
     }
 
     #[inline]
     fn add(&self, _path: RepoPath, _node: &NodeHash, _linknode: &NodeHash) -> Self::Effect {
         Ok(()).into_future()
     }
+
+    #[inline]
+    fn upsert(&self, _path: RepoPath, _node: &NodeHash, _linknode: &NodeHash) -> Self::Effect {
+        Ok(()).into_future()
+    }
+
+    #[inline]
+    fn iter(&self) -> BoxStream<LinknodeData, Error> {
+        stream::empty().boxify()
+    }
 }
 
 impl<L> Linknodes for Arc<L>
@@ -111,17 +243,164 @@ where
         (**self).get(path, node)
     }
 
+    #[inline]
+    fn try_get(&self, path: RepoPath, node: &NodeHash) -> BoxFuture<Option<NodeHash>, Error> {
+        (**self).try_get(path, node)
+    }
+
+    #[inline]
+    fn contains(&self, path: RepoPath, node: &NodeHash) -> BoxFuture<bool, Error> {
+        (**self).contains(path, node)
+    }
+
+    #[inline]
+    fn get_or(
+        &self,
+        path: RepoPath,
+        node: &NodeHash,
+        default: NodeHash,
+    ) -> BoxFuture<NodeHash, Error> {
+        (**self).get_or(path, node, default)
+    }
+
     #[inline]
     fn add(&self, path: RepoPath, node: &NodeHash, linknode: &NodeHash) -> Self::Effect {
         (**self).add(path, node, linknode)
     }
+
+    #[inline]
+    fn upsert(&self, path: RepoPath, node: &NodeHash, linknode: &NodeHash) -> Self::Effect {
+        (**self).upsert(path, node, linknode)
+    }
+
+    #[inline]
+    fn iter(&self) -> BoxStream<LinknodeData, Error> {
+        (**self).iter()
+    }
+
+    #[inline]
+    fn iter_sorted(&self) -> BoxStream<LinknodeData, Error> {
+        (**self).iter_sorted()
+    }
+
+    #[inline]
+    fn count_for_path(&self, path: RepoPath) -> BoxFuture<usize, Error> {
+        (**self).count_for_path(path)
+    }
+
+    fn snapshot(&self) -> BoxFuture<Vec<u8>, Error> {
+        (**self).snapshot()
+    }
 }
 
 /// A struct representing all the data associated with a linknode. This definition is here so that
 /// it can be shared across memory-based and file-based linknodes.
-#[derive(Clone, Serialize, Deserialize)]
+#[derive(Clone, Debug, Eq, Hash, PartialEq, Serialize, Deserialize)]
 pub struct LinknodeData {
     pub path: RepoPath,
     pub node: NodeHash,
     pub linknode: NodeHash,
 }
+
+/// Summary of what `merge_linknodes` did.
+#[derive(Clone, Default)]
+pub struct MergeReport {
+    /// Number of entries copied from the source store into the destination.
+    pub added: usize,
+    /// Number of entries already present in the destination with the same linknode.
+    pub skipped_identical: usize,
+    /// Entries from the source store whose linknode disagrees with the destination's. These are
+    /// left as they are in the destination.
+    pub conflicts: Vec<LinknodeData>,
+}
+
+/// Fold all the entries in `src` into `dst`.
+///
+/// Conflicting entries (same path and node, but a different linknode) are collected in the
+/// returned report and don't stop the merge, unless `strict` is set, in which case the first
+/// conflict fails the merge with `ErrorKind::AlreadyExists`.
+pub fn merge_linknodes<A, B>(dst: Arc<A>, src: &B, strict: bool) -> BoxFuture<MergeReport, Error>
+where
+    A: Linknodes,
+    B: Linknodes,
+{
+    src.iter()
+        .fold(MergeReport::default(), move |mut report, data| {
+            let dst_add = dst.clone();
+            dst.try_get(data.path.clone(), &data.node)
+                .and_then(move |existing| match existing {
+                    Some(existing) if existing == data.linknode => {
+                        report.skipped_identical += 1;
+                        future::ok(report).boxify()
+                    }
+                    Some(existing) => {
+                        if strict {
+                            let err = ErrorKind::AlreadyExists {
+                                path: data.path,
+                                node: data.node,
+                                old_linknode: OptionNodeHash(Some(existing)),
+                                new_linknode: data.linknode,
+                            };
+                            return future::err(err.into()).boxify();
+                        }
+                        report.conflicts.push(data);
+                        future::ok(report).boxify()
+                    }
+                    None => dst_add
+                        .add(data.path, &data.node, &data.linknode)
+                        .map(move |()| {
+                            report.added += 1;
+                            report
+                        })
+                        .boxify(),
+                })
+        })
+        .boxify()
+}
+
+/// Add all the entries in a `snapshot` to `store`. As with `add`, entries that are already there
+/// with a different linknode fail the restore, so this is meant for empty stores.
+pub fn restore<L>(store: &L, snapshot: &[u8]) -> BoxFuture<(), Error>
+where
+    L: Linknodes,
+{
+    let entries: Vec<LinknodeData> = match bincode::deserialize(snapshot) {
+        Ok(entries) => entries,
+        Err(err) => return future::err(err.into()).boxify(),
+    };
+    let adds: Vec<_> = entries
+        .into_iter()
+        .map(|data| store.add(data.path, &data.node, &data.linknode))
+        .collect();
+    future::join_all(adds).map(|_| ()).boxify()
+}
+
+/// Write all the entries in `linknodes` to `writer` as newline-delimited JSON, one
+/// `LinknodeData` per line. Resolves to the writer and the number of entries written.
+pub fn dump_ndjson<L, W>(linknodes: &L, writer: W) -> BoxFuture<(W, usize), Error>
+where
+    L: Linknodes,
+    W: Write + Send + 'static,
+{
+    linknodes
+        .iter()
+        .fold((writer, 0), |(mut writer, count), data| {
+            serde_json::to_writer(&mut writer, &data)?;
+            writer.write_all(b"\n")?;
+            Ok::<_, Error>((writer, count + 1))
+        })
+        .boxify()
+}
+
+/// Parse a dump written by `dump_ndjson`, one entry at a time. Blank lines are skipped.
+pub fn read_ndjson<R>(reader: R) -> Box<Iterator<Item = Result<LinknodeData>>>
+where
+    R: BufRead + 'static,
+{
+    let entries = reader.lines().filter_map(|line| match line {
+        Ok(ref line) if line.trim().is_empty() => None,
+        Ok(line) => Some(serde_json::from_str(&line).map_err(Error::from)),
+        Err(err) => Some(Err(err.into())),
+    });
+    Box::new(entries)
+}
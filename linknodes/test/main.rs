@@ -14,19 +14,29 @@ extern crate futures;
 extern crate tempdir;
 
 extern crate filelinknodes;
+extern crate futures_ext;
 extern crate linknodes;
 extern crate memlinknodes;
 extern crate mercurial_types;
 extern crate mercurial_types_mocks;
+extern crate sqlitelinknodes;
 
-use futures::Future;
+use std::collections::HashSet;
+use std::io::Cursor;
+use std::sync::Arc;
+
+use futures::{stream, Future, IntoFuture, Stream};
+use futures::future::FutureResult;
 use tempdir::TempDir;
 
-use filelinknodes::FileLinknodes;
-use linknodes::{ErrorKind, Linknodes, OptionNodeHash};
+use filelinknodes::{FileLinknodes, ShardedFileLinknodes};
+use futures_ext::{BoxStream, StreamExt};
+use linknodes::{dump_ndjson, merge_linknodes, restore, Error, ErrorKind, LinknodeData, Linknodes,
+                NoopLinknodes, OptionNodeHash};
 use memlinknodes::MemLinknodes;
-use mercurial_types::RepoPath;
+use mercurial_types::{MPath, NodeHash, RepoPath};
 use mercurial_types_mocks::nodehash::*;
+use sqlitelinknodes::SqliteLinknodes;
 
 fn add_and_get<L: Linknodes>(linknodes: L) {
     let path = RepoPath::file("abc".as_ref()).unwrap();
@@ -63,6 +73,32 @@ fn add_and_get<L: Linknodes>(linknodes: L) {
     assert_eq!(linknodes.get(path, &AS_HASH).wait().unwrap(), TWOS_HASH);
 }
 
+fn add_identical<L: Linknodes>(linknodes: L) {
+    let path = RepoPath::file("abc".as_ref()).unwrap();
+    linknodes
+        .add(path.clone(), &NULL_HASH, &ONES_HASH)
+        .wait()
+        .unwrap();
+
+    // Adding the same mapping again is fine.
+    linknodes
+        .add(path.clone(), &NULL_HASH, &ONES_HASH)
+        .wait()
+        .unwrap();
+
+    // A different linknode for the same entry is a genuine conflict.
+    let err = linknodes
+        .add(path.clone(), &NULL_HASH, &TWOS_HASH)
+        .wait()
+        .unwrap_err()
+        .downcast::<ErrorKind>()
+        .unwrap();
+    assert_matches!(err, ErrorKind::AlreadyExists { .. });
+    assert!(!err.is_benign_conflict());
+
+    assert_eq!(linknodes.get(path, &NULL_HASH).wait().unwrap(), ONES_HASH);
+}
+
 fn not_found<L: Linknodes>(linknodes: L) {
     let path = RepoPath::dir("abc".as_ref()).unwrap();
     assert_matches!(
@@ -75,6 +111,161 @@ fn not_found<L: Linknodes>(linknodes: L) {
     );
 }
 
+fn get_or<L: Linknodes>(linknodes: L) {
+    let path = RepoPath::file("abc".as_ref()).unwrap();
+    linknodes
+        .add(path.clone(), &NULL_HASH, &ONES_HASH)
+        .wait()
+        .unwrap();
+
+    assert_eq!(
+        linknodes
+            .get_or(path.clone(), &NULL_HASH, TWOS_HASH)
+            .wait()
+            .unwrap(),
+        ONES_HASH
+    );
+    assert_eq!(
+        linknodes.get_or(path, &AS_HASH, TWOS_HASH).wait().unwrap(),
+        TWOS_HASH
+    );
+}
+
+fn try_get<L: Linknodes>(linknodes: L) {
+    let path = RepoPath::file("abc".as_ref()).unwrap();
+    linknodes
+        .add(path.clone(), &NULL_HASH, &ONES_HASH)
+        .wait()
+        .unwrap();
+
+    assert_eq!(
+        linknodes.try_get(path.clone(), &NULL_HASH).wait().unwrap(),
+        Some(ONES_HASH)
+    );
+    assert_eq!(linknodes.try_get(path, &AS_HASH).wait().unwrap(), None);
+}
+
+fn contains<L: Linknodes>(linknodes: L) {
+    let path = RepoPath::file("abc".as_ref()).unwrap();
+    assert!(!linknodes.contains(path.clone(), &NULL_HASH).wait().unwrap());
+    linknodes
+        .add(path.clone(), &NULL_HASH, &ONES_HASH)
+        .wait()
+        .unwrap();
+
+    assert!(linknodes.contains(path.clone(), &NULL_HASH).wait().unwrap());
+    assert!(!linknodes.contains(path, &AS_HASH).wait().unwrap());
+    let other = RepoPath::file("def".as_ref()).unwrap();
+    assert!(!linknodes.contains(other, &NULL_HASH).wait().unwrap());
+}
+
+fn upsert<L: Linknodes>(linknodes: L) {
+    let path = RepoPath::file("abc".as_ref()).unwrap();
+    linknodes
+        .add(path.clone(), &NULL_HASH, &ONES_HASH)
+        .wait()
+        .unwrap();
+
+    // Unlike add, upsert replaces the existing linknode rather than erroring out.
+    linknodes
+        .upsert(path.clone(), &NULL_HASH, &TWOS_HASH)
+        .wait()
+        .unwrap();
+    assert_eq!(
+        linknodes.get(path.clone(), &NULL_HASH).wait().unwrap(),
+        TWOS_HASH
+    );
+
+    // upsert of a mapping that doesn't exist yet behaves like add.
+    linknodes
+        .upsert(path.clone(), &AS_HASH, &THREES_HASH)
+        .wait()
+        .unwrap();
+    assert_eq!(linknodes.get(path, &AS_HASH).wait().unwrap(), THREES_HASH);
+}
+
+fn iter<L: Linknodes>(linknodes: L) {
+    let path = RepoPath::file("abc".as_ref()).unwrap();
+    linknodes
+        .add(path.clone(), &NULL_HASH, &ONES_HASH)
+        .wait()
+        .unwrap();
+    linknodes
+        .add(RepoPath::root(), &AS_HASH, &TWOS_HASH)
+        .wait()
+        .unwrap();
+
+    let mut entries: Vec<_> = linknodes
+        .iter()
+        .map(|data| (data.path, data.node, data.linknode))
+        .collect()
+        .wait()
+        .unwrap();
+    entries.sort_by_key(|&(_, node, _)| node);
+    assert_eq!(
+        entries,
+        vec![
+            (path, NULL_HASH, ONES_HASH),
+            (RepoPath::root(), AS_HASH, TWOS_HASH),
+        ]
+    );
+}
+
+fn iter_sorted<L: Linknodes>(linknodes: L) {
+    let abc = RepoPath::file("abc".as_ref()).unwrap();
+    let abd = RepoPath::file("abd".as_ref()).unwrap();
+    let dir = RepoPath::dir("abc".as_ref()).unwrap();
+    // The root comes first, then directories, then files.
+    let sorted = vec![
+        (RepoPath::root(), AS_HASH, ONES_HASH),
+        (dir, BS_HASH, TWOS_HASH),
+        (abc.clone(), NULL_HASH, ONES_HASH),
+        (abc, AS_HASH, TWOS_HASH),
+        (abd, NULL_HASH, THREES_HASH),
+    ];
+
+    let other = MemLinknodes::new();
+    for &(ref path, node, linknode) in &sorted {
+        other.add(path.clone(), &node, &linknode).wait().unwrap();
+    }
+    for &(ref path, node, linknode) in sorted.iter().rev() {
+        linknodes.add(path.clone(), &node, &linknode).wait().unwrap();
+    }
+
+    assert_eq!(sorted_entries(&linknodes), sorted);
+    assert_eq!(sorted_entries(&other), sorted);
+}
+
+fn sorted_entries<L: Linknodes>(linknodes: &L) -> Vec<(RepoPath, NodeHash, NodeHash)> {
+    linknodes
+        .iter_sorted()
+        .map(|data| (data.path, data.node, data.linknode))
+        .collect()
+        .wait()
+        .unwrap()
+}
+
+fn count_for_path<L: Linknodes>(linknodes: L) {
+    let abc = RepoPath::file("abc".as_ref()).unwrap();
+    let def = RepoPath::file("dir/def".as_ref()).unwrap();
+    for node in &[NULL_HASH, AS_HASH, BS_HASH] {
+        linknodes.add(abc.clone(), node, &ONES_HASH).wait().unwrap();
+    }
+    linknodes
+        .add(def.clone(), &NULL_HASH, &TWOS_HASH)
+        .wait()
+        .unwrap();
+    // Same name, but a directory rather than a file.
+    linknodes
+        .add(RepoPath::dir("abc".as_ref()).unwrap(), &CS_HASH, &TWOS_HASH)
+        .wait()
+        .unwrap();
+
+    assert_eq!(linknodes.count_for_path(abc).wait().unwrap(), 3);
+    assert_eq!(linknodes.count_for_path(def).wait().unwrap(), 1);
+    assert_eq!(linknodes.count_for_path(RepoPath::root()).wait().unwrap(), 0);
+}
+
 fn persistence<F, L>(mut new_linknodes: F)
 where
     F: FnMut() -> L,
@@ -108,12 +299,60 @@ macro_rules! linknodes_test_impl {
                 add_and_get($new_cb(&state));
             }
 
+            #[test]
+            fn test_add_identical() {
+                let state = $state;
+                add_identical($new_cb(&state));
+            }
+
             #[test]
             fn test_not_found() {
                 let state = $state;
                 not_found($new_cb(&state));
             }
 
+            #[test]
+            fn test_get_or() {
+                let state = $state;
+                get_or($new_cb(&state));
+            }
+
+            #[test]
+            fn test_try_get() {
+                let state = $state;
+                try_get($new_cb(&state));
+            }
+
+            #[test]
+            fn test_contains() {
+                let state = $state;
+                contains($new_cb(&state));
+            }
+
+            #[test]
+            fn test_iter() {
+                let state = $state;
+                iter($new_cb(&state));
+            }
+
+            #[test]
+            fn test_iter_sorted() {
+                let state = $state;
+                iter_sorted($new_cb(&state));
+            }
+
+            #[test]
+            fn test_upsert() {
+                let state = $state;
+                upsert($new_cb(&state));
+            }
+
+            #[test]
+            fn test_count_for_path() {
+                let state = $state;
+                count_for_path($new_cb(&state));
+            }
+
             #[test]
             fn test_persistence() {
                 // Not all linknode implementations support persistence. There doesn't seem to be
@@ -143,3 +382,366 @@ linknodes_test_impl! {
         persistent: true,
     }
 }
+
+linknodes_test_impl! {
+    shardedfilelinknodes_test => {
+        state: TempDir::new("shardedfilelinknodes_test").unwrap(),
+        new: |dir: &TempDir| ShardedFileLinknodes::create(dir.as_ref(), 4).unwrap(),
+        persistent: true,
+    }
+}
+
+linknodes_test_impl! {
+    sqlitelinknodes_test => {
+        state: TempDir::new("sqlitelinknodes_test").unwrap(),
+        new: |dir: &TempDir| SqliteLinknodes::open(dir.as_ref().join("linknodes.sqlite")).unwrap(),
+        persistent: true,
+    }
+}
+
+#[test]
+fn sharded_routing() {
+    let dir = TempDir::new("sharded_routing").unwrap();
+    let linknodes = ShardedFileLinknodes::create(dir.as_ref(), 8).unwrap();
+
+    // Everything under the same top-level directory goes to the same shard.
+    let first = RepoPath::file("dir/a".as_ref()).unwrap();
+    let second = RepoPath::dir("dir/sub".as_ref()).unwrap();
+    let shard = linknodes.shard_for(&first);
+    assert_eq!(linknodes.shard_for(&second), shard);
+    assert_eq!(
+        linknodes.shard_for(&RepoPath::dir("dir".as_ref()).unwrap()),
+        shard
+    );
+
+    linknodes.add(first.clone(), &NULL_HASH, &ONES_HASH).wait().unwrap();
+    linknodes.add(second.clone(), &AS_HASH, &TWOS_HASH).wait().unwrap();
+    linknodes
+        .add(RepoPath::root(), &NULL_HASH, &THREES_HASH)
+        .wait()
+        .unwrap();
+
+    // The entries are only stored in the shard they were routed to.
+    let shard_dir = dir.as_ref().join(format!("shard-{}", shard));
+    let shard_store = FileLinknodes::open(shard_dir).unwrap();
+    assert_eq!(shard_store.get(first.clone(), &NULL_HASH).wait().unwrap(), ONES_HASH);
+    for idx in (0..8).filter(|idx| *idx != shard) {
+        let other = FileLinknodes::open(dir.as_ref().join(format!("shard-{}", idx))).unwrap();
+        assert_matches!(
+            other
+                .get(first.clone(), &NULL_HASH)
+                .wait()
+                .unwrap_err()
+                .downcast::<ErrorKind>()
+                .unwrap(),
+            ErrorKind::NotFound(..)
+        );
+    }
+
+    // Reopening with the same shard count routes lookups the same way.
+    let reopened = ShardedFileLinknodes::open(dir.as_ref(), 8).unwrap();
+    assert_eq!(reopened.get(first, &NULL_HASH).wait().unwrap(), ONES_HASH);
+    assert_eq!(reopened.get(second, &AS_HASH).wait().unwrap(), TWOS_HASH);
+    assert_eq!(
+        reopened.get(RepoPath::root(), &NULL_HASH).wait().unwrap(),
+        THREES_HASH
+    );
+    assert_eq!(reopened.iter().collect().wait().unwrap().len(), 3);
+}
+
+#[test]
+fn merge() {
+    let path = RepoPath::file("abc".as_ref()).unwrap();
+    let dst = Arc::new(MemLinknodes::new());
+    let src = MemLinknodes::new();
+
+    // Identical in both stores.
+    dst.add(path.clone(), &NULL_HASH, &ONES_HASH).wait().unwrap();
+    src.add(path.clone(), &NULL_HASH, &ONES_HASH).wait().unwrap();
+    // Conflicting linknodes.
+    dst.add(path.clone(), &AS_HASH, &TWOS_HASH).wait().unwrap();
+    src.add(path.clone(), &AS_HASH, &THREES_HASH).wait().unwrap();
+    // Only in the source.
+    src.add(RepoPath::root(), &NULL_HASH, &TWOS_HASH)
+        .wait()
+        .unwrap();
+
+    let report = merge_linknodes(dst.clone(), &src, false).wait().unwrap();
+    assert_eq!(report.added, 1);
+    assert_eq!(report.skipped_identical, 1);
+    assert_eq!(report.conflicts.len(), 1);
+    assert_eq!(report.conflicts[0].node, AS_HASH);
+    assert_eq!(report.conflicts[0].linknode, THREES_HASH);
+
+    // The conflict leaves the destination alone, and the new entry was copied over.
+    assert_eq!(dst.get(path, &AS_HASH).wait().unwrap(), TWOS_HASH);
+    assert_eq!(
+        dst.get(RepoPath::root(), &NULL_HASH).wait().unwrap(),
+        TWOS_HASH
+    );
+
+    // In strict mode the conflict is an error.
+    assert_matches!(
+        merge_linknodes(dst, &src, true)
+            .wait()
+            .unwrap_err()
+            .downcast::<ErrorKind>()
+            .unwrap(),
+        ErrorKind::AlreadyExists { .. }
+    );
+}
+
+#[test]
+fn snapshot_round_trip() {
+    let mem = MemLinknodes::new();
+    let file = RepoPath::file("dir/abc".as_ref()).unwrap();
+    mem.add(file.clone(), &NULL_HASH, &ONES_HASH).wait().unwrap();
+    mem.add(file, &AS_HASH, &TWOS_HASH).wait().unwrap();
+    mem.add(RepoPath::dir("dir".as_ref()).unwrap(), &BS_HASH, &ONES_HASH)
+        .wait()
+        .unwrap();
+    mem.add(RepoPath::root(), &CS_HASH, &THREES_HASH)
+        .wait()
+        .unwrap();
+    let snapshot = mem.snapshot().wait().unwrap();
+
+    let tmp = TempDir::new("linknodes_snapshot_round_trip").unwrap();
+    let on_disk = FileLinknodes::open(tmp.path()).unwrap();
+    restore(&on_disk, &snapshot).wait().unwrap();
+    assert_eq!(on_disk.iter().collect().wait().unwrap().len(), 4);
+    assert_eq!(on_disk.snapshot().wait().unwrap(), snapshot);
+
+    // And back again.
+    let restored = MemLinknodes::new();
+    restore(&restored, &on_disk.snapshot().wait().unwrap())
+        .wait()
+        .unwrap();
+    assert_eq!(restored.snapshot().wait().unwrap(), snapshot);
+}
+
+#[test]
+fn ndjson_load() {
+    let src = MemLinknodes::new();
+    let file = RepoPath::file("dir/abc".as_ref()).unwrap();
+    let dir = RepoPath::dir("dir".as_ref()).unwrap();
+    src.add(file.clone(), &NULL_HASH, &ONES_HASH).wait().unwrap();
+    src.add(file.clone(), &AS_HASH, &TWOS_HASH).wait().unwrap();
+    src.add(dir.clone(), &BS_HASH, &ONES_HASH).wait().unwrap();
+    src.add(RepoPath::root(), &CS_HASH, &THREES_HASH)
+        .wait()
+        .unwrap();
+
+    let (dump, count) = dump_ndjson(&src, Vec::new()).wait().unwrap();
+    assert_eq!(count, 4);
+
+    let tmp = TempDir::new("linknodes_ndjson_load").unwrap();
+    let dst = FileLinknodes::load_ndjson(tmp.path(), Cursor::new(dump)).unwrap();
+    assert_eq!(dst.get(file.clone(), &NULL_HASH).wait().unwrap(), ONES_HASH);
+    assert_eq!(dst.get(file, &AS_HASH).wait().unwrap(), TWOS_HASH);
+    assert_eq!(dst.get(dir, &BS_HASH).wait().unwrap(), ONES_HASH);
+    assert_eq!(
+        dst.get(RepoPath::root(), &CS_HASH).wait().unwrap(),
+        THREES_HASH
+    );
+    assert_eq!(dst.iter().collect().wait().unwrap().len(), 4);
+}
+
+#[test]
+fn ndjson_load_conflict() {
+    let a = MemLinknodes::new();
+    a.add(RepoPath::root(), &NULL_HASH, &ONES_HASH)
+        .wait()
+        .unwrap();
+    let b = MemLinknodes::new();
+    b.add(RepoPath::root(), &NULL_HASH, &TWOS_HASH)
+        .wait()
+        .unwrap();
+
+    let (dump, _) = dump_ndjson(&a, Vec::new()).wait().unwrap();
+    // An identical duplicate is fine.
+    let (dump, _) = dump_ndjson(&a, dump).wait().unwrap();
+    let tmp = TempDir::new("linknodes_ndjson_load_conflict").unwrap();
+    assert!(FileLinknodes::load_ndjson(tmp.path().join("ok"), Cursor::new(dump.clone())).is_ok());
+
+    let (dump, _) = dump_ndjson(&b, dump).wait().unwrap();
+    assert_matches!(
+        FileLinknodes::load_ndjson(tmp.path().join("conflict"), Cursor::new(dump))
+            .unwrap_err()
+            .downcast::<ErrorKind>()
+            .unwrap(),
+        ErrorKind::AlreadyExists { .. }
+    );
+}
+
+#[test]
+fn linknode_data_set() {
+    let data = |path: &str, node: NodeHash, linknode: NodeHash| LinknodeData {
+        path: RepoPath::file(path.as_ref()).unwrap(),
+        node,
+        linknode,
+    };
+    let mut set = HashSet::new();
+    set.insert(data("abc", ONES_HASH, TWOS_HASH));
+    set.insert(data("abc", ONES_HASH, TWOS_HASH));
+    // Differing in just the linknode, or just the path, makes for a different entry.
+    set.insert(data("abc", ONES_HASH, THREES_HASH));
+    set.insert(data("def", ONES_HASH, TWOS_HASH));
+    assert_eq!(set.len(), 3);
+    assert!(set.contains(&data("abc", ONES_HASH, TWOS_HASH)));
+}
+
+#[test]
+fn sqlite_prefix_query() {
+    let linknodes = SqliteLinknodes::open_in_memory().unwrap();
+    let dir = RepoPath::dir("dir".as_ref()).unwrap();
+    let file = RepoPath::file("dir/sub/file".as_ref()).unwrap();
+    linknodes.add(dir.clone(), &NULL_HASH, &ONES_HASH).wait().unwrap();
+    linknodes.add(file.clone(), &AS_HASH, &ONES_HASH).wait().unwrap();
+    // Neither a sibling that shares the prefix nor the root count as being under "dir".
+    linknodes
+        .add(RepoPath::file("dirx/file".as_ref()).unwrap(), &BS_HASH, &TWOS_HASH)
+        .wait()
+        .unwrap();
+    linknodes
+        .add(RepoPath::root(), &NULL_HASH, &TWOS_HASH)
+        .wait()
+        .unwrap();
+
+    let paths = linknodes
+        .iter_prefix(&MPath::new("dir").unwrap())
+        .map(|data| data.path)
+        .collect()
+        .wait()
+        .unwrap();
+    assert_eq!(paths.len(), 2);
+    assert!(paths.contains(&dir));
+    assert!(paths.contains(&file));
+}
+
+#[test]
+fn sqlite_find_by_linknode() {
+    let linknodes = SqliteLinknodes::open_in_memory().unwrap();
+    let first = RepoPath::file("abc".as_ref()).unwrap();
+    let second = RepoPath::file("def".as_ref()).unwrap();
+    linknodes.add(first.clone(), &NULL_HASH, &ONES_HASH).wait().unwrap();
+    linknodes.add(second.clone(), &AS_HASH, &ONES_HASH).wait().unwrap();
+    linknodes.add(first, &AS_HASH, &TWOS_HASH).wait().unwrap();
+
+    let found = linknodes.find_by_linknode(&TWOS_HASH).collect().wait().unwrap();
+    assert_eq!(found.len(), 1);
+    assert_eq!(found[0].node, AS_HASH);
+    assert_eq!(
+        linknodes.find_by_linknode(&ONES_HASH).collect().wait().unwrap().len(),
+        2
+    );
+}
+
+#[test]
+fn sqlite_add_many_rollback() {
+    let linknodes = SqliteLinknodes::open_in_memory().unwrap();
+    let path = RepoPath::file("abc".as_ref()).unwrap();
+    linknodes.add(path.clone(), &NULL_HASH, &ONES_HASH).wait().unwrap();
+
+    // The second entry conflicts, so the first one isn't added either.
+    let entries = vec![
+        LinknodeData {
+            path: path.clone(),
+            node: AS_HASH,
+            linknode: TWOS_HASH,
+        },
+        LinknodeData {
+            path: path.clone(),
+            node: NULL_HASH,
+            linknode: THREES_HASH,
+        },
+    ];
+    assert_matches!(
+        linknodes
+            .add_many(entries)
+            .wait()
+            .unwrap_err()
+            .downcast::<ErrorKind>()
+            .unwrap(),
+        ErrorKind::AlreadyExists { .. }
+    );
+    assert_matches!(
+        linknodes
+            .get(path, &AS_HASH)
+            .wait()
+            .unwrap_err()
+            .downcast::<ErrorKind>()
+            .unwrap(),
+        ErrorKind::NotFound(..)
+    );
+}
+
+/// A store whose backend is always broken.
+struct BrokenLinknodes;
+
+impl Linknodes for BrokenLinknodes {
+    type Get = FutureResult<NodeHash, Error>;
+    type Effect = FutureResult<(), Error>;
+
+    fn add(&self, _path: RepoPath, _node: &NodeHash, _linknode: &NodeHash) -> Self::Effect {
+        Err(ErrorKind::StorageError.into()).into_future()
+    }
+
+    fn get(&self, _path: RepoPath, _node: &NodeHash) -> Self::Get {
+        Err(ErrorKind::StorageError.into()).into_future()
+    }
+
+    fn upsert(&self, _path: RepoPath, _node: &NodeHash, _linknode: &NodeHash) -> Self::Effect {
+        Err(ErrorKind::StorageError.into()).into_future()
+    }
+
+    fn iter(&self) -> BoxStream<LinknodeData, Error> {
+        stream::once(Err(ErrorKind::StorageError.into())).boxify()
+    }
+}
+
+#[test]
+fn try_get_storage_error() {
+    let path = RepoPath::file("abc".as_ref()).unwrap();
+    assert_matches!(
+        BrokenLinknodes
+            .try_get(path, &NULL_HASH)
+            .wait()
+            .unwrap_err()
+            .downcast::<ErrorKind>()
+            .unwrap(),
+        ErrorKind::StorageError
+    );
+}
+
+#[test]
+fn noop_always_found() {
+    let linknodes = NoopLinknodes::always_found(THREES_HASH);
+    let paths = vec![
+        RepoPath::root(),
+        RepoPath::dir("abc".as_ref()).unwrap(),
+        RepoPath::file("abc/def".as_ref()).unwrap(),
+    ];
+    for path in paths {
+        for node in &[NULL_HASH, ONES_HASH, AS_HASH] {
+            assert_eq!(
+                linknodes.get(path.clone(), node).wait().unwrap(),
+                THREES_HASH
+            );
+        }
+    }
+
+    // Adding doesn't change what's found, and the store stays empty.
+    let path = RepoPath::file("abc".as_ref()).unwrap();
+    linknodes
+        .add(path.clone(), &NULL_HASH, &ONES_HASH)
+        .wait()
+        .unwrap();
+    assert_eq!(
+        linknodes.get(path.clone(), &NULL_HASH).wait().unwrap(),
+        THREES_HASH
+    );
+    assert!(linknodes.iter().collect().wait().unwrap().is_empty());
+
+    assert!(linknodes.contains(path.clone(), &NULL_HASH).wait().unwrap());
+    assert!(!NoopLinknodes::new().contains(path, &NULL_HASH).wait().unwrap());
+}
@@ -7,6 +7,8 @@
 use bytes::Bytes;
 use futures::{Future, Stream};
 
+use changegroup;
+use changegroup::packer::Cg2Packer;
 use errors::*;
 use part_encode::PartEncodeBuilder;
 
@@ -35,3 +37,17 @@ where
 
     Ok(builder)
 }
+
+/// A version 02 changegroup part, with the chunks generated from `parts`. `parts` should have
+/// the changesets, manifests and filelogs in that order, each section with its `SectionEnd`, and
+/// finish with `End`.
+pub fn changegroup_part<S>(parts: S) -> Result<PartEncodeBuilder>
+where
+    S: Stream<Item = changegroup::Part, Error = Error> + Send + 'static,
+{
+    let mut builder = PartEncodeBuilder::mandatory("changegroup")?;
+    builder.add_mparam("version", "02")?;
+    builder.set_data_generated(Cg2Packer::new(parts));
+
+    Ok(builder)
+}
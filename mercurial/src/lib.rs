@@ -46,7 +46,9 @@ pub mod changeset;
 pub mod revlogrepo;
 pub mod file;
 pub mod symlink;
+pub mod obsmarkers;
 mod errors;
 pub use errors::*;
 
+pub use obsmarkers::Obsmarker;
 pub use revlogrepo::{RevlogManifest, RevlogRepo};
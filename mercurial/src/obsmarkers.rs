@@ -0,0 +1,196 @@
+// Copyright (c) 2004-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+//! Obsolescence markers, as stored in `.hg/store/obsstore`.
+//!
+//! Only version 1 of the obsstore format is supported. Mercurial has written it by default since
+//! 3.4, and rewrites older obsstores when `hg debugupgraderepo` is run.
+
+use mercurial_types::NodeHash;
+
+use errors::*;
+
+const FORMAT_V1: u8 = 1;
+/// Set in a marker's flags if its nodes are SHA-256 rather than SHA-1 hashes.
+const FLAG_SHA256: u16 = 2;
+/// Number of parents recorded for markers that don't record their precursor's parents.
+const PARENTS_NONE: u8 = 3;
+const NODE_SIZE: usize = 20;
+/// Size, date, timezone, flags, the successor, parent and metadata counts, and the precursor.
+const FIXED_SIZE: usize = 4 + 8 + 2 + 2 + 1 + 1 + 1 + NODE_SIZE;
+
+/// A marker recording that `precursor` was replaced by `successors`, or pruned if there are none.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Obsmarker {
+    pub precursor: NodeHash,
+    pub successors: Vec<NodeHash>,
+    /// The parents of the precursor, if they were recorded.
+    pub parents: Option<Vec<NodeHash>>,
+    pub flags: u16,
+    /// Seconds since the epoch.
+    pub time: f64,
+    /// Timezone offset in minutes.
+    pub tz: i16,
+    pub metadata: Vec<(Vec<u8>, Vec<u8>)>,
+    /// The marker exactly as it was encoded in the obsstore.
+    pub raw: Vec<u8>,
+}
+
+fn obsstore_err<S: Into<String>>(msg: S) -> Error {
+    ErrorKind::Obsstore(msg.into()).into()
+}
+
+fn be_u16(data: &[u8]) -> u16 {
+    (data[0] as u16) << 8 | data[1] as u16
+}
+
+fn be_u32(data: &[u8]) -> u32 {
+    (be_u16(data) as u32) << 16 | be_u16(&data[2..]) as u32
+}
+
+fn be_u64(data: &[u8]) -> u64 {
+    (be_u32(data) as u64) << 32 | be_u32(&data[4..]) as u64
+}
+
+/// The next `len` bytes of a marker, starting at `offset`.
+fn take<'a>(raw: &'a [u8], offset: &mut usize, len: usize) -> Result<&'a [u8]> {
+    if *offset + len > raw.len() {
+        return Err(obsstore_err("truncated marker"));
+    }
+    let field = &raw[*offset..*offset + len];
+    *offset += len;
+    Ok(field)
+}
+
+/// Parse the contents of an obsstore. An empty obsstore has no markers.
+pub fn parse_obsstore(data: &[u8]) -> Result<Vec<Obsmarker>> {
+    let mut markers = Vec::new();
+    let mut rest = match data.split_first() {
+        None => return Ok(markers),
+        Some((&FORMAT_V1, rest)) => rest,
+        Some((&version, _)) => {
+            return Err(obsstore_err(format!(
+                "unsupported obsstore version {}",
+                version
+            )))
+        }
+    };
+    while !rest.is_empty() {
+        if rest.len() < FIXED_SIZE {
+            return Err(obsstore_err("truncated marker header"));
+        }
+        let size = be_u32(rest) as usize;
+        if size < FIXED_SIZE || size > rest.len() {
+            return Err(obsstore_err(format!("bad marker size {}", size)));
+        }
+        let (raw, next) = rest.split_at(size);
+        markers.push(parse_marker(raw)?);
+        rest = next;
+    }
+    Ok(markers)
+}
+
+fn parse_marker(raw: &[u8]) -> Result<Obsmarker> {
+    let time = f64::from_bits(be_u64(&raw[4..]));
+    let tz = be_u16(&raw[12..]) as i16;
+    let flags = be_u16(&raw[14..]);
+    let (numsuc, numpar, nummeta) = (raw[16] as usize, raw[17], raw[18] as usize);
+    if flags & FLAG_SHA256 != 0 {
+        return Err(obsstore_err("markers with SHA-256 nodes are not supported"));
+    }
+
+    let mut offset = FIXED_SIZE - NODE_SIZE;
+    let precursor = NodeHash::from_bytes(take(raw, &mut offset, NODE_SIZE)?)?;
+    let mut successors = Vec::with_capacity(numsuc);
+    for _ in 0..numsuc {
+        successors.push(NodeHash::from_bytes(take(raw, &mut offset, NODE_SIZE)?)?);
+    }
+    let parents = if numpar == PARENTS_NONE {
+        None
+    } else {
+        let mut parents = Vec::with_capacity(numpar as usize);
+        for _ in 0..numpar {
+            parents.push(NodeHash::from_bytes(take(raw, &mut offset, NODE_SIZE)?)?);
+        }
+        Some(parents)
+    };
+    // The sizes of each key and value come first, then the keys and values themselves.
+    let sizes = take(raw, &mut offset, 2 * nummeta)?;
+    let mut metadata = Vec::with_capacity(nummeta);
+    for size in sizes.chunks(2) {
+        let key = take(raw, &mut offset, size[0] as usize)?.to_vec();
+        let value = take(raw, &mut offset, size[1] as usize)?.to_vec();
+        metadata.push((key, value));
+    }
+
+    Ok(Obsmarker {
+        precursor,
+        successors,
+        parents,
+        flags,
+        time,
+        tz,
+        metadata,
+        raw: raw.to_vec(),
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// The encoding of a marker from a node of all 1s to one of all 2s, with no recorded parents
+    /// and a `user` metadata entry.
+    fn marker_bytes() -> Vec<u8> {
+        let mut raw = vec![0, 0, 0, 0];
+        let time = 1500000000f64.to_bits();
+        raw.extend((0..8).rev().map(|byte| (time >> (8 * byte)) as u8));
+        raw.extend_from_slice(&[0xff, 0xc4]); // -60
+        raw.extend_from_slice(&[0, 0, 1, PARENTS_NONE, 1]);
+        raw.extend_from_slice(&[1; NODE_SIZE]);
+        raw.extend_from_slice(&[2; NODE_SIZE]);
+        raw.extend_from_slice(&[4, 1, b'u', b's', b'e', b'r', b'x']);
+        let size = raw.len() as u8;
+        raw[3] = size;
+        raw
+    }
+
+    #[test]
+    fn empty() {
+        assert_eq!(parse_obsstore(b"").unwrap(), vec![]);
+        assert_eq!(parse_obsstore(&[FORMAT_V1]).unwrap(), vec![]);
+    }
+
+    #[test]
+    fn one_marker() {
+        let mut obsstore = vec![FORMAT_V1];
+        obsstore.extend(marker_bytes());
+        let markers = parse_obsstore(&obsstore).unwrap();
+        assert_eq!(markers.len(), 1);
+        let marker = &markers[0];
+        assert_eq!(marker.precursor, NodeHash::from_bytes(&[1; NODE_SIZE]).unwrap());
+        assert_eq!(
+            marker.successors,
+            vec![NodeHash::from_bytes(&[2; NODE_SIZE]).unwrap()]
+        );
+        assert_eq!(marker.parents, None);
+        assert_eq!(marker.time, 1500000000.0);
+        assert_eq!(marker.tz, -60);
+        assert_eq!(marker.metadata, vec![(b"user".to_vec(), b"x".to_vec())]);
+        assert_eq!(marker.raw, marker_bytes());
+    }
+
+    #[test]
+    fn bad_obsstores() {
+        // Version 0.
+        assert!(parse_obsstore(&[0]).is_err());
+
+        let mut truncated = vec![FORMAT_V1];
+        truncated.extend(marker_bytes());
+        truncated.pop();
+        assert!(parse_obsstore(&truncated).is_err());
+    }
+}
@@ -29,8 +29,7 @@ mod lz4;
 #[cfg(test)]
 mod test;
 
-use self::parser::{Header, Version};
-pub use self::parser::Entry;
+pub use self::parser::{Entry, Header, Version};
 pub use self::revidx::RevIdx;
 
 #[derive(Debug)]
@@ -242,6 +241,16 @@ impl Revlog {
     pub fn get_heads(&self) -> Result<HashSet<NodeHash>> {
         self.inner.get_heads()
     }
+
+    /// Return the number of entries in the revlog. This is computed when the index is parsed,
+    /// so it's cheap to call.
+    pub fn len(&self) -> usize {
+        self.inner.idxoff.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
 }
 
 impl RevlogInner {
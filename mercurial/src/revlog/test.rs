@@ -17,3 +17,10 @@ fn emptyrev() {
 
     assert_eq!(node.size(), Some(0));
 }
+
+#[test]
+fn len() {
+    let revlog = Revlog::new(EMPTY.to_vec(), None).expect("construction failed");
+    assert_eq!(revlog.len(), 1);
+    assert!(!revlog.is_empty());
+}
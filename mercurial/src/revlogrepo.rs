@@ -8,7 +8,7 @@ use std::collections::HashSet;
 use std::collections::hash_map::{Entry, HashMap};
 use std::fmt::{self, Display};
 use std::fs;
-use std::io::{BufRead, BufReader};
+use std::io::{BufRead, BufReader, Read};
 use std::path::PathBuf;
 use std::str::FromStr;
 use std::sync::{Arc, RwLock};
@@ -28,6 +28,7 @@ use storage_types::Version;
 pub use changeset::RevlogChangeset;
 use errors::*;
 pub use manifest::RevlogManifest;
+use obsmarkers::{self, Obsmarker};
 use revlog::{self, Revlog, RevlogIter};
 
 type FutureResult<T> = future::FutureResult<T, Error>;
@@ -177,6 +178,12 @@ impl RevlogRepo {
         &self.changelog
     }
 
+    /// Return the total number of changesets in the repo.
+    #[inline]
+    pub fn changeset_count(&self) -> usize {
+        self.changelog.len()
+    }
+
     pub fn changeset_exists(&self, nodeid: &NodeHash) -> FutureResult<bool> {
         Ok(self.changelog.get_idx_by_nodeid(nodeid).is_ok()).into_future()
     }
@@ -358,6 +365,19 @@ impl RevlogRepo {
         }
     }
 
+    /// The repo's obsolescence markers. A repo without an obsstore has none.
+    pub fn get_obsmarkers(&self) -> Result<Vec<Obsmarker>> {
+        let path = self.basepath.join("store").join("obsstore");
+        if !path.exists() {
+            return Ok(Vec::new());
+        }
+        let mut data = Vec::new();
+        fs::File::open(path)
+            .and_then(|mut file| file.read_to_end(&mut data))
+            .context("Can't read `obsstore`")?;
+        obsmarkers::parse_obsstore(&data)
+    }
+
     pub fn bookmarks(&self) -> Result<StockBookmarks> {
         Ok(StockBookmarks::read(self.basepath.clone())?)
     }
@@ -200,6 +200,26 @@ where
         self.set(key, value, &Version::absent(), new_version)
     }
 
+    /// Write a batch of new entries straight to disk, all with version `version`. This skips the
+    /// per-key locks and `flock(2)` that `set` goes through, so it's only safe on a store that
+    /// nothing else is using yet, such as one that's just been created. Fails if any of the keys
+    /// already exist.
+    pub fn bulk_load<I, Q>(&self, entries: I, version: Version) -> Result<()>
+    where
+        I: IntoIterator<Item = (Q, V)>,
+        Q: Into<String>,
+    {
+        for (key, value) in entries {
+            let path = self.base.join(format!("{}{}", self.prefix, key.into()));
+            let out = serialize(&(value, version), Infinite)?;
+            let mut file = OpenOptions::new()
+                .write(true)
+                .create_new(true)
+                .open(&path)?;
+            file.write_all(&out)?;
+        }
+        Ok(())
+    }
 
     pub fn delete<Q: Into<String>>(
         &self,
@@ -211,7 +231,7 @@ where
         self.get_path_mutex(key)
             .into_future()
             .and_then(move |mutex| {
-                let future = poll_fn(move || poll_delete(&mutex, &version));
+                let future = poll_fn(move || poll_delete::<V>(&mutex, &version));
                 pool.spawn(future)
             })
     }
@@ -261,7 +281,7 @@ fn poll_set<V>(
     new_version: Version,
 ) -> Poll<Option<Version>, Error>
 where
-    V: Serialize,
+    V: Serialize + DeserializeOwned,
 {
     let path = path_mutex.lock().expect("Lock poisoned");
     let mut options = OpenOptions::new();
@@ -284,7 +304,7 @@ where
             } else {
                 let mut buf = Vec::new();
                 let _ = file.read_to_end(&mut buf)?;
-                deserialize::<(String, Version)>(&buf)?.1
+                deserialize::<(V, Version)>(&buf)?.1
             };
 
             // Write out new value if versions match.
@@ -313,10 +333,13 @@ where
 
 /// Synchronous implementation of the delete operation for the bookmark store. Intended to
 /// be used in conjunction with poll_fn() and a CpuPool to dispatch it onto a thread pool.
-fn poll_delete(
+fn poll_delete<V>(
     path_mutex: &Arc<Mutex<PathBuf>>,
     version: &Version,
-) -> Poll<Option<Version>, Error> {
+) -> Poll<Option<Version>, Error>
+where
+    V: DeserializeOwned,
+{
     let path = path_mutex.lock().expect("Lock poisoned");
 
     let result = match File::open(&*path) {
@@ -328,7 +351,7 @@ fn poll_delete(
             // Read version.
             let mut buf = Vec::new();
             let _ = file.read_to_end(&mut buf)?;
-            let file_version = deserialize::<(String, Version)>(&buf)?.1;
+            let file_version = deserialize::<(V, Version)>(&buf)?.1;
 
             // Unlink files if version matches, reporting success if the file
             // has already been deleted by another thread or process.
@@ -399,6 +422,22 @@ mod test {
         assert_eq!(kv.delete(foo, &absent).wait().unwrap().unwrap(), absent);
     }
 
+    #[test]
+    fn bulk_load() {
+        let tmp = TempDir::new("filekv_bulk_load").unwrap();
+        let kv = FileKV::open(tmp.path(), "kv:").unwrap();
+        let version = Version::from(1);
+
+        kv.bulk_load(vec![("foo", "1".to_string()), ("bar", "2".to_string())], version)
+            .unwrap();
+        assert_eq!(kv.get("foo").wait().unwrap(), Some(("1".to_string(), version)));
+        assert_eq!(kv.get("bar").wait().unwrap(), Some(("2".to_string(), version)));
+
+        // Existing keys aren't overwritten.
+        assert!(kv.bulk_load(vec![("foo", "3".to_string())], version).is_err());
+        assert_eq!(kv.get("foo").wait().unwrap(), Some(("1".to_string(), version)));
+    }
+
     #[test]
     fn persistence() {
         let tmp = TempDir::new("filebookmarks_heads_persistence").unwrap();